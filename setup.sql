@@ -58,6 +58,8 @@ CREATE TABLE `querydetails` (
  * Table storing the name of a file, and `rname` for the name actually used on the HDD (ASCII sanitized).
  * delete: files yayd should delete, marker for deletion, user triggered
  * valid: false if file was deleted
+ * cache_key: canonical media id + quality, for reusing the file on identical requests
+ * size: file size in bytes, used for storage quotas
  */
 CREATE TABLE `files` (
  `fid` int(10) unsigned NOT NULL AUTO_INCREMENT,
@@ -67,7 +69,10 @@ CREATE TABLE `files` (
  `delete` tinyint(1) NOT NULL DEFAULT '0',
  `created` timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
  `changed` timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+ `cache_key` varchar(100) CHARACTER SET ascii DEFAULT NULL,
+ `size` bigint(20) unsigned DEFAULT NULL,
  PRIMARY KEY (`fid`) USING BTREE,
+ KEY `cache_key` (`cache_key`),
  KEY `valid` (`valid`),
  KEY `delete` (`delete`),
  KEY `created` (`created`)
@@ -94,11 +99,131 @@ CREATE TABLE `subqueries` (
 /*
  * For file-query relations
  * Required for auto deletion of queries of old files
+ * delete: marker for deletion by this query, a file shared with other queries is kept for them
  */
 CREATE TABLE `query_files` (
  `qid` int(11) unsigned NOT NULL,
  `fid` int(10) unsigned NOT NULL,
+ `delete` tinyint(1) NOT NULL DEFAULT '0',
  UNIQUE KEY `qid` (`qid`,`fid`),
  KEY `qid_2` (`qid`),
  KEY `fid` (`fid`)
 ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+/*
+ * Finished processing stages of running jobs (video, audio download..)
+ * Used to skip these stages when resuming a job after a crash or restart
+ */
+CREATE TABLE `querystages` (
+ `qid` int(10) unsigned NOT NULL,
+ `stage` varchar(20) NOT NULL,
+ PRIMARY KEY (`qid`,`stage`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+/*
+ * Notification targets for finished jobs, either for all jobs of an user (`uid`) or a single job (`qid`)
+ * kind: `webhook` for an URL receiving a signed JSON POST, `email` for an address
+ */
+CREATE TABLE `notifytargets` (
+ `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+ `uid` int(11) unsigned DEFAULT NULL,
+ `qid` int(10) unsigned DEFAULT NULL,
+ `kind` varchar(10) NOT NULL,
+ `target` varchar(255) NOT NULL,
+ PRIMARY KEY (`id`),
+ KEY `uid` (`uid`),
+ KEY `qid` (`qid`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+/*
+ * Pending notifications, removed after delivery
+ * Failed deliveries are retried at `next_try` until `attempts` reaches the configured maximum
+ */
+CREATE TABLE `notifyoutbox` (
+ `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+ `qid` int(10) unsigned NOT NULL,
+ `kind` varchar(10) NOT NULL,
+ `target` varchar(255) NOT NULL,
+ `payload` text NOT NULL,
+ `attempts` smallint(6) NOT NULL DEFAULT '0',
+ `next_try` datetime NOT NULL,
+ PRIMARY KEY (`id`),
+ KEY `qid` (`qid`),
+ KEY `next_try` (`next_try`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+/*
+ * Append-only event history of queries: code changes, steps, finished stages and errors
+ * worker: host & process id of the yayd instance writing the event
+ */
+CREATE TABLE `queryevents` (
+ `id` bigint(20) unsigned NOT NULL AUTO_INCREMENT,
+ `qid` int(10) unsigned NOT NULL,
+ `created` datetime(3) NOT NULL,
+ `stage` varchar(20) NOT NULL,
+ `code` tinyint(4) DEFAULT NULL,
+ `worker` varchar(100) NOT NULL,
+ `message` text DEFAULT NULL,
+ PRIMARY KEY (`id`),
+ KEY `qid` (`qid`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+/*
+ * Encrypted credential profiles of users, for login-gated content
+ * kind: `cookies` for a cookies.txt file, `netrc` for a netrc file
+ * data: base64 of nonce & ciphertext, encrypted via `credentials.key`
+ */
+CREATE TABLE `credentials` (
+ `uid` int(11) unsigned NOT NULL,
+ `name` varchar(50) NOT NULL,
+ `kind` varchar(10) NOT NULL,
+ `data` mediumtext NOT NULL,
+ PRIMARY KEY (`uid`,`name`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+/*
+ * Credential profile used by a query, `name` of a profile of the query's user
+ */
+CREATE TABLE `querycredentials` (
+ `qid` int(10) unsigned NOT NULL,
+ `name` varchar(50) NOT NULL,
+ PRIMARY KEY (`qid`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+/*
+ * yt-dl version active on a host, updated on startup & after updates
+ * channel: `stable`, `nightly` or `pinned`
+ */
+CREATE TABLE `ytdlversions` (
+ `host` varchar(100) NOT NULL,
+ `version` varchar(50) NOT NULL,
+ `channel` varchar(10) NOT NULL,
+ `updated` datetime NOT NULL,
+ PRIMARY KEY (`host`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+/*
+ * Failed entries of zipped playlist queries, the query finishes with warnings
+ * idx: position of the entry in the extracted playlist, starting at 0
+ * kind: error kind, like `private` or `geo_blocked`, see README
+ * msg: error message, like the yt-dl output
+ */
+CREATE TABLE `playlistfailures` (
+ `qid` int(10) unsigned NOT NULL,
+ `idx` int(10) unsigned NOT NULL,
+ `url` varchar(125) NOT NULL,
+ `title` varchar(255) DEFAULT NULL,
+ `kind` varchar(20) NOT NULL,
+ `msg` text NOT NULL,
+ PRIMARY KEY (`qid`,`idx`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+/*
+ * Retry jobs of failed playlist entries, `origin` is the qid of the retried zip job
+ * The rebuilt archive replaces the file of the origin in `query_files`
+ */
+CREATE TABLE `queryretries` (
+ `qid` int(10) unsigned NOT NULL,
+ `origin` int(10) unsigned NOT NULL,
+ PRIMARY KEY (`qid`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,149 @@
+/* Copyright (c) 2015, Aron Heinecke
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+/*
+ * Setup SQL for required tables on PostgreSQL, see setup.sql for the table descriptions
+ * Unsigned integers are stored as BIGINT, reserved column names are quoted
+ */
+
+CREATE TABLE queries (
+ qid BIGSERIAL PRIMARY KEY,
+ url varchar(125) NOT NULL,
+ quality SMALLINT NOT NULL,
+ "type" SMALLINT NOT NULL,
+ created TIMESTAMP NOT NULL,
+ uid BIGINT NOT NULL
+);
+CREATE INDEX queries_created ON queries (created);
+CREATE INDEX queries_uid ON queries (uid);
+
+CREATE TABLE playlists (
+ qid BIGINT PRIMARY KEY,
+ "from" SMALLINT NOT NULL,
+ "to" SMALLINT NOT NULL,
+ split BOOLEAN NOT NULL
+);
+
+CREATE TABLE querydetails (
+ qid BIGINT PRIMARY KEY,
+ code SMALLINT NOT NULL,
+ progress DOUBLE PRECISION DEFAULT NULL,
+ status varchar(10) DEFAULT NULL,
+ luc TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX querydetails_code ON querydetails (code);
+
+CREATE TABLE files (
+ fid BIGSERIAL PRIMARY KEY,
+ name varchar(125) NOT NULL,
+ rname varchar(125) NOT NULL,
+ valid BOOLEAN NOT NULL,
+ "delete" BOOLEAN NOT NULL DEFAULT FALSE,
+ created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+ changed TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+ cache_key varchar(100) DEFAULT NULL,
+ size BIGINT DEFAULT NULL
+);
+CREATE INDEX files_cache_key ON files (cache_key);
+CREATE INDEX files_valid ON files (valid);
+CREATE INDEX files_delete ON files ("delete");
+CREATE INDEX files_created ON files (created);
+
+CREATE TABLE queryerror (
+ qid BIGINT PRIMARY KEY,
+ msg TEXT NOT NULL
+);
+
+CREATE TABLE subqueries (
+ qid BIGINT NOT NULL,
+ origin_id BIGINT NOT NULL,
+ PRIMARY KEY (qid,origin_id)
+);
+
+CREATE TABLE query_files (
+ qid BIGINT NOT NULL,
+ fid BIGINT NOT NULL,
+ "delete" BOOLEAN NOT NULL DEFAULT FALSE,
+ UNIQUE (qid,fid)
+);
+CREATE INDEX query_files_fid ON query_files (fid);
+
+CREATE TABLE querystages (
+ qid BIGINT NOT NULL,
+ stage varchar(20) NOT NULL,
+ PRIMARY KEY (qid,stage)
+);
+
+CREATE TABLE notifytargets (
+ id BIGSERIAL PRIMARY KEY,
+ uid BIGINT DEFAULT NULL,
+ qid BIGINT DEFAULT NULL,
+ kind varchar(10) NOT NULL,
+ target varchar(255) NOT NULL
+);
+CREATE INDEX notifytargets_uid ON notifytargets (uid);
+CREATE INDEX notifytargets_qid ON notifytargets (qid);
+
+CREATE TABLE notifyoutbox (
+ id BIGSERIAL PRIMARY KEY,
+ qid BIGINT NOT NULL,
+ kind varchar(10) NOT NULL,
+ target varchar(255) NOT NULL,
+ payload TEXT NOT NULL,
+ attempts SMALLINT NOT NULL DEFAULT 0,
+ next_try TIMESTAMP NOT NULL
+);
+CREATE INDEX notifyoutbox_qid ON notifyoutbox (qid);
+CREATE INDEX notifyoutbox_next_try ON notifyoutbox (next_try);
+
+CREATE TABLE queryevents (
+ id BIGSERIAL PRIMARY KEY,
+ qid BIGINT NOT NULL,
+ created TIMESTAMP(3) NOT NULL,
+ stage varchar(20) NOT NULL,
+ code SMALLINT DEFAULT NULL,
+ worker varchar(100) NOT NULL,
+ message TEXT DEFAULT NULL
+);
+CREATE INDEX queryevents_qid ON queryevents (qid);
+
+CREATE TABLE credentials (
+ uid BIGINT NOT NULL,
+ name varchar(50) NOT NULL,
+ kind varchar(10) NOT NULL,
+ data TEXT NOT NULL,
+ PRIMARY KEY (uid, name)
+);
+
+CREATE TABLE querycredentials (
+ qid BIGINT PRIMARY KEY,
+ name varchar(50) NOT NULL
+);
+
+CREATE TABLE ytdlversions (
+ host varchar(100) PRIMARY KEY,
+ version varchar(50) NOT NULL,
+ channel varchar(10) NOT NULL,
+ updated TIMESTAMP NOT NULL
+);
+
+CREATE TABLE playlistfailures (
+ qid BIGINT NOT NULL,
+ idx INTEGER NOT NULL,
+ url varchar(125) NOT NULL,
+ title varchar(255) DEFAULT NULL,
+ kind varchar(20) NOT NULL,
+ msg TEXT NOT NULL,
+ PRIMARY KEY (qid,idx)
+);
+
+CREATE TABLE queryretries (
+ qid BIGINT PRIMARY KEY,
+ origin BIGINT NOT NULL
+);
@@ -0,0 +1,144 @@
+/* Copyright (c) 2015, Aron Heinecke
+ * All rights reserved.
+ * Redistribution and use in source and binary forms, with or without modification, are permitted provided that the following conditions are met:
+ * 1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the following disclaimer in the documentation and/or other materials provided with the distribution.
+ * 3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote products derived from this software without specific prior written permission.
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+/*
+ * Setup SQL for required tables on SQLite, see setup.sql for the table descriptions
+ * Applied automatically on startup, existing tables are kept
+ * Timestamps are stored as text, written by yayd
+ */
+
+CREATE TABLE IF NOT EXISTS `queries` (
+ `qid` INTEGER PRIMARY KEY AUTOINCREMENT,
+ `url` TEXT NOT NULL,
+ `quality` INTEGER NOT NULL,
+ `type` INTEGER NOT NULL,
+ `created` TEXT NOT NULL,
+ `uid` INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS `queries_created` ON `queries` (`created`);
+CREATE INDEX IF NOT EXISTS `queries_uid` ON `queries` (`uid`);
+
+CREATE TABLE IF NOT EXISTS `playlists` (
+ `qid` INTEGER PRIMARY KEY,
+ `from` INTEGER NOT NULL,
+ `to` INTEGER NOT NULL,
+ `split` INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS `querydetails` (
+ `qid` INTEGER PRIMARY KEY,
+ `code` INTEGER NOT NULL,
+ `progress` REAL DEFAULT NULL,
+ `status` TEXT DEFAULT NULL,
+ `luc` TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS `querydetails_code` ON `querydetails` (`code`);
+
+CREATE TABLE IF NOT EXISTS `files` (
+ `fid` INTEGER PRIMARY KEY AUTOINCREMENT,
+ `name` TEXT NOT NULL,
+ `rname` TEXT NOT NULL,
+ `valid` INTEGER NOT NULL,
+ `delete` INTEGER NOT NULL DEFAULT 0,
+ `created` TEXT NOT NULL,
+ `cache_key` TEXT DEFAULT NULL,
+ `size` INTEGER DEFAULT NULL
+);
+CREATE INDEX IF NOT EXISTS `files_cache_key` ON `files` (`cache_key`);
+CREATE INDEX IF NOT EXISTS `files_created` ON `files` (`created`);
+
+CREATE TABLE IF NOT EXISTS `queryerror` (
+ `qid` INTEGER PRIMARY KEY,
+ `msg` TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS `subqueries` (
+ `qid` INTEGER NOT NULL,
+ `origin_id` INTEGER NOT NULL,
+ PRIMARY KEY (`qid`,`origin_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `query_files` (
+ `qid` INTEGER NOT NULL,
+ `fid` INTEGER NOT NULL,
+ `delete` INTEGER NOT NULL DEFAULT 0,
+ UNIQUE (`qid`,`fid`)
+);
+CREATE INDEX IF NOT EXISTS `query_files_fid` ON `query_files` (`fid`);
+
+CREATE TABLE IF NOT EXISTS `querystages` (
+ `qid` INTEGER NOT NULL,
+ `stage` TEXT NOT NULL,
+ PRIMARY KEY (`qid`,`stage`)
+);
+
+CREATE TABLE IF NOT EXISTS `notifytargets` (
+ `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+ `uid` INTEGER DEFAULT NULL,
+ `qid` INTEGER DEFAULT NULL,
+ `kind` TEXT NOT NULL,
+ `target` TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS `notifyoutbox` (
+ `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+ `qid` INTEGER NOT NULL,
+ `kind` TEXT NOT NULL,
+ `target` TEXT NOT NULL,
+ `payload` TEXT NOT NULL,
+ `attempts` INTEGER NOT NULL DEFAULT 0,
+ `next_try` TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS `notifyoutbox_next_try` ON `notifyoutbox` (`next_try`);
+
+CREATE TABLE IF NOT EXISTS `queryevents` (
+ `id` INTEGER PRIMARY KEY AUTOINCREMENT,
+ `qid` INTEGER NOT NULL,
+ `created` TEXT NOT NULL,
+ `stage` TEXT NOT NULL,
+ `code` INTEGER DEFAULT NULL,
+ `worker` TEXT NOT NULL,
+ `message` TEXT DEFAULT NULL
+);
+CREATE INDEX IF NOT EXISTS `queryevents_qid` ON `queryevents` (`qid`);
+
+CREATE TABLE IF NOT EXISTS `credentials` (
+ `uid` INTEGER NOT NULL,
+ `name` TEXT NOT NULL,
+ `kind` TEXT NOT NULL,
+ `data` TEXT NOT NULL,
+ PRIMARY KEY (`uid`,`name`)
+);
+
+CREATE TABLE IF NOT EXISTS `querycredentials` (
+ `qid` INTEGER PRIMARY KEY,
+ `name` TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS `ytdlversions` (
+ `host` TEXT PRIMARY KEY,
+ `version` TEXT NOT NULL,
+ `channel` TEXT NOT NULL,
+ `updated` TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS `playlistfailures` (
+ `qid` INTEGER NOT NULL,
+ `idx` INTEGER NOT NULL,
+ `url` TEXT NOT NULL,
+ `title` TEXT DEFAULT NULL,
+ `kind` TEXT NOT NULL,
+ `msg` TEXT NOT NULL,
+ PRIMARY KEY (`qid`,`idx`)
+);
+
+CREATE TABLE IF NOT EXISTS `queryretries` (
+ `qid` INTEGER PRIMARY KEY,
+ `origin` INTEGER NOT NULL
+);
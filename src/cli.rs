@@ -0,0 +1,522 @@
+//! Command line interface, administrative subcommands next to running the worker
+
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{eyre, Result};
+
+use std::fs::{create_dir_all, remove_dir};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::handler::init_handlers;
+use crate::lib;
+use crate::lib::config;
+use crate::lib::console::Reporter;
+use crate::lib::converter::Converter;
+use crate::lib::credentials;
+use crate::lib::db::{self, DeleteRequestType, PlaylistRange, Repository, SqliteRepository};
+use crate::lib::downloader::Downloader;
+use crate::lib::ffmpeg;
+use crate::lib::notify;
+use crate::lib::signals;
+use crate::lib::storage::{self, LocalStorage, Storage};
+use crate::CONFIG;
+use crate::{CODE_FAILED_AGE_RESTRICTED, CODE_FAILED_COPYRIGHT, CODE_FAILED_GEO_BLOCKED};
+use crate::{CODE_FAILED_INTERNAL, CODE_FAILED_QUALITY, CODE_FAILED_QUOTA, CODE_FAILED_TIMEOUT};
+use crate::{CODE_FAILED_LOGIN_REQUIRED, CODE_FAILED_PREMIERE, CODE_FAILED_PRIVATE};
+use crate::{CODE_FAILED_RATE_LIMITED, CODE_FAILED_REMOVED};
+use crate::{CODE_FAILED_UNAVAILABLE, CODE_FAILED_UNKNOWN};
+use crate::{CODE_SUCCESS, CODE_SUCCESS_WARNINGS};
+
+/// Interval of the console progress output in one-shot mode
+const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Parser)]
+#[clap(
+    version,
+    about = "Download backend for yt-dlp, processing jobs queued in a DB"
+)]
+pub struct Cli {
+    /// Config file to use, defaults to $YAYD_CONFIG or config.cfg next to the binary
+    #[clap(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Process queued jobs, default without a subcommand
+    Run,
+    /// Queue a job, prints its qid
+    Enqueue {
+        url: String,
+        /// Quality code, see the README
+        #[clap(long, default_value_t = 137, allow_hyphen_values = true)]
+        quality: i16,
+        /// Download all entries of a playlist
+        #[clap(long)]
+        playlist: bool,
+        /// Queue a job per playlist entry, instead of one zip file
+        #[clap(long, requires = "playlist")]
+        split: bool,
+        /// User id of the job
+        #[clap(long, default_value_t = 0)]
+        uid: u32,
+        /// Credential profile of the user to use for the job
+        #[clap(long, value_name = "NAME")]
+        credentials: Option<String>,
+    },
+    /// Run a single job without DB, storing its files in the output dir
+    Get {
+        url: String,
+        /// Quality code, see the README
+        #[clap(long, default_value_t = 137, allow_hyphen_values = true)]
+        quality: i16,
+        /// Download all entries of a playlist
+        #[clap(long)]
+        playlist: bool,
+        /// Store every playlist entry, instead of one zip file
+        #[clap(long, requires = "playlist")]
+        split: bool,
+        /// Directory for the finished files
+        #[clap(long, default_value = ".")]
+        out: PathBuf,
+    },
+    /// Show the state, files & events of a job
+    Status { qid: u64 },
+    /// Queue a job retrying the failed entries of a playlist job, prints its qid
+    Retry { qid: u64 },
+    /// Delete aged & marked files, both if none is selected
+    Cleanup {
+        /// Delete files older than `auto_delete_age`
+        #[clap(long)]
+        aged: bool,
+        /// Delete files marked by the `delete` flag
+        #[clap(long)]
+        marked: bool,
+        /// Only list the files to delete
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Create missing tables & apply the schema upgrades of the last release
+    Migrate,
+    /// Validate the config, check the DB connection & external binaries
+    CheckConfig,
+    /// Update yt-dl to its latest release
+    UpdateYtdl,
+    /// Install the ffmpeg build of `ffmpeg.mirror` if it changed
+    UpdateFfmpeg,
+    /// Manage the encrypted credential profiles of users
+    Credentials {
+        #[clap(subcommand)]
+        command: CredentialsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CredentialsCommand {
+    /// Store a cookies.txt or netrc file as profile, replacing an existing one
+    Add {
+        uid: u32,
+        name: String,
+        /// Profile type: cookies or netrc
+        kind: String,
+        file: PathBuf,
+    },
+    /// List the profiles of a user
+    List { uid: u32 },
+    /// Delete a profile
+    Remove { uid: u32, name: String },
+}
+
+/// Execute an administrative command, `Run` is handled by main
+pub fn execute(command: Command) -> Result<()> {
+    match command {
+        Command::Run => unreachable!("run is handled by main"),
+        Command::Enqueue {
+            url,
+            quality,
+            playlist,
+            split,
+            uid,
+            credentials,
+        } => enqueue(&url, quality, playlist, split, uid, credentials.as_deref()),
+        Command::Get {
+            url,
+            quality,
+            playlist,
+            split,
+            out,
+        } => {
+            signals::install(
+                Duration::from_secs(CONFIG.general.shutdown_grace as u64),
+                crate::reload,
+            );
+            get(&url, quality, playlist, split, &out)
+        }
+        Command::Status { qid } => status(qid),
+        Command::Retry { qid } => {
+            let db = db::open(&CONFIG)?;
+            let info = db
+                .get_query_info(&qid)?
+                .ok_or(eyre!("No job with qid {}", qid))?;
+            println!("{}", db::add_retry_query(&*db, &info)?);
+            Ok(())
+        }
+        Command::Cleanup {
+            aged,
+            marked,
+            dry_run,
+        } => {
+            let all = !aged && !marked;
+            if aged || all {
+                cleanup(
+                    DeleteRequestType::AgedMin(&CONFIG.cleanup.auto_delete_age),
+                    dry_run,
+                )?;
+            }
+            if marked || all {
+                cleanup(DeleteRequestType::Marked, dry_run)?;
+            }
+            Ok(())
+        }
+        Command::Migrate => {
+            db::open(&CONFIG)?.migrate()?;
+            println!("Schema is up to date");
+            Ok(())
+        }
+        Command::CheckConfig => check_config(),
+        Command::UpdateYtdl => {
+            let db = db::open(&CONFIG)?;
+            let mut downloader = Downloader::new(&CONFIG.general);
+            downloader.set_db(db.clone());
+            downloader.update_downloader()?;
+            match db.get_ytdl_version(&lib::hostname())? {
+                Some((version, channel)) => println!("yt-dl version {} ({})", version, channel),
+                None => println!("yt-dl version {}", downloader.version()?),
+            }
+            Ok(())
+        }
+        Command::UpdateFfmpeg => {
+            if !CONFIG.ffmpeg.managed {
+                return Err(eyre!("ffmpeg isn't managed, see ffmpeg.managed"));
+            }
+            let bin_dir = Path::new(&CONFIG.general.ffmpeg_bin_dir);
+            match ffmpeg::update(&CONFIG.ffmpeg, bin_dir)? {
+                true => println!("Installed new ffmpeg build"),
+                false => println!("ffmpeg is up to date"),
+            }
+            Ok(())
+        }
+        Command::Credentials { command } => manage_credentials(command),
+    }
+}
+
+/// Returns the range of a playlist request, containing all entries
+fn playlist_range(playlist: bool, split: bool) -> Option<PlaylistRange> {
+    match playlist {
+        true => Some(PlaylistRange {
+            from: 0,
+            to: 0,
+            split,
+        }),
+        false => None,
+    }
+}
+
+fn enqueue(
+    url: &str,
+    quality: i16,
+    playlist: bool,
+    split: bool,
+    uid: u32,
+    credentials: Option<&str>,
+) -> Result<()> {
+    let db = db::open(&CONFIG)?;
+    if let Some(name) = credentials {
+        if db.get_credential(&uid, name)?.is_none() {
+            return Err(eyre!("No credential profile {} for uid {}", name, uid));
+        }
+    }
+    let range = playlist_range(playlist, split);
+    let qid = db.add_query(url, &quality, &uid, &0, range)?;
+    if let Some(name) = credentials {
+        db.set_query_credential(&qid, name)?;
+    }
+    println!("{}", qid);
+    Ok(())
+}
+
+fn manage_credentials(command: CredentialsCommand) -> Result<()> {
+    let db = db::open(&CONFIG)?;
+    match command {
+        CredentialsCommand::Add {
+            uid,
+            name,
+            kind,
+            file,
+        } => {
+            let kind = credentials::Kind::parse(&kind)
+                .ok_or(eyre!("Unknown profile type {}, use cookies or netrc", kind))?;
+            let content = std::fs::read(&file)?;
+            credentials::store(&*db, &uid, &name, kind, &content)?;
+            println!("Stored profile {} for uid {}", name, uid);
+        }
+        CredentialsCommand::List { uid } => {
+            for (name, kind) in db.get_credential_names(&uid)? {
+                println!("{}\t{}", name, kind);
+            }
+        }
+        CredentialsCommand::Remove { uid, name } => {
+            if !db.delete_credential(&uid, &name)? {
+                return Err(eyre!("No credential profile {} for uid {}", name, uid));
+            }
+            println!("Removed profile {} of uid {}", name, uid);
+        }
+    }
+    Ok(())
+}
+
+/// One-shot mode, handling the job & its sub jobs in an in-memory DB
+/// Prints the names of the stored files, fails if any job failed
+fn get(url: &str, quality: i16, playlist: bool, split: bool, out: &Path) -> Result<()> {
+    let converter = Converter::new(&CONFIG.general.ffmpeg_bin_dir, &CONFIG.general.mp3_quality);
+    if !converter.startup_test() {
+        return Err(eyre!("Converter self test failed"));
+    }
+    let downloader = Arc::new(Downloader::new(&CONFIG.general));
+    if !downloader.startup_test() {
+        return Err(eyre!("Downloader self test failed"));
+    }
+
+    create_dir_all(out)?;
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(&out.to_string_lossy()));
+    let existing = storage.list()?;
+    // private workspaces, a worker sharing the temp dir would collide with our qids
+    let workspace = out.join(format!(".yayd-{}", std::process::id()));
+    let mut registry = init_handlers(downloader, converter, storage.clone());
+    registry.set_workspace_dir(workspace.clone());
+
+    let db: Arc<dyn Repository> = Arc::new(SqliteRepository::open_in_memory()?);
+    db.add_query(url, &quality, &0, &0, playlist_range(playlist, split))?;
+    let reporter = Reporter::start(db.clone(), REPORT_INTERVAL);
+    let shutdown = signals::state();
+    while !shutdown.shutdown_requested() && crate::handle_next(&db, &mut registry) {}
+    reporter.stop();
+    let _ = remove_dir(&workspace);
+    if shutdown.shutdown_requested() {
+        return Err(eyre!("Interrupted"));
+    }
+
+    let failed_codes = [
+        CODE_FAILED_INTERNAL,
+        CODE_FAILED_QUALITY,
+        CODE_FAILED_UNAVAILABLE,
+        CODE_FAILED_UNKNOWN,
+        CODE_FAILED_QUOTA,
+        CODE_FAILED_TIMEOUT,
+        CODE_FAILED_GEO_BLOCKED,
+        CODE_FAILED_AGE_RESTRICTED,
+        CODE_FAILED_LOGIN_REQUIRED,
+        CODE_FAILED_PRIVATE,
+        CODE_FAILED_REMOVED,
+        CODE_FAILED_PREMIERE,
+        CODE_FAILED_RATE_LIMITED,
+        CODE_FAILED_COPYRIGHT,
+    ];
+    let failed = db.get_queries_by_code(&failed_codes)?;
+    let finished = db.get_queries_by_code(&[CODE_SUCCESS, CODE_SUCCESS_WARNINGS])?;
+    for qid in failed.iter().chain(finished.iter()) {
+        if let Some(info) = db.get_query_info(qid)? {
+            eprintln!(
+                "[{}] {}: {}{}",
+                qid,
+                info.url,
+                notify::status_name(info.code),
+                info.error.map(|e| format!(", {}", e)).unwrap_or_default()
+            );
+        }
+        for failure in db.get_entry_failures(qid)? {
+            eprintln!("  #{} {}: {}", failure.index, failure.url, failure.kind);
+        }
+    }
+    for name in storage.list()? {
+        if !existing.contains(&name) {
+            println!("{}", out.join(name).display());
+        }
+    }
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(eyre!(
+            "{} of {} jobs failed",
+            failed.len(),
+            failed.len() + finished.len()
+        )),
+    }
+}
+
+fn status(qid: u64) -> Result<()> {
+    let db = db::open(&CONFIG)?;
+    let info = db
+        .get_query_info(&qid)?
+        .ok_or(eyre!("No job with qid {}", qid))?;
+    println!("qid:      {}", info.qid);
+    println!("uid:      {}", info.uid);
+    println!("url:      {}", info.url);
+    println!("quality:  {}", info.quality);
+    println!("created:  {}", info.created);
+    if let Some(origin) = db.get_query_retry(&qid)? {
+        println!("retry of: {}", origin);
+    }
+    println!(
+        "code:     {} ({})",
+        info.code,
+        notify::status_name(info.code)
+    );
+    if let Some(progress) = info.progress {
+        println!("progress: {}%", progress);
+    }
+    if let Some(status) = info.status {
+        println!("status:   {}", status);
+    }
+    if let Some(error) = info.error {
+        println!("error:    {}", error);
+    }
+    for (name, real_name) in db.get_query_files(&qid)? {
+        println!("file:     {} ({})", name, real_name);
+    }
+    for event in db.get_events(&qid)? {
+        println!(
+            "event:    {} {} {} {}",
+            event.created,
+            event.stage,
+            event.code.map(|c| c.to_string()).unwrap_or_default(),
+            event.message.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+fn cleanup(delete_type: DeleteRequestType, dry_run: bool) -> Result<()> {
+    let kind = match delete_type {
+        DeleteRequestType::AgedMin(_) => "aged",
+        DeleteRequestType::Marked => "marked",
+    };
+    let db = db::open(&CONFIG)?;
+    let (qids, files) = lib::files_to_delete(&*db, delete_type)?;
+    for (fid, name) in &files {
+        println!("{}\t{}", fid, name);
+    }
+    if dry_run {
+        println!(
+            "{}: {} files of {} jobs to delete",
+            kind,
+            files.len(),
+            qids.len()
+        );
+    } else {
+        let storage = storage::init_storage(&CONFIG);
+        lib::delete_files(&*db, delete_type, &*storage)?;
+        println!("{}: deleted {} files", kind, files.len());
+    }
+    Ok(())
+}
+
+fn check_config() -> Result<()> {
+    // validate before accessing CONFIG, which would exit on the first problem
+    let path = config::config_path();
+    let problems = config::load_config(&path)?.validate();
+    for problem in &problems {
+        println!("config: FAILED {}", problem);
+    }
+    if !problems.is_empty() {
+        return Err(eyre!("{} problems in {}", problems.len(), path.display()));
+    }
+    println!("config: ok");
+
+    let mut ok = true;
+    let mut check = |name: &str, result: std::result::Result<(), String>| match result {
+        Ok(_) => println!("{}: ok", name),
+        Err(e) => {
+            ok = false;
+            println!("{}: FAILED {}", name, e);
+        }
+    };
+    check(
+        "db",
+        db::open(&CONFIG)
+            .and_then(|db| db.get_queue_stats())
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    );
+    let converter = Converter::new(&CONFIG.general.ffmpeg_bin_dir, &CONFIG.general.mp3_quality);
+    check(
+        "ffmpeg",
+        match converter.startup_test() {
+            true => Ok(()),
+            false => Err(format!("not found in {}", CONFIG.general.ffmpeg_bin_dir)),
+        },
+    );
+    check(
+        "yt-dl",
+        Downloader::new(&CONFIG.general)
+            .version()
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    );
+    match ok {
+        true => Ok(()),
+        false => Err(eyre!("Config check failed")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::harness::Harness;
+
+    /// Returns the sorted file names of a dir
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn get_test() {
+        let _harness = Harness::new();
+        let out = Path::new(&CONFIG.general.download_dir).join("get");
+        get(
+            "https://www.youtube.com/watch?v=okGet",
+            137,
+            false,
+            false,
+            &out,
+        )
+        .unwrap();
+        assert_eq!(vec!["video_okGet.mp4"], files(&out));
+
+        assert!(get(
+            "https://www.youtube.com/watch?v=brokenGet",
+            137,
+            false,
+            false,
+            &out
+        )
+        .is_err());
+        assert_eq!(vec!["video_okGet.mp4"], files(&out));
+    }
+
+    #[test]
+    fn get_playlist_test() {
+        let _harness = Harness::new();
+        let out = Path::new(&CONFIG.general.download_dir).join("get");
+        let url = "https://www.youtube.com/playlist?list=PLget";
+        get(url, 22, true, true, &out).unwrap();
+        assert_eq!(vec!["video_okFirst.mp4", "video_okSecond.mp4"], files(&out));
+    }
+}
@@ -2,20 +2,32 @@ mod soundcloud;
 mod twitch;
 mod youtube;
 
+use crate::lib;
+use crate::lib::config;
 use crate::lib::converter::Converter;
-use crate::lib::db;
-use crate::lib::downloader::Downloader;
+use crate::lib::credentials;
+use crate::lib::downloader::{Downloader, Filename};
+use crate::lib::signals;
+use crate::lib::storage::{self, Storage};
 use crate::lib::Request;
 use crate::lib::{Error, Result};
-use std::fs::remove_dir_all;
-use std::fs::remove_file;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::fs::{metadata, remove_file};
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::vec::Vec;
 
+use crate::CODE_SUCCESS;
 use crate::CONFIG;
 
+/// Video download stage, recorded for resuming
+const STAGE_VIDEO: &str = "video";
+/// Audio download stage, recorded for resuming
+const STAGE_AUDIO: &str = "audio";
+/// Workspace sub dir for finished files, before they're stored
+const OUT_DIR: &str = "out";
+
 /// Structure holding a list of produced and left files
 /// `left_files` is a storage used for temporary files
 /// on handler failure all files listed in the temporary variable will be deleted
@@ -27,6 +39,7 @@ struct HandleData<'a> {
     left_files: Vec<PathBuf>,
     pub downloader: &'a Downloader,
     pub converter: &'a Converter<'a>,
+    pub storage: &'a dyn Storage,
 }
 
 /// Structure for storing files to be inserted into the file db later
@@ -38,12 +51,17 @@ struct FileEntry {
 
 #[allow(non_snake_case)]
 impl<'a> HandleData<'a> {
-    pub fn new(converter: &'a Converter, downloader: &'a Downloader) -> HandleData<'a> {
+    pub fn new(
+        converter: &'a Converter,
+        downloader: &'a Downloader,
+        storage: &'a dyn Storage,
+    ) -> HandleData<'a> {
         HandleData {
             files: Vec::new(),
             left_files: Vec::new(),
             converter: converter,
             downloader: downloader,
+            storage: storage,
         }
     }
 
@@ -78,10 +96,15 @@ impl<'a> HandleData<'a> {
 /// A Module consisting of it's checker, handler & information if files need to be deleted in a extended way on errors.
 /// Every handler (XY.rs) can register multiple modules, see youtube.rs for example
 pub struct Module {
+    /// Name of the handler, used for metrics
+    name: &'static str,
     /// Checking module, returning true if it's able to handle the URL
     checker: Box<dyn Fn(&Request) -> bool>,
     /// Handler, called when the checking module returns true
     handler: Box<dyn Fn(&mut HandleData, &mut Request) -> Result<()>>,
+    /// Result cache key, canonical media id plus quality profile
+    /// None for requests which can't be cached
+    cache_key: Box<dyn Fn(&Request) -> Option<String>>,
 }
 
 /// Registry holding all available modules
@@ -89,29 +112,169 @@ pub struct Registry<'a> {
     modules: Vec<Module>,
     downloader: Arc<Downloader>,
     converter: Converter<'a>,
+    storage: Arc<dyn Storage>,
+    /// Directory holding the per query workspaces
+    workspace_dir: PathBuf,
 }
 
 impl<'a> Registry<'a> {
-    pub fn new(downloader: Arc<Downloader>, converter: Converter<'a>) -> Registry<'a> {
+    pub fn new(
+        downloader: Arc<Downloader>,
+        converter: Converter<'a>,
+        storage: Arc<dyn Storage>,
+    ) -> Registry<'a> {
         Registry {
             downloader: downloader,
             converter: converter,
+            storage: storage,
             modules: Vec::new(),
+            workspace_dir: PathBuf::from(&CONFIG.general.temp_dir),
         }
     }
 
+    /// Set the directory of the per query workspaces, defaults to `temp_dir`
+    pub fn set_workspace_dir(&mut self, dir: PathBuf) {
+        self.workspace_dir = dir;
+    }
+
     /// Register a module
     fn register(&mut self, module: Module) {
         self.modules.push(module);
     }
 
+    /// Link the request to an already existing file with the same cache key
+    /// Returns false if no valid file was found
+    fn link_cached(&self, data: &Request, key: &str) -> Result<bool> {
+        let db = data.db();
+        if let Some((fid, name)) = db.get_cached_file(key)? {
+            if self.storage.exists(&name)? {
+                debug!("cache hit for {}: fid {}", key, fid);
+                db.add_file_link(&data.qid, &fid)?;
+                return Ok(true);
+            }
+            warn!("cached file {:?} is missing, invalidating", name);
+            db.set_file_valid_flag(&fid, false)?;
+        }
+        Ok(false)
+    }
+
+    /// Move the produced files into the storage and insert them into the files db
+    /// Files of a successful request producing a single file are reusable by the result cache
+    /// Returns the fids of the stored files
+    fn store_files(
+        &self,
+        handle_db: &mut HandleData,
+        data: &Request,
+        cache_key: Option<String>,
+        success: bool,
+    ) -> Result<Vec<u64>> {
+        let cacheable = success && handle_db.getFiles().len() == 1;
+        let mut fids = Vec::new();
+        for file in handle_db.getFiles() {
+            let size = metadata(&file.path).map(|m| m.len()).unwrap_or(0);
+            let name = Filename::from_path(&file.path)?;
+            let name = storage::unique_name(&*self.storage, &name)?;
+            self.storage.store(&file.path, &name)?;
+            let fid = data
+                .db()
+                .add_file_entry(&data.qid, &name, &file.origin_name, &size)?;
+            if cacheable {
+                if let Some(ref key) = cache_key {
+                    data.db().set_file_cache_key(&fid, key)?;
+                }
+            }
+            fids.push(fid);
+        }
+        Ok(fids)
+    }
+
+    /// Replace the files of the retried query by the rebuilt archive
+    /// The failed entries of the retry are taken over, the query succeeded if none are left
+    fn replace_retried(&self, data: &Request, origin: &u64, fids: &[u64]) -> Result<()> {
+        let db = data.db();
+        let new_fid = match fids.first() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let old_fids = db
+            .get_query_info(origin)?
+            .map(|v| v.files)
+            .unwrap_or_default();
+        let mut replaced = Vec::new();
+        for fid in old_fids {
+            if let Some(file) = db.get_file(&fid)? {
+                debug!("replacing file {} of {} by {}", fid, origin, new_fid);
+                db.replace_file_link(&fid, new_fid)?;
+                replaced.push((fid, file.name));
+            }
+        }
+        db.delete_requests(Vec::new(), replaced.clone())?;
+
+        db.clear_entry_failures(origin)?;
+        let failures = db.get_entry_failures(&data.qid)?;
+        for failure in &failures {
+            db.add_entry_failure(origin, failure)?;
+        }
+        if failures.is_empty() {
+            db.set_query_code(origin, &CODE_SUCCESS);
+        }
+        // removed from the storage only after the DB no longer references them
+        for (_, name) in &replaced {
+            storage::remove_logged(&*self.storage, name);
+        }
+        Ok(())
+    }
+
     /// Handle a request with it's appropriate handler, if existing
     /// Returns an error on failure
     pub fn handle(&mut self, data: &mut Request) -> Result<()> {
-        let mut handle_db = HandleData::new(&self.converter, &self.downloader);
+        let mut handle_db = HandleData::new(&self.converter, &self.downloader, &*self.storage);
 
         if let Some(module) = self.modules.iter().find(|module| (module.checker)(&data)) {
-            let result = (module.handler)(&mut handle_db, data);
+            // credential profile of the job, its files aren't shared via the result cache
+            let profile = data.db().get_query_credential(&data.qid)?;
+            data.retry = data.db().get_query_retry(&data.qid)?;
+            let cache_key =
+                if profile.is_none() && config::live().result_cache && CONFIG.general.link_files {
+                    (module.cache_key)(data)
+                } else {
+                    None
+                };
+            if let Some(ref key) = cache_key {
+                if self.link_cached(data, key)? {
+                    data.cached = true;
+                    return Ok(());
+                }
+            }
+
+            data.handler = Some(module.name);
+
+            // per query workspace, left over from an interrupted run when resuming
+            // finished files are created in its `out` dir and stored afterwards
+            data.temp_path = self.workspace_dir.join(data.qid.to_string());
+            data.path = data.temp_path.join(OUT_DIR);
+            create_dir_all(&data.path)?;
+
+            let session = credentials::Session::open(data, profile.as_deref());
+            let result = session.and_then(|credentials| {
+                data.ytdl_args = credentials.args();
+                // the size probe requires the login of the profile too
+                let result = lib::quota::check_request(&self.downloader, &*self.storage, data)
+                    .and_then(|_| (module.handler)(&mut handle_db, data));
+                // wipe the profile file before anything else happens to the workspace
+                drop(credentials);
+                result
+            });
+
+            // requeued by the worker, partial outputs aren't stored even if the handler succeeded
+            let aborted = signals::state().job_aborted();
+            if aborted && CONFIG.general.resume_interrupted {
+                debug!(
+                    "keeping workspace & stages of aborted {} for resuming",
+                    data.qid
+                );
+                return result;
+            }
 
             if !handle_db.getLeftFiles().is_empty() {
                 // cleanup if left files isn't empty
@@ -124,6 +287,19 @@ impl<'a> Registry<'a> {
                 }
             }
 
+            // store files before the workspace is removed
+            let stored = if aborted {
+                Ok(())
+            } else {
+                self.store_files(&mut handle_db, data, cache_key, result.is_ok())
+                    .and_then(|fids| match data.retry {
+                        Some(ref origin) if result.is_ok() => {
+                            self.replace_retried(data, origin, &fids)
+                        }
+                        _ => Ok(()),
+                    })
+            };
+
             if data.temp_path != PathBuf::from(&CONFIG.general.temp_dir) {
                 // delete temp path if different from default
                 match remove_dir_all(&data.temp_path) {
@@ -132,27 +308,34 @@ impl<'a> Registry<'a> {
                 }
             }
 
-            if !handle_db.getFiles().is_empty() {
-                // insert processed files into the db
-                for file in handle_db.getFiles() {
-                    db::add_file_entry(
-                        &mut data.get_conn(),
-                        &data.qid,
-                        &file.path.file_name().unwrap().to_string_lossy(),
-                        &file.origin_name,
-                    )?;
-                }
+            if let Err(e) = data.db().clear_stages(&data.qid) {
+                warn!("unable to clear stages of {}: {}", data.qid, e);
             }
-            result
+
+            result.and(stored)
         } else {
             Err(Error::UnknownURL)
         }
     }
 }
 
+/// Returns true if the stage was already finished by an interrupted run of this request
+fn stage_finished(request: &Request, stage: &str) -> Result<bool> {
+    request.db().is_stage_done(&request.qid, stage)
+}
+
+/// Record a finished stage, allowing to skip it when resuming this request
+fn finish_stage(request: &Request, stage: &str) -> Result<()> {
+    request.db().set_stage_done(&request.qid, stage)
+}
+
 /// Init handlers
-pub fn init_handlers<'a>(downloader: Arc<Downloader>, converter: Converter<'a>) -> Registry<'a> {
-    let mut registry = Registry::new(downloader, converter);
+pub fn init_handlers<'a>(
+    downloader: Arc<Downloader>,
+    converter: Converter<'a>,
+    storage: Arc<dyn Storage>,
+) -> Registry<'a> {
+    let mut registry = Registry::new(downloader, converter, storage);
     youtube::init(&mut registry);
     twitch::init(&mut registry);
 
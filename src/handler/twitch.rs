@@ -1,10 +1,7 @@
 extern crate regex;
 
 use super::{HandleData, Module, Registry};
-use crate::lib::db;
 use crate::lib::{self, Error, Request, Result};
-use std::fs::create_dir;
-use std::fs::remove_dir_all;
 use std::fs::rename;
 
 use crate::CODE_IN_PROGRESS;
@@ -16,14 +13,16 @@ macro_rules! regex(
 lazy_static! {
 // https://regex101.com/r/sI0lK2/1
 // we need to remove the / escaping!
-    pub static ref REGEX_VIDEO: regex::Regex = regex!(r"https?://(secure|www)\.twitch\.tv/[A-Za-z0-9]+/v/[0-9]+");
+    pub static ref REGEX_VIDEO: regex::Regex = regex!(r"https?://(secure|www)\.twitch\.tv/[A-Za-z0-9]+/v/([0-9]+)");
 }
 
 /// Init twitch handler, registering it
 pub fn init(registry: &mut Registry) {
     registry.register(Module {
+        name: "twitch",
         checker: Box::new(checker_file),
         handler: Box::new(handle_file),
+        cache_key: Box::new(cache_key_file),
     });
 }
 
@@ -35,32 +34,36 @@ fn checker_file(data: &Request) -> bool {
     REGEX_VIDEO.is_match(&data.url)
 }
 
+/// Result cache key of a VOD, VOD id plus quality
+fn cache_key_file(data: &Request) -> Option<String> {
+    REGEX_VIDEO
+        .captures(&data.url)
+        .map(|cap| format!("twitch:{}:{}:{}", &cap[2], data.quality, data.r_type))
+}
+
 /// Handle file request
 fn handle_file(hdb: &mut HandleData, request: &mut Request) -> Result<()> {
-    db::set_query_code(&mut request.get_conn(), &request.qid, &CODE_IN_PROGRESS);
+    request.db().set_query_code(&request.qid, &CODE_IN_PROGRESS);
 
-    request.temp_path.push(request.qid.to_string()); // create sub dir for part files
-    create_dir(&request.temp_path)?;
-    let mut temp_file_v = request.temp_path.clone(); // create file with qid in dir
+    let mut temp_file_v = request.temp_path.clone(); // create file with qid in workspace
     temp_file_v.push(request.qid.to_string());
     hdb.push(&temp_file_v);
 
     trace!("Retriving name");
-    db::update_steps(&mut request.get_conn(), &request.qid, 1, 2);
+    request.db().update_steps(&request.qid, 1, 2);
     let quality = get_quality(&request.quality)?;
-    let name = hdb.downloader.get_file_name(&request.url, None)?;
+    let name = hdb.downloader.get_file_name(request, None)?;
     debug!("name: {}.{}", &name.name, &name.extension);
 
     let save_file = lib::format_save_path(&request.path, &name)?;
 
-    db::update_steps(&mut request.get_conn(), &request.qid, 2, 2);
+    request.db().update_steps(&request.qid, 2, 2);
     trace!("downloading video");
     hdb.downloader
         .download_file(&request, &temp_file_v, &quality)?;
     rename(&temp_file_v, &save_file)?;
 
     hdb.addFile(&save_file, &name.full_name());
-    remove_dir_all(&request.temp_path)?;
     hdb.pop();
 
     Ok(())
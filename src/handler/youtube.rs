@@ -1,9 +1,12 @@
 extern crate regex;
 
-use super::{HandleData, Module, Registry};
-use crate::lib::downloader::Filename;
+use super::{finish_stage, stage_finished, HandleData, Module, Registry};
+use super::{STAGE_AUDIO, STAGE_VIDEO};
+use crate::lib::downloader::{Filename, PlaylistEntry};
+use crate::lib::failure::Failure;
+use crate::lib::signals;
 use crate::lib::{self, db, Error, Request, Result};
-use std::fs::create_dir;
+use std::fs::create_dir_all;
 use std::fs::remove_dir_all;
 use std::fs::remove_file;
 use std::path::Path;
@@ -19,25 +22,32 @@ macro_rules! condition(
     ($e:expr,$y:expr) => (match $y { true => {$e;}, false => ()} );
 );
 
-const YT_VIDEO_URL: &'static str = "https://www.youtube.com/watch?v=";
+const YT_VIDEO_URL: &str = "https://www.youtube.com/watch?v=";
+/// Workspace file holding the archive of a retried playlist job
+const RETRY_ARCHIVE: &str = "retry.zip";
 
 lazy_static! {
 // https://regex101.com/r/lZ6lC1/3
 // we need to remove the / escaping!
     pub static ref REGEX_VIDEO: regex::Regex = regex!(r"https?://(www\.|m\.)?(youtube\.[a-z]{2,3}/watch\?(feature=player_embedded&)?(list=[a-zA-Z0-9_-]+&)?v=[a-zA-Z0-9_-]+|youtu\.be/[a-zA-Z0-9_-]+)");
 // https://regex101.com/r/aV1jS1/2
+    pub static ref REGEX_VIDEO_ID: regex::Regex = regex!(r"(youtu\.be/|[?&]v=)([a-zA-Z0-9_-]+)");
     pub static ref REGEX_PLAYLIST: regex::Regex = regex!(r"https?://(www\.|m\.)?youtube\.[a-z]{2,4}/(watch\?(feature=player_embedded&)?(v=[a-zA-Z0-9_-]+.*&)?list=[A-Za-z0-9_-]+|playlist\?list=[a-zA-Z0-9_-]+)");
 }
 
 /// Init youtube handler, registering it
 pub fn init(registry: &mut Registry) {
     registry.register(Module {
+        name: "youtube",
         checker: Box::new(checker_file),
         handler: Box::new(handle_file),
+        cache_key: Box::new(cache_key_file),
     });
     registry.register(Module {
+        name: "youtube_playlist",
         checker: Box::new(checker_playlist),
         handler: Box::new(handle_playlist),
+        cache_key: Box::new(|_| None),
     });
 }
 
@@ -57,21 +67,31 @@ fn checker_playlist(data: &Request) -> bool {
     REGEX_PLAYLIST.is_match(&data.url)
 }
 
+/// Result cache key of a single video, video id plus quality
+fn cache_key_file(data: &Request) -> Option<String> {
+    REGEX_VIDEO_ID
+        .captures(&data.url)
+        .map(|cap| format!("yt:{}:{}:{}", &cap[2], data.quality, data.r_type))
+}
+
 /// Playlist request handler for youtube
 /// If compression is enabled all files will be downloaded into one dir and zipped afterwards
 /// Otherwise for every entry in the playlist a new query is created. These will be handled one after another,
 /// creating a file per entry.
 fn handle_playlist(handle_db: &mut HandleData, request: &mut Request) -> Result<()> {
     trace!("youtube playlist handler started");
-    db::set_query_code(&mut request.get_conn(), &request.qid, &CODE_IN_PROGRESS);
+    request.db().set_query_code(&request.qid, &CODE_IN_PROGRESS);
+
+    if let Some(origin) = request.retry {
+        return handle_retry(handle_db, request, &origin);
+    }
 
     let name = Filename {
-        name: handle_db.downloader.get_playlist_name(&request.url)?,
+        name: handle_db.downloader.get_playlist_name(request)?,
         extension: "zip".to_string(),
     };
-    let mut step: i32 = 1;
 
-    db::set_query_state(&mut request.get_conn(), &request.qid, "1/?");
+    request.db().set_query_state(&request.qid, "1/?");
     trace!("crawling ids");
     let playlist_entries = handle_db.downloader.get_playlist_entries(request)?;
 
@@ -81,48 +101,128 @@ fn handle_playlist(handle_db: &mut HandleData, request: &mut Request) -> Result<
             db::add_sub_query(&entry.url, &request)?;
         }
     } else {
-        let save_path = lib::format_save_path(&request.path, &name)?;
-        request.temp_path.push(request.qid.to_string());
-        request.path = request.temp_path.clone();
-        create_dir(&request.path)?;
-        let mut warnings = false;
-        let mut failed_log: String = String::from("Following urls couldn't be downloaded: \n");
-
-        let max_steps = playlist_entries.len() as i32 + 2;
-        db::update_steps(&mut request.get_conn(), &request.qid, 2, max_steps);
-        for entry in playlist_entries.iter() {
-            step += 1;
-            db::update_steps(&mut request.get_conn(), &request.qid, step, max_steps);
-            request.url = entry.url.clone();
-            match handle_file_int(handle_db, &request) {
-                Err(e) => {
-                    warn!("error downloading {}: {:?}", entry.url, e);
-                    failed_log.push_str(&format!("{} {:?}\n", entry.url, e));
-                    warnings = true;
-                }
-                Ok(_) => {}
-            }
-        }
+        let entries: Vec<(u32, PlaylistEntry)> = playlist_entries
+            .into_iter()
+            .enumerate()
+            .map(|(i, entry)| (i as u32, entry))
+            .collect();
+        zip_entries(handle_db, request, &name, &entries, None)?;
+    }
 
-        if warnings {
-            debug!("found warnings");
-            db::add_query_error(&mut request.get_conn(), &request.qid, &failed_log);
-        }
+    Ok(())
+}
+
+/// Retry the failed entries of a zip playlist job, merging them into its archive
+fn handle_retry(handle_db: &mut HandleData, request: &mut Request, origin: &u64) -> Result<()> {
+    debug!("retrying failed entries of {}", origin);
+    let db = request.db.clone();
+    let file = match db
+        .get_query_info(origin)?
+        .and_then(|v| v.files.first().cloned())
+    {
+        Some(fid) => db.get_file(&fid)?,
+        None => None,
+    }
+    .ok_or_else(|| Error::InternalError(format!("no archive of query {}", origin)))?;
+    let name = Filename::from_path(Path::new(&file.real_name))?;
+    let archive = request.temp_path.join(RETRY_ARCHIVE);
+    handle_db.storage.fetch(&file.name, &archive)?;
+
+    let entries: Vec<(u32, PlaylistEntry)> = db
+        .get_entry_failures(origin)?
+        .into_iter()
+        .map(|failure| {
+            (
+                failure.index,
+                PlaylistEntry {
+                    url: failure.url,
+                    title: failure.title,
+                },
+            )
+        })
+        .collect();
+    zip_entries(handle_db, request, &name, &entries, Some(&archive))
+}
+
+/// Download the playlist entries into one zip file, recording failed entries by their index
+/// The files of `archive` are included, when retrying entries of a previous job
+fn zip_entries(
+    handle_db: &mut HandleData,
+    request: &mut Request,
+    name: &Filename,
+    entries: &[(u32, PlaylistEntry)],
+    archive: Option<&Path>,
+) -> Result<()> {
+    let mut step: i32 = 1;
+    let save_path = lib::format_save_path(&request.path, name)?;
+    // entries are collected in the workspace, temp files of the current entry are kept beside
+    request.path = request.temp_path.join("files");
+    create_dir_all(&request.path)?;
+    if let Some(archive) = archive {
+        lib::unzip_folder(archive, &request.path)?;
+    }
+    // failed entries are retried on resume, recorded again if failing
+    request.db().clear_entry_failures(&request.qid)?;
+    let mut failed = 0;
 
+    let max_steps = entries.len() as i32 + 2;
+    request.db().update_steps(&request.qid, 2, max_steps);
+    for (i, entry) in entries.iter() {
         step += 1;
-        db::update_steps(&mut request.get_conn(), &request.qid, step, max_steps);
-        trace!("starting zipping");
-        lib::zip_folder(&request.temp_path, &save_path)?;
-        trace!("adding file");
-        handle_db.addFile(&save_path, &name.full_name());
-        trace!("removing dir {}", request.path.to_string_lossy());
-        remove_dir_all(&request.path)?;
-        trace!("updating state");
+        let entry_stage = entry_stage(entry);
+        if stage_finished(request, &entry_stage)? {
+            trace!("skipping finished entry {}", entry.url);
+            continue;
+        }
+        request.db().update_steps(&request.qid, step, max_steps);
+        request.url = entry.url.clone();
+        match handle_file_int(handle_db, request) {
+            // killed by the shutdown, not a failure of the entry
+            Err(e) if signals::state().job_aborted() => return Err(e),
+            Err(e) => {
+                warn!("error downloading {}: {:?}", entry.url, e);
+                failed += 1;
+                request.db().add_entry_failure(
+                    &request.qid,
+                    &db::EntryFailure {
+                        index: *i,
+                        url: entry.url.clone(),
+                        title: entry.title.clone(),
+                        kind: e.kind().to_string(),
+                        message: e.details(),
+                    },
+                )?;
+            }
+            Ok(_) => finish_stage(request, &entry_stage)?,
+        }
+    }
+
+    // the failures are recorded per entry, queryerror is kept for errors of the job
+    if failed > 0 {
+        debug!("{} of {} entries failed", failed, entries.len());
+        request.warnings = true;
     }
 
+    step += 1;
+    request.db().update_steps(&request.qid, step, max_steps);
+    trace!("starting zipping");
+    lib::zip_folder(&request.path, &save_path)?;
+    trace!("adding file");
+    handle_db.addFile(&save_path, &name.full_name());
+    trace!("removing dir {}", request.path.to_string_lossy());
+    remove_dir_all(&request.path)?;
+    trace!("updating state");
     Ok(())
 }
 
+/// Stage of a playlist entry, keyed by its video id as the index changes with the playlist
+fn entry_stage(entry: &PlaylistEntry) -> String {
+    match REGEX_VIDEO_ID.captures(&entry.url) {
+        Some(cap) => format!("entry_{}", &cap[2]),
+        None => format!("entry_{}", entry.url),
+    }
+}
+
 /// Handle file request
 fn handle_file(handle_db: &mut HandleData, request: &mut Request) -> Result<()> {
     handle_file_int(handle_db, &request)?;
@@ -145,7 +245,7 @@ fn handle_file_int(handle_db: &mut HandleData, request: &Request) -> Result<()>
 /// Handler for videos
 fn handle_video(hdb: &mut HandleData, request: &Request) -> Result<()> {
     condition!(
-        db::set_query_code(&mut request.get_conn(), &request.qid, &CODE_IN_PROGRESS),
+        request.db().set_query_code(&request.qid, &CODE_IN_PROGRESS),
         !request.playlist
     );
     let mut temp_file_v = request.temp_path.clone();
@@ -175,26 +275,32 @@ fn handle_video(hdb: &mut HandleData, request: &Request) -> Result<()> {
     };
 
     condition!(
-        db::update_steps(&mut request.get_conn(), &request.qid, 1, 3),
+        request.db().update_steps(&request.qid, 1, 3),
         !request.playlist
     );
-    trace!("downloading video");
-    hdb.downloader
-        .download_file(&request, &temp_file_v, &request.quality.to_string())?;
+    if request.playlist || !stage_finished(request, STAGE_VIDEO)? {
+        trace!("downloading video");
+        hdb.downloader
+            .download_file(request, &temp_file_v, &request.quality.to_string())?;
+        condition!(finish_stage(request, STAGE_VIDEO)?, !request.playlist);
+    }
 
     condition!(
-        db::update_steps(&mut request.get_conn(), &request.qid, 2, 3),
+        request.db().update_steps(&request.qid, 2, 3),
         !request.playlist
     );
     let mut temp_file_a = request.temp_path.clone();
     temp_file_a.push(format!("{}a", request.qid));
     hdb.push(&temp_file_a);
-    trace!("downloading audio");
-    hdb.downloader
-        .download_file(&request, &temp_file_a, &audio_id.to_string())?;
+    if request.playlist || !stage_finished(request, STAGE_AUDIO)? {
+        trace!("downloading audio");
+        hdb.downloader
+            .download_file(request, &temp_file_a, &audio_id.to_string())?;
+        condition!(finish_stage(request, STAGE_AUDIO)?, !request.playlist);
+    }
 
     condition!(
-        db::update_steps(&mut request.get_conn(), &request.qid, 3, 3),
+        request.db().update_steps(&request.qid, 3, 3),
         !request.playlist
     );
     trace!("merging");
@@ -203,7 +309,7 @@ fn handle_video(hdb: &mut HandleData, request: &Request) -> Result<()> {
         &temp_file_v,
         &temp_file_a,
         &save_file,
-        &mut request.get_conn(),
+        request.db(),
     )?;
     if !request.playlist {
         hdb.addFile(&save_file, &origin_name);
@@ -219,7 +325,7 @@ fn handle_video(hdb: &mut HandleData, request: &Request) -> Result<()> {
 /// Handler for audios
 fn handle_audio(hdb: &mut HandleData, request: &Request) -> Result<()> {
     condition!(
-        db::set_query_code(&mut request.get_conn(), &request.qid, &CODE_IN_PROGRESS),
+        request.db().set_query_code(&request.qid, &CODE_IN_PROGRESS),
         !request.playlist
     );
     let mut dmca = false;
@@ -230,7 +336,7 @@ fn handle_audio(hdb: &mut HandleData, request: &Request) -> Result<()> {
     hdb.push(&temp_file_v);
 
     condition!(
-        db::update_steps(&mut request.get_conn(), &request.qid, 1, 3),
+        request.db().update_steps(&request.qid, 1, 3),
         !request.playlist
     );
     let mut name = get_name(&hdb, &request, false, false, &temp_file_v, &mut dmca)?;
@@ -246,11 +352,14 @@ fn handle_audio(hdb: &mut HandleData, request: &Request) -> Result<()> {
     }
 
     condition!(
-        db::update_steps(&mut request.get_conn(), &request.qid, 2, 3),
+        request.db().update_steps(&request.qid, 2, 3),
         !request.playlist
     );
-    hdb.downloader
-        .download_file(&request, &temp_file_v, &quality)?;
+    if request.playlist || !stage_finished(request, STAGE_AUDIO)? {
+        hdb.downloader
+            .download_file(request, &temp_file_v, &quality)?;
+        condition!(finish_stage(request, STAGE_AUDIO)?, !request.playlist);
+    }
 
     if request.quality == CONFIG.codecs.audio_raw
         || request.quality == CONFIG.codecs.audio_source_hq
@@ -261,7 +370,7 @@ fn handle_audio(hdb: &mut HandleData, request: &Request) -> Result<()> {
     }
 
     condition!(
-        db::update_steps(&mut request.get_conn(), &request.qid, 3, 3),
+        request.db().update_steps(&request.qid, 3, 3),
         !request.playlist
     );
     let file = lib::format_save_path(&request.path, &name)?;
@@ -270,7 +379,7 @@ fn handle_audio(hdb: &mut HandleData, request: &Request) -> Result<()> {
         &temp_file_v,
         &file,
         request.quality == CONFIG.codecs.audio_mp3,
-        &mut request.get_conn(),
+        request.db(),
     )?;
     remove_file(&temp_file_v)?;
     hdb.pop();
@@ -299,15 +408,15 @@ fn get_name<'a>(
     } else {
         None
     };
-    Ok(match hdb.downloader.get_file_name(&request.url, quality) {
+    Ok(match hdb.downloader.get_file_name(request, quality) {
         // get filename
         Ok(v) => v,
-        Err(Error::DMCAError) => {
+        Err(Error::DMCAError(output)) => {
             // now request via lib.. // k if( k == Err(DownloadError::DMCAError) )
             info!("DMCA error!");
             if CONFIG.general.lib_use {
                 if !request.split {
-                    db::set_query_code(&mut request.get_conn(), &request.qid, &CODE_IN_PROGRESS);
+                    request.db().set_query_code(&request.qid, &CODE_IN_PROGRESS);
                 }
                 match hdb.downloader.lib_request_video(
                     1,
@@ -327,7 +436,8 @@ fn get_name<'a>(
                     }
                 }
             } else {
-                return Err(Error::NotAvailable);
+                let kind = Failure::classify(&output).unwrap_or(Failure::GeoBlocked);
+                return Err(Error::Unavailable(kind, output));
             }
         }
         Err(e) => {
@@ -387,4 +497,34 @@ mod test {
         ));
         assert!(!REGEX_PLAYLIST.is_match(r"https://www.youtube.com/watch?v=IO-_EoRSpUA"));
     }
+
+    #[test]
+    fn video_id() {
+        for url in &[
+            r"https://m.youtube.com/watch?list=PLTXoSHLJey0RR60hjLhuUAaj_ftAdShqv&v=IO-_EoRSpUA",
+            r"http://youtu.be/IO-_EoRSpUA",
+            r"https://www.youtube.com/watch?v=IO-_EoRSpUA&list=PL6DA1502C5DDC0317&index=21",
+            r"https://www.youtube.com/watch?feature=player_embedded&v=IO-_EoRSpUA",
+        ] {
+            assert_eq!(&REGEX_VIDEO_ID.captures(url).unwrap()[2], "IO-_EoRSpUA");
+        }
+    }
+
+    #[test]
+    fn entry_stage_key() {
+        let entry = |url: &str| PlaylistEntry {
+            url: url.to_string(),
+            title: None,
+        };
+        assert_eq!(
+            entry_stage(&entry("https://www.youtube.com/watch?v=IO-_EoRSpUA")),
+            "entry_IO-_EoRSpUA"
+        );
+        assert_eq!(
+            entry_stage(&entry(
+                "https://www.youtube.com/watch?v=IO-_EoRSpUA&list=PL6DA1502C5DDC0317&index=21"
+            )),
+            "entry_IO-_EoRSpUA"
+        );
+    }
 }
@@ -0,0 +1,684 @@
+//! End-to-end tests of the request handling, no DB server, network, yt-dl or ffmpeg required
+//!
+//! Jobs are queued in an in-memory SQLite DB and handled via `handle_next`, like the main loop does.
+//! yt-dl, ffmpeg & ffprobe are replaced by the fake binaries below, installed into the `bin` dir
+//! of the hermetic test config. The outcome of a job is selected by the prefix of its video/playlist id:
+//!
+//! * `ok` success
+//! * `unavailable` login required, succeeds with a credential profile
+//! * `unavailableHuge` login required, the size estimate with login exceeds the free disk space
+//! * `blocked` region lock (DMCA)
+//! * `private` private video
+//! * `throttled` HTTP 429 on download
+//! * `extractor` extractor error on every download attempt
+//! * `badquality` requested format not available
+//! * `broken` unknown download error
+//! * `huge` size estimate exceeding the free disk space
+//! * `stalled` download without progress after 50%, killed by the stall timeout
+//! * `aborted` audio download waiting to be killed, writing its pid beside the output; succeeds once resumed
+//! * `PLmixed` playlist of an `ok`, a `broken` and a `private` entry, any other playlist has two `ok` entries
+
+use std::fs::{self, create_dir_all, remove_dir_all};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Once};
+
+use crate::handler::{init_handlers, Registry};
+use crate::lib::converter::Converter;
+use crate::lib::db::{PlaylistRange, QueryInfo, Repository, SqliteRepository};
+use crate::lib::downloader::Downloader;
+use crate::lib::storage::{self, Storage};
+
+use crate::handle_next;
+use crate::CONFIG;
+
+/// Fake yt-dl, printing names & progress like the real one
+const FAKE_YTDL: &str = r#"#!/bin/sh
+mode=download
+out=""
+url=""
+login=""
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --version) echo "2000.01.01"; exit 0 ;;
+        --get-filename) mode=name ;;
+        --print) mode=size; shift ;;
+        --dump-json) mode=entries ;;
+        -o) shift; out="$1" ;;
+        --cookies|--netrc-location) shift; login="$1" ;;
+        -f|-r|--ffmpeg-location|--playlist-start|--playlist-end) shift ;;
+        -*) ;;
+        *) url="$1" ;;
+    esac
+    shift
+done
+id="${url##*=}"
+case "$mode" in
+    name)
+        case "$id" in
+            unavailable*) [ -s "$login" ] || { echo "ERROR: Please sign in to view this video" >&2; exit 1; } ;;
+            blocked*) echo "ERROR: This video is not available in your country" >&2; exit 1 ;;
+            private*) echo "ERROR: [youtube] $id: Private video. Sign in if you've been granted access to this video" >&2; exit 1 ;;
+        esac
+        echo "video_$id.mp4" ;;
+    size)
+        case "$id" in
+            unavailableHuge*) [ -s "$login" ] || { echo "ERROR: Please sign in to view this video" >&2; exit 1; }
+                echo "1000000000000000000" ;;
+            huge*) echo "1000000000000000000" ;;
+            *) echo "1024" ;;
+        esac ;;
+    entries)
+        case "$id" in
+            PLmixed*) entries="okFirst brokenEntry privateEntry" ;;
+            *) entries="okFirst okSecond" ;;
+        esac
+        for entry in $entries; do
+            echo "{\"url\": \"https://www.youtube.com/watch?v=$entry\", \"title\": \"Title $entry\"}"
+        done ;;
+    download)
+        if [ -z "$out" ]; then
+            echo "[download] Downloading playlist: playlist_$id"
+            exit 0
+        fi
+        case "$id" in
+            badquality*) echo "ERROR: requested format not available" >&2; exit 1 ;;
+            extractor*) echo "ERROR: ExtractorError: unable to extract" >&2; exit 1 ;;
+            broken*) echo "ERROR: unable to download video data" >&2; exit 1 ;;
+            throttled*) echo "ERROR: unable to download video data: HTTP Error 429: Too Many Requests" >&2; exit 1 ;;
+        esac
+        echo "[download]  50.0% of 1.00KiB at 1.00KiB/s ETA 00:01"
+        case "$id" in
+            stalled*) sleep 30 ;;
+            aborted*)
+                case "$out" in
+                    *a) [ -e "$out.pid" ] || { echo $$ > "$out.tmp"; mv "$out.tmp" "$out.pid"; sleep 30; exit 0; } ;;
+                esac ;;
+        esac
+        echo "content of $id" > "$out"
+        echo "[download] 100.0% of 1.00KiB at 1.00KiB/s ETA 00:00" ;;
+esac
+"#;
+
+/// Fake ffmpeg, concatenating all inputs into the output, which is the last argument
+const FAKE_FFMPEG: &str = r#"#!/bin/sh
+if [ "$3" = "-version" ]; then
+    echo "ffmpeg version fake"
+    exit 0
+fi
+if [ "$4" = "-encoders" ]; then
+    echo " A....D libmp3lame           libmp3lame MP3 (MPEG audio layer 3) (codec mp3)"
+    exit 0
+fi
+for out; do :; done
+: > "$out"
+prev=""
+for arg; do
+    if [ "$prev" = "-i" ]; then
+        cat "$arg" >> "$out"
+    fi
+    prev="$arg"
+done
+printf 'frame=  125 fps=0.0 q=-1.0 size=1kB time=00:00:05.00 bitrate=1.6kbits/s\r' >&2
+printf 'frame=  250 fps=0.0 q=-1.0 size=2kB time=00:00:10.00 bitrate=1.6kbits/s\r' >&2
+"#;
+
+/// Fake ffprobe, every file is 10 seconds at 25 fps
+const FAKE_FFPROBE: &str = r#"#!/bin/sh
+if [ "$3" = "-version" ]; then
+    echo "ffprobe version fake"
+    exit 0
+fi
+echo "r_frame_rate=25/1"
+echo "duration=10.000000"
+"#;
+
+lazy_static! {
+    /// All harnesses share the directories of the config, qids of separate DBs would collide
+    static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+static INSTALL: Once = Once::new();
+
+/// Write a fake binary into the bin dir
+fn install(name: &str, content: &str) {
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = Path::new(&CONFIG.general.youtube_dl_dir).join(name);
+    fs::write(&path, content).unwrap();
+    #[cfg(unix)]
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Remove & recreate a directory of the config
+fn reset_dir(dir: &str) {
+    let _ = remove_dir_all(dir);
+    create_dir_all(dir).unwrap();
+}
+
+/// Test environment of one in-memory DB, fake binaries & empty directories
+pub struct Harness {
+    pub db: Arc<dyn Repository>,
+    pub storage: Arc<dyn Storage>,
+    registry: Registry<'static>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Harness {
+    pub fn new() -> Harness {
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        INSTALL.call_once(|| {
+            create_dir_all(&CONFIG.general.youtube_dl_dir).unwrap();
+            install("yt-dlp", FAKE_YTDL);
+            install("ffmpeg", FAKE_FFMPEG);
+            install("ffprobe", FAKE_FFPROBE);
+        });
+        reset_dir(&CONFIG.general.temp_dir);
+        reset_dir(&CONFIG.general.download_dir);
+
+        let storage: Arc<dyn Storage> = Arc::from(storage::init_storage(&CONFIG));
+        let downloader = Arc::new(Downloader::new(&CONFIG.general));
+        let converter = Converter::new(&CONFIG.general.ffmpeg_bin_dir, &CONFIG.general.mp3_quality);
+        Harness {
+            db: Arc::new(SqliteRepository::open_in_memory().unwrap()),
+            registry: init_handlers(downloader, converter, storage.clone()),
+            storage,
+            _lock: lock,
+        }
+    }
+
+    /// Queue a job of uid 1
+    pub fn add_job(&self, url: &str, quality: i16) -> u64 {
+        self.db.add_query(url, &quality, &1, &0, None).unwrap()
+    }
+
+    /// Queue a playlist job of uid 1, containing all entries
+    pub fn add_playlist(&self, url: &str, split: bool) -> u64 {
+        let range = PlaylistRange {
+            from: 0,
+            to: 0,
+            split,
+        };
+        self.db.add_query(url, &22, &1, &0, Some(range)).unwrap()
+    }
+
+    /// Handle the next waiting job, returns false if there is none
+    pub fn run(&mut self) -> bool {
+        handle_next(&self.db, &mut self.registry)
+    }
+
+    /// Handle jobs until the queue is empty, returns the amount of handled jobs
+    pub fn run_all(&mut self) -> usize {
+        let mut handled = 0;
+        while self.run() {
+            handled += 1;
+        }
+        handled
+    }
+
+    pub fn info(&self, qid: &u64) -> QueryInfo {
+        self.db.get_query_info(qid).unwrap().unwrap()
+    }
+
+    /// Returns the names of all files in the download dir, sorted
+    pub fn stored_files(&self) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(&CONFIG.general.download_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Returns all left overs in the temp dir
+    pub fn temp_files(&self) -> Vec<PathBuf> {
+        fs::read_dir(&CONFIG.general.temp_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lib;
+    use crate::lib::db::{self, EntryFailure};
+    use crate::lib::db::{DeleteRequestType, EVENT_FINISHED, EVENT_STARTED};
+    use crate::{CODE_FAILED_GEO_BLOCKED, CODE_FAILED_LOGIN_REQUIRED};
+    use crate::{
+        CODE_FAILED_INTERNAL, CODE_FAILED_QUALITY, CODE_FAILED_QUOTA, CODE_FAILED_TIMEOUT,
+    };
+    use crate::{CODE_FAILED_PRIVATE, CODE_FAILED_RATE_LIMITED};
+    use crate::{CODE_FAILED_UNAVAILABLE, CODE_FAILED_UNKNOWN};
+    use crate::{
+        CODE_IN_PROGRESS, CODE_STARTED, CODE_SUCCESS, CODE_SUCCESS_WARNINGS, CODE_WAITING,
+    };
+
+    const VIDEO_URL: &str = "https://www.youtube.com/watch?v=";
+    const PLAYLIST_URL: &str = "https://www.youtube.com/playlist?list=";
+
+    fn read_stored(name: &str) -> String {
+        fs::read_to_string(Path::new(&CONFIG.general.download_dir).join(name)).unwrap()
+    }
+
+    #[test]
+    fn empty_queue_test() {
+        let mut h = Harness::new();
+        assert!(!h.run());
+    }
+
+    #[test]
+    fn video_test() {
+        let mut h = Harness::new();
+        let qid = h.add_job(&format!("{}okVideo", VIDEO_URL), 22);
+        assert!(h.run());
+        let info = h.info(&qid);
+        assert_eq!(info.code, CODE_SUCCESS);
+        assert_eq!(info.progress, Some(100.0));
+        assert!(info.status.is_none());
+        assert!(info.error.is_none());
+        assert_eq!(info.files.len(), 1);
+        assert_eq!(h.stored_files(), vec!["video_okVideo.mp4"]);
+        // video & audio merged
+        assert_eq!(
+            read_stored("video_okVideo.mp4"),
+            "content of okVideo\ncontent of okVideo\n"
+        );
+        assert!(h.temp_files().is_empty());
+
+        let events = h.db.get_events(&qid).unwrap();
+        assert_eq!(events.first().unwrap().stage, EVENT_STARTED);
+        let last = events.last().unwrap();
+        assert_eq!(last.stage, EVENT_FINISHED);
+        assert_eq!(last.code, Some(CODE_SUCCESS));
+        assert!(events.iter().any(|e| e.stage == "video"));
+        assert!(events.iter().any(|e| e.stage == "audio"));
+        assert!(!h.run());
+    }
+
+    #[test]
+    fn audio_test() {
+        let mut h = Harness::new();
+        let qid = h.add_job(&format!("{}okAudio", VIDEO_URL), CONFIG.codecs.audio_mp3);
+        assert!(h.run());
+        assert_eq!(h.info(&qid).code, CODE_SUCCESS);
+        assert_eq!(h.stored_files(), vec!["video_okAudio.mp3"]);
+        assert_eq!(
+            h.db.get_query_files(&qid).unwrap(),
+            vec![(
+                String::from("video_okAudio.mp3"),
+                String::from("video_okAudio.mp3")
+            )]
+        );
+        assert!(h.temp_files().is_empty());
+    }
+
+    #[test]
+    fn playlist_warnings_test() {
+        let mut h = Harness::new();
+        let qid = h.add_playlist(&format!("{}PLmixed", PLAYLIST_URL), false);
+        assert_eq!(h.run_all(), 1);
+        let info = h.info(&qid);
+        assert_eq!(info.code, CODE_SUCCESS_WARNINGS);
+        assert!(info.error.is_none());
+        let failures = h.db.get_entry_failures(&qid).unwrap();
+        assert_eq!(
+            failures
+                .iter()
+                .map(|v| (v.index, v.kind.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(1, "internal"), (2, "private")]
+        );
+        assert_eq!(
+            failures[1].url,
+            "https://www.youtube.com/watch?v=privateEntry"
+        );
+        assert_eq!(failures[1].title.as_deref(), Some("Title privateEntry"));
+        assert!(failures[1].message.contains("Private video"));
+        assert_eq!(h.stored_files(), vec!["playlist_PLmixed.zip"]);
+        assert!(h.temp_files().is_empty());
+    }
+
+    /// Returns the sorted file names inside a stored zip file
+    fn zip_entries(name: &str) -> Vec<String> {
+        let file = fs::File::open(Path::new(&CONFIG.general.download_dir).join(name)).unwrap();
+        let archive = zip::ZipArchive::new(file).unwrap();
+        let mut names: Vec<String> = archive.file_names().map(String::from).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn playlist_retry_test() {
+        let mut h = Harness::new();
+        let qid = h.add_playlist(&format!("{}PLmixed", PLAYLIST_URL), false);
+        assert_eq!(h.run_all(), 1);
+        let recovered = |index: u32, id: &str| EntryFailure {
+            index,
+            url: format!("{}{}", VIDEO_URL, id),
+            title: None,
+            kind: String::from("internal"),
+            message: String::new(),
+        };
+        // the broken entry works again
+        h.db.add_entry_failure(&qid, &recovered(1, "okRecovered"))
+            .unwrap();
+
+        let retry = db::add_retry_query(&*h.db, &h.info(&qid)).unwrap();
+        assert_eq!(h.db.get_query_retry(&retry).unwrap(), Some(qid));
+        assert_eq!(h.run_all(), 1);
+        let info = h.info(&retry);
+        assert_eq!(info.code, CODE_SUCCESS_WARNINGS);
+        assert!(info.error.is_none());
+        // the rebuilt archive replaces the file of the origin
+        assert_eq!(h.stored_files(), vec!["playlist_PLmixed-1.zip"]);
+        assert_eq!(
+            zip_entries("playlist_PLmixed-1.zip"),
+            vec!["video_okFirst.mp4", "video_okRecovered.mp4"]
+        );
+        assert_eq!(h.info(&qid).files, info.files);
+        assert_eq!(
+            h.db.get_query_files(&qid).unwrap(),
+            vec![(
+                String::from("playlist_PLmixed-1.zip"),
+                String::from("playlist_PLmixed.zip")
+            )]
+        );
+        let failures = h.db.get_entry_failures(&qid).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].index, 2);
+        assert_eq!(failures[0].kind, "private");
+        assert_eq!(h.info(&qid).code, CODE_SUCCESS_WARNINGS);
+
+        h.db.add_entry_failure(&qid, &recovered(2, "okLast"))
+            .unwrap();
+        let retry = db::add_retry_query(&*h.db, &h.info(&qid)).unwrap();
+        // a second retry would replace the same archive
+        assert!(db::add_retry_query(&*h.db, &h.info(&qid)).is_err());
+        assert_eq!(h.run_all(), 1);
+        assert_eq!(h.info(&retry).code, CODE_SUCCESS);
+        assert_eq!(h.info(&qid).code, CODE_SUCCESS);
+        assert!(h.db.get_entry_failures(&qid).unwrap().is_empty());
+        assert_eq!(
+            zip_entries(&h.stored_files()[0]),
+            vec![
+                "video_okFirst.mp4",
+                "video_okLast.mp4",
+                "video_okRecovered.mp4"
+            ]
+        );
+        assert_eq!(h.stored_files().len(), 1);
+        assert!(h.temp_files().is_empty());
+        // nothing left to retry
+        assert!(db::add_retry_query(&*h.db, &h.info(&qid)).is_err());
+    }
+
+    #[test]
+    fn playlist_split_test() {
+        let mut h = Harness::new();
+        let qid = h.add_playlist(&format!("{}PLsplit", PLAYLIST_URL), true);
+        assert!(h.run());
+        assert_eq!(h.info(&qid).code, CODE_SUCCESS);
+        assert!(h.stored_files().is_empty());
+        let mut waiting = h.db.get_queries_by_code(&[CODE_WAITING]).unwrap();
+        waiting.sort();
+        assert_eq!(waiting.len(), 2);
+
+        assert_eq!(h.run_all(), 2);
+        for sub in waiting {
+            assert_eq!(h.info(&sub).code, CODE_SUCCESS);
+        }
+        assert_eq!(
+            h.stored_files(),
+            vec!["video_okFirst.mp4", "video_okSecond.mp4"]
+        );
+    }
+
+    #[test]
+    fn failure_codes_test() {
+        let mut h = Harness::new();
+        let cases = [
+            ("https://example.com/video", 22, CODE_FAILED_UNKNOWN, false),
+            (
+                &format!("{}unavailable", VIDEO_URL)[..],
+                22,
+                CODE_FAILED_LOGIN_REQUIRED,
+                true,
+            ),
+            (
+                &format!("{}blocked", VIDEO_URL)[..],
+                22,
+                CODE_FAILED_GEO_BLOCKED,
+                true,
+            ),
+            (
+                &format!("{}private", VIDEO_URL)[..],
+                22,
+                CODE_FAILED_PRIVATE,
+                true,
+            ),
+            (
+                &format!("{}throttled", VIDEO_URL)[..],
+                22,
+                CODE_FAILED_RATE_LIMITED,
+                true,
+            ),
+            (
+                &format!("{}extractor", VIDEO_URL)[..],
+                22,
+                CODE_FAILED_UNAVAILABLE,
+                false,
+            ),
+            (
+                &format!("{}badquality", VIDEO_URL)[..],
+                22,
+                CODE_FAILED_QUALITY,
+                false,
+            ),
+            (
+                &format!("{}broken", VIDEO_URL)[..],
+                22,
+                CODE_FAILED_INTERNAL,
+                true,
+            ),
+            (
+                &format!("{}okInvalid", VIDEO_URL)[..],
+                -9,
+                CODE_FAILED_INTERNAL,
+                true,
+            ),
+            (
+                &format!("{}huge", VIDEO_URL)[..],
+                22,
+                CODE_FAILED_QUOTA,
+                true,
+            ),
+            (
+                &format!("{}stalled", VIDEO_URL)[..],
+                22,
+                CODE_FAILED_TIMEOUT,
+                true,
+            ),
+        ];
+        for &(url, quality, code, error) in cases.iter() {
+            let qid = h.add_job(url, quality);
+            assert!(h.run());
+            let info = h.info(&qid);
+            assert_eq!(info.code, code, "{}", url);
+            assert_eq!(info.error.is_some(), error, "{}", url);
+            assert!(info.files.is_empty(), "{}", url);
+            let events = h.db.get_events(&qid).unwrap();
+            assert_eq!(events.last().unwrap().code, Some(code));
+        }
+        assert!(h.stored_files().is_empty());
+        assert!(h.temp_files().is_empty());
+    }
+
+    #[test]
+    fn credentials_test() {
+        let mut h = Harness::new();
+        lib::credentials::store(
+            &*h.db,
+            &1,
+            "member",
+            lib::credentials::Kind::Cookies,
+            b"# Netscape HTTP Cookie File",
+        )
+        .unwrap();
+        let qid = h.add_job(&format!("{}unavailableMember", VIDEO_URL), 22);
+        h.db.set_query_credential(&qid, "member").unwrap();
+        let qid_missing = h.add_job(&format!("{}okMissing", VIDEO_URL), 22);
+        h.db.set_query_credential(&qid_missing, "missing").unwrap();
+        // only known to be too large with the login
+        let qid_huge = h.add_job(&format!("{}unavailableHuge", VIDEO_URL), 22);
+        h.db.set_query_credential(&qid_huge, "member").unwrap();
+        assert_eq!(h.run_all(), 3);
+
+        assert_eq!(h.info(&qid).code, CODE_SUCCESS);
+        assert_eq!(h.stored_files(), vec!["video_unavailableMember.mp4"]);
+        let info = h.info(&qid_missing);
+        assert_eq!(info.code, CODE_FAILED_INTERNAL);
+        assert!(info.error.unwrap().contains("unknown credential profile"));
+        assert_eq!(h.info(&qid_huge).code, CODE_FAILED_QUOTA);
+        // profile files are wiped with the workspace
+        assert!(h.temp_files().is_empty());
+    }
+
+    #[test]
+    fn cleanup_test() {
+        let mut h = Harness::new();
+        let qid = h.add_job(&format!("{}okCleanup", VIDEO_URL), 22);
+        let qid_kept = h.add_job(&format!("{}okKept", VIDEO_URL), 22);
+        assert_eq!(h.run_all(), 2);
+        assert_eq!(h.stored_files().len(), 2);
+
+        // nothing marked
+        lib::delete_files(&*h.db, DeleteRequestType::Marked, &*h.storage).unwrap();
+        assert_eq!(h.stored_files().len(), 2);
+
+        let fid = h.info(&qid).files[0];
+        h.db.set_file_delete_flag(&fid, true).unwrap();
+        lib::delete_files(&*h.db, DeleteRequestType::Marked, &*h.storage).unwrap();
+        assert_eq!(h.stored_files(), vec!["video_okKept.mp4"]);
+        assert!(h.db.get_query_info(&qid).unwrap().is_none());
+        assert_eq!(h.info(&qid_kept).code, CODE_SUCCESS);
+    }
+
+    #[test]
+    fn cleanup_shared_test() {
+        let mut h = Harness::new();
+        let qid = h.add_job(&format!("{}okShared", VIDEO_URL), 22);
+        assert!(h.run());
+        // second query linked to the same file, like a result cache hit
+        let qid_other = h.db.add_query("cached", &22, &2, &0, None).unwrap();
+        let fid = h.info(&qid).files[0];
+        h.db.add_file_link(&qid_other, &fid).unwrap();
+
+        h.db.set_file_link_delete_flag(&qid, &fid, true).unwrap();
+        lib::delete_files(&*h.db, DeleteRequestType::Marked, &*h.storage).unwrap();
+        assert_eq!(h.stored_files(), vec!["video_okShared.mp4"]);
+        assert!(h.db.get_query_info(&qid).unwrap().is_none());
+        assert_eq!(h.info(&qid_other).files, vec![fid]);
+
+        h.db.set_file_link_delete_flag(&qid_other, &fid, true)
+            .unwrap();
+        lib::delete_files(&*h.db, DeleteRequestType::Marked, &*h.storage).unwrap();
+        assert!(h.stored_files().is_empty());
+        assert!(h.db.get_query_info(&qid_other).unwrap().is_none());
+    }
+
+    #[test]
+    fn cleanup_shared_file_mark_test() {
+        let mut h = Harness::new();
+        let qid = h.add_job(&format!("{}okFileMark", VIDEO_URL), 22);
+        assert!(h.run());
+        let qid_other = h.db.add_query("cached", &22, &2, &0, None).unwrap();
+        let fid = h.info(&qid).files[0];
+        h.db.add_file_link(&qid_other, &fid).unwrap();
+
+        // marked via the file, like the frontend does: only removed for the creating query
+        h.db.set_file_delete_flag(&fid, true).unwrap();
+        let (qids, files) = lib::files_to_delete(&*h.db, DeleteRequestType::Marked).unwrap();
+        assert_eq!(qids, vec![qid]);
+        assert!(files.is_empty());
+        lib::delete_files(&*h.db, DeleteRequestType::Marked, &*h.storage).unwrap();
+        assert_eq!(h.stored_files(), vec!["video_okFileMark.mp4"]);
+        assert!(h.db.get_query_info(&qid).unwrap().is_none());
+        assert_eq!(h.info(&qid_other).files, vec![fid]);
+        assert!(!h.db.get_file(&fid).unwrap().unwrap().delete);
+
+        h.db.set_file_delete_flag(&fid, true).unwrap();
+        lib::delete_files(&*h.db, DeleteRequestType::Marked, &*h.storage).unwrap();
+        assert!(h.stored_files().is_empty());
+        assert!(h.db.get_query_info(&qid_other).unwrap().is_none());
+    }
+
+    #[test]
+    fn resume_test() {
+        let mut h = Harness::new();
+        let qid = h.add_job(&format!("{}okResumed", VIDEO_URL), 22);
+        let qid_running = h.add_job(&format!("{}okRunning", VIDEO_URL), 22);
+        // crashed while downloading the audio
+        h.db.set_query_code(&qid, &CODE_IN_PROGRESS);
+        h.db.add_stage(&qid, "video").unwrap();
+        let workspace = lib::workspace_path(&qid);
+        create_dir_all(&workspace).unwrap();
+        fs::write(workspace.join(qid.to_string()), "content of okResumed\n").unwrap();
+        h.db.set_query_code(&qid_running, &CODE_STARTED);
+
+        let mut interrupted = h.db.clear_query_states();
+        interrupted.sort();
+        assert_eq!(interrupted, vec![qid, qid_running]);
+        assert_eq!(h.run_all(), 2);
+        assert_eq!(h.info(&qid).code, CODE_SUCCESS);
+        assert_eq!(h.info(&qid_running).code, CODE_SUCCESS);
+        assert_eq!(
+            h.stored_files(),
+            vec!["video_okResumed.mp4", "video_okRunning.mp4"]
+        );
+        assert!(h.temp_files().is_empty());
+    }
+
+    #[test]
+    fn abort_resume_test() {
+        let mut h = Harness::new();
+        let qid = h.add_job(&format!("{}abortedJob", VIDEO_URL), 22);
+        let workspace = lib::workspace_path(&qid);
+        let pid_file = workspace.join(format!("{}a.pid", qid));
+        // shutdown after the grace period, while downloading the audio
+        let pid_file_c = pid_file.clone();
+        let killer = std::thread::spawn(move || {
+            while !pid_file_c.exists() {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            let pid: i32 = fs::read_to_string(&pid_file_c)
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap();
+            lib::signals::state().abort_job();
+            unsafe {
+                libc::kill(-pid, libc::SIGKILL);
+            }
+        });
+        assert!(h.run());
+        killer.join().unwrap();
+
+        let info = h.info(&qid);
+        assert_eq!(info.code, CODE_WAITING);
+        assert!(info.files.is_empty());
+        let events = h.db.get_events(&qid).unwrap();
+        assert_eq!(events.last().unwrap().stage, db::EVENT_INTERRUPTED);
+        assert!(h.stored_files().is_empty());
+        // kept for resuming, the killed download isn't recorded as finished
+        assert!(workspace.join(qid.to_string()).exists());
+        assert!(h.db.is_stage_done(&qid, "video").unwrap());
+        assert!(!h.db.is_stage_done(&qid, "audio").unwrap());
+
+        assert!(h.run());
+        assert_eq!(h.info(&qid).code, CODE_SUCCESS);
+        assert_eq!(h.stored_files(), vec!["video_abortedJob.mp4"]);
+        assert!(h.temp_files().is_empty());
+    }
+}
@@ -0,0 +1,526 @@
+//! HTTP API for submitting jobs and reading their state
+//!
+//! All data is read & written via the db module, see api.md for the endpoints.
+
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::lib::config::{ConfigApi, ConfigApiKey};
+use crate::lib::credentials;
+use crate::lib::db::{self, PlaylistRange, Repository};
+use crate::lib::notify;
+use crate::lib::{Error, Result};
+
+/// Max size of accepted request bodies
+const MAX_BODY_SIZE: u64 = 8192;
+/// Max size of credential profile bodies, leaving room for the JSON escaping
+const MAX_CREDENTIAL_SIZE: u64 = 2 * credentials::MAX_SIZE as u64;
+/// Max url length, see `queries` table
+const MAX_URL_LENGTH: usize = 125;
+
+/// API routes
+#[derive(Debug, PartialEq)]
+enum Route {
+    /// POST /jobs
+    AddJob,
+    /// GET /jobs/<qid>
+    Job(u64),
+    /// GET /jobs/<qid>/events
+    JobEvents(u64),
+    /// GET /jobs/<qid>/failures
+    JobFailures(u64),
+    /// POST /jobs/<qid>/retry
+    RetryJob(u64),
+    /// GET /users/<uid>/files
+    UserFiles(u32),
+    /// DELETE /files/<fid>
+    DeleteFile(u64),
+    /// GET /users/<uid>/credentials
+    UserCredentials(u32),
+    /// PUT /users/<uid>/credentials/<name>
+    PutCredential(u32, String),
+    /// DELETE /users/<uid>/credentials/<name>
+    DeleteCredential(u32, String),
+}
+
+impl Route {
+    /// Parse the route of a request, None for unknown routes
+    fn parse(method: &Method, url: &str) -> Option<Route> {
+        let path = url.split('?').next().unwrap_or_default();
+        let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, parts.as_slice()) {
+            (Method::Post, ["jobs"]) => Some(Route::AddJob),
+            (Method::Get, ["jobs", qid]) => qid.parse().ok().map(Route::Job),
+            (Method::Get, ["jobs", qid, "events"]) => qid.parse().ok().map(Route::JobEvents),
+            (Method::Get, ["jobs", qid, "failures"]) => qid.parse().ok().map(Route::JobFailures),
+            (Method::Post, ["jobs", qid, "retry"]) => qid.parse().ok().map(Route::RetryJob),
+            (Method::Get, ["users", uid, "files"]) => uid.parse().ok().map(Route::UserFiles),
+            (Method::Delete, ["files", fid]) => fid.parse().ok().map(Route::DeleteFile),
+            (Method::Get, ["users", uid, "credentials"]) => {
+                uid.parse().ok().map(Route::UserCredentials)
+            }
+            (Method::Put, ["users", uid, "credentials", name]) => uid
+                .parse()
+                .ok()
+                .map(|uid| Route::PutCredential(uid, name.to_string())),
+            (Method::Delete, ["users", uid, "credentials", name]) => uid
+                .parse()
+                .ok()
+                .map(|uid| Route::DeleteCredential(uid, name.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Job submitted via POST /jobs
+#[derive(Debug, Deserialize)]
+struct NewJob {
+    url: String,
+    quality: i16,
+    #[serde(default, rename = "type")]
+    r_type: i16,
+    /// required for keys not bound to a user
+    uid: Option<u32>,
+    playlist: Option<NewPlaylist>,
+    /// notification targets for this job
+    #[serde(default)]
+    notify: Vec<NewTarget>,
+    /// credential profile of the user for this job
+    credentials: Option<String>,
+}
+
+/// Credential profile submitted via PUT /users/<uid>/credentials/<name>
+#[derive(Debug, Deserialize)]
+struct NewCredential {
+    kind: String,
+    data: String,
+}
+
+/// Notification target of a submitted job
+#[derive(Debug, Deserialize)]
+struct NewTarget {
+    kind: String,
+    target: String,
+}
+
+/// Playlist settings of a submitted job, 0 for from & to selects all entries
+#[derive(Debug, Deserialize)]
+struct NewPlaylist {
+    #[serde(default)]
+    from: i16,
+    #[serde(default)]
+    to: i16,
+    #[serde(default)]
+    split: bool,
+}
+
+/// Status code & JSON body of a response
+type ApiResponse = (u16, Value);
+
+/// Returns an error response
+fn api_error(code: u16, msg: &str) -> ApiResponse {
+    (code, json!({ "error": msg }))
+}
+
+/// Start the API server with its worker threads
+pub fn start(db: Arc<dyn Repository>, conf: &ConfigApi) -> Result<()> {
+    let server = Arc::new(Server::http(&conf.listen).map_err(|e| {
+        Error::InternalError(format!("Unable to bind API to {}: {}", conf.listen, e))
+    })?);
+    info!("API listening on {}", conf.listen);
+    for _ in 0..conf.threads.max(1) {
+        let server = server.clone();
+        let db = db.clone();
+        let keys = conf.keys.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                handle_request(&*db, &keys, request);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Handle a single request, responding with JSON
+fn handle_request(db: &dyn Repository, keys: &[ConfigApiKey], mut request: Request) {
+    trace!("API request {} {}", request.method(), request.url());
+    let (code, body) = match authorize(keys, &request) {
+        None => api_error(401, "invalid or missing api key"),
+        Some(key) => match Route::parse(request.method(), request.url()) {
+            None => api_error(404, "unknown endpoint"),
+            Some(route) => {
+                let limit = match route {
+                    Route::PutCredential(..) => MAX_CREDENTIAL_SIZE,
+                    _ => MAX_BODY_SIZE,
+                };
+                let mut body = String::new();
+                let read = request.as_reader().take(limit).read_to_string(&mut body);
+                match read {
+                    Err(_) => api_error(400, "invalid body"),
+                    Ok(_) => match dispatch(db, key, route, &body) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            error!("API error: {:?}", e);
+                            api_error(500, "internal error")
+                        }
+                    },
+                }
+            }
+        },
+    };
+    let response = Response::from_string(body.to_string())
+        .with_status_code(code)
+        .with_header(
+            "Content-Type: application/json"
+                .parse::<Header>()
+                .expect("valid header"),
+        );
+    if let Err(e) = request.respond(response) {
+        debug!("Unable to send API response: {}", e);
+    }
+}
+
+/// Returns the matching key of the request
+fn authorize<'a>(keys: &'a [ConfigApiKey], request: &Request) -> Option<&'a ConfigApiKey> {
+    let header = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))?;
+    find_key(keys, header.value.as_str())
+}
+
+/// Returns the key matching the Authorization header value
+fn find_key<'a>(keys: &'a [ConfigApiKey], header: &str) -> Option<&'a ConfigApiKey> {
+    let token = header.strip_prefix("Bearer ")?.trim();
+    if token.is_empty() {
+        return None;
+    }
+    keys.iter().find(|k| k.key == token)
+}
+
+/// Returns true if the key is allowed to act for the user
+fn allowed(key: &ConfigApiKey, uid: &u32) -> bool {
+    match key.uid {
+        Some(v) => v == *uid,
+        None => true,
+    }
+}
+
+/// Perform the request of the route
+fn dispatch(
+    db: &dyn Repository,
+    key: &ConfigApiKey,
+    route: Route,
+    body: &str,
+) -> Result<ApiResponse> {
+    match route {
+        Route::AddJob => {
+            let job: NewJob = match serde_json::from_str(body) {
+                Ok(v) => v,
+                Err(e) => return Ok(api_error(400, &format!("invalid job: {}", e))),
+            };
+            let uid = match (key.uid, job.uid) {
+                (Some(bound), Some(uid)) if bound != uid => {
+                    return Ok(api_error(403, "key is bound to another user"))
+                }
+                (Some(bound), _) => bound,
+                (None, Some(uid)) => uid,
+                (None, None) => return Ok(api_error(400, "missing uid")),
+            };
+            if job.url.is_empty() || job.url.len() > MAX_URL_LENGTH {
+                return Ok(api_error(400, "invalid url length"));
+            }
+            if let Some(t) = job
+                .notify
+                .iter()
+                .find(|t| !notify::valid_target(&t.kind, &t.target))
+            {
+                return Ok(api_error(
+                    400,
+                    &format!("invalid notification target {:?}", t.target),
+                ));
+            }
+            if let Some(ref name) = job.credentials {
+                if db.get_credential(&uid, name)?.is_none() {
+                    return Ok(api_error(400, "unknown credential profile"));
+                }
+            }
+            let playlist = job.playlist.map(|p| PlaylistRange {
+                from: p.from,
+                to: p.to,
+                split: p.split,
+            });
+            let qid = db.add_query(&job.url, &job.quality, &uid, &job.r_type, playlist)?;
+            for t in &job.notify {
+                db.add_notify_target(None, Some(qid), &t.kind, &t.target)?;
+            }
+            if let Some(ref name) = job.credentials {
+                db.set_query_credential(&qid, name)?;
+            }
+            debug!("API added job {} for {}", qid, uid);
+            Ok((201, json!({ "qid": qid })))
+        }
+        Route::Job(qid) => match db.get_query_info(&qid)? {
+            Some(ref info) if allowed(key, &info.uid) => Ok((
+                200,
+                json!({
+                    "qid": info.qid,
+                    "uid": info.uid,
+                    "url": info.url,
+                    "quality": info.quality,
+                    "type": info.r_type,
+                    "created": info.created.to_string(),
+                    "code": info.code,
+                    "progress": info.progress,
+                    "status": info.status,
+                    "message": notify::status_name(info.code),
+                    "error": info.error,
+                    "files": info.files,
+                }),
+            )),
+            _ => Ok(api_error(404, "unknown job")),
+        },
+        Route::JobEvents(qid) => match db.get_query_info(&qid)? {
+            Some(ref info) if allowed(key, &info.uid) => {
+                let events: Vec<Value> = db
+                    .get_events(&qid)?
+                    .into_iter()
+                    .map(|e| {
+                        json!({
+                            "created": e.created.to_string(),
+                            "stage": e.stage,
+                            "code": e.code,
+                            "worker": e.worker,
+                            "message": e.message,
+                        })
+                    })
+                    .collect();
+                Ok((200, Value::Array(events)))
+            }
+            _ => Ok(api_error(404, "unknown job")),
+        },
+        Route::JobFailures(qid) => match db.get_query_info(&qid)? {
+            Some(ref info) if allowed(key, &info.uid) => {
+                let failures: Vec<Value> = db
+                    .get_entry_failures(&qid)?
+                    .into_iter()
+                    .map(|f| {
+                        json!({
+                            "index": f.index,
+                            "url": f.url,
+                            "title": f.title,
+                            "kind": f.kind,
+                            "message": f.message,
+                        })
+                    })
+                    .collect();
+                Ok((200, Value::Array(failures)))
+            }
+            _ => Ok(api_error(404, "unknown job")),
+        },
+        Route::RetryJob(qid) => match db.get_query_info(&qid)? {
+            Some(ref info) if allowed(key, &info.uid) => match db::add_retry_query(db, info) {
+                Ok(retry) => {
+                    debug!("API added retry {} of job {}", retry, qid);
+                    Ok((201, json!({ "qid": retry })))
+                }
+                Err(Error::InputError(e)) => Ok(api_error(409, &e)),
+                Err(e) => Err(e),
+            },
+            _ => Ok(api_error(404, "unknown job")),
+        },
+        Route::UserFiles(uid) => {
+            if !allowed(key, &uid) {
+                return Ok(api_error(403, "key is bound to another user"));
+            }
+            let files: Vec<Value> = db
+                .get_user_files(&uid)?
+                .into_iter()
+                .map(|f| {
+                    json!({
+                        "fid": f.fid,
+                        "name": f.name,
+                        "real_name": f.real_name,
+                        "size": f.size,
+                        "valid": f.valid,
+                        "delete": f.delete,
+                        "created": f.created.to_string(),
+                    })
+                })
+                .collect();
+            Ok((200, Value::Array(files)))
+        }
+        Route::DeleteFile(fid) => {
+            let links = db.get_file_links(&fid)?;
+            let own: Vec<u64> = links
+                .iter()
+                .filter(|(_, uid)| allowed(key, uid))
+                .map(|(qid, _)| *qid)
+                .collect();
+            if own.is_empty() {
+                return Ok(api_error(404, "unknown file"));
+            }
+            // deleted by the cleanup task, see delete_files
+            if own.len() == links.len() {
+                db.set_file_delete_flag(&fid, true)?;
+            } else {
+                // shared with other users, only unlink the queries of the caller
+                for qid in &own {
+                    db.set_file_link_delete_flag(qid, &fid, true)?;
+                }
+            }
+            Ok((202, json!({ "fid": fid })))
+        }
+        Route::UserCredentials(uid) => {
+            if !allowed(key, &uid) {
+                return Ok(api_error(403, "key is bound to another user"));
+            }
+            let profiles: Vec<Value> = db
+                .get_credential_names(&uid)?
+                .into_iter()
+                .map(|(name, kind)| json!({ "name": name, "kind": kind }))
+                .collect();
+            Ok((200, Value::Array(profiles)))
+        }
+        Route::PutCredential(uid, name) => {
+            if !allowed(key, &uid) {
+                return Ok(api_error(403, "key is bound to another user"));
+            }
+            let profile: NewCredential = match serde_json::from_str(body) {
+                Ok(v) => v,
+                Err(e) => return Ok(api_error(400, &format!("invalid profile: {}", e))),
+            };
+            let kind = match credentials::Kind::parse(&profile.kind) {
+                Some(v) => v,
+                None => return Ok(api_error(400, "kind has to be cookies or netrc")),
+            };
+            match credentials::store(db, &uid, &name, kind, profile.data.as_bytes()) {
+                Ok(_) => Ok((200, json!({ "name": name, "kind": kind.as_str() }))),
+                Err(Error::InputError(e)) => Ok(api_error(400, &e)),
+                Err(e) => Err(e),
+            }
+        }
+        Route::DeleteCredential(uid, name) => {
+            if !allowed(key, &uid) {
+                return Ok(api_error(403, "key is bound to another user"));
+            }
+            match db.delete_credential(&uid, &name)? {
+                true => Ok((200, json!({ "name": name }))),
+                false => Ok(api_error(404, "unknown credential profile")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn route() {
+        assert_eq!(Route::parse(&Method::Post, "/jobs"), Some(Route::AddJob));
+        assert_eq!(Route::parse(&Method::Get, "/jobs/12"), Some(Route::Job(12)));
+        assert_eq!(
+            Route::parse(&Method::Get, "/jobs/12?a=b"),
+            Some(Route::Job(12))
+        );
+        assert_eq!(
+            Route::parse(&Method::Get, "/users/3/files/"),
+            Some(Route::UserFiles(3))
+        );
+        assert_eq!(
+            Route::parse(&Method::Delete, "/files/7"),
+            Some(Route::DeleteFile(7))
+        );
+        assert_eq!(
+            Route::parse(&Method::Get, "/jobs/12/events"),
+            Some(Route::JobEvents(12))
+        );
+        assert_eq!(
+            Route::parse(&Method::Get, "/jobs/12/failures"),
+            Some(Route::JobFailures(12))
+        );
+        assert_eq!(
+            Route::parse(&Method::Post, "/jobs/12/retry"),
+            Some(Route::RetryJob(12))
+        );
+        assert_eq!(
+            Route::parse(&Method::Put, "/users/3/credentials/main"),
+            Some(Route::PutCredential(3, String::from("main")))
+        );
+        assert_eq!(
+            Route::parse(&Method::Get, "/users/3/credentials"),
+            Some(Route::UserCredentials(3))
+        );
+        assert_eq!(Route::parse(&Method::Get, "/jobs"), None);
+        assert_eq!(Route::parse(&Method::Get, "/jobs/abc"), None);
+        assert_eq!(Route::parse(&Method::Delete, "/jobs/12"), None);
+    }
+
+    #[test]
+    fn keys() {
+        let keys = vec![
+            ConfigApiKey {
+                key: String::from("frontend"),
+                uid: None,
+            },
+            ConfigApiKey {
+                key: String::from("user"),
+                uid: Some(3),
+            },
+        ];
+        assert!(find_key(&keys, "Bearer frontend").unwrap().uid.is_none());
+        assert_eq!(find_key(&keys, "Bearer user").unwrap().uid, Some(3));
+        assert!(find_key(&keys, "Bearer other").is_none());
+        assert!(find_key(&keys, "user").is_none());
+        assert!(find_key(&keys, "Bearer ").is_none());
+
+        assert!(allowed(&keys[0], &1));
+        assert!(allowed(&keys[1], &3));
+        assert!(!allowed(&keys[1], &1));
+    }
+
+    #[test]
+    fn new_job() {
+        let job: NewJob = serde_json::from_str(
+            r#"{"url":"https://www.youtube.com/playlist?list=x","quality":-1,"playlist":{"to":5}}"#,
+        )
+        .unwrap();
+        assert_eq!(job.r_type, 0);
+        assert!(job.uid.is_none());
+        let playlist = job.playlist.unwrap();
+        assert_eq!((playlist.from, playlist.to, playlist.split), (0, 5, false));
+        assert!(job.notify.is_empty());
+        assert!(job.credentials.is_none());
+        assert!(serde_json::from_str::<NewJob>(r#"{"url":"x"}"#).is_err());
+    }
+
+    #[test]
+    fn delete_shared_file() {
+        let db = db::SqliteRepository::open_in_memory().unwrap();
+        let qid = db.add_query("a", &137, &1, &0, None).unwrap();
+        let qid_other = db.add_query("a", &137, &2, &0, None).unwrap();
+        let fid = db.add_file_entry(&qid, "a", "A", &10).unwrap();
+        db.add_file_link(&qid_other, &fid).unwrap();
+        let key = |uid| ConfigApiKey {
+            key: String::from("key"),
+            uid,
+        };
+
+        let (status, _) = dispatch(&db, &key(Some(3)), Route::DeleteFile(fid), "").unwrap();
+        assert_eq!(status, 404);
+        // only the link of the user is marked, the file is kept for the other one
+        let (status, _) = dispatch(&db, &key(Some(1)), Route::DeleteFile(fid), "").unwrap();
+        assert_eq!(status, 202);
+        assert!(!db.get_file(&fid).unwrap().unwrap().delete);
+        assert_eq!(db.get_file_refs(&fid).unwrap(), vec![qid_other]);
+
+        let (status, _) = dispatch(&db, &key(None), Route::DeleteFile(fid), "").unwrap();
+        assert_eq!(status, 202);
+        assert!(db.get_file(&fid).unwrap().unwrap().delete);
+    }
+}
@@ -0,0 +1,303 @@
+//! Global bandwidth budget shared by all running downloads
+//!
+//! The limit is `download_mbps`, overridden by the first matching window of `bandwidth.schedule`.
+//! Every download holds a `Lease`, getting an even share of the budget.
+//! yt-dl & the lib receive their share as rate limit on start, `lib::http` downloads follow it live.
+
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+
+use std::cmp::max;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::lib::config::{self, ConfigBandwidthWindow};
+
+/// Bytes per second of 1 MBit/s
+const BYTES_PER_MBIT: u64 = 125_000;
+/// Lowest share of a single download in bytes per second
+const MIN_RATE: u64 = 16 * 1024;
+/// Interval of live downloads re-checking their share
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref BUDGET: Budget = Budget::new(Box::new(current_limit));
+}
+
+/// Acquire a share of the global budget for a download, released on drop
+pub fn acquire() -> Lease<'static> {
+    BUDGET.acquire()
+}
+
+/// Parsed schedule window
+#[derive(Debug, PartialEq)]
+pub struct Window {
+    days: Vec<Weekday>,
+    from: NaiveTime,
+    to: NaiveTime,
+    /// bytes per second, None for unlimited
+    limit: Option<u64>,
+}
+
+impl Window {
+    /// Parse a configured window, returns a description of the problem for invalid ones
+    pub fn parse(config: &ConfigBandwidthWindow) -> Result<Window, String> {
+        let time = |v: &str| {
+            NaiveTime::parse_from_str(v, "%H:%M")
+                .map_err(|_| format!("{} is no valid HH:MM time", v))
+        };
+        let from = time(&config.from)?;
+        let to = time(&config.to)?;
+        if from == to {
+            return Err(format!("{}-{} is an empty window", config.from, config.to));
+        }
+        let days = config
+            .days
+            .iter()
+            .map(|v| {
+                v.parse::<Weekday>()
+                    .map_err(|_| format!("{} is no valid day", v))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Window {
+            days,
+            from,
+            to,
+            limit: mbps_limit(config.mbps),
+        })
+    }
+
+    /// Returns true if the window applies at `now`
+    /// Windows spanning midnight belong to the day they start.
+    fn contains(&self, now: &NaiveDateTime) -> bool {
+        let time = now.time();
+        let day = now.weekday();
+        let on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if self.from < self.to {
+            time >= self.from && time < self.to && on(day)
+        } else {
+            (time >= self.from && on(day)) || (time < self.to && on(day.pred()))
+        }
+    }
+}
+
+/// Parse all windows of a schedule
+pub fn parse_schedule(windows: &[ConfigBandwidthWindow]) -> Result<Vec<Window>, String> {
+    windows.iter().map(Window::parse).collect()
+}
+
+/// Limit in bytes per second of a MBit/s value, None for 0 = unlimited
+fn mbps_limit(mbps: u16) -> Option<u64> {
+    match mbps {
+        0 => None,
+        v => Some(v as u64 * BYTES_PER_MBIT),
+    }
+}
+
+/// Returns the limit at `now` in bytes per second, None for unlimited
+fn limit_at(schedule: &[Window], default_mbps: u16, now: &NaiveDateTime) -> Option<u64> {
+    match schedule.iter().find(|v| v.contains(now)) {
+        Some(window) => window.limit,
+        None => mbps_limit(default_mbps),
+    }
+}
+
+/// Returns the current limit of the live config in bytes per second, None for unlimited
+pub fn current_limit() -> Option<u64> {
+    let live = config::live();
+    // validated on load & reload
+    let schedule = parse_schedule(&live.bandwidth.schedule).unwrap_or_default();
+    limit_at(&schedule, live.download_mbps, &Local::now().naive_local())
+}
+
+/// Bandwidth budget split between its leases
+pub struct Budget {
+    limit: Box<dyn Fn() -> Option<u64> + Send + Sync>,
+    active: Mutex<u64>,
+}
+
+impl Budget {
+    pub fn new(limit: Box<dyn Fn() -> Option<u64> + Send + Sync>) -> Budget {
+        Budget {
+            limit,
+            active: Mutex::new(0),
+        }
+    }
+
+    pub fn acquire(&self) -> Lease<'_> {
+        *self.active.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        Lease { budget: self }
+    }
+}
+
+/// Share of a budget held by a running download
+pub struct Lease<'a> {
+    budget: &'a Budget,
+}
+
+impl<'a> Lease<'a> {
+    /// Current share in bytes per second, None for unlimited
+    pub fn rate(&self) -> Option<u64> {
+        let active = *self.budget.active.lock().unwrap_or_else(|e| e.into_inner());
+        (self.budget.limit)().map(|v| max(v / max(active, 1), MIN_RATE))
+    }
+
+    /// Current share as yt-dl rate limit argument, None for unlimited
+    pub fn rate_arg(&self) -> Option<String> {
+        self.rate().map(|v| format!("{}K", v / 1024))
+    }
+}
+
+impl<'a> Drop for Lease<'a> {
+    fn drop(&mut self) {
+        *self.budget.active.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+    }
+}
+
+/// Reader limited to the share of its lease
+pub struct Throttled<'a, R> {
+    inner: R,
+    lease: Lease<'a>,
+    rate: Option<u64>,
+    checked: Instant,
+    /// start & bytes read since the last rate change
+    started: Instant,
+    bytes: u64,
+}
+
+impl<'a, R: Read> Throttled<'a, R> {
+    pub fn new(inner: R, lease: Lease<'a>) -> Throttled<'a, R> {
+        let now = Instant::now();
+        Throttled {
+            inner,
+            rate: lease.rate(),
+            lease,
+            checked: now,
+            started: now,
+            bytes: 0,
+        }
+    }
+}
+
+impl<'a, R: Read> Read for Throttled<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if self.checked.elapsed() >= RECHECK_INTERVAL {
+            self.checked = Instant::now();
+            let rate = self.lease.rate();
+            if rate != self.rate {
+                trace!("download rate changed to {:?} B/s", rate);
+                self.rate = rate;
+                self.started = self.checked;
+                self.bytes = 0;
+            }
+        }
+        if let Some(rate) = self.rate {
+            self.bytes += read as u64;
+            let expected = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+            let elapsed = self.started.elapsed();
+            if expected > elapsed {
+                sleep(expected - elapsed);
+            }
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn window(days: &[&str], from: &str, to: &str, mbps: u16) -> ConfigBandwidthWindow {
+        ConfigBandwidthWindow {
+            days: days.iter().map(|v| v.to_string()).collect(),
+            from: from.to_string(),
+            to: to.to_string(),
+            mbps,
+        }
+    }
+
+    /// 2021-10-18 is a monday
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2021, 10, day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn window_test() {
+        let office = Window::parse(&window(&["mon", "tue"], "08:00", "18:00", 10)).unwrap();
+        assert!(office.contains(&at(18, "08:00")));
+        assert!(office.contains(&at(19, "17:59")));
+        assert!(!office.contains(&at(18, "18:00")));
+        assert!(!office.contains(&at(20, "12:00")));
+
+        // spans midnight, belongs to the day it starts
+        let night = Window::parse(&window(&["sun"], "22:00", "06:00", 0)).unwrap();
+        assert!(night.contains(&at(17, "23:00")));
+        assert!(night.contains(&at(18, "05:59")));
+        assert!(!night.contains(&at(18, "23:00")));
+        assert!(!night.contains(&at(17, "05:00")));
+
+        assert!(Window::parse(&window(&[], "8:00", "24:00", 1)).is_err());
+        assert!(Window::parse(&window(&[], "08:00", "08:00", 1)).is_err());
+        assert!(Window::parse(&window(&["weekday"], "08:00", "18:00", 1)).is_err());
+    }
+
+    #[test]
+    fn limit_test() {
+        let schedule = parse_schedule(&[
+            window(&["mon"], "08:00", "18:00", 10),
+            window(&[], "00:00", "12:00", 0),
+        ])
+        .unwrap();
+        // first matching window applies
+        assert_eq!(
+            Some(10 * BYTES_PER_MBIT),
+            limit_at(&schedule, 48, &at(18, "09:00"))
+        );
+        assert_eq!(None, limit_at(&schedule, 48, &at(19, "09:00")));
+        assert_eq!(
+            Some(48 * BYTES_PER_MBIT),
+            limit_at(&schedule, 48, &at(19, "13:00"))
+        );
+    }
+
+    #[test]
+    fn lease_test() {
+        let budget = Budget::new(Box::new(|| Some(1024 * 1024)));
+        let first = budget.acquire();
+        assert_eq!(Some(1024 * 1024), first.rate());
+        assert_eq!(Some("1024K".to_string()), first.rate_arg());
+        let second = budget.acquire();
+        assert_eq!(Some(512 * 1024), first.rate());
+        let rest: Vec<_> = (0..100).map(|_| budget.acquire()).collect();
+        assert_eq!(Some(MIN_RATE), second.rate());
+        drop(rest);
+        drop(first);
+        assert_eq!(Some(1024 * 1024), second.rate());
+
+        let unlimited = Budget::new(Box::new(|| None));
+        assert_eq!(None, unlimited.acquire().rate_arg());
+    }
+
+    #[test]
+    fn throttle_test() {
+        let budget = Budget::new(Box::new(|| Some(50_000)));
+        let data = vec![1u8; 10_000];
+        let start = Instant::now();
+        let mut reader = Throttled::new(&data[..], budget.acquire());
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(data, out);
+        assert!(start.elapsed() >= Duration::from_millis(190));
+
+        let unlimited = Budget::new(Box::new(|| None));
+        let start = Instant::now();
+        let mut reader = Throttled::new(&data[..], unlimited.acquire());
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
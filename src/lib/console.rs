@@ -0,0 +1,88 @@
+//! Console progress reporter, used by the one-shot mode instead of a DB consumer
+//!
+//! Polls the progress & step state of all active queries, printing changes to stderr.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::lib::db::Repository;
+
+/// Running reporter thread, stopped on `stop`
+pub struct Reporter {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Reporter {
+    /// Start reporting, polling every interval
+    pub fn start(db: Arc<dyn Repository>, interval: Duration) -> Reporter {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_c = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut last = HashMap::new();
+            while !stop_c.load(Ordering::Relaxed) {
+                report(&*db, &mut last);
+                thread::sleep(interval);
+            }
+            report(&*db, &mut last);
+        });
+        Reporter { stop, handle }
+    }
+
+    /// Stop reporting, after printing the last changes
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.handle.join().is_err() {
+            warn!("console reporter panicked");
+        }
+    }
+}
+
+/// Print the state of active queries, if changed since the last report
+fn report(db: &dyn Repository, last: &mut HashMap<u64, String>) {
+    let qids = match db.get_active_queries() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Unable to retrieve active queries: {}", e);
+            return;
+        }
+    };
+    for qid in qids {
+        if let Ok(Some(info)) = db.get_query_info(&qid) {
+            let line = state_line(&info.url, info.progress, info.status.as_deref());
+            if last.get(&qid) != Some(&line) {
+                eprintln!("[{}] {}", qid, line);
+                last.insert(qid, line);
+            }
+        }
+    }
+}
+
+/// Format the state of a query
+fn state_line(url: &str, progress: Option<f64>, steps: Option<&str>) -> String {
+    let mut line = url.to_string();
+    if let Some(steps) = steps {
+        line.push_str(&format!(" step {}", steps.replace('|', "/")));
+    }
+    if let Some(progress) = progress {
+        line.push_str(&format!(" {:.1}%", progress));
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn state_line_test() {
+        assert_eq!("url", state_line("url", None, None));
+        assert_eq!(
+            "url step 1/3 45.0%",
+            state_line("url", Some(45.0), Some("1|3"))
+        );
+    }
+}
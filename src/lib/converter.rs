@@ -1,18 +1,18 @@
 extern crate regex;
 
-use std::convert::Into;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::{Child, Command, Stdio};
+use std::process::{Command, Stdio};
 use std::str;
+use std::sync::Arc;
 
+use crate::lib::db::Repository;
+use crate::lib::metrics;
+use crate::lib::process::{self, Process, Runner, SystemRunner, Timeouts, Watchdog};
 use crate::lib::{Error, Result};
-
-use mysql::prelude::Queryable;
-use mysql::PooledConn;
-use mysql::Statement;
+use crate::CONFIG;
 
 macro_rules! regex(
     ($s:expr) => (regex::Regex::new($s).unwrap());
@@ -22,6 +22,8 @@ macro_rules! regex(
 pub struct Converter<'a> {
     ffmpeg_dir: PathBuf,
     mp3_quality: &'a i16,
+    runner: Arc<dyn Runner>,
+    timeouts: Timeouts,
 }
 
 /// Struct containing file information needed for progress calculation
@@ -32,28 +34,30 @@ struct FileInfo {
 
 impl<'a> Converter<'a> {
     pub fn new(ffmpeg_dir: &'a str, mp3_quality: &'a i16) -> Converter<'a> {
+        Converter::with_runner(ffmpeg_dir, mp3_quality, Arc::new(SystemRunner))
+    }
+
+    /// Create a converter spawning its processes via `runner`
+    pub fn with_runner(
+        ffmpeg_dir: &'a str,
+        mp3_quality: &'a i16,
+        runner: Arc<dyn Runner>,
+    ) -> Converter<'a> {
         debug!("ffmpeg dir: {}", ffmpeg_dir);
         Converter {
             ffmpeg_dir: PathBuf::from(ffmpeg_dir),
             mp3_quality: mp3_quality,
+            runner: runner,
+            timeouts: Timeouts::from_config(&CONFIG.timeouts),
         }
     }
 
-    /// Run a self-test checking for ffmpeg binaries
+    /// Run a self-test checking for ffmpeg binaries & the encoders of `ffmpeg.encoders`
     /// Returns true on success
     pub fn startup_test(&self) -> bool {
         info!("Testing converter settings");
-        let mut result = self.test_cmd("ffmpeg");
-        match result {
-            Ok(v) => {
-                if v {
-                    result = self.test_cmd("ffprobe");
-                }
-            }
-            _ => {}
-        }
-        match result {
-            Ok(v) => v,
+        match self.verify(&CONFIG.ffmpeg.encoders) {
+            Ok(_) => true,
             Err(e) => {
                 error!("Error on converter self test: {:?}", e);
                 false
@@ -61,31 +65,62 @@ impl<'a> Converter<'a> {
         }
     }
 
+    /// Check for working ffmpeg & ffprobe binaries, ffmpeg supporting all `encoders`
+    pub fn verify(&self, encoders: &[String]) -> Result<()> {
+        for command in ["ffmpeg", "ffprobe"] {
+            if !self.test_cmd(command)? {
+                return Err(Error::InternalError(format!(
+                    "{} not found in {:?}",
+                    command, self.ffmpeg_dir
+                )));
+            }
+        }
+        let missing = self.missing_encoders(encoders)?;
+        if !missing.is_empty() {
+            return Err(Error::InternalError(format!(
+                "ffmpeg lacks the encoders {}",
+                missing.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns all `encoders` not supported by ffmpeg
+    fn missing_encoders<'b>(&self, encoders: &'b [String]) -> Result<Vec<&'b str>> {
+        let mut cmd = self.create_ffmpeg_base("ffmpeg");
+        cmd.args(["-hide_banner", "-encoders"]);
+        let output = self.runner.output(&mut cmd)?;
+        // " A....D libmp3lame           libmp3lame MP3 (MPEG audio layer 3) (codec mp3)"
+        let available: Vec<&str> = output
+            .stdout
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(1))
+            .collect();
+        Ok(encoders
+            .iter()
+            .map(|v| v.as_str())
+            .filter(|v| !available.contains(v))
+            .collect())
+    }
+
     /// Calls the provided binary of ffmpeg, expecting a '[binary] version] response on stdout
     /// Returns true on success
     fn test_cmd(&self, command: &str) -> Result<bool> {
         let mut cmd = self.create_ffmpeg_base(command);
         cmd.arg("-version");
 
-        match cmd.spawn() {
+        match self.runner.output(&mut cmd) {
             Err(why) => {
                 error!("Error on converter test for {}: {}", command, why);
                 return Ok(false);
             }
-            Ok(mut process) => {
-                let mut stdout_buffer = BufReader::new(process.stdout.take().unwrap());
-                let mut stdout: String = String::new();
-                stdout_buffer.read_to_string(&mut stdout)?;
-                let mut stderr_buffer = BufReader::new(process.stderr.take().unwrap());
-                let mut stderr: String = String::new();
-                stderr_buffer.read_to_string(&mut stderr)?;
-                process.wait()?;
-                trace!("{} stdout: {}", command, stdout);
-                if !stderr.is_empty() {
-                    warn!("{} test stderr: {}", command, stderr);
+            Ok(output) => {
+                trace!("{} stdout: {}", command, output.stdout);
+                if !output.stderr.is_empty() {
+                    warn!("{} test stderr: {}", command, output.stderr);
                     return Ok(false);
                 }
-                Ok(stdout.contains(&format!("{} version", command)))
+                Ok(output.stdout.contains(&format!("{} version", command)))
             }
         }
     }
@@ -99,16 +134,17 @@ impl<'a> Converter<'a> {
         video_file: &Path,
         audio_file: &Path,
         output_file: &Path,
-        conn: &mut PooledConn,
+        db: &dyn Repository,
     ) -> Result<()> {
+        let _timer = metrics::stage_timer(metrics::STAGE_MERGE);
         let file_info = self.get_file_info(video_file)?;
         trace!("Total frames: {}", file_info.frames);
 
         let mut child = self.run_merge_cmd(audio_file, video_file, output_file)?;
+        let watchdog = Watchdog::start(&*child, "merge", self.timeouts.convert);
         trace!("started merge process");
-        let mut stdout = BufReader::new(child.stderr.take().unwrap());
+        let mut stdout = BufReader::new(child.take_stderr().unwrap());
 
-        let statement = self.prepare_progress_updater(conn);
         let re = regex!(r"frame=\s*(\d+)");
 
         let mut buf = vec![];
@@ -132,19 +168,19 @@ impl<'a> Converter<'a> {
                 if let Some(cap) = re.captures(&line) {
                     debug!("frame: {}", cap.get(1).unwrap().as_str());
                     cur_frame = cap.get(1).unwrap().as_str().parse::<f32>().unwrap();
-                    self.update_progress(
-                        conn,
-                        &statement,
-                        format!("{:.2}", (cur_frame / file_info.frames) * 100.0),
-                        qid,
-                    )?;
+                    watchdog.progress();
+                    self.update_progress(db, (cur_frame / file_info.frames) * 100.0, qid)?;
                 }
             }
 
             buf.clear();
         }
 
-        child.wait()?;
+        let success = child.wait()?;
+        watchdog.finish()?;
+        if !success {
+            return Err(Error::FFMPEGError(String::from("merge process failed")));
+        }
 
         Ok(())
     }
@@ -157,8 +193,9 @@ impl<'a> Converter<'a> {
         video_file: &Path,
         output_file: &Path,
         convert_mp3: bool,
-        conn: &mut PooledConn,
+        db: &dyn Repository,
     ) -> Result<()> {
+        let _timer = metrics::stage_timer(metrics::STAGE_EXTRACT);
         let file_info = self.get_file_info(video_file)?;
         debug!("duration: {}", file_info.duration);
 
@@ -168,10 +205,10 @@ impl<'a> Converter<'a> {
         } else {
             child = self.run_audio_extract(video_file, output_file)?;
         }
+        let watchdog = Watchdog::start(&*child, "audio extraction", self.timeouts.convert);
 
-        let mut stdout = BufReader::new(child.stderr.take().unwrap());
+        let mut stdout = BufReader::new(child.take_stderr().unwrap());
 
-        let statement = self.prepare_progress_updater(conn);
         let re = regex!(r"time=(\d+):(\d+):(\d+.?\d*)");
 
         let mut buf = vec![];
@@ -197,19 +234,21 @@ impl<'a> Converter<'a> {
 
                     let seconds: f32 =
                         seconds as f32 + cap.get(3).unwrap().as_str().parse::<f32>().unwrap();
-                    self.update_progress(
-                        conn,
-                        &statement,
-                        format!("{:.2}", (seconds / file_info.duration) * 100.0),
-                        qid,
-                    )?;
+                    watchdog.progress();
+                    self.update_progress(db, (seconds / file_info.duration) * 100.0, qid)?;
                 }
             }
 
             buf.clear();
         }
 
-        child.wait()?;
+        let success = child.wait()?;
+        watchdog.finish()?;
+        if !success {
+            return Err(Error::FFMPEGError(String::from(
+                "audio extraction process failed",
+            )));
+        }
 
         Ok(())
     }
@@ -246,26 +285,20 @@ impl<'a> Converter<'a> {
     /// Runs a file probe and returns its output, used in progress calculation
     fn run_file_probe(&self, video_file: &Path) -> Result<String> {
         let mut command = self.create_ffmpeg_base("ffprobe");
-        command.args(&["-select_streams", "0"]);
-        command.args(&["-show_entries", "format=duration:stream=r_frame_rate"]);
-        command.args(&["-of", "default=noprint_wrappers=1"]);
+        command.args(["-select_streams", "0"]);
+        command.args(["-show_entries", "format=duration:stream=r_frame_rate"]);
+        command.args(["-of", "default=noprint_wrappers=1"]);
         command.arg(video_file);
 
-        match command.spawn() {
-            Err(why) => Err(why.into()),
-            Ok(mut process) => {
-                let mut stdout_buffer = BufReader::new(process.stdout.take().unwrap());
-                let mut stdout: String = String::new();
-                stdout_buffer.read_to_string(&mut stdout)?;
-                let mut stderr_buffer = BufReader::new(process.stderr.take().unwrap());
-                let mut stderr: String = String::new();
-                stderr_buffer.read_to_string(&mut stderr)?;
-                process.wait()?;
-                debug!("ffprobe: {}", stdout);
-                debug!("ffprobe err: {}", stderr);
-                Ok(stdout)
-            }
-        }
+        let output = process::output_limited(
+            &*self.runner,
+            &mut command,
+            "file probe",
+            self.timeouts.probe,
+        )?;
+        debug!("ffprobe: {}", output.stdout);
+        debug!("ffprobe err: {}", output.stderr);
+        Ok(output.stdout)
     }
 
     /// Merges an audio & an video file together.
@@ -275,84 +308,172 @@ impl<'a> Converter<'a> {
         audio_file: &Path,
         video_file: &Path,
         output_file: &Path,
-    ) -> Result<Child> {
+    ) -> Result<Box<dyn Process>> {
         let mut command = self.create_ffmpeg_base("ffmpeg");
-        command.args(&["-threads", "0"]);
+        command.args(["-threads", "0"]);
         command.arg("-i");
         command.arg(video_file);
         command.arg("-i");
         command.arg(audio_file);
-        command.args(&["-map", "0"]);
-        command.args(&["-map", "1"]);
-        command.args(&["-codec", "copy"]);
+        command.args(["-map", "0"]);
+        command.args(["-map", "1"]);
+        command.args(["-codec", "copy"]);
         command.arg("-shortest");
         command.arg(output_file);
         //-stats -threads 0 -i "{}" -i "{}" -map 0 -map 1 -codec copy -shortest "{}"
         debug!("Cmd: {:?}", command);
-        match command.spawn() {
-            Err(why) => Err(why.into()),
-            Ok(process) => Ok(process),
-        }
+        self.runner.spawn(&mut command)
     }
 
     ///Create a ffmpeg instance with the audio extract cmd
-    fn run_audio_extract(&self, video_file: &Path, output_file: &Path) -> Result<Child> {
+    fn run_audio_extract(&self, video_file: &Path, output_file: &Path) -> Result<Box<dyn Process>> {
         let mut command = self.create_ffmpeg_base("ffmpeg");
-        command.args(&["-threads", "0"]);
+        command.args(["-threads", "0"]);
         command.arg("-i");
         command.arg(video_file);
-        command.args(&["-vn", "-acodec", "copy"]);
+        command.args(["-vn", "-acodec", "copy"]);
         command.arg(output_file);
 
-        match command.spawn() {
-            Err(why) => Err(why.into()),
-            Ok(process) => Ok(process),
-        }
+        self.runner.spawn(&mut command)
     }
 
-    fn run_audio_extract_to_mp3(&self, video_file: &Path, output_file: &Path) -> Result<Child> {
+    fn run_audio_extract_to_mp3(
+        &self,
+        video_file: &Path,
+        output_file: &Path,
+    ) -> Result<Box<dyn Process>> {
         let mut command = self.create_ffmpeg_base("ffmpeg");
-        command.args(&["-threads", "0"]);
+        command.args(["-threads", "0"]);
         command.arg("-i");
         command.arg(video_file);
-        command.args(&["-codec:a", "libmp3lame"]);
-        command.args(&["-qscale:a", &self.mp3_quality.to_string()]);
+        command.args(["-codec:a", "libmp3lame"]);
+        command.args(["-qscale:a", &self.mp3_quality.to_string()]);
         command.arg(output_file);
 
-        match command.spawn() {
-            Err(why) => Err(why.into()),
-            Ok(process) => Ok(process),
-        }
+        self.runner.spawn(&mut command)
     }
 
     /// Create FFMPEG basic command
     /// executable is the called ffmpeg binary
     fn create_ffmpeg_base(&self, executable: &str) -> Command {
         let mut cmd = Command::new(self.ffmpeg_dir.join(executable));
-        cmd.args(&["-v", "info"]); // below we're not getting any frame updates, rendering every progress update useless
+        cmd.args(["-v", "info"]); // below we're not getting any frame updates, rendering every progress update useless
         cmd.stdin(Stdio::null());
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
         cmd
     }
 
-    // MyPooledConn does only live when MyOpts is alive -> lifetime needs to be declared
-    fn prepare_progress_updater(&'a self, conn: &'a mut PooledConn) -> Statement {
-        // no livetime needed: struct livetime used
-        conn.prep("UPDATE querydetails SET progress = ? WHERE qid = ?")
-            .unwrap()
+    ///updater called from the stdout progress
+    fn update_progress(&self, db: &dyn Repository, progress: f32, qid: &u64) -> Result<()> {
+        trace!("updating progress {:.2}", progress);
+        db.set_progress(qid, (progress as f64 * 100.0).round() / 100.0)
     }
+}
 
-    ///updater called from the stdout progress
-    fn update_progress(
-        &self,
-        conn: &'a mut PooledConn,
-        stmt: &Statement,
-        progress: String,
-        qid: &u64,
-    ) -> Result<()> {
-        trace!("updating progress {}", progress);
-        conn.exec_drop(stmt, (&progress, qid))?;
-        Ok(())
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lib::db::SqliteRepository;
+    use crate::lib::process::{Transcript, TranscriptRunner};
+
+    #[test]
+    fn merge_test() {
+        let runner = Arc::new(
+            TranscriptRunner::new()
+                .add(
+                    "ffprobe",
+                    None,
+                    Transcript::ok(include_str!("../../tests/transcripts/ffprobe.txt")),
+                )
+                .add(
+                    "ffmpeg",
+                    None,
+                    Transcript {
+                        stdout: String::new(),
+                        stderr: include_str!("../../tests/transcripts/ffmpeg_merge.txt").into(),
+                        success: true,
+                    },
+                ),
+        );
+        let converter = Converter::with_runner("/ffmpeg", &2, runner.clone());
+        let db = SqliteRepository::open_in_memory().unwrap();
+        let qid = db.add_query("url", &22, &1, &0, None).unwrap();
+        converter
+            .merge_files(
+                &qid,
+                Path::new("video.mp4"),
+                Path::new("audio.m4a"),
+                Path::new("out.mp4"),
+                &db,
+            )
+            .unwrap();
+        assert_eq!(
+            Some(100.0),
+            db.get_query_info(&qid).unwrap().unwrap().progress
+        );
+
+        let calls = runner.calls();
+        assert_eq!("ffprobe", calls[0][0]);
+        assert_eq!(Some(&"video.mp4".to_string()), calls[0].last());
+        let merge = &calls[1];
+        assert!(merge.windows(2).any(|v| v == ["-i", "video.mp4"]));
+        assert!(merge.windows(2).any(|v| v == ["-i", "audio.m4a"]));
+        assert_eq!(Some(&"out.mp4".to_string()), merge.last());
+    }
+
+    #[test]
+    fn verify_test() {
+        let runner = |encoders: &str| {
+            Arc::new(
+                TranscriptRunner::new()
+                    .add(
+                        "ffmpeg",
+                        Some("-version"),
+                        Transcript::ok("ffmpeg version 4.4"),
+                    )
+                    .add(
+                        "ffprobe",
+                        Some("-version"),
+                        Transcript::ok("ffprobe version 4.4"),
+                    )
+                    .add("ffmpeg", Some("-encoders"), Transcript::ok(encoders)),
+            )
+        };
+        let encoders = vec![String::from("libmp3lame"), String::from("libopus")];
+        let converter = Converter::with_runner(
+            "/ffmpeg",
+            &2,
+            runner(" A....D libmp3lame  libmp3lame MP3\n A....D libopus  libopus Opus\n"),
+        );
+        converter.verify(&encoders).unwrap();
+
+        let converter = Converter::with_runner(
+            "/ffmpeg",
+            &2,
+            runner(" A....D libmp3lame  libmp3lame MP3\n"),
+        );
+        match converter.verify(&encoders) {
+            Err(Error::InternalError(e)) => assert!(e.ends_with("encoders libopus")),
+            v => panic!("unexpected {:?}", v),
+        }
+    }
+
+    #[test]
+    fn file_info_test() {
+        let runner = TranscriptRunner::new().add(
+            "ffprobe",
+            None,
+            Transcript::ok(include_str!("../../tests/transcripts/ffprobe.txt")),
+        );
+        let converter = Converter::with_runner("/ffmpeg", &2, Arc::new(runner));
+        let info = converter.get_file_info(Path::new("video.mp4")).unwrap();
+        assert_eq!(10.0, info.duration);
+        assert_eq!(250.0, info.frames);
+
+        let runner =
+            TranscriptRunner::new().add("ffprobe", None, Transcript::failed("No such file"));
+        let converter = Converter::with_runner("/ffmpeg", &2, Arc::new(runner));
+        assert!(converter.get_file_info(Path::new("video.mp4")).is_err());
     }
 }
@@ -0,0 +1,291 @@
+//! Encrypted credential profiles of users, cookies.txt or netrc files for login-gated content
+//!
+//! Profiles are stored encrypted in the DB, selected per job and written into its workspace
+//! only while the job runs. The files are overwritten & removed afterwards.
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::lib::db::Repository;
+use crate::lib::{Error, Request, Result};
+use crate::CONFIG;
+
+/// Max size of a profile file
+pub const MAX_SIZE: usize = 256 * 1024;
+/// Max length of profile names, see `credentials` table
+const MAX_NAME_LENGTH: usize = 50;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+/// Name of the profile file in the job workspace
+const FILE_NAME: &str = ".credentials";
+
+/// Type of a credential profile
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// Netscape cookies.txt file, for example exported from a browser session
+    Cookies,
+    /// netrc file with login & password per machine
+    Netrc,
+}
+
+impl Kind {
+    pub fn parse(kind: &str) -> Option<Kind> {
+        match kind {
+            "cookies" => Some(Kind::Cookies),
+            "netrc" => Some(Kind::Netrc),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Cookies => "cookies",
+            Kind::Netrc => "netrc",
+        }
+    }
+
+    /// yt-dl arguments using the profile file
+    fn ytdl_args(&self, file: &str) -> Vec<String> {
+        match self {
+            Kind::Cookies => vec!["--cookies".into(), file.into()],
+            Kind::Netrc => vec!["--netrc".into(), "--netrc-location".into(), file.into()],
+        }
+    }
+}
+
+/// Decode a base64 encryption key, None if it isn't 32 bytes long
+pub fn parse_key(key: &str) -> Option<Vec<u8>> {
+    base64::decode(key.trim())
+        .ok()
+        .filter(|v| v.len() == KEY_LEN)
+}
+
+/// Returns the configured encryption key
+fn key() -> Result<Vec<u8>> {
+    if CONFIG.credentials.key.is_empty() {
+        return Err(Error::InputError(
+            "credential profiles are disabled, no credentials.key set".into(),
+        ));
+    }
+    parse_key(&CONFIG.credentials.key)
+        .ok_or_else(|| Error::InternalError("invalid credentials.key".into()))
+}
+
+/// Associated data of a profile, binding its ciphertext to user & name
+fn aad(uid: &u32, name: &str) -> String {
+    format!("{}/{}", uid, name)
+}
+
+/// Encrypt a profile, returns base64 of nonce & ciphertext
+fn encrypt(key: &[u8], aad: &str, plain: &[u8]) -> Result<String> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce)
+        .map_err(|e| Error::InternalError(format!("no random nonce: {}", e)))?;
+    let payload = Payload {
+        msg: plain,
+        aad: aad.as_bytes(),
+    };
+    let mut data = nonce.to_vec();
+    data.extend(
+        cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| Error::InternalError("encryption failed".into()))?,
+    );
+    Ok(base64::encode(data))
+}
+
+/// Decrypt a profile encrypted via `encrypt`
+fn decrypt(key: &[u8], aad: &str, data: &str) -> Result<Vec<u8>> {
+    let data = base64::decode(data)
+        .map_err(|e| Error::InternalError(format!("invalid profile data: {}", e)))?;
+    if data.len() < NONCE_LEN {
+        return Err(Error::InternalError("invalid profile data".into()));
+    }
+    let (nonce, msg) = data.split_at(NONCE_LEN);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg,
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| {
+            Error::InternalError("unable to decrypt profile, was credentials.key changed?".into())
+        })
+}
+
+/// Returns true for valid profile names: letters, digits, `-` & `_`
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Encrypt & store a profile of the user, replacing an existing one of the same name
+pub fn store(db: &dyn Repository, uid: &u32, name: &str, kind: Kind, content: &[u8]) -> Result<()> {
+    if !valid_name(name) {
+        return Err(Error::InputError(format!(
+            "invalid profile name {:?}",
+            name
+        )));
+    }
+    if content.len() > MAX_SIZE {
+        return Err(Error::InputError(format!(
+            "profile exceeds {} bytes",
+            MAX_SIZE
+        )));
+    }
+    let data = encrypt(&key()?, &aad(uid, name), content)?;
+    db.set_credential(uid, name, kind.as_str(), &data)
+}
+
+/// Profile file of a running job, wiped when dropped
+pub struct Session {
+    file: Option<(Kind, PathBuf)>,
+}
+
+impl Session {
+    /// Write the profile selected for the request into its workspace
+    /// Returns an empty session if no profile is selected.
+    pub fn open(request: &Request, profile: Option<&str>) -> Result<Session> {
+        let name = match profile {
+            Some(v) => v,
+            None => return Ok(Session { file: None }),
+        };
+        let (kind, data) = request
+            .db()
+            .get_credential(&request.uid, name)?
+            .ok_or_else(|| Error::InputError(format!("unknown credential profile {:?}", name)))?;
+        let kind = Kind::parse(&kind)
+            .ok_or_else(|| Error::InternalError(format!("unknown profile kind {}", kind)))?;
+        let content = decrypt(&key()?, &aad(&request.uid, name), &data)?;
+
+        let path = request.temp_path.join(FILE_NAME);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        // assigned before writing, a partially written file is wiped too
+        let session = Session {
+            file: Some((kind, path.clone())),
+        };
+        options.open(&path)?.write_all(&content)?;
+        debug!("using credential profile {} of {}", name, request.uid);
+        Ok(session)
+    }
+
+    /// yt-dl arguments using the profile, empty without profile
+    pub fn args(&self) -> Vec<String> {
+        match self.file {
+            Some((kind, ref path)) => kind.ytdl_args(&path.to_string_lossy()),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some((_, ref path)) = self.file {
+            if let Err(e) = wipe(path) {
+                error!("Unable to wipe credential file {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Overwrite a file with zeros & remove it
+fn wipe(path: &Path) -> std::io::Result<()> {
+    let len = match fs::metadata(path) {
+        Ok(v) => v.len() as usize,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0u8; len])?;
+    file.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lib::db::{self, SqliteRepository};
+    use std::sync::Arc;
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    #[test]
+    fn encrypt_test() {
+        let data = encrypt(&KEY, "1/main", b"secret").unwrap();
+        assert!(!data.contains("secret"));
+        assert_ne!(data, encrypt(&KEY, "1/main", b"secret").unwrap());
+        assert_eq!(b"secret".to_vec(), decrypt(&KEY, "1/main", &data).unwrap());
+        // bound to key, user & name
+        assert!(decrypt(&[8; KEY_LEN], "1/main", &data).is_err());
+        assert!(decrypt(&KEY, "2/main", &data).is_err());
+        assert!(decrypt(&KEY, "1/main", "c2hvcnQ=").is_err());
+    }
+
+    #[test]
+    fn key_test() {
+        assert!(parse_key(&CONFIG.credentials.key).is_some());
+        assert!(parse_key("c2hvcnQ=").is_none());
+        assert!(parse_key("no base64").is_none());
+    }
+
+    #[test]
+    fn name_test() {
+        assert!(valid_name("main_account-2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("../main"));
+        assert!(!valid_name(&"a".repeat(MAX_NAME_LENGTH + 1)));
+    }
+
+    #[test]
+    fn session_test() {
+        let db: Arc<dyn Repository> = Arc::new(SqliteRepository::open_in_memory().unwrap());
+        db.add_query("url", &137, &1, &0, None).unwrap();
+        let mut request = db::request_entry(&db).unwrap();
+        request.temp_path =
+            std::env::temp_dir().join(format!("yayd-credentials-{}", std::process::id()));
+        fs::create_dir_all(&request.temp_path).unwrap();
+        store(
+            &*db,
+            &1,
+            "main",
+            Kind::Netrc,
+            b"machine youtube login a password b",
+        )
+        .unwrap();
+        let path = request.temp_path.join(FILE_NAME);
+
+        let session = Session::open(&request, None).unwrap();
+        assert!(session.args().is_empty());
+
+        let session = Session::open(&request, Some("main")).unwrap();
+        let args = session.args();
+        assert_eq!(vec!["--netrc", "--netrc-location"], args[..2].to_vec());
+        assert_eq!(
+            "machine youtube login a password b",
+            fs::read_to_string(&args[2]).unwrap()
+        );
+        drop(session);
+        assert!(!path.exists());
+
+        // profiles of other users aren't accessible
+        request.uid = 2;
+        assert!(Session::open(&request, Some("main")).is_err());
+        fs::remove_dir_all(&request.temp_path).unwrap();
+    }
+}
@@ -0,0 +1,984 @@
+//! Persistence of queries, files & their state
+//!
+//! All access goes through the [`Repository`] trait, implemented for MySQL/MariaDB,
+//! PostgreSQL and SQLite. The backend is selected via `backend` in the `[db]` config section.
+
+mod mysql;
+mod postgres;
+mod sqlite;
+
+pub use self::mysql::MysqlRepository;
+pub use self::postgres::PostgresRepository;
+pub use self::sqlite::SqliteRepository;
+
+use chrono::{Local, NaiveDateTime};
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::lib::config::{Config, DbBackend};
+use crate::lib::{Error, Request, Result};
+
+use crate::CODE_FAILED_INTERNAL;
+use crate::CODE_IN_PROGRESS;
+use crate::CODE_STARTED;
+use crate::CODE_SUCCESS_WARNINGS;
+use crate::CODE_WAITING;
+use crate::CONFIG;
+
+/// from,to non playlist value
+const DEFAULT_PLAYLIST_VAL: i16 = -2;
+
+/// Required database tables to be checked on deletion
+const REQ_DB_TABLES: [&str; 12] = [
+    "queries",
+    "querydetails",
+    "playlists",
+    "subqueries",
+    "query_files",
+    "querystages",
+    "notifytargets",
+    "notifyoutbox",
+    "queryevents",
+    "querycredentials",
+    "playlistfailures",
+    "queryretries",
+];
+
+/// Event of a started query
+pub const EVENT_STARTED: &str = "started";
+/// Event of a finished query, with its final code
+pub const EVENT_FINISHED: &str = "finished";
+/// Event of a query interrupted by a crash or restart
+pub const EVENT_INTERRUPTED: &str = "interrupted";
+/// Step update event, message is the "step|max" status
+pub const EVENT_STEP: &str = "step";
+/// Error event, message is the error
+pub const EVENT_ERROR: &str = "error";
+
+/// Playlist settings of a new query
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlaylistRange {
+    pub from: i16,
+    pub to: i16,
+    pub split: bool,
+}
+
+/// Waiting query, as retrieved for handling
+#[derive(Debug, PartialEq)]
+pub struct QueuedQuery {
+    pub qid: u64,
+    pub url: String,
+    pub quality: i16,
+    pub r_type: i16,
+    pub uid: u32,
+    pub playlist: Option<PlaylistRange>,
+}
+
+/// Details & state of a query
+#[derive(Debug)]
+pub struct QueryInfo {
+    pub qid: u64,
+    pub uid: u32,
+    pub url: String,
+    pub quality: i16,
+    pub r_type: i16,
+    pub created: NaiveDateTime,
+    pub code: i8,
+    pub progress: Option<f64>,
+    pub status: Option<String>,
+    pub error: Option<String>,
+    /// fids of the files linked to this query
+    pub files: Vec<u64>,
+}
+
+/// Entry of the files table
+#[derive(Debug, PartialEq)]
+pub struct FileInfo {
+    pub fid: u64,
+    pub name: String,
+    pub real_name: String,
+    pub size: Option<u64>,
+    pub valid: bool,
+    pub delete: bool,
+    pub created: NaiveDateTime,
+}
+
+/// Entry of the query event history
+#[derive(Debug)]
+pub struct QueryEvent {
+    pub created: NaiveDateTime,
+    pub stage: String,
+    pub code: Option<i8>,
+    pub worker: String,
+    pub message: Option<String>,
+}
+
+/// Failed entry of a zipped playlist query
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryFailure {
+    /// position in the extracted playlist, starting at 0
+    pub index: u32,
+    pub url: String,
+    pub title: Option<String>,
+    /// error kind, see `Error::kind`
+    pub kind: String,
+    pub message: String,
+}
+
+/// Pending notification of the outbox
+#[derive(Debug, PartialEq)]
+pub struct Notification {
+    pub id: u64,
+    pub qid: u64,
+    pub kind: String,
+    pub target: String,
+    pub payload: String,
+    pub attempts: u16,
+}
+
+#[derive(Clone, Copy)]
+pub enum DeleteRequestType<'a> {
+    Marked,           // delete = 1
+    AgedMin(&'a u16), // valid = 1, age
+}
+
+/// Queue, progress, file & cleanup operations of a DBMS
+///
+/// Methods without a return value log their errors, as progress updates shouldn't fail a job.
+pub trait Repository: Send + Sync {
+    /// Returns the oldest waiting query
+    fn next_query(&self) -> Result<Option<QueuedQuery>>;
+
+    /// Insert a new query, waiting to be handled
+    /// Playlist settings are stored along if specified
+    fn add_query(
+        &self,
+        url: &str,
+        quality: &i16,
+        uid: &u32,
+        r_type: &i16,
+        playlist: Option<PlaylistRange>,
+    ) -> Result<u64>;
+
+    /// Link a sub query to the query it was created for
+    fn add_sub_query_link(&self, qid: &u64, origin: &u64) -> Result<()>;
+
+    /// Returns details & state of a query, None if it doesn't exist
+    fn get_query_info(&self, qid: &u64) -> Result<Option<QueryInfo>>;
+
+    /// Returns the qids of all queries having one of the codes
+    fn get_queries_by_code(&self, codes: &[i8]) -> Result<Vec<u64>>;
+
+    /// Returns the count of waiting queries and the age of the oldest one in seconds
+    fn get_queue_stats(&self) -> Result<(u64, Option<i64>)>;
+
+    /// Update query status code
+    fn set_query_code(&self, qid: &u64, code: &i8);
+
+    /// Set state of query, resetting its progress
+    fn set_query_state(&self, qid: &u64, state: &str);
+
+    /// Set state of query to null & finished
+    ///
+    /// Saves table space for finished downloads & sets progress to 100
+    fn set_null_state(&self, qid: &u64);
+
+    /// Set the status code of a query and clear its state, keeping the progress
+    fn reset_query(&self, qid: &u64, code: &i8) -> Result<()>;
+
+    /// Update the progress in percent
+    fn set_progress(&self, qid: &u64, progress: f64) -> Result<()>;
+
+    /// Insert the error message of a query
+    fn insert_query_error(&self, qid: &u64, msg: &str) -> Result<()>;
+
+    /// Append an event to the history of the query
+    fn add_event(&self, qid: &u64, stage: &str, code: Option<i8>, message: Option<&str>);
+
+    /// Returns the event history of the query, oldest first
+    fn get_events(&self, qid: &u64) -> Result<Vec<QueryEvent>>;
+
+    /// Check whether a processing stage of the query was already finished
+    fn is_stage_done(&self, qid: &u64, stage: &str) -> Result<bool>;
+
+    /// Record a finished processing stage, ignoring duplicates
+    fn add_stage(&self, qid: &u64, stage: &str) -> Result<()>;
+
+    /// Remove all recorded stages of a query
+    fn clear_stages(&self, qid: &u64) -> Result<()>;
+
+    /// Insert a valid file, returns its fid
+    fn insert_file(&self, name: &str, real_name: &str, size: &u64) -> Result<u64>;
+
+    /// Link a file to a query
+    fn add_file_link(&self, qid: &u64, fid: &u64) -> Result<()>;
+
+    /// Link all queries of the file to the new file instead
+    fn replace_file_link(&self, fid: &u64, new_fid: &u64) -> Result<()>;
+
+    /// Set the result cache key of a file, making it reusable by other queries
+    fn set_file_cache_key(&self, fid: &u64, key: &str) -> Result<()>;
+
+    /// Retrieve a valid, not to be deleted file for the cache key
+    /// Returns the newest (fid, name) if existing
+    fn get_cached_file(&self, key: &str) -> Result<Option<(u64, String)>>;
+
+    /// Returns all queries linked to the file, which aren't older than age
+    /// These are keeping a shared file alive on aged deletions
+    fn get_live_file_refs(&self, fid: &u64, age: &u16) -> Result<Vec<u64>>;
+
+    /// Mark file as to be deleted via delete flag
+    fn set_file_delete_flag(&self, fid: &u64, delete: bool) -> Result<()>;
+
+    /// Mark the link of a query to the file for deletion
+    /// A file shared with other queries is kept for them, see `get_file_refs`
+    fn set_file_link_delete_flag(&self, qid: &u64, fid: &u64, delete: bool) -> Result<()>;
+
+    /// Returns all queries linked to the file, which didn't mark their link for deletion
+    fn get_file_refs(&self, fid: &u64) -> Result<Vec<u64>>;
+
+    /// Remove the links marked for deletion to valid files, which are kept for other queries
+    fn remove_marked_file_links(&self) -> Result<()>;
+
+    /// Set file valid flag, fails if the file doesn't exist
+    fn set_file_valid_flag(&self, fid: &u64, valid: bool) -> Result<()>;
+
+    /// (Auto) file deletion retriver
+    /// Returns a tuple of Vec<qid> and Vec<fid,file name> older then age
+    fn get_files_to_delete(
+        &self,
+        del_type: DeleteRequestType,
+    ) -> Result<(Vec<u64>, Vec<(u64, String)>)>;
+
+    /// Returns the summed size of all valid files linked to queries of the user
+    fn get_user_usage(&self, uid: &u32) -> Result<u64>;
+
+    /// Returns the summed size of all valid files
+    fn get_total_usage(&self) -> Result<u64>;
+
+    /// Returns all valid files only linked to queries of this user, oldest first
+    /// Returns a Vec<(fid,name,size)>
+    fn get_user_files_oldest(&self, uid: &u32) -> Result<Vec<(u64, String, u64)>>;
+
+    /// Returns the names of all files having a files entry
+    fn get_file_names(&self) -> Result<Vec<String>>;
+
+    /// Check whether a files entry exists for the name
+    fn file_name_exists(&self, name: &str) -> Result<bool>;
+
+    /// Returns fid & name of all files marked as valid
+    fn get_valid_files(&self) -> Result<Vec<(u64, String)>>;
+
+    /// Returns all files linked to queries of the user, newest first
+    fn get_user_files(&self, uid: &u32) -> Result<Vec<FileInfo>>;
+
+    /// Returns the files entry, None if it doesn't exist
+    fn get_file(&self, fid: &u64) -> Result<Option<FileInfo>>;
+
+    /// Returns qid & uid of all queries linked to the file
+    fn get_file_links(&self, fid: &u64) -> Result<Vec<(u64, u32)>>;
+
+    /// Returns name & real name of all files linked to the query
+    fn get_query_files(&self, qid: &u64) -> Result<Vec<(String, String)>>;
+
+    /// Delete request or file entry
+    /// If a qid is specified, all file entries will also be erased
+    /// For files to be erased the `link_files` config has to be enabled
+    /// On deletion error all is rolled back to avoid data inconsistency
+    fn delete_requests(&self, qids: Vec<u64>, files: Vec<(u64, String)>) -> Result<()>;
+
+    /// Add a notification target for all queries of the user or a single query
+    fn add_notify_target(
+        &self,
+        uid: Option<u32>,
+        qid: Option<u64>,
+        kind: &str,
+        target: &str,
+    ) -> Result<()>;
+
+    /// Returns kind & target of all notification targets for the query of the user
+    fn get_notify_targets(&self, qid: &u64, uid: &u32) -> Result<Vec<(String, String)>>;
+
+    /// Insert a notification into the outbox, to be delivered immediately
+    fn add_notification(&self, qid: &u64, kind: &str, target: &str, payload: &str) -> Result<()>;
+
+    /// Returns all notifications of the outbox which are due for delivery
+    fn get_due_notifications(&self) -> Result<Vec<Notification>>;
+
+    /// Remove a delivered or expired notification from the outbox
+    fn remove_notification(&self, id: &u64) -> Result<()>;
+
+    /// Schedule the retry of a failed notification
+    fn retry_notification(&self, id: &u64, delay_min: &u32) -> Result<()>;
+
+    /// Insert or replace the encrypted credential profile of the user
+    fn set_credential(&self, uid: &u32, name: &str, kind: &str, data: &str) -> Result<()>;
+
+    /// Returns kind & encrypted data of the credential profile of the user
+    fn get_credential(&self, uid: &u32, name: &str) -> Result<Option<(String, String)>>;
+
+    /// Returns name & kind of all credential profiles of the user, ordered by name
+    fn get_credential_names(&self, uid: &u32) -> Result<Vec<(String, String)>>;
+
+    /// Delete a credential profile, returns false if it doesn't exist
+    fn delete_credential(&self, uid: &u32, name: &str) -> Result<bool>;
+
+    /// Select the credential profile of the query, by name of the users profiles
+    fn set_query_credential(&self, qid: &u64, name: &str) -> Result<()>;
+
+    /// Returns the name of the credential profile selected for the query
+    fn get_query_credential(&self, qid: &u64) -> Result<Option<String>>;
+
+    /// Mark the query as retry of the failed playlist entries of `origin`
+    fn set_query_retry(&self, qid: &u64, origin: &u64) -> Result<()>;
+
+    /// Returns the qid of the query retried by this one
+    fn get_query_retry(&self, qid: &u64) -> Result<Option<u64>>;
+
+    /// Returns the waiting or running retries of `origin`
+    fn get_pending_retries(&self, origin: &u64) -> Result<Vec<u64>>;
+
+    /// Record the yt-dl version active on `host`
+    fn set_ytdl_version(&self, host: &str, version: &str, channel: &str) -> Result<()>;
+
+    /// Returns version & channel of the yt-dl active on `host`
+    fn get_ytdl_version(&self, host: &str) -> Result<Option<(String, String)>>;
+
+    /// Record a failed playlist entry of the query, replacing a previous failure of the entry
+    fn add_entry_failure(&self, qid: &u64, failure: &EntryFailure) -> Result<()>;
+
+    /// Returns the failed playlist entries of the query, ordered by index
+    fn get_entry_failures(&self, qid: &u64) -> Result<Vec<EntryFailure>>;
+
+    /// Remove all recorded entry failures of the query
+    fn clear_entry_failures(&self, qid: &u64) -> Result<()>;
+
+    /// Create missing tables & indices, applying the schema upgrades of the last release
+    fn migrate(&self) -> Result<()>;
+
+    /// Update progress steps for db entrys
+    fn update_steps(&self, qid: &u64, step: i32, max_steps: i32) {
+        trace!("Updating steps to {} for id {}", step, qid);
+        let state = format!("{}|{}", step, max_steps);
+        self.set_query_state(qid, &state);
+        self.add_event(qid, EVENT_STEP, None, Some(&state));
+    }
+
+    /// Add query status msg for error reporting
+    fn add_query_error(&self, qid: &u64, status: &str) {
+        if let Err(why) = self.insert_query_error(qid, status) {
+            error!("Error inserting query error: {}", why);
+        }
+        self.add_event(qid, EVENT_ERROR, None, Some(status));
+    }
+
+    /// Mark a processing stage of the query as finished
+    fn set_stage_done(&self, qid: &u64, stage: &str) -> Result<()> {
+        self.add_stage(qid, stage)?;
+        self.add_event(qid, stage, None, Some("done"));
+        Ok(())
+    }
+
+    /// Add file to db including it's name, size & fid based on the qid
+    fn add_file_entry(&self, qid: &u64, name: &str, real_name: &str, size: &u64) -> Result<u64> {
+        trace!("name: {}", name);
+        let fid = self.insert_file(name, real_name, size)?;
+        if CONFIG.general.link_files {
+            self.add_file_link(qid, &fid)?;
+        }
+        Ok(fid)
+    }
+
+    /// Returns the qids of all waiting or running queries
+    fn get_active_queries(&self) -> Result<Vec<u64>> {
+        self.get_queries_by_code(&[CODE_WAITING, CODE_STARTED, CODE_IN_PROGRESS])
+    }
+
+    /// Handle queries interrupted by a crash or restart
+    ///
+    /// If `resume_interrupted` is enabled these are requeued, keeping their finished stages.
+    /// Otherwise they're set to failed and their stages are removed.
+    /// Returns the qids of all interrupted queries.
+    fn clear_query_states(&self) -> Vec<u64> {
+        let qids = match self.get_queries_by_code(&[CODE_STARTED, CODE_IN_PROGRESS]) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("{}", e);
+                return Vec::new();
+            }
+        };
+        if qids.is_empty() {
+            info!("No entries to clean.");
+            return qids;
+        }
+        let code = if CONFIG.general.resume_interrupted {
+            CODE_WAITING
+        } else {
+            CODE_FAILED_INTERNAL
+        };
+        for qid in &qids {
+            if let Err(e) = self.reset_query(qid, &code) {
+                warn!("{}", e);
+            }
+            self.add_event(qid, EVENT_INTERRUPTED, Some(code), None);
+            if !CONFIG.general.resume_interrupted {
+                if let Err(e) = self.clear_stages(qid) {
+                    warn!("{}", e);
+                }
+            }
+        }
+        if CONFIG.general.resume_interrupted {
+            info!("Requeued {} interrupted entries.", qids.len());
+        } else {
+            info!("Cleaned {} entries.", qids.len());
+        }
+        qids
+    }
+}
+
+/// Open the configured DBMS
+pub fn open(conf: &Config) -> Result<Arc<dyn Repository>> {
+    Ok(match conf.db.backend {
+        DbBackend::Mysql => Arc::new(MysqlRepository::new(mysql::mysql_options(conf))?),
+        DbBackend::Postgres => Arc::new(PostgresRepository::new(&conf.db)?),
+        DbBackend::Sqlite => Arc::new(SqliteRepository::open(Path::new(&conf.db.path))?),
+    })
+}
+
+/// Connect to DBMS, retry on failure.
+pub fn db_connect(conf: &Config, sleep_time: Option<Duration>) -> Arc<dyn Repository> {
+    loop {
+        match open(conf) {
+            Ok(db) => {
+                return db;
+            }
+            Err(err) => error!("Unable to establish a connection: {}", err),
+        };
+        if let Some(time) = sleep_time {
+            sleep(time);
+        } else {
+            panic!("Couldn't connect, sleep disabled!");
+        }
+    }
+}
+
+/// Request an entry from the DB to handle
+pub fn request_entry(db: &Arc<dyn Repository>) -> Option<Request> {
+    let query = match db.next_query() {
+        Ok(Some(v)) => v,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("{}", e);
+            return None;
+        }
+    };
+    trace!("query: {:?}", query);
+    debug!("playlist: {}", query.playlist.is_some());
+    let range = query.playlist.unwrap_or(PlaylistRange {
+        from: DEFAULT_PLAYLIST_VAL,
+        to: DEFAULT_PLAYLIST_VAL,
+        split: false,
+    });
+    Some(Request {
+        url: query.url,
+        quality: query.quality,
+        qid: query.qid,
+        r_type: query.r_type,
+        db: db.clone(),
+        playlist: query.playlist.is_some(),
+        split: range.split,
+        from: range.from,
+        to: range.to,
+        path: PathBuf::from(&CONFIG.general.download_dir),
+        temp_path: PathBuf::from(&CONFIG.general.temp_dir),
+        uid: query.uid,
+        handler: None,
+        ytdl_args: Vec::new(),
+        warnings: false,
+        cached: false,
+        retry: None,
+    })
+}
+
+/// Create new sub query, exmaple: for un-zipped playlist downloads, per-entry handle
+pub fn add_sub_query(url: &str, request: &Request) -> Result<u64> {
+    let db = request.db();
+    let id = db.add_query(url, &request.quality, &request.uid, &request.r_type, None)?;
+
+    if CONFIG.general.link_subqueries {
+        db.add_sub_query_link(&id, &request.qid)?;
+    }
+    if let Some(name) = db.get_query_credential(&request.qid)? {
+        db.set_query_credential(&id, &name)?;
+    }
+
+    Ok(id)
+}
+
+/// Create a job retrying the failed entries of a zip playlist job which finished with warnings
+/// Returns the qid of the new job, fails with an InputError if the job can't be retried
+pub fn add_retry_query(db: &dyn Repository, origin: &QueryInfo) -> Result<u64> {
+    if !CONFIG.general.link_files {
+        return Err(Error::InputError(String::from(
+            "retries require `link_files`",
+        )));
+    }
+    if origin.code != CODE_SUCCESS_WARNINGS || db.get_entry_failures(&origin.qid)?.is_empty() {
+        return Err(Error::InputError(String::from(
+            "job has no failed playlist entries",
+        )));
+    }
+    if origin.files.is_empty() {
+        return Err(Error::InputError(String::from(
+            "archive of the job was deleted",
+        )));
+    }
+    // both would replace the same archive
+    if !db.get_pending_retries(&origin.qid)?.is_empty() {
+        return Err(Error::InputError(String::from(
+            "job is already being retried",
+        )));
+    }
+    // entries are taken from the failures of the origin, not the range
+    let range = PlaylistRange {
+        from: 0,
+        to: 0,
+        split: false,
+    };
+    let qid = db.add_query(
+        &origin.url,
+        &origin.quality,
+        &origin.uid,
+        &origin.r_type,
+        Some(range),
+    )?;
+    db.set_query_retry(&qid, &origin.qid)?;
+    if let Some(name) = db.get_query_credential(&origin.qid)? {
+        db.set_query_credential(&qid, &name)?;
+    }
+
+    Ok(qid)
+}
+
+/// Collect the rows of a deletion lookup: (qid, fid, name)
+/// Returns the deduplicated qids and all files
+fn split_deletions(rows: Vec<(Option<u64>, u64, String)>) -> (Vec<u64>, Vec<(u64, String)>) {
+    let mut qids = Vec::new();
+    let mut files = Vec::new();
+    for (qid, fid, name) in rows {
+        if let Some(qid) = qid {
+            qids.push(qid);
+        }
+        files.push((fid, name));
+    }
+    qids.sort();
+    qids.dedup();
+    (qids, files)
+}
+
+/// Current local time, as stored by the backends computing their timestamps client side
+fn local_now() -> NaiveDateTime {
+    Local::now().naive_local()
+}
+
+/// Returns the sql statements of a setup script, without comments
+fn split_sql(raw_sql: &str) -> Vec<String> {
+    let reg = regex::Regex::new(r"(/\*(.|\s)*?\*/)").unwrap(); // https://regex101.com/r/bG6aF2/6, replace `\/` with `/`
+    let raw_sql = reg.replace_all(raw_sql, "");
+
+    let raw_sql = raw_sql.replace("\n", "");
+    let raw_sql = raw_sql.replace("\r", "");
+
+    raw_sql
+        .split(";")
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| x.to_owned())
+        .collect()
+}
+
+/// Turn the `CREATE` statement of a setup script into one skipping existing tables & indices
+fn create_if_missing(sql: &str) -> String {
+    sql.replacen("CREATE TABLE ", "CREATE TABLE IF NOT EXISTS ", 1)
+        .replacen("CREATE INDEX ", "CREATE INDEX IF NOT EXISTS ", 1)
+}
+
+/// Tests shared by all backends, run against an empty database
+/// Avoids config dependent methods, so no config is required
+#[cfg(test)]
+pub(crate) mod suite {
+    use super::*;
+
+    /// Insert a waiting query of uid 1
+    fn insert(db: &dyn Repository, playlist: Option<PlaylistRange>) -> u64 {
+        db.add_query("test.com", &1, &1, &-2, playlist).unwrap()
+    }
+
+    /// Run all checks
+    pub fn run(db: &dyn Repository) {
+        queue(db);
+        state(db);
+        files(db);
+        deletion(db);
+        notifications(db);
+        credentials(db);
+        ytdl_versions(db);
+        entry_failures(db);
+        retries(db);
+    }
+
+    fn queue(db: &dyn Repository) {
+        assert!(db.next_query().unwrap().is_none());
+        assert_eq!(db.get_queue_stats().unwrap(), (0, None));
+        let range = PlaylistRange {
+            from: 1,
+            to: 5,
+            split: true,
+        };
+        let qid = insert(db, Some(range));
+        let qid_2 = insert(db, None);
+        let query = db.next_query().unwrap().unwrap();
+        assert_eq!(
+            query,
+            QueuedQuery {
+                qid,
+                url: String::from("test.com"),
+                quality: 1,
+                r_type: -2,
+                uid: 1,
+                playlist: Some(range),
+            }
+        );
+        let (waiting, oldest) = db.get_queue_stats().unwrap();
+        assert_eq!(waiting, 2);
+        assert!(oldest.unwrap() >= 0);
+
+        db.set_query_code(&qid, &CODE_STARTED);
+        assert_eq!(db.next_query().unwrap().unwrap().playlist, None);
+        assert_eq!(db.get_queries_by_code(&[CODE_STARTED]).unwrap(), vec![qid]);
+        let mut active = db.get_active_queries().unwrap();
+        active.sort();
+        assert_eq!(active, vec![qid, qid_2]);
+
+        db.add_sub_query_link(&qid_2, &qid).unwrap();
+        db.reset_query(&qid, &CODE_WAITING).unwrap();
+        db.set_query_code(&qid, &crate::CODE_SUCCESS);
+        db.set_query_code(&qid_2, &crate::CODE_SUCCESS);
+        assert!(db.next_query().unwrap().is_none());
+        db.delete_requests(vec![qid, qid_2], Vec::new()).unwrap();
+    }
+
+    fn state(db: &dyn Repository) {
+        let qid = insert(db, None);
+        let info = db.get_query_info(&qid).unwrap().unwrap();
+        assert_eq!((info.qid, info.uid, info.code), (qid, 1, CODE_WAITING));
+        assert!(info.error.is_none());
+        assert!(info.status.is_none());
+        assert!(db.get_query_info(&(qid + 1)).unwrap().is_none());
+
+        db.set_query_code(&qid, &-9);
+        db.set_query_state(&qid, "asd");
+        db.set_progress(&qid, 45.5).unwrap();
+        let info = db.get_query_info(&qid).unwrap().unwrap();
+        assert_eq!(info.code, -9);
+        assert_eq!(info.status, Some(String::from("asd")));
+        assert_eq!(info.progress, Some(45.5));
+        db.set_null_state(&qid);
+        let info = db.get_query_info(&qid).unwrap().unwrap();
+        assert!(info.status.is_none());
+        assert_eq!(info.progress, Some(100.0));
+
+        assert!(!db.is_stage_done(&qid, "video").unwrap());
+        db.set_stage_done(&qid, "video").unwrap();
+        db.set_stage_done(&qid, "video").unwrap(); // duplicates are ignored
+        assert!(db.is_stage_done(&qid, "video").unwrap());
+        assert!(!db.is_stage_done(&qid, "audio").unwrap());
+        db.clear_stages(&qid).unwrap();
+        assert!(!db.is_stage_done(&qid, "video").unwrap());
+
+        db.add_event(&qid, EVENT_STARTED, Some(CODE_STARTED), None);
+        db.update_steps(&qid, 1, 3);
+        db.add_query_error(&qid, "failure");
+        let info = db.get_query_info(&qid).unwrap().unwrap();
+        assert_eq!(info.error, Some(String::from("failure")));
+        let events = db.get_events(&qid).unwrap();
+        let stages: Vec<&str> = events.iter().map(|e| e.stage.as_str()).collect();
+        assert_eq!(
+            stages,
+            vec!["video", "video", EVENT_STARTED, EVENT_STEP, EVENT_ERROR]
+        );
+        assert_eq!(events[2].code, Some(CODE_STARTED));
+        assert_eq!(events[3].message, Some(String::from("1|3")));
+        assert_eq!(events[2].worker, crate::lib::worker_id());
+
+        db.delete_requests(vec![qid], Vec::new()).unwrap();
+        assert!(db.get_query_info(&qid).unwrap().is_none());
+        assert!(db.get_events(&qid).unwrap().is_empty());
+    }
+
+    fn files(db: &dyn Repository) {
+        let qid = insert(db, None);
+        let fid_1 = db.insert_file("a", "A", &100).unwrap();
+        let fid_2 = db.insert_file("b", "B", &50).unwrap();
+        db.add_file_link(&qid, &fid_1).unwrap();
+        db.add_file_link(&qid, &fid_2).unwrap();
+        let qid_other = db.add_query("test.com", &1, &2, &-2, None).unwrap();
+        let fid_3 = db.insert_file("c", "C", &10).unwrap();
+        db.add_file_link(&qid_other, &fid_3).unwrap();
+        db.add_file_link(&qid_other, &fid_2).unwrap(); // shared file
+
+        assert_eq!(
+            db.get_query_info(&qid).unwrap().unwrap().files,
+            vec![fid_1, fid_2]
+        );
+        assert_eq!(
+            db.get_query_files(&qid).unwrap(),
+            vec![
+                (String::from("a"), String::from("A")),
+                (String::from("b"), String::from("B"))
+            ]
+        );
+        assert_eq!(db.get_user_usage(&1).unwrap(), 150);
+        assert_eq!(db.get_user_usage(&2).unwrap(), 60);
+        assert_eq!(db.get_total_usage().unwrap(), 160);
+        assert_eq!(
+            db.get_user_files_oldest(&1).unwrap(),
+            vec![(fid_1, String::from("a"), 100)]
+        );
+        let files = db.get_user_files(&1).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].size, Some(50));
+        assert!(files[0].valid);
+        let mut links = db.get_file_links(&fid_2).unwrap();
+        links.sort();
+        assert_eq!(links, vec![(qid, 1), (qid_other, 2)]);
+
+        assert!(db.get_cached_file("yt:a:1:-2").unwrap().is_none());
+        db.set_file_cache_key(&fid_1, "yt:a:1:-2").unwrap();
+        assert_eq!(
+            db.get_cached_file("yt:a:1:-2").unwrap(),
+            Some((fid_1, String::from("a")))
+        );
+        let mut refs = db.get_live_file_refs(&fid_2, &10).unwrap();
+        refs.sort();
+        assert_eq!(refs, vec![qid, qid_other]);
+
+        let mut names = db.get_file_names().unwrap();
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        assert!(db.file_name_exists("b").unwrap());
+        assert!(!db.file_name_exists("d").unwrap());
+
+        db.set_file_valid_flag(&fid_1, false).unwrap();
+        assert!(db.set_file_valid_flag(&(fid_3 + 1), false).is_err());
+        assert!(db.get_cached_file("yt:a:1:-2").unwrap().is_none());
+        assert_eq!(db.get_user_usage(&1).unwrap(), 50);
+        let mut valid = db.get_valid_files().unwrap();
+        valid.sort();
+        assert_eq!(
+            valid,
+            vec![(fid_2, String::from("b")), (fid_3, String::from("c"))]
+        );
+
+        db.delete_requests(
+            vec![qid, qid_other],
+            vec![
+                (fid_1, String::from("a")),
+                (fid_2, String::from("b")),
+                (fid_3, String::from("c")),
+            ],
+        )
+        .unwrap();
+        assert!(db.get_file_names().unwrap().is_empty());
+    }
+
+    fn deletion(db: &dyn Repository) {
+        let qid = insert(db, None);
+        let fid_marked = db.insert_file("marked", "marked", &0).unwrap();
+        let fid_new = db.insert_file("new", "new", &0).unwrap();
+        db.add_file_link(&qid, &fid_marked).unwrap();
+        db.add_file_link(&qid, &fid_new).unwrap();
+
+        assert_eq!(
+            db.get_files_to_delete(DeleteRequestType::Marked).unwrap(),
+            (Vec::new(), Vec::new())
+        );
+        // a marked link only selects its own query
+        let qid_shared = insert(db, None);
+        db.add_file_link(&qid_shared, &fid_new).unwrap();
+        db.set_file_link_delete_flag(&qid_shared, &fid_new, true)
+            .unwrap();
+        assert_eq!(
+            db.get_files_to_delete(DeleteRequestType::Marked).unwrap(),
+            (vec![qid_shared], vec![(fid_new, String::from("new"))])
+        );
+        assert_eq!(db.get_file_refs(&fid_new).unwrap(), vec![qid]);
+        db.remove_marked_file_links().unwrap();
+        assert!(db
+            .get_query_info(&qid_shared)
+            .unwrap()
+            .unwrap()
+            .files
+            .is_empty());
+        assert_eq!(
+            db.get_files_to_delete(DeleteRequestType::Marked).unwrap(),
+            (Vec::new(), Vec::new())
+        );
+
+        db.set_file_delete_flag(&fid_marked, true).unwrap();
+        assert_eq!(
+            db.get_files_to_delete(DeleteRequestType::Marked).unwrap(),
+            (vec![qid], vec![(fid_marked, String::from("marked"))])
+        );
+        assert_eq!(db.get_file_refs(&fid_marked).unwrap(), vec![qid]);
+        // nothing is older than 10 minutes
+        assert_eq!(
+            db.get_files_to_delete(DeleteRequestType::AgedMin(&10))
+                .unwrap()
+                .1
+                .len(),
+            0
+        );
+        db.delete_requests(
+            vec![qid],
+            vec![
+                (fid_marked, String::from("marked")),
+                (fid_new, String::from("new")),
+            ],
+        )
+        .unwrap();
+    }
+
+    fn notifications(db: &dyn Repository) {
+        let qid = insert(db, None);
+        let qid_other = insert(db, None);
+        db.add_notify_target(Some(1), None, "email", "a@localhost")
+            .unwrap();
+        db.add_notify_target(None, Some(qid), "webhook", "http://localhost")
+            .unwrap();
+        assert_eq!(db.get_notify_targets(&qid, &1).unwrap().len(), 2);
+        assert_eq!(
+            db.get_notify_targets(&qid_other, &1).unwrap(),
+            vec![(String::from("email"), String::from("a@localhost"))]
+        );
+        assert!(db.get_notify_targets(&qid_other, &2).unwrap().is_empty());
+
+        db.add_notification(&qid, "webhook", "http://localhost", "{}")
+            .unwrap();
+        let due = db.get_due_notifications().unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].qid, due[0].attempts), (qid, 0));
+        assert_eq!(due[0].payload, "{}");
+        db.retry_notification(&due[0].id, &5).unwrap();
+        assert!(db.get_due_notifications().unwrap().is_empty());
+        db.remove_notification(&due[0].id).unwrap();
+        db.delete_requests(vec![qid, qid_other], Vec::new())
+            .unwrap();
+    }
+
+    fn credentials(db: &dyn Repository) {
+        db.set_credential(&1, "main", "cookies", "old").unwrap();
+        db.set_credential(&1, "main", "netrc", "data").unwrap();
+        db.set_credential(&1, "alt", "cookies", "data").unwrap();
+        assert_eq!(
+            db.get_credential(&1, "main").unwrap(),
+            Some((String::from("netrc"), String::from("data")))
+        );
+        assert!(db.get_credential(&2, "main").unwrap().is_none());
+        assert_eq!(
+            db.get_credential_names(&1).unwrap(),
+            vec![
+                (String::from("alt"), String::from("cookies")),
+                (String::from("main"), String::from("netrc"))
+            ]
+        );
+
+        let qid = insert(db, None);
+        assert!(db.get_query_credential(&qid).unwrap().is_none());
+        db.set_query_credential(&qid, "main").unwrap();
+        assert_eq!(
+            db.get_query_credential(&qid).unwrap(),
+            Some(String::from("main"))
+        );
+
+        assert!(db.delete_credential(&1, "alt").unwrap());
+        assert!(!db.delete_credential(&1, "alt").unwrap());
+        db.delete_credential(&1, "main").unwrap();
+        db.delete_requests(vec![qid], Vec::new()).unwrap();
+        assert!(db.get_query_credential(&qid).unwrap().is_none());
+    }
+
+    fn ytdl_versions(db: &dyn Repository) {
+        assert!(db.get_ytdl_version("host-a").unwrap().is_none());
+        db.set_ytdl_version("host-a", "2021.12.01", "stable")
+            .unwrap();
+        db.set_ytdl_version("host-a", "2021.12.27", "pinned")
+            .unwrap();
+        db.set_ytdl_version("host-b", "2021.12.01", "nightly")
+            .unwrap();
+        assert_eq!(
+            db.get_ytdl_version("host-a").unwrap(),
+            Some((String::from("2021.12.27"), String::from("pinned")))
+        );
+    }
+
+    fn entry_failures(db: &dyn Repository) {
+        let qid = insert(db, None);
+        assert!(db.get_entry_failures(&qid).unwrap().is_empty());
+        let failure = |index: u32, kind: &str| EntryFailure {
+            index,
+            url: format!("entry-{}", index),
+            title: None,
+            kind: kind.to_string(),
+            message: String::from("ERROR: Private video"),
+        };
+        db.add_entry_failure(&qid, &failure(3, "internal")).unwrap();
+        db.add_entry_failure(&qid, &failure(1, "private")).unwrap();
+        // replaces the failure of a retried entry
+        let mut titled = failure(3, "removed");
+        titled.title = Some(String::from("Title"));
+        db.add_entry_failure(&qid, &titled).unwrap();
+        assert_eq!(
+            db.get_entry_failures(&qid).unwrap(),
+            vec![failure(1, "private"), titled]
+        );
+
+        db.clear_entry_failures(&qid).unwrap();
+        assert!(db.get_entry_failures(&qid).unwrap().is_empty());
+        db.add_entry_failure(&qid, &failure(0, "private")).unwrap();
+        db.delete_requests(vec![qid], Vec::new()).unwrap();
+        assert!(db.get_entry_failures(&qid).unwrap().is_empty());
+    }
+
+    fn retries(db: &dyn Repository) {
+        let origin = insert(db, None);
+        let qid = insert(db, None);
+        assert_eq!(db.get_query_retry(&qid).unwrap(), None);
+        db.set_query_retry(&qid, &origin).unwrap();
+        assert_eq!(db.get_query_retry(&qid).unwrap(), Some(origin));
+        assert_eq!(db.get_pending_retries(&origin).unwrap(), vec![qid]);
+        assert!(db.get_pending_retries(&qid).unwrap().is_empty());
+
+        let fid = db.insert_file("list.zip", "List.zip", &100).unwrap();
+        db.add_file_link(&origin, &fid).unwrap();
+        let new_fid = db.insert_file("list-1.zip", "List.zip", &150).unwrap();
+        db.add_file_link(&qid, &new_fid).unwrap();
+        let file = db.get_file(&fid).unwrap().unwrap();
+        assert_eq!(file.name, "list.zip");
+        assert_eq!(file.real_name, "List.zip");
+        assert_eq!(file.size, Some(100));
+        db.replace_file_link(&fid, &new_fid).unwrap();
+        assert_eq!(
+            db.get_query_info(&origin).unwrap().unwrap().files,
+            vec![new_fid]
+        );
+        db.delete_requests(Vec::new(), vec![(fid, file.name)])
+            .unwrap();
+        assert!(db.get_file(&fid).unwrap().is_none());
+
+        db.delete_requests(vec![qid], Vec::new()).unwrap();
+        assert_eq!(db.get_query_retry(&qid).unwrap(), None);
+    }
+}
@@ -0,0 +1,1438 @@
+use chrono::NaiveDateTime;
+use mysql::prelude::Queryable;
+use mysql::{from_row_opt, TxOpts, Value};
+use mysql::{Opts, OptsBuilder};
+use mysql::{Pool, PooledConn};
+
+use crate::lib;
+use crate::lib::{Error, Result};
+
+use super::{
+    create_if_missing, split_deletions, split_sql, DeleteRequestType, EntryFailure, FileInfo,
+    Notification, PlaylistRange, QueryEvent, QueryInfo, QueuedQuery, Repository, REQ_DB_TABLES,
+};
+
+use crate::CODE_WAITING;
+use crate::VERSION;
+
+/// Error codes of upgrade statements which were already applied:
+/// table exists, duplicate column, duplicate key
+const ALREADY_APPLIED: [u16; 3] = [1050, 1060, 1061];
+
+/// MySQL / MariaDB repository
+pub struct MysqlRepository {
+    pool: Pool,
+}
+
+impl MysqlRepository {
+    /// Connect to the DBMS
+    pub fn new(opts: Opts) -> Result<MysqlRepository> {
+        Ok(MysqlRepository {
+            pool: Pool::new(opts)?,
+        })
+    }
+
+    fn conn(&self) -> Result<PooledConn> {
+        Ok(self.pool.get_conn()?)
+    }
+}
+
+/// Set DBMS connection settings
+pub fn mysql_options(conf: &lib::config::Config) -> Opts {
+    let port = match conf.db.port {
+        0 => 3306,
+        v => v,
+    };
+    OptsBuilder::new()
+        .ip_or_hostname(Some(conf.db.ip.clone()))
+        .tcp_port(port)
+        .user(Some(conf.db.user.clone()))
+        .pass(Some(conf.db.password.clone()))
+        .db_name(Some(conf.db.db.clone()))
+        .into()
+}
+
+/// Inserts a new query
+fn _insert_query<Q: Queryable>(
+    url: &str,
+    quality: &i16,
+    uid: &u32,
+    r_type: &i16,
+    conn: &mut Q,
+) -> Result<u64> {
+    let id: u64;
+    {
+        let result = conn.exec_iter(
+            "INSERT INTO `queries` (url,quality,uid,created,`type`) VALUES(?,?,?,Now(),?)",
+            (url, quality, uid, r_type),
+        )?;
+        id = result.last_insert_id().unwrap();
+    }
+    {
+        conn.exec_drop(
+            "INSERT INTO `querydetails` (qid,`code`) VALUES(?,?)",
+            (&id, &CODE_WAITING),
+        )?;
+    }
+    Ok(id)
+}
+
+/// Returns `?,?,..` placeholders for the amount of values
+fn placeholders(amount: usize) -> String {
+    vec!["?"; amount].join(",")
+}
+
+impl Repository for MysqlRepository {
+    fn next_query(&self) -> Result<Option<QueuedQuery>> {
+        let row: Option<(
+            u64,
+            String,
+            i16,
+            Option<bool>,
+            Option<i16>,
+            Option<i16>,
+            u32,
+            i16,
+        )> = self.conn()?.query_first(
+            "SELECT queries.qid,url,quality,`split`,`from`,`to`,uid,`type` FROM queries \
+             JOIN querydetails ON queries.qid = querydetails.qid \
+             LEFT JOIN playlists ON queries.qid = playlists.qid \
+             WHERE querydetails.code = -1 \
+             ORDER BY queries.created, queries.qid \
+             LIMIT 1",
+        )?;
+        Ok(
+            row.map(|(qid, url, quality, split, from, to, uid, r_type)| {
+                let playlist = match (split, from, to) {
+                    (Some(split), Some(from), Some(to)) => Some(PlaylistRange { from, to, split }),
+                    _ => None,
+                };
+                QueuedQuery {
+                    qid,
+                    url,
+                    quality,
+                    r_type,
+                    uid,
+                    playlist,
+                }
+            }),
+        )
+    }
+
+    fn add_query(
+        &self,
+        url: &str,
+        quality: &i16,
+        uid: &u32,
+        r_type: &i16,
+        playlist: Option<PlaylistRange>,
+    ) -> Result<u64> {
+        let mut conn = self.conn()?;
+        let mut transaction = conn.start_transaction(TxOpts::default())?;
+        let qid = _insert_query(url, quality, uid, r_type, &mut transaction)?;
+        if let Some(range) = playlist {
+            transaction.exec_drop(
+                "INSERT INTO `playlists` (`qid`,`from`,`to`,`split`) VALUES(?,?,?,?)",
+                (&qid, &range.from, &range.to, &range.split),
+            )?;
+        }
+        transaction.commit()?;
+        Ok(qid)
+    }
+
+    fn add_sub_query_link(&self, qid: &u64, origin: &u64) -> Result<()> {
+        self.conn()?.exec_drop(
+            "INSERT INTO `subqueries` (qid,origin_id) VALUES(?,?)",
+            (qid, origin),
+        )?;
+        Ok(())
+    }
+
+    fn get_query_info(&self, qid: &u64) -> Result<Option<QueryInfo>> {
+        let mut conn = self.conn()?;
+        let row: Option<(u64, u32, String, i16, i16, NaiveDateTime, i8, Option<f64>, Option<String>, Option<String>)> =
+            conn.exec_first(
+                "SELECT queries.qid,uid,url,quality,`type`,created,`code`,progress,`status`,msg FROM queries \
+                 JOIN querydetails ON queries.qid = querydetails.qid \
+                 LEFT JOIN queryerror ON queries.qid = queryerror.qid \
+                 WHERE queries.qid = ?",
+                (qid,),
+            )?;
+        let (qid, uid, url, quality, r_type, created, code, progress, status, error) = match row {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let files = conn.exec(
+            "SELECT `fid` FROM `query_files` WHERE `qid` = ? ORDER BY `fid`",
+            (&qid,),
+        )?;
+        Ok(Some(QueryInfo {
+            qid,
+            uid,
+            url,
+            quality,
+            r_type,
+            created,
+            code,
+            progress,
+            status,
+            error,
+            files,
+        }))
+    }
+
+    fn get_queries_by_code(&self, codes: &[i8]) -> Result<Vec<u64>> {
+        let params: Vec<Value> = codes.iter().map(Value::from).collect();
+        Ok(self.conn()?.exec(
+            format!(
+                "SELECT `qid` FROM `querydetails` WHERE `code` IN ({})",
+                placeholders(codes.len())
+            ),
+            params,
+        )?)
+    }
+
+    fn get_queue_stats(&self) -> Result<(u64, Option<i64>)> {
+        let result: Option<(u64, Option<i64>)> = self.conn()?.exec_first(
+            "SELECT COUNT(*), TIMESTAMPDIFF(SECOND, MIN(`created`), NOW()) FROM `queries` \
+             JOIN `querydetails` ON `queries`.`qid` = `querydetails`.`qid` \
+             WHERE `querydetails`.`code` = ?",
+            (CODE_WAITING,),
+        )?;
+        Ok(result.unwrap_or((0, None)))
+    }
+
+    fn set_query_code(&self, qid: &u64, code: &i8) {
+        trace!("Setting query code {} for id {}", code, qid);
+        match self.conn().and_then(|mut conn| {
+            Ok(conn.exec_drop(
+                "UPDATE querydetails SET code = ? WHERE qid = ?",
+                (&code, &qid),
+            )?)
+        }) {
+            Ok(_) => (),
+            Err(why) => error!("Error inserting querystatus: {}", why),
+        }
+    }
+
+    fn set_query_state(&self, qid: &u64, state: &str) {
+        match self.conn().and_then(|mut conn| {
+            Ok(conn.exec_drop(
+                "UPDATE querydetails SET status = ? , progress = ? WHERE qid = ?",
+                (&state, &0, qid),
+            )?)
+        }) {
+            Ok(_) => (),
+            Err(why) => error!("Error setting query state: {}", why),
+        }
+    }
+
+    fn set_null_state(&self, qid: &u64) {
+        match self.conn().and_then(|mut conn| {
+            Ok(conn.exec_drop(
+                "UPDATE querydetails SET status = NULL, progress = 100 WHERE qid = ?",
+                (qid,),
+            )?)
+        }) {
+            Ok(_) => (),
+            Err(why) => error!("Error setting query null sate: {}", why),
+        }
+    }
+
+    fn reset_query(&self, qid: &u64, code: &i8) -> Result<()> {
+        self.conn()?.exec_drop(
+            "UPDATE `querydetails` SET `code` = ?, `status` = NULL WHERE `qid` = ?",
+            (code, qid),
+        )?;
+        Ok(())
+    }
+
+    fn set_progress(&self, qid: &u64, progress: f64) -> Result<()> {
+        self.conn()?.exec_drop(
+            "UPDATE querydetails SET progress = ? WHERE qid = ?",
+            (progress, qid),
+        )?;
+        Ok(())
+    }
+
+    fn insert_query_error(&self, qid: &u64, msg: &str) -> Result<()> {
+        self.conn()?
+            .exec_drop("INSERT INTO queryerror (qid,msg) VALUES (?,?)", (qid, msg))?;
+        Ok(())
+    }
+
+    fn add_event(&self, qid: &u64, stage: &str, code: Option<i8>, message: Option<&str>) {
+        match self.conn().and_then(|mut conn| {
+            Ok(conn.exec_drop(
+                "INSERT INTO `queryevents` (`qid`,`created`,`stage`,`code`,`worker`,`message`) \
+                 VALUES (?,NOW(3),?,?,?,?)",
+                (qid, stage, code, lib::worker_id(), message),
+            )?)
+        }) {
+            Ok(_) => (),
+            Err(why) => error!("Error inserting query event: {}", why),
+        }
+    }
+
+    fn get_events(&self, qid: &u64) -> Result<Vec<QueryEvent>> {
+        let rows: Vec<(NaiveDateTime, String, Option<i8>, String, Option<String>)> =
+            self.conn()?.exec(
+                "SELECT `created`,`stage`,`code`,`worker`,`message` FROM `queryevents` \
+                 WHERE `qid` = ? ORDER BY `id`",
+                (qid,),
+            )?;
+        Ok(rows
+            .into_iter()
+            .map(|(created, stage, code, worker, message)| QueryEvent {
+                created,
+                stage,
+                code,
+                worker,
+                message,
+            })
+            .collect())
+    }
+
+    fn is_stage_done(&self, qid: &u64, stage: &str) -> Result<bool> {
+        let result: Option<u64> = self.conn()?.exec_first(
+            "SELECT `qid` FROM `querystages` WHERE `qid` = ? AND `stage` = ?",
+            (qid, stage),
+        )?;
+        Ok(result.is_some())
+    }
+
+    fn add_stage(&self, qid: &u64, stage: &str) -> Result<()> {
+        self.conn()?.exec_drop(
+            "INSERT IGNORE INTO `querystages` (`qid`,`stage`) VALUES (?,?)",
+            (qid, stage),
+        )?;
+        Ok(())
+    }
+
+    fn clear_stages(&self, qid: &u64) -> Result<()> {
+        self.conn()?
+            .exec_drop("DELETE FROM `querystages` WHERE `qid` = ?", (qid,))?;
+        Ok(())
+    }
+
+    fn insert_file(&self, name: &str, real_name: &str, size: &u64) -> Result<u64> {
+        let mut conn = self.conn()?;
+        let result = conn.exec_iter(
+            "INSERT INTO files (rname,name,valid,size) VALUES (?,?,?,?)",
+            (&real_name, &name, &true, size),
+        )?;
+        Ok(result.last_insert_id().unwrap())
+    }
+
+    fn add_file_link(&self, qid: &u64, fid: &u64) -> Result<()> {
+        self.conn()?.exec_drop(
+            "INSERT INTO `query_files` (qid,fid) VALUES(?,?)",
+            (&qid, &fid),
+        )?;
+        Ok(())
+    }
+
+    fn replace_file_link(&self, fid: &u64, new_fid: &u64) -> Result<()> {
+        self.conn()?.exec_drop(
+            "UPDATE `query_files` SET `fid` = ? WHERE `fid` = ?",
+            (new_fid, fid),
+        )?;
+        Ok(())
+    }
+
+    fn set_file_cache_key(&self, fid: &u64, key: &str) -> Result<()> {
+        self.conn()?.exec_drop(
+            "UPDATE `files` SET `cache_key` = ? WHERE `fid` = ?",
+            (key, fid),
+        )?;
+        Ok(())
+    }
+
+    fn get_cached_file(&self, key: &str) -> Result<Option<(u64, String)>> {
+        Ok(self.conn()?.exec_first(
+            "SELECT `fid`,`name` FROM `files` \
+             WHERE `cache_key` = ? AND `valid` = 1 AND `delete` = 0 \
+             ORDER BY `fid` DESC LIMIT 1",
+            (key,),
+        )?)
+    }
+
+    fn get_live_file_refs(&self, fid: &u64, age: &u16) -> Result<Vec<u64>> {
+        Ok(self.conn()?.exec(
+            "SELECT `query_files`.`qid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` \
+             WHERE `query_files`.`fid` = ? AND `queries`.`created` >= (NOW() - INTERVAL ? MINUTE)",
+            (fid, age),
+        )?)
+    }
+
+    fn set_file_delete_flag(&self, fid: &u64, delete: bool) -> Result<()> {
+        self.conn()?
+            .exec_drop("UPDATE files SET `delete` = ? WHERE fid = ?", (delete, fid))?;
+        Ok(())
+    }
+
+    fn set_file_link_delete_flag(&self, qid: &u64, fid: &u64, delete: bool) -> Result<()> {
+        self.conn()?.exec_drop(
+            "UPDATE `query_files` SET `delete` = ? WHERE `qid` = ? AND `fid` = ?",
+            (delete, qid, fid),
+        )?;
+        Ok(())
+    }
+
+    fn get_file_refs(&self, fid: &u64) -> Result<Vec<u64>> {
+        Ok(self.conn()?.exec(
+            "SELECT `qid` FROM `query_files` WHERE `fid` = ? AND `delete` = 0",
+            (fid,),
+        )?)
+    }
+
+    fn remove_marked_file_links(&self) -> Result<()> {
+        self.conn()?.query_drop(
+            "DELETE FROM `query_files` WHERE `delete` = 1 \
+             AND `fid` IN (SELECT `fid` FROM `files` WHERE `valid` = 1)",
+        )?;
+        Ok(())
+    }
+
+    fn set_file_valid_flag(&self, fid: &u64, valid: bool) -> Result<()> {
+        if self
+            .conn()?
+            .exec_iter(
+                "UPDATE `files` SET `valid` = ? WHERE `fid` = ?",
+                (valid, fid),
+            )?
+            .affected_rows()
+            != 1
+        {
+            return Err(Error::InternalError(String::from(format!(
+                "Invalid affected lines count!"
+            ))));
+        }
+        Ok(())
+    }
+
+    fn get_files_to_delete(
+        &self,
+        del_type: DeleteRequestType,
+    ) -> Result<(Vec<u64>, Vec<(u64, String)>)> {
+        let sql = String::from(
+            "SELECT `query_files`.`qid`,`files`.`fid`,`name` FROM files \
+             LEFT JOIN `query_files` ON files.fid = query_files.fid ",
+        );
+        let sql = sql
+            + &match del_type {
+                DeleteRequestType::AgedMin(x) => String::from(
+                    "WHERE `valid` = 1 AND `created` < (NOW() - INTERVAL %min% DAY_MINUTE)",
+                )
+                .replace("%min%", &x.to_string()),
+                DeleteRequestType::Marked => String::from(
+                    "WHERE `valid` = 1 AND (files.`delete` = 1 OR query_files.`delete` = 1)",
+                ),
+            };
+        debug!("sql: {}", sql);
+        let mut rows = Vec::new();
+        for result in self.conn()?.exec_iter(sql, ())? {
+            rows.push(from_row_opt::<(Option<u64>, u64, String)>(result?)?);
+        }
+        Ok(split_deletions(rows))
+    }
+
+    fn get_user_usage(&self, uid: &u32) -> Result<u64> {
+        let usage: Option<u64> = self.conn()?.exec_first(
+            "SELECT CAST(COALESCE(SUM(`size`),0) AS UNSIGNED) FROM `files` \
+             WHERE `valid` = 1 AND `fid` IN ( \
+             SELECT `query_files`.`fid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `queries`.`uid` = ?)",
+            (uid,),
+        )?;
+        Ok(usage.unwrap_or(0))
+    }
+
+    fn get_total_usage(&self) -> Result<u64> {
+        let usage: Option<u64> = self.conn()?.query_first(
+            "SELECT CAST(COALESCE(SUM(`size`),0) AS UNSIGNED) FROM `files` WHERE `valid` = 1",
+        )?;
+        Ok(usage.unwrap_or(0))
+    }
+
+    fn get_user_files_oldest(&self, uid: &u32) -> Result<Vec<(u64, String, u64)>> {
+        Ok(self.conn()?.exec(
+            "SELECT `fid`,`name`,COALESCE(`size`,0) FROM `files` \
+             WHERE `valid` = 1 AND `fid` IN ( \
+             SELECT `query_files`.`fid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `queries`.`uid` = ?) \
+             AND `fid` NOT IN ( \
+             SELECT `query_files`.`fid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `queries`.`uid` != ?) \
+             ORDER BY `created` ASC",
+            (uid, uid),
+        )?)
+    }
+
+    fn get_file_names(&self) -> Result<Vec<String>> {
+        Ok(self.conn()?.query("SELECT `name` FROM `files`")?)
+    }
+
+    fn file_name_exists(&self, name: &str) -> Result<bool> {
+        let result: Option<u64> = self.conn()?.exec_first(
+            "SELECT `fid` FROM `files` WHERE `name` = ? LIMIT 1",
+            (name,),
+        )?;
+        Ok(result.is_some())
+    }
+
+    fn get_valid_files(&self) -> Result<Vec<(u64, String)>> {
+        Ok(self
+            .conn()?
+            .query("SELECT `fid`,`name` FROM `files` WHERE `valid` = 1")?)
+    }
+
+    fn get_user_files(&self, uid: &u32) -> Result<Vec<FileInfo>> {
+        let rows: Vec<(u64, String, String, Option<u64>, bool, bool, NaiveDateTime)> =
+            self.conn()?.exec(
+                "SELECT `fid`,`name`,`rname`,`size`,`valid`,`delete`,`created` FROM `files` \
+                 WHERE `fid` IN ( \
+                 SELECT `query_files`.`fid` FROM `query_files` \
+                 JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `queries`.`uid` = ?) \
+                 ORDER BY `created` DESC, `fid` DESC",
+                (uid,),
+            )?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(fid, name, real_name, size, valid, delete, created)| FileInfo {
+                    fid,
+                    name,
+                    real_name,
+                    size,
+                    valid,
+                    delete,
+                    created,
+                },
+            )
+            .collect())
+    }
+
+    fn get_file(&self, fid: &u64) -> Result<Option<FileInfo>> {
+        let row: Option<(u64, String, String, Option<u64>, bool, bool, NaiveDateTime)> =
+            self.conn()?.exec_first(
+                "SELECT `fid`,`name`,`rname`,`size`,`valid`,`delete`,`created` FROM `files` \
+                 WHERE `fid` = ?",
+                (fid,),
+            )?;
+        Ok(row.map(
+            |(fid, name, real_name, size, valid, delete, created)| FileInfo {
+                fid,
+                name,
+                real_name,
+                size,
+                valid,
+                delete,
+                created,
+            },
+        ))
+    }
+
+    fn get_file_links(&self, fid: &u64) -> Result<Vec<(u64, u32)>> {
+        Ok(self.conn()?.exec(
+            "SELECT `queries`.`qid`,`queries`.`uid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `query_files`.`fid` = ?",
+            (fid,),
+        )?)
+    }
+
+    fn get_query_files(&self, qid: &u64) -> Result<Vec<(String, String)>> {
+        Ok(self.conn()?.exec(
+            "SELECT `name`,`rname` FROM `files` \
+             JOIN `query_files` ON `files`.`fid` = `query_files`.`fid` \
+             WHERE `query_files`.`qid` = ? ORDER BY `files`.`fid`",
+            (qid,),
+        )?)
+    }
+
+    fn delete_requests(&self, qids: Vec<u64>, files: Vec<(u64, String)>) -> Result<()> {
+        let mut conn = self.conn()?;
+        let mut transaction = conn.start_transaction(TxOpts::default())?;
+
+        {
+            let stmt = transaction.prep("DELETE FROM files WHERE fid = ?")?;
+            for (fid, _) in files {
+                transaction.exec_drop(&stmt, (&fid,))?;
+            }
+        }
+
+        let delete_sql_tmpl = "DELETE FROM %db% WHERE qid = ?";
+        for db in REQ_DB_TABLES.iter() {
+            let stmt = transaction.prep(delete_sql_tmpl.replace("%db%", db))?;
+            for qid in &qids {
+                transaction.exec_drop(&stmt, (qid,))?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn add_notify_target(
+        &self,
+        uid: Option<u32>,
+        qid: Option<u64>,
+        kind: &str,
+        target: &str,
+    ) -> Result<()> {
+        self.conn()?.exec_drop(
+            "INSERT INTO `notifytargets` (`uid`,`qid`,`kind`,`target`) VALUES(?,?,?,?)",
+            (uid, qid, kind, target),
+        )?;
+        Ok(())
+    }
+
+    fn get_notify_targets(&self, qid: &u64, uid: &u32) -> Result<Vec<(String, String)>> {
+        Ok(self.conn()?.exec(
+            "SELECT DISTINCT `kind`,`target` FROM `notifytargets` WHERE `qid` = ? OR `uid` = ?",
+            (qid, uid),
+        )?)
+    }
+
+    fn add_notification(&self, qid: &u64, kind: &str, target: &str, payload: &str) -> Result<()> {
+        self.conn()?.exec_drop(
+            "INSERT INTO `notifyoutbox` (`qid`,`kind`,`target`,`payload`,`next_try`) \
+             VALUES(?,?,?,?,NOW())",
+            (qid, kind, target, payload),
+        )?;
+        Ok(())
+    }
+
+    fn get_due_notifications(&self) -> Result<Vec<Notification>> {
+        let rows: Vec<(u64, u64, String, String, String, u16)> = self.conn()?.query(
+            "SELECT `id`,`qid`,`kind`,`target`,`payload`,`attempts` FROM `notifyoutbox` \
+             WHERE `next_try` <= NOW() ORDER BY `id`",
+        )?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, qid, kind, target, payload, attempts)| Notification {
+                id,
+                qid,
+                kind,
+                target,
+                payload,
+                attempts,
+            })
+            .collect())
+    }
+
+    fn remove_notification(&self, id: &u64) -> Result<()> {
+        self.conn()?
+            .exec_drop("DELETE FROM `notifyoutbox` WHERE `id` = ?", (id,))?;
+        Ok(())
+    }
+
+    fn retry_notification(&self, id: &u64, delay_min: &u32) -> Result<()> {
+        self.conn()?.exec_drop(
+            "UPDATE `notifyoutbox` SET `attempts` = `attempts` + 1, \
+             `next_try` = NOW() + INTERVAL ? MINUTE WHERE `id` = ?",
+            (delay_min, id),
+        )?;
+        Ok(())
+    }
+
+    fn set_credential(&self, uid: &u32, name: &str, kind: &str, data: &str) -> Result<()> {
+        self.conn()?.exec_drop(
+            "REPLACE INTO `credentials` (`uid`,`name`,`kind`,`data`) VALUES(?,?,?,?)",
+            (uid, name, kind, data),
+        )?;
+        Ok(())
+    }
+
+    fn get_credential(&self, uid: &u32, name: &str) -> Result<Option<(String, String)>> {
+        Ok(self.conn()?.exec_first(
+            "SELECT `kind`,`data` FROM `credentials` WHERE `uid` = ? AND `name` = ?",
+            (uid, name),
+        )?)
+    }
+
+    fn get_credential_names(&self, uid: &u32) -> Result<Vec<(String, String)>> {
+        Ok(self.conn()?.exec(
+            "SELECT `name`,`kind` FROM `credentials` WHERE `uid` = ? ORDER BY `name`",
+            (uid,),
+        )?)
+    }
+
+    fn delete_credential(&self, uid: &u32, name: &str) -> Result<bool> {
+        let mut conn = self.conn()?;
+        conn.exec_drop(
+            "DELETE FROM `credentials` WHERE `uid` = ? AND `name` = ?",
+            (uid, name),
+        )?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    fn set_query_credential(&self, qid: &u64, name: &str) -> Result<()> {
+        self.conn()?.exec_drop(
+            "REPLACE INTO `querycredentials` (`qid`,`name`) VALUES(?,?)",
+            (qid, name),
+        )?;
+        Ok(())
+    }
+
+    fn get_query_credential(&self, qid: &u64) -> Result<Option<String>> {
+        Ok(self.conn()?.exec_first(
+            "SELECT `name` FROM `querycredentials` WHERE `qid` = ?",
+            (qid,),
+        )?)
+    }
+
+    fn set_query_retry(&self, qid: &u64, origin: &u64) -> Result<()> {
+        self.conn()?.exec_drop(
+            "REPLACE INTO `queryretries` (`qid`,`origin`) VALUES(?,?)",
+            (qid, origin),
+        )?;
+        Ok(())
+    }
+
+    fn get_query_retry(&self, qid: &u64) -> Result<Option<u64>> {
+        Ok(self.conn()?.exec_first(
+            "SELECT `origin` FROM `queryretries` WHERE `qid` = ?",
+            (qid,),
+        )?)
+    }
+
+    fn get_pending_retries(&self, origin: &u64) -> Result<Vec<u64>> {
+        Ok(self.conn()?.exec(
+            "SELECT `queryretries`.`qid` FROM `queryretries` \
+             JOIN `querydetails` ON `querydetails`.`qid` = `queryretries`.`qid` \
+             WHERE `origin` = ? AND `code` IN (?,?,?)",
+            (
+                origin,
+                CODE_WAITING,
+                crate::CODE_STARTED,
+                crate::CODE_IN_PROGRESS,
+            ),
+        )?)
+    }
+
+    fn set_ytdl_version(&self, host: &str, version: &str, channel: &str) -> Result<()> {
+        self.conn()?.exec_drop(
+            "REPLACE INTO `ytdlversions` (`host`,`version`,`channel`,`updated`) \
+             VALUES(?,?,?,NOW())",
+            (host, version, channel),
+        )?;
+        Ok(())
+    }
+
+    fn get_ytdl_version(&self, host: &str) -> Result<Option<(String, String)>> {
+        Ok(self.conn()?.exec_first(
+            "SELECT `version`,`channel` FROM `ytdlversions` WHERE `host` = ?",
+            (host,),
+        )?)
+    }
+
+    fn add_entry_failure(&self, qid: &u64, failure: &EntryFailure) -> Result<()> {
+        self.conn()?.exec_drop(
+            "REPLACE INTO `playlistfailures` (`qid`,`idx`,`url`,`title`,`kind`,`msg`) \
+             VALUES(?,?,?,?,?,?)",
+            (
+                qid,
+                failure.index,
+                &failure.url,
+                &failure.title,
+                &failure.kind,
+                &failure.message,
+            ),
+        )?;
+        Ok(())
+    }
+
+    fn get_entry_failures(&self, qid: &u64) -> Result<Vec<EntryFailure>> {
+        let rows: Vec<(u32, String, Option<String>, String, String)> = self.conn()?.exec(
+            "SELECT `idx`,`url`,`title`,`kind`,`msg` FROM `playlistfailures` \
+             WHERE `qid` = ? ORDER BY `idx`",
+            (qid,),
+        )?;
+        Ok(rows
+            .into_iter()
+            .map(|(index, url, title, kind, message)| EntryFailure {
+                index,
+                url,
+                title,
+                kind,
+                message,
+            })
+            .collect())
+    }
+
+    fn clear_entry_failures(&self, qid: &u64) -> Result<()> {
+        self.conn()?
+            .exec_drop("DELETE FROM `playlistfailures` WHERE `qid` = ?", (qid,))?;
+        Ok(())
+    }
+
+    fn migrate(&self) -> Result<()> {
+        let mut conn = self.conn()?;
+        for sql in split_sql(include_str!("../../../setup.sql")) {
+            conn.query_drop(create_if_missing(&sql))?;
+        }
+        for sql in upgrade_sql(include_str!("../../../upgrade.sql"), VERSION) {
+            match conn.query_drop(&sql) {
+                Ok(_) => (),
+                Err(mysql::Error::MySqlError(ref e)) if ALREADY_APPLIED.contains(&e.code) => {
+                    debug!("upgrade already applied: {}", e.message)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the statements of the upgrade script section `from <version>`
+fn upgrade_sql(script: &str, version: &str) -> Vec<String> {
+    let marker = format!("/* from {} */", version);
+    match script.find(&marker) {
+        Some(start) => {
+            let section = &script[start + marker.len()..];
+            let end = section.find("/* from ").unwrap_or(section.len());
+            split_sql(&section[..end])
+        }
+        None => Vec::new(),
+    }
+}
+
+/// Setup tables
+/// Created as temporary if specified (valid for the current connection)
+#[cfg(test)]
+fn setup_db(conn: &mut PooledConn, temp: bool) -> Result<()> {
+    let tables = get_db_create_sql();
+    for a in tables {
+        conn.query_drop(if temp {
+            a.replace("CREATE TABLE", "CREATE TEMPORARY TABLE")
+        } else {
+            a
+        })
+        .unwrap();
+    }
+    Ok(())
+}
+
+/// Returns a vector of table setup sql
+#[cfg(test)]
+fn get_db_create_sql<'a>() -> Vec<String> {
+    let split_sql = super::split_sql(include_str!("../../../setup.sql"));
+    debug!("\n\nGroups: {:?}\n\n", split_sql);
+    split_sql
+}
+
+/// For all DB tests the DB itself has to be clear from any tables matching the names used here!
+/// These require a MySQL test DB and are ignored by default, see test.sh
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crate::CONFIG;
+
+    use super::*; // import only public items
+    use super::{get_db_create_sql, setup_db, upgrade_sql};
+    use crate::lib::db::{request_entry, suite, DEFAULT_PLAYLIST_VAL, EVENT_ERROR};
+    use crate::lib::db::{EVENT_STARTED, EVENT_STEP};
+    use mysql;
+    use mysql::from_row;
+    use mysql::Pool;
+
+    use chrono::naive::NaiveDateTime;
+    use chrono::offset::Local;
+    use chrono::Duration;
+
+    use crate::lib;
+    use crate::lib::logger;
+    use crate::lib::ReqCore;
+
+    fn create_request(playlist: bool) -> ReqCore {
+        let mut req = ReqCore {
+            url: String::from("test.com"),
+            quality: 1,
+            qid: 1,
+            playlist: false,
+            split: false,
+            r_type: -2,
+            from: DEFAULT_PLAYLIST_VAL,
+            to: DEFAULT_PLAYLIST_VAL,
+            path: PathBuf::from(&CONFIG.general.download_dir),
+            temp_path: PathBuf::from(&CONFIG.general.temp_dir),
+            uid: 1,
+        };
+
+        if playlist {
+            req.playlist = true;
+            req.from = 0;
+            req.to = 100;
+            req.split = true;
+        }
+        req
+    }
+
+    /// Connect with a single connection, required for temporary tables
+    fn connect() -> MysqlRepository {
+        let config = lib::config::init_config_test();
+        let pool = Pool::new_manual(1, 1, mysql_options(&config)).unwrap();
+        MysqlRepository { pool }
+    }
+
+    fn setup(db: &MysqlRepository) {
+        let _ = setup_db(&mut db.conn().unwrap(), true);
+    }
+
+    fn get_status(db: &MysqlRepository, qid: &u64) -> (i8, Option<f64>, Option<String>) {
+        let mut conn = db.conn().unwrap();
+        let stmt = conn
+            .prep("SELECT `code`,`progress`,`status` FROM `querydetails` WHERE `qid`=?")
+            .unwrap();
+        let mut result = conn.exec_iter(&stmt, (qid,)).unwrap();
+        mysql::from_row(result.next().unwrap().unwrap())
+    }
+
+    fn get_error(db: &MysqlRepository, qid: &u64) -> Option<String> {
+        let mut conn = db.conn().unwrap();
+        let stmt = conn
+            .prep("SELECT `msg` FROM `queryerror` WHERE `qid`=?")
+            .unwrap();
+        let mut result = conn.exec_iter(&stmt, (qid,)).unwrap();
+        result.next().unwrap().unwrap().take("msg")
+    }
+
+    /// Test wrapper, accepting ReqCore structs, with additional playlist insertion
+    fn insert_query_core(req: &lib::ReqCore, db: &MysqlRepository) -> Result<u64> {
+        let playlist = if req.playlist {
+            Some(PlaylistRange {
+                from: req.from,
+                to: req.to,
+                split: req.split,
+            })
+        } else {
+            None
+        };
+        db.add_query(&req.url, &req.quality, &req.uid, &req.r_type, playlist)
+    }
+
+    /// Set last update check date, used for deletion checks
+    fn set_file_created(db: &MysqlRepository, qid: &u64, date: NaiveDateTime) {
+        let mut conn = db.conn().unwrap();
+        let stmt = conn
+            .prep("UPDATE files SET `created`= ? WHERE fid = ?")
+            .unwrap();
+        assert!(conn.exec_iter(&stmt, (date, qid)).is_ok());
+    }
+
+    /// Get fid,name, r_name of files for qid to test against an insertion
+    /// Retrusn an Vec<(fid,name,rname)>
+    fn get_files(db: &MysqlRepository, qid: &u64) -> Vec<(u64, String, String)> {
+        let mut conn = db.conn().unwrap();
+        let stmt = conn
+            .prep(
+                "SELECT files.fid,name, rname FROM files \
+                 JOIN `query_files` ON files.fid = query_files.fid \
+                 WHERE query_files.qid = ? ORDER BY fid",
+            )
+            .unwrap();
+        let result = conn.exec_iter(&stmt, (qid,)).unwrap();
+        let a: Vec<(u64, String, String)> = result.map(|row| from_row(row.unwrap())).collect();
+        a
+    }
+
+    #[test]
+    fn sql_test() {
+        get_db_create_sql();
+    }
+
+    #[test]
+    fn upgrade_sql_test() {
+        let script = include_str!("../../../upgrade.sql");
+        let sql = upgrade_sql(script, "0.7.0");
+        assert!(sql[0].starts_with("CREATE TABLE `querystages`"));
+        assert!(sql.iter().all(|v| !v.contains("KEY_BLOCK_SIZE")));
+        assert_eq!(5, upgrade_sql(script, "0.6.4").len());
+        assert!(upgrade_sql(script, "0.1.0").is_empty());
+    }
+
+    #[test]
+    #[ignore]
+    fn connect_setup_test() {
+        let db = connect();
+        setup(&db);
+    }
+
+    #[test]
+    #[ignore]
+    fn suite_test() {
+        let db = connect();
+        setup(&db);
+        suite::run(&db);
+    }
+
+    #[test]
+    #[ignore]
+    fn insert_query_test() {
+        let db = connect();
+        let request = create_request(true);
+        setup(&db);
+        insert_query_core(&request, &db).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn file_test() {
+        lib::config::init_config_test();
+
+        let db = connect();
+        let mut request = create_request(false);
+        setup(&db);
+        request.qid = insert_query_core(&request, &db).unwrap();
+
+        let f_name = "f_test";
+        let f_r_name = "f_r_test";
+        let n_fid = db
+            .add_file_entry(&request.qid, &f_name, &f_r_name, &0)
+            .unwrap();
+        let (fid, ref retr_name, ref retr_r_name) = get_files(&db, &request.qid)[0];
+        assert_eq!(retr_name, f_name);
+        assert_eq!(retr_r_name, f_r_name);
+        assert_eq!(n_fid, fid);
+        assert!(db.set_file_valid_flag(&fid, false).is_ok());
+    }
+
+    #[test]
+    #[ignore]
+    fn query_delete_test() {
+        lib::config::init_config_test();
+        let db = connect();
+        setup(&db);
+
+        let mut request = create_request(true);
+        request.qid = insert_query_core(&request, &db).unwrap();
+
+        let fid = db
+            .add_file_entry(&request.qid, &"test", &"test", &0)
+            .unwrap();
+
+        let mut files = Vec::new();
+        files.push((fid, "asd".to_string()));
+        let mut qids = Vec::new();
+        qids.push(request.qid.clone());
+
+        db.delete_requests(qids, files).unwrap();
+
+        let sql = "SELECT COUNT(*) as amount FROM %db% WHERE 1";
+        let mut conn = db.conn().unwrap();
+        for table in REQ_DB_TABLES.iter() {
+            let mut res = conn.exec_iter(sql.replace("%db%", table), ()).unwrap();
+            let amount: i32 = res.next().unwrap().unwrap().take("amount").unwrap();
+            assert_eq!(amount, 0);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn file_delete_sql_test() {
+        lib::config::init_config_test();
+
+        const AGE: u16 = 60 * 25; // minutes, age subtracted per iter
+        const MAX_AGE_DIFF: u16 = AGE - 10;
+        const AFFECTED_INVALID: i16 = 2; // i count file which will be invalidated
+        const AMOUNT_FILES: i16 = 16;
+        const AGE_DEL_RATIO: i16 = 50;
+
+        let db = connect();
+        setup(&db);
+
+        let start_time = Local::now();
+        let mut requests = Vec::new();
+        {
+            let mut time = start_time.naive_local();
+            let subtr_time = Duration::days(1);
+            //let deleteSwitchTime = Duration::days()
+            let req_template = create_request(false);
+
+            let treshold = AGE_DEL_RATIO * AMOUNT_FILES / 100;
+            let mut amount_flagged_delete = 0;
+
+            for i in 0..AMOUNT_FILES {
+                // create AMOUNT_FILES files, affected_invalid of them are marked
+                // as deleted, AGE_DEL_RATIO of them are marked with the delete flag
+                let mut req_new = req_template.clone();
+                req_new.qid = insert_query_core(&req_new, &db).unwrap();
+                let f_name = format!("f_{}", i);
+                let f_r_name = format!("f_r_{}", i);
+                let fid = db
+                    .add_file_entry(&req_new.qid, &f_name, &f_r_name, &0)
+                    .unwrap();
+                let delete = amount_flagged_delete < treshold;
+
+                let valid = match i == AFFECTED_INVALID {
+                    true => {
+                        assert!(db.set_file_valid_flag(&fid, false).is_ok());
+                        false
+                    }
+                    false => true,
+                };
+                set_file_created(&db, &fid, time);
+
+                if delete && valid {
+                    assert!(db.set_file_delete_flag(&fid, true).is_ok());
+                    amount_flagged_delete += 1;
+                }
+
+                requests.push((
+                    req_new.qid,
+                    fid,
+                    time.clone(),
+                    f_name,
+                    f_r_name,
+                    valid,
+                    delete,
+                ));
+                time = time - subtr_time;
+            }
+        }
+
+        assert!((Local::now().signed_duration_since(start_time)).num_milliseconds() < 1_000); // took too long to be accurate at retrieving
+
+        {
+            // get aged files-test
+            let (qids, files) = db
+                .get_files_to_delete(DeleteRequestType::AgedMin(&MAX_AGE_DIFF))
+                .unwrap();
+            // Vec<u64>,Vec<(u64,String)>
+            assert_eq!(files.is_empty(), false);
+            for (fid, name) in files {
+                // check file for file that all data is correct
+                let mut iter = requests
+                    .iter()
+                    .filter(|&&(_, ref r_fid, _, _, _, _, _)| r_fid == &fid);
+                let &(ref r_qid, ref r_fid, ref time, ref f_name, _, ref r_valid, ref _r_delete) =
+                    iter.next().unwrap();
+                assert_eq!(f_name, &name);
+                assert_eq!(r_valid, &true);
+                assert_eq!(r_fid, &fid);
+                let diff = start_time - Duration::minutes(MAX_AGE_DIFF as i64);
+                assert!(time <= &diff.naive_local());
+                assert!(qids.contains(&r_qid));
+                assert!(iter.next().is_none());
+                assert!(db.set_file_valid_flag(&fid, false).is_ok());
+            }
+            // re-check that no results remain
+            let (qids, files) = db
+                .get_files_to_delete(DeleteRequestType::AgedMin(&MAX_AGE_DIFF))
+                .unwrap();
+            assert!(qids.is_empty());
+            assert!(files.is_empty());
+        }
+
+        {
+            // delete marked test
+            let (qids, files) = db.get_files_to_delete(DeleteRequestType::Marked).unwrap();
+            // Vec<u64>,Vec<(u64,String)>
+            assert_eq!(files.is_empty(), false);
+            for (fid, name) in files {
+                // check file for file that all data is correct
+                let mut iter = requests
+                    .iter()
+                    .filter(|&&(_, ref r_fid, _, _, _, _, _)| r_fid == &fid);
+                let &(ref r_qid, ref r_fid, ref time, ref f_name, _, ref r_valid, ref r_delete) =
+                    iter.next().unwrap();
+                assert_eq!(f_name, &name);
+                assert_eq!(r_valid, &true);
+                assert_eq!(r_delete, &true);
+                assert_eq!(r_fid, &fid);
+                let diff = start_time - Duration::minutes(MAX_AGE_DIFF as i64);
+                assert!(time >= &diff.naive_local());
+                assert!(qids.contains(&r_qid));
+                assert!(iter.next().is_none());
+                assert!(db.set_file_valid_flag(&fid, false).is_ok()); // set as invalid: deleted
+                assert!(db.set_file_delete_flag(&fid, false).is_ok()); // set to be deleted: false
+            }
+            // re-check that no results remain
+            let (qids, files) = db.get_files_to_delete(DeleteRequestType::Marked).unwrap();
+            assert!(qids.is_empty());
+            assert!(files.is_empty());
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn query_test() {
+        logger::init_config_test();
+        lib::config::init_config_test();
+        {
+            let db = connect();
+            let mut request = create_request(false);
+            setup(&db);
+            let id = insert_query_core(&request, &db).unwrap();
+            request.qid = id;
+
+            let db: Arc<dyn Repository> = Arc::new(db);
+            let out_req = request_entry(&db).unwrap();
+            request.verify(&out_req);
+        }
+
+        {
+            let db = connect();
+            let mut request = create_request(true);
+            setup(&db);
+            let id = insert_query_core(&request, &db).unwrap();
+            request.qid = id;
+
+            let db: Arc<dyn Repository> = Arc::new(db);
+            let out_req = request_entry(&db).unwrap();
+            request.verify(&out_req);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn query_update_test() {
+        let db = connect();
+
+        let request = create_request(false);
+        setup(&db);
+        let id = insert_query_core(&request, &db).unwrap();
+
+        let new_code = -9;
+        let new_state = String::from("asd");
+        db.set_query_code(&id, &new_code);
+        db.set_query_state(&id, &new_state);
+        let (code, _progr, state) = get_status(&db, &id);
+        assert_eq!(code, new_code);
+        assert!(state.is_some());
+        assert_eq!(new_state, state.unwrap());
+    }
+
+    #[test]
+    #[ignore]
+    fn add_query_error_test() {
+        let db = connect();
+
+        let request = create_request(false);
+        setup(&db);
+        let id = insert_query_core(&request, &db).unwrap();
+
+        let new_error = String::from("asd");
+        db.add_query_error(&id, &new_error);
+        let error = get_error(&db, &id);
+        assert!(error.is_some());
+        assert_eq!(new_error, error.unwrap());
+    }
+
+    #[test]
+    #[ignore]
+    fn stage_test() {
+        let db = connect();
+
+        let request = create_request(false);
+        setup(&db);
+        let id = insert_query_core(&request, &db).unwrap();
+
+        assert!(!db.is_stage_done(&id, "video").unwrap());
+        db.set_stage_done(&id, "video").unwrap();
+        db.set_stage_done(&id, "video").unwrap(); // duplicates are ignored
+        assert!(db.is_stage_done(&id, "video").unwrap());
+        assert!(!db.is_stage_done(&id, "audio").unwrap());
+
+        db.clear_stages(&id).unwrap();
+        assert!(!db.is_stage_done(&id, "video").unwrap());
+    }
+
+    #[test]
+    #[ignore]
+    fn file_cache_test() {
+        let db = connect();
+        setup(&db);
+
+        let request = create_request(false);
+        let qid = insert_query_core(&request, &db).unwrap();
+        let fid = db.add_file_entry(&qid, "cached", "cached", &0).unwrap();
+        assert!(db.get_cached_file("yt:a:1:-2").unwrap().is_none());
+        db.set_file_cache_key(&fid, "yt:a:1:-2").unwrap();
+        assert_eq!(
+            db.get_cached_file("yt:a:1:-2").unwrap(),
+            Some((fid, String::from("cached")))
+        );
+
+        let qid_2 = insert_query_core(&request, &db).unwrap();
+        db.add_file_link(&qid_2, &fid).unwrap();
+        let mut refs = db.get_live_file_refs(&fid, &10).unwrap();
+        refs.sort();
+        assert_eq!(refs, vec![qid, qid_2]);
+
+        db.set_file_delete_flag(&fid, true).unwrap();
+        assert!(db.get_cached_file("yt:a:1:-2").unwrap().is_none());
+    }
+
+    #[test]
+    #[ignore]
+    fn usage_test() {
+        let db = connect();
+        setup(&db);
+
+        let mut request = create_request(false);
+        let qid = insert_query_core(&request, &db).unwrap();
+        let fid_1 = db.add_file_entry(&qid, "a", "a", &100).unwrap();
+        let fid_2 = db.add_file_entry(&qid, "b", "b", &50).unwrap();
+        request.uid = 2;
+        let qid_other = insert_query_core(&request, &db).unwrap();
+        db.add_file_entry(&qid_other, "c", "c", &10).unwrap();
+        db.add_file_link(&qid_other, &fid_2).unwrap(); // shared file
+
+        assert_eq!(db.get_user_usage(&1).unwrap(), 150);
+        assert_eq!(db.get_user_usage(&2).unwrap(), 60);
+        assert_eq!(db.get_total_usage().unwrap(), 160);
+        let files = db.get_user_files_oldest(&1).unwrap();
+        assert_eq!(files, vec![(fid_1, String::from("a"), 100)]);
+
+        db.set_file_valid_flag(&fid_1, false).unwrap();
+        assert_eq!(db.get_user_usage(&1).unwrap(), 50);
+    }
+
+    #[test]
+    #[ignore]
+    fn reconcile_test() {
+        let db = connect();
+        setup(&db);
+
+        let request = create_request(false);
+        let qid = insert_query_core(&request, &db).unwrap();
+        let fid_1 = db.add_file_entry(&qid, "a", "a", &0).unwrap();
+        let fid_2 = db.add_file_entry(&qid, "b", "b", &0).unwrap();
+        db.set_file_valid_flag(&fid_2, false).unwrap();
+
+        let mut names = db.get_file_names().unwrap();
+        names.sort();
+        assert_eq!(names, vec![String::from("a"), String::from("b")]);
+        assert!(db.file_name_exists("b").unwrap());
+        assert!(!db.file_name_exists("c").unwrap());
+        assert_eq!(
+            db.get_valid_files().unwrap(),
+            vec![(fid_1, String::from("a"))]
+        );
+
+        assert_eq!(db.get_active_queries().unwrap(), vec![qid]);
+        db.set_query_code(&qid, &crate::CODE_SUCCESS);
+        assert!(db.get_active_queries().unwrap().is_empty());
+    }
+
+    #[test]
+    #[ignore]
+    fn api_query_test() {
+        let db = connect();
+        setup(&db);
+
+        let range = PlaylistRange {
+            from: 1,
+            to: 5,
+            split: true,
+        };
+        let qid = db.add_query("url", &-1, &3, &0, Some(range)).unwrap();
+        let query = db.next_query().unwrap().unwrap();
+        assert_eq!(query.qid, qid);
+        assert_eq!(query.playlist, Some(range));
+
+        let info = db.get_query_info(&qid).unwrap().unwrap();
+        assert_eq!(info.uid, 3);
+        assert_eq!(info.code, CODE_WAITING);
+        assert!(info.error.is_none());
+        assert!(info.files.is_empty());
+
+        db.add_query_error(&qid, "error");
+        let fid = db.add_file_entry(&qid, "a", "A", &10).unwrap();
+        let info = db.get_query_info(&qid).unwrap().unwrap();
+        assert_eq!(info.error, Some(String::from("error")));
+        assert_eq!(info.files, vec![fid]);
+        assert!(db.get_query_info(&(qid + 1)).unwrap().is_none());
+
+        let files = db.get_user_files(&3).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].real_name, "A");
+        assert_eq!(files[0].size, Some(10));
+        assert!(db.get_user_files(&1).unwrap().is_empty());
+        assert_eq!(db.get_file_links(&fid).unwrap(), vec![(qid, 3)]);
+    }
+
+    #[test]
+    #[ignore]
+    fn queue_stats_test() {
+        let db = connect();
+        setup(&db);
+
+        assert_eq!(db.get_queue_stats().unwrap(), (0, None));
+        let request = create_request(false);
+        let qid = insert_query_core(&request, &db).unwrap();
+        insert_query_core(&request, &db).unwrap();
+        let (waiting, oldest) = db.get_queue_stats().unwrap();
+        assert_eq!(waiting, 2);
+        assert!(oldest.unwrap() >= 0);
+        db.set_query_code(&qid, &crate::CODE_STARTED);
+        assert_eq!(db.get_queue_stats().unwrap().0, 1);
+    }
+
+    #[test]
+    #[ignore]
+    fn notification_test() {
+        let db = connect();
+        setup(&db);
+
+        let request = create_request(false);
+        let qid = insert_query_core(&request, &db).unwrap();
+        let qid_other = insert_query_core(&request, &db).unwrap();
+        db.add_file_entry(&qid, "a.mp4", "A", &0).unwrap();
+        assert_eq!(
+            db.get_query_files(&qid).unwrap(),
+            vec![(String::from("a.mp4"), String::from("A"))]
+        );
+
+        db.add_notify_target(Some(request.uid), None, "email", "a@localhost")
+            .unwrap();
+        db.add_notify_target(None, Some(qid), "webhook", "http://localhost")
+            .unwrap();
+        assert_eq!(db.get_notify_targets(&qid, &request.uid).unwrap().len(), 2);
+        assert_eq!(
+            db.get_notify_targets(&qid_other, &request.uid).unwrap(),
+            vec![(String::from("email"), String::from("a@localhost"))]
+        );
+        assert!(db
+            .get_notify_targets(&qid_other, &(request.uid + 1))
+            .unwrap()
+            .is_empty());
+
+        db.add_notification(&qid, "webhook", "http://localhost", "{}")
+            .unwrap();
+        let due = db.get_due_notifications().unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 0);
+        db.retry_notification(&due[0].id, &5).unwrap();
+        assert!(db.get_due_notifications().unwrap().is_empty());
+        db.remove_notification(&due[0].id).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn event_test() {
+        let db = connect();
+        setup(&db);
+
+        let request = create_request(false);
+        let qid = insert_query_core(&request, &db).unwrap();
+        db.add_event(&qid, EVENT_STARTED, Some(crate::CODE_STARTED), None);
+        db.update_steps(&qid, 1, 3);
+        db.add_query_error(&qid, "failure");
+
+        let events = db.get_events(&qid).unwrap();
+        let stages: Vec<&str> = events.iter().map(|e| e.stage.as_str()).collect();
+        assert_eq!(stages, vec![EVENT_STARTED, EVENT_STEP, EVENT_ERROR]);
+        assert_eq!(events[0].code, Some(crate::CODE_STARTED));
+        assert_eq!(events[1].message, Some(String::from("1|3")));
+        assert_eq!(events[2].message, Some(String::from("failure")));
+        assert_eq!(events[0].worker, lib::worker_id());
+
+        db.delete_requests(vec![qid], Vec::new()).unwrap();
+        assert!(db.get_events(&qid).unwrap().is_empty());
+    }
+}
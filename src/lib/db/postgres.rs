@@ -0,0 +1,872 @@
+use chrono::{Duration, NaiveDateTime};
+use postgres::types::ToSql;
+use postgres::{Config, NoTls};
+use r2d2_postgres::r2d2::{Pool, PooledConnection};
+use r2d2_postgres::PostgresConnectionManager;
+
+use crate::lib;
+use crate::lib::config::ConfigDB;
+use crate::lib::{Error, Result};
+
+use super::{
+    create_if_missing, local_now, split_deletions, split_sql, DeleteRequestType, EntryFailure,
+    FileInfo, Notification, PlaylistRange, QueryEvent, QueryInfo, QueuedQuery, Repository,
+    REQ_DB_TABLES,
+};
+
+use crate::CODE_WAITING;
+
+type Manager = PostgresConnectionManager<NoTls>;
+
+/// PostgreSQL repository, see setup_postgres.sql
+///
+/// Unsigned ids are stored as BIGINT, status codes as SMALLINT.
+pub struct PostgresRepository {
+    pool: Pool<Manager>,
+}
+
+impl PostgresRepository {
+    /// Connect to the DBMS
+    pub fn new(conf: &ConfigDB) -> Result<PostgresRepository> {
+        let port = match conf.port {
+            0 => 5432,
+            v => v,
+        };
+        let mut config = Config::new();
+        config
+            .host(&conf.ip)
+            .port(port)
+            .user(&conf.user)
+            .password(&conf.password)
+            .dbname(&conf.db);
+        Ok(PostgresRepository {
+            pool: Pool::new(PostgresConnectionManager::new(config, NoTls))?,
+        })
+    }
+
+    fn conn(&self) -> Result<PooledConnection<Manager>> {
+        Ok(self.pool.get()?)
+    }
+
+    /// Execute a statement, returns the amount of affected rows
+    fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64> {
+        Ok(self.conn()?.execute(sql, params)?)
+    }
+
+    /// Query a single id column
+    fn query_ids(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<u64>> {
+        Ok(self
+            .conn()?
+            .query(sql, params)?
+            .iter()
+            .map(|row| row.get::<_, i64>(0) as u64)
+            .collect())
+    }
+}
+
+/// Convert an unsigned id into its column type
+fn id(v: &u64) -> i64 {
+    *v as i64
+}
+
+impl Repository for PostgresRepository {
+    fn next_query(&self) -> Result<Option<QueuedQuery>> {
+        let row = self.conn()?.query_opt(
+            "SELECT queries.qid,url,quality,split,\"from\",\"to\",uid,\"type\" FROM queries \
+             JOIN querydetails ON queries.qid = querydetails.qid \
+             LEFT JOIN playlists ON queries.qid = playlists.qid \
+             WHERE querydetails.code = $1 \
+             ORDER BY queries.created, queries.qid \
+             LIMIT 1",
+            &[&(CODE_WAITING as i16)],
+        )?;
+        Ok(row.map(|row| {
+            let split: Option<bool> = row.get(3);
+            let from: Option<i16> = row.get(4);
+            let to: Option<i16> = row.get(5);
+            let playlist = match (split, from, to) {
+                (Some(split), Some(from), Some(to)) => Some(PlaylistRange { from, to, split }),
+                _ => None,
+            };
+            QueuedQuery {
+                qid: row.get::<_, i64>(0) as u64,
+                url: row.get(1),
+                quality: row.get(2),
+                r_type: row.get(7),
+                uid: row.get::<_, i64>(6) as u32,
+                playlist,
+            }
+        }))
+    }
+
+    fn add_query(
+        &self,
+        url: &str,
+        quality: &i16,
+        uid: &u32,
+        r_type: &i16,
+        playlist: Option<PlaylistRange>,
+    ) -> Result<u64> {
+        let mut conn = self.conn()?;
+        let mut transaction = conn.transaction()?;
+        let qid: i64 = transaction
+            .query_one(
+                "INSERT INTO queries (url,quality,uid,created,\"type\") VALUES($1,$2,$3,$4,$5) \
+                 RETURNING qid",
+                &[&url, quality, &(*uid as i64), &local_now(), r_type],
+            )?
+            .get(0);
+        transaction.execute(
+            "INSERT INTO querydetails (qid,code) VALUES($1,$2)",
+            &[&qid, &(CODE_WAITING as i16)],
+        )?;
+        if let Some(range) = playlist {
+            transaction.execute(
+                "INSERT INTO playlists (qid,\"from\",\"to\",split) VALUES($1,$2,$3,$4)",
+                &[&qid, &range.from, &range.to, &range.split],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(qid as u64)
+    }
+
+    fn add_sub_query_link(&self, qid: &u64, origin: &u64) -> Result<()> {
+        self.execute(
+            "INSERT INTO subqueries (qid,origin_id) VALUES($1,$2)",
+            &[&id(qid), &id(origin)],
+        )?;
+        Ok(())
+    }
+
+    fn get_query_info(&self, qid: &u64) -> Result<Option<QueryInfo>> {
+        let mut conn = self.conn()?;
+        let row = match conn.query_opt(
+            "SELECT queries.qid,uid,url,quality,\"type\",created,code,progress,status,msg FROM queries \
+             JOIN querydetails ON queries.qid = querydetails.qid \
+             LEFT JOIN queryerror ON queries.qid = queryerror.qid \
+             WHERE queries.qid = $1",
+            &[&id(qid)],
+        )? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let files = conn
+            .query(
+                "SELECT fid FROM query_files WHERE qid = $1 ORDER BY fid",
+                &[&id(qid)],
+            )?
+            .iter()
+            .map(|row| row.get::<_, i64>(0) as u64)
+            .collect();
+        Ok(Some(QueryInfo {
+            qid: row.get::<_, i64>(0) as u64,
+            uid: row.get::<_, i64>(1) as u32,
+            url: row.get(2),
+            quality: row.get(3),
+            r_type: row.get(4),
+            created: row.get(5),
+            code: row.get::<_, i16>(6) as i8,
+            progress: row.get(7),
+            status: row.get(8),
+            error: row.get(9),
+            files,
+        }))
+    }
+
+    fn get_queries_by_code(&self, codes: &[i8]) -> Result<Vec<u64>> {
+        let codes: Vec<i16> = codes.iter().map(|c| *c as i16).collect();
+        self.query_ids(
+            "SELECT qid FROM querydetails WHERE code = ANY($1)",
+            &[&codes],
+        )
+    }
+
+    fn get_queue_stats(&self) -> Result<(u64, Option<i64>)> {
+        let row = self.conn()?.query_one(
+            "SELECT COUNT(*), MIN(created) FROM queries \
+             JOIN querydetails ON queries.qid = querydetails.qid \
+             WHERE querydetails.code = $1",
+            &[&(CODE_WAITING as i16)],
+        )?;
+        let oldest: Option<NaiveDateTime> = row.get(1);
+        Ok((
+            row.get::<_, i64>(0) as u64,
+            oldest.map(|v| (local_now() - v).num_seconds()),
+        ))
+    }
+
+    fn set_query_code(&self, qid: &u64, code: &i8) {
+        trace!("Setting query code {} for id {}", code, qid);
+        if let Err(why) = self.execute(
+            "UPDATE querydetails SET code = $1 WHERE qid = $2",
+            &[&(*code as i16), &id(qid)],
+        ) {
+            error!("Error inserting querystatus: {}", why);
+        }
+    }
+
+    fn set_query_state(&self, qid: &u64, state: &str) {
+        if let Err(why) = self.execute(
+            "UPDATE querydetails SET status = $1, progress = 0 WHERE qid = $2",
+            &[&state, &id(qid)],
+        ) {
+            error!("Error setting query state: {}", why);
+        }
+    }
+
+    fn set_null_state(&self, qid: &u64) {
+        if let Err(why) = self.execute(
+            "UPDATE querydetails SET status = NULL, progress = 100 WHERE qid = $1",
+            &[&id(qid)],
+        ) {
+            error!("Error setting query null sate: {}", why);
+        }
+    }
+
+    fn reset_query(&self, qid: &u64, code: &i8) -> Result<()> {
+        self.execute(
+            "UPDATE querydetails SET code = $1, status = NULL WHERE qid = $2",
+            &[&(*code as i16), &id(qid)],
+        )?;
+        Ok(())
+    }
+
+    fn set_progress(&self, qid: &u64, progress: f64) -> Result<()> {
+        self.execute(
+            "UPDATE querydetails SET progress = $1 WHERE qid = $2",
+            &[&progress, &id(qid)],
+        )?;
+        Ok(())
+    }
+
+    fn insert_query_error(&self, qid: &u64, msg: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO queryerror (qid,msg) VALUES ($1,$2)",
+            &[&id(qid), &msg],
+        )?;
+        Ok(())
+    }
+
+    fn add_event(&self, qid: &u64, stage: &str, code: Option<i8>, message: Option<&str>) {
+        if let Err(why) = self.execute(
+            "INSERT INTO queryevents (qid,created,stage,code,worker,message) \
+             VALUES ($1,$2,$3,$4,$5,$6)",
+            &[
+                &id(qid),
+                &local_now(),
+                &stage,
+                &code.map(|c| c as i16),
+                &lib::worker_id(),
+                &message,
+            ],
+        ) {
+            error!("Error inserting query event: {}", why);
+        }
+    }
+
+    fn get_events(&self, qid: &u64) -> Result<Vec<QueryEvent>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT created,stage,code,worker,message FROM queryevents \
+                 WHERE qid = $1 ORDER BY id",
+                &[&id(qid)],
+            )?
+            .iter()
+            .map(|row| QueryEvent {
+                created: row.get(0),
+                stage: row.get(1),
+                code: row.get::<_, Option<i16>>(2).map(|c| c as i8),
+                worker: row.get(3),
+                message: row.get(4),
+            })
+            .collect())
+    }
+
+    fn is_stage_done(&self, qid: &u64, stage: &str) -> Result<bool> {
+        Ok(self
+            .conn()?
+            .query_opt(
+                "SELECT qid FROM querystages WHERE qid = $1 AND stage = $2",
+                &[&id(qid), &stage],
+            )?
+            .is_some())
+    }
+
+    fn add_stage(&self, qid: &u64, stage: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO querystages (qid,stage) VALUES ($1,$2) ON CONFLICT DO NOTHING",
+            &[&id(qid), &stage],
+        )?;
+        Ok(())
+    }
+
+    fn clear_stages(&self, qid: &u64) -> Result<()> {
+        self.execute("DELETE FROM querystages WHERE qid = $1", &[&id(qid)])?;
+        Ok(())
+    }
+
+    fn insert_file(&self, name: &str, real_name: &str, size: &u64) -> Result<u64> {
+        let fid: i64 = self
+            .conn()?
+            .query_one(
+                "INSERT INTO files (rname,name,valid,size,created) VALUES ($1,$2,TRUE,$3,$4) \
+                 RETURNING fid",
+                &[&real_name, &name, &id(size), &local_now()],
+            )?
+            .get(0);
+        Ok(fid as u64)
+    }
+
+    fn add_file_link(&self, qid: &u64, fid: &u64) -> Result<()> {
+        self.execute(
+            "INSERT INTO query_files (qid,fid) VALUES($1,$2)",
+            &[&id(qid), &id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn replace_file_link(&self, fid: &u64, new_fid: &u64) -> Result<()> {
+        self.execute(
+            "UPDATE query_files SET fid = $1 WHERE fid = $2",
+            &[&id(new_fid), &id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn set_file_cache_key(&self, fid: &u64, key: &str) -> Result<()> {
+        self.execute(
+            "UPDATE files SET cache_key = $1 WHERE fid = $2",
+            &[&key, &id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn get_cached_file(&self, key: &str) -> Result<Option<(u64, String)>> {
+        Ok(self
+            .conn()?
+            .query_opt(
+                "SELECT fid,name FROM files \
+                 WHERE cache_key = $1 AND valid AND NOT \"delete\" \
+                 ORDER BY fid DESC LIMIT 1",
+                &[&key],
+            )?
+            .map(|row| (row.get::<_, i64>(0) as u64, row.get(1))))
+    }
+
+    fn get_live_file_refs(&self, fid: &u64, age: &u16) -> Result<Vec<u64>> {
+        self.query_ids(
+            "SELECT query_files.qid FROM query_files \
+             JOIN queries ON queries.qid = query_files.qid \
+             WHERE query_files.fid = $1 AND queries.created >= $2",
+            &[&id(fid), &(local_now() - Duration::minutes(*age as i64))],
+        )
+    }
+
+    fn set_file_delete_flag(&self, fid: &u64, delete: bool) -> Result<()> {
+        self.execute(
+            "UPDATE files SET \"delete\" = $1 WHERE fid = $2",
+            &[&delete, &id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn set_file_link_delete_flag(&self, qid: &u64, fid: &u64, delete: bool) -> Result<()> {
+        self.execute(
+            "UPDATE query_files SET \"delete\" = $1 WHERE qid = $2 AND fid = $3",
+            &[&delete, &id(qid), &id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn get_file_refs(&self, fid: &u64) -> Result<Vec<u64>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT qid FROM query_files WHERE fid = $1 AND NOT \"delete\"",
+                &[&id(fid)],
+            )?
+            .iter()
+            .map(|row| row.get::<_, i64>(0) as u64)
+            .collect())
+    }
+
+    fn remove_marked_file_links(&self) -> Result<()> {
+        self.execute(
+            "DELETE FROM query_files WHERE \"delete\" \
+             AND fid IN (SELECT fid FROM files WHERE valid)",
+            &[],
+        )?;
+        Ok(())
+    }
+
+    fn set_file_valid_flag(&self, fid: &u64, valid: bool) -> Result<()> {
+        if self.execute(
+            "UPDATE files SET valid = $1 WHERE fid = $2",
+            &[&valid, &id(fid)],
+        )? != 1
+        {
+            return Err(Error::InternalError(String::from(
+                "Invalid affected lines count!",
+            )));
+        }
+        Ok(())
+    }
+
+    fn get_files_to_delete(
+        &self,
+        del_type: DeleteRequestType,
+    ) -> Result<(Vec<u64>, Vec<(u64, String)>)> {
+        let sql = "SELECT query_files.qid,files.fid,name FROM files \
+                   LEFT JOIN query_files ON files.fid = query_files.fid ";
+        let rows = match del_type {
+            DeleteRequestType::AgedMin(x) => self.conn()?.query(
+                format!("{} WHERE valid AND created < $1", sql).as_str(),
+                &[&(local_now() - Duration::minutes(*x as i64))],
+            )?,
+            DeleteRequestType::Marked => self.conn()?.query(
+                format!(
+                    "{} WHERE valid AND (files.\"delete\" OR query_files.\"delete\")",
+                    sql
+                )
+                .as_str(),
+                &[],
+            )?,
+        };
+        Ok(split_deletions(
+            rows.iter()
+                .map(|row| {
+                    (
+                        row.get::<_, Option<i64>>(0).map(|v| v as u64),
+                        row.get::<_, i64>(1) as u64,
+                        row.get(2),
+                    )
+                })
+                .collect(),
+        ))
+    }
+
+    fn get_user_usage(&self, uid: &u32) -> Result<u64> {
+        let usage: i64 = self
+            .conn()?
+            .query_one(
+                "SELECT CAST(COALESCE(SUM(size),0) AS BIGINT) FROM files \
+                 WHERE valid AND fid IN ( \
+                 SELECT query_files.fid FROM query_files \
+                 JOIN queries ON queries.qid = query_files.qid WHERE queries.uid = $1)",
+                &[&(*uid as i64)],
+            )?
+            .get(0);
+        Ok(usage as u64)
+    }
+
+    fn get_total_usage(&self) -> Result<u64> {
+        let usage: i64 = self
+            .conn()?
+            .query_one(
+                "SELECT CAST(COALESCE(SUM(size),0) AS BIGINT) FROM files WHERE valid",
+                &[],
+            )?
+            .get(0);
+        Ok(usage as u64)
+    }
+
+    fn get_user_files_oldest(&self, uid: &u32) -> Result<Vec<(u64, String, u64)>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT fid,name,COALESCE(size,0) FROM files \
+                 WHERE valid AND fid IN ( \
+                 SELECT query_files.fid FROM query_files \
+                 JOIN queries ON queries.qid = query_files.qid WHERE queries.uid = $1) \
+                 AND fid NOT IN ( \
+                 SELECT query_files.fid FROM query_files \
+                 JOIN queries ON queries.qid = query_files.qid WHERE queries.uid != $1) \
+                 ORDER BY created ASC",
+                &[&(*uid as i64)],
+            )?
+            .iter()
+            .map(|row| {
+                (
+                    row.get::<_, i64>(0) as u64,
+                    row.get(1),
+                    row.get::<_, i64>(2) as u64,
+                )
+            })
+            .collect())
+    }
+
+    fn get_file_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .conn()?
+            .query("SELECT name FROM files", &[])?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    fn file_name_exists(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .conn()?
+            .query_opt("SELECT fid FROM files WHERE name = $1 LIMIT 1", &[&name])?
+            .is_some())
+    }
+
+    fn get_valid_files(&self) -> Result<Vec<(u64, String)>> {
+        Ok(self
+            .conn()?
+            .query("SELECT fid,name FROM files WHERE valid", &[])?
+            .iter()
+            .map(|row| (row.get::<_, i64>(0) as u64, row.get(1)))
+            .collect())
+    }
+
+    fn get_user_files(&self, uid: &u32) -> Result<Vec<FileInfo>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT fid,name,rname,size,valid,\"delete\",created FROM files \
+                 WHERE fid IN ( \
+                 SELECT query_files.fid FROM query_files \
+                 JOIN queries ON queries.qid = query_files.qid WHERE queries.uid = $1) \
+                 ORDER BY created DESC, fid DESC",
+                &[&(*uid as i64)],
+            )?
+            .iter()
+            .map(|row| FileInfo {
+                fid: row.get::<_, i64>(0) as u64,
+                name: row.get(1),
+                real_name: row.get(2),
+                size: row.get::<_, Option<i64>>(3).map(|v| v as u64),
+                valid: row.get(4),
+                delete: row.get(5),
+                created: row.get(6),
+            })
+            .collect())
+    }
+
+    fn get_file(&self, fid: &u64) -> Result<Option<FileInfo>> {
+        Ok(self
+            .conn()?
+            .query_opt(
+                "SELECT fid,name,rname,size,valid,\"delete\",created FROM files WHERE fid = $1",
+                &[&id(fid)],
+            )?
+            .map(|row| FileInfo {
+                fid: row.get::<_, i64>(0) as u64,
+                name: row.get(1),
+                real_name: row.get(2),
+                size: row.get::<_, Option<i64>>(3).map(|v| v as u64),
+                valid: row.get(4),
+                delete: row.get(5),
+                created: row.get(6),
+            }))
+    }
+
+    fn get_file_links(&self, fid: &u64) -> Result<Vec<(u64, u32)>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT queries.qid, queries.uid FROM query_files \
+                 JOIN queries ON queries.qid = query_files.qid WHERE query_files.fid = $1",
+                &[&id(fid)],
+            )?
+            .iter()
+            .map(|row| (row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u32))
+            .collect())
+    }
+
+    fn get_query_files(&self, qid: &u64) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT name,rname FROM files \
+                 JOIN query_files ON files.fid = query_files.fid \
+                 WHERE query_files.qid = $1 ORDER BY files.fid",
+                &[&id(qid)],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect())
+    }
+
+    fn delete_requests(&self, qids: Vec<u64>, files: Vec<(u64, String)>) -> Result<()> {
+        let mut conn = self.conn()?;
+        let mut transaction = conn.transaction()?;
+        {
+            let stmt = transaction.prepare("DELETE FROM files WHERE fid = $1")?;
+            for (fid, _) in files {
+                transaction.execute(&stmt, &[&id(&fid)])?;
+            }
+        }
+        for table in REQ_DB_TABLES.iter() {
+            let stmt = transaction.prepare(&format!("DELETE FROM {} WHERE qid = $1", table))?;
+            for qid in &qids {
+                transaction.execute(&stmt, &[&id(qid)])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn add_notify_target(
+        &self,
+        uid: Option<u32>,
+        qid: Option<u64>,
+        kind: &str,
+        target: &str,
+    ) -> Result<()> {
+        self.execute(
+            "INSERT INTO notifytargets (uid,qid,kind,target) VALUES($1,$2,$3,$4)",
+            &[
+                &uid.map(|v| v as i64),
+                &qid.map(|v| v as i64),
+                &kind,
+                &target,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_notify_targets(&self, qid: &u64, uid: &u32) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT DISTINCT kind,target FROM notifytargets WHERE qid = $1 OR uid = $2",
+                &[&id(qid), &(*uid as i64)],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect())
+    }
+
+    fn add_notification(&self, qid: &u64, kind: &str, target: &str, payload: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO notifyoutbox (qid,kind,target,payload,next_try) VALUES($1,$2,$3,$4,$5)",
+            &[&id(qid), &kind, &target, &payload, &local_now()],
+        )?;
+        Ok(())
+    }
+
+    fn get_due_notifications(&self) -> Result<Vec<Notification>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT id,qid,kind,target,payload,attempts FROM notifyoutbox \
+                 WHERE next_try <= $1 ORDER BY id",
+                &[&local_now()],
+            )?
+            .iter()
+            .map(|row| Notification {
+                id: row.get::<_, i64>(0) as u64,
+                qid: row.get::<_, i64>(1) as u64,
+                kind: row.get(2),
+                target: row.get(3),
+                payload: row.get(4),
+                attempts: row.get::<_, i16>(5) as u16,
+            })
+            .collect())
+    }
+
+    fn remove_notification(&self, id_: &u64) -> Result<()> {
+        self.execute("DELETE FROM notifyoutbox WHERE id = $1", &[&id(id_)])?;
+        Ok(())
+    }
+
+    fn retry_notification(&self, id_: &u64, delay_min: &u32) -> Result<()> {
+        self.execute(
+            "UPDATE notifyoutbox SET attempts = attempts + 1, next_try = $1 WHERE id = $2",
+            &[
+                &(local_now() + Duration::minutes(*delay_min as i64)),
+                &id(id_),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn set_credential(&self, uid: &u32, name: &str, kind: &str, data: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO credentials (uid,name,kind,data) VALUES($1,$2,$3,$4) \
+             ON CONFLICT (uid,name) DO UPDATE SET kind = EXCLUDED.kind, data = EXCLUDED.data",
+            &[&(*uid as i64), &name, &kind, &data],
+        )?;
+        Ok(())
+    }
+
+    fn get_credential(&self, uid: &u32, name: &str) -> Result<Option<(String, String)>> {
+        Ok(self
+            .conn()?
+            .query_opt(
+                "SELECT kind,data FROM credentials WHERE uid = $1 AND name = $2",
+                &[&(*uid as i64), &name],
+            )?
+            .map(|row| (row.get(0), row.get(1))))
+    }
+
+    fn get_credential_names(&self, uid: &u32) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT name,kind FROM credentials WHERE uid = $1 ORDER BY name",
+                &[&(*uid as i64)],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect())
+    }
+
+    fn delete_credential(&self, uid: &u32, name: &str) -> Result<bool> {
+        Ok(self.execute(
+            "DELETE FROM credentials WHERE uid = $1 AND name = $2",
+            &[&(*uid as i64), &name],
+        )? > 0)
+    }
+
+    fn set_query_credential(&self, qid: &u64, name: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO querycredentials (qid,name) VALUES($1,$2) \
+             ON CONFLICT (qid) DO UPDATE SET name = EXCLUDED.name",
+            &[&id(qid), &name],
+        )?;
+        Ok(())
+    }
+
+    fn get_query_credential(&self, qid: &u64) -> Result<Option<String>> {
+        Ok(self
+            .conn()?
+            .query_opt(
+                "SELECT name FROM querycredentials WHERE qid = $1",
+                &[&id(qid)],
+            )?
+            .map(|row| row.get(0)))
+    }
+
+    fn set_query_retry(&self, qid: &u64, origin: &u64) -> Result<()> {
+        self.execute(
+            "INSERT INTO queryretries (qid,origin) VALUES($1,$2) \
+             ON CONFLICT (qid) DO UPDATE SET origin = EXCLUDED.origin",
+            &[&id(qid), &id(origin)],
+        )?;
+        Ok(())
+    }
+
+    fn get_query_retry(&self, qid: &u64) -> Result<Option<u64>> {
+        Ok(self
+            .conn()?
+            .query_opt(
+                "SELECT origin FROM queryretries WHERE qid = $1",
+                &[&id(qid)],
+            )?
+            .map(|row| row.get::<_, i64>(0) as u64))
+    }
+
+    fn get_pending_retries(&self, origin: &u64) -> Result<Vec<u64>> {
+        let codes: Vec<i16> = [CODE_WAITING, crate::CODE_STARTED, crate::CODE_IN_PROGRESS]
+            .iter()
+            .map(|c| *c as i16)
+            .collect();
+        self.query_ids(
+            "SELECT queryretries.qid FROM queryretries \
+             JOIN querydetails ON querydetails.qid = queryretries.qid \
+             WHERE origin = $1 AND code = ANY($2)",
+            &[&id(origin), &codes],
+        )
+    }
+
+    fn set_ytdl_version(&self, host: &str, version: &str, channel: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO ytdlversions (host,version,channel,updated) VALUES($1,$2,$3,$4) \
+             ON CONFLICT (host) DO UPDATE SET version = EXCLUDED.version, \
+             channel = EXCLUDED.channel, updated = EXCLUDED.updated",
+            &[&host, &version, &channel, &local_now()],
+        )?;
+        Ok(())
+    }
+
+    fn get_ytdl_version(&self, host: &str) -> Result<Option<(String, String)>> {
+        Ok(self
+            .conn()?
+            .query_opt(
+                "SELECT version,channel FROM ytdlversions WHERE host = $1",
+                &[&host],
+            )?
+            .map(|row| (row.get(0), row.get(1))))
+    }
+
+    fn add_entry_failure(&self, qid: &u64, failure: &EntryFailure) -> Result<()> {
+        self.execute(
+            "INSERT INTO playlistfailures (qid,idx,url,title,kind,msg) VALUES($1,$2,$3,$4,$5,$6) \
+             ON CONFLICT (qid,idx) DO UPDATE SET url = EXCLUDED.url, title = EXCLUDED.title, \
+             kind = EXCLUDED.kind, msg = EXCLUDED.msg",
+            &[
+                &id(qid),
+                &(failure.index as i32),
+                &failure.url,
+                &failure.title,
+                &failure.kind,
+                &failure.message,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_entry_failures(&self, qid: &u64) -> Result<Vec<EntryFailure>> {
+        Ok(self
+            .conn()?
+            .query(
+                "SELECT idx,url,title,kind,msg FROM playlistfailures \
+                 WHERE qid = $1 ORDER BY idx",
+                &[&id(qid)],
+            )?
+            .iter()
+            .map(|row| EntryFailure {
+                index: row.get::<_, i32>(0) as u32,
+                url: row.get(1),
+                title: row.get(2),
+                kind: row.get(3),
+                message: row.get(4),
+            })
+            .collect())
+    }
+
+    fn clear_entry_failures(&self, qid: &u64) -> Result<()> {
+        self.execute("DELETE FROM playlistfailures WHERE qid = $1", &[&id(qid)])?;
+        Ok(())
+    }
+
+    fn migrate(&self) -> Result<()> {
+        let mut conn = self.conn()?;
+        for sql in split_sql(include_str!("../../../setup_postgres.sql")) {
+            conn.batch_execute(&create_if_missing(&sql))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lib::db::suite;
+    use std::env;
+
+    /// Requires an empty PostgreSQL database, see the pg_* env vars
+    #[test]
+    #[ignore]
+    fn suite_test() {
+        let var = |v: &str| env::var(v).expect(v);
+        let conf = ConfigDB {
+            backend: crate::lib::config::DbBackend::Postgres,
+            user: var("pg_user"),
+            password: var("pg_pass"),
+            port: var("pg_port").parse().unwrap(),
+            db: var("pg_db"),
+            ip: var("pg_ip"),
+            path: String::new(),
+        };
+        let db = PostgresRepository::new(&conf).unwrap();
+        db.migrate().unwrap();
+        // existing tables are skipped
+        db.migrate().unwrap();
+        suite::run(&db);
+    }
+}
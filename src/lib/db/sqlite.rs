@@ -0,0 +1,828 @@
+use chrono::{Duration, NaiveDateTime};
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OptionalExtension, Row, NO_PARAMS};
+
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::lib;
+use crate::lib::{Error, Result};
+
+use super::{
+    local_now, split_deletions, DeleteRequestType, EntryFailure, FileInfo, Notification,
+    PlaylistRange, QueryEvent, QueryInfo, QueuedQuery, Repository, REQ_DB_TABLES,
+};
+
+use crate::CODE_WAITING;
+
+/// Wait time for locks of other processes accessing the database
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// SQLite repository for single machine setups
+///
+/// The tables of setup_sqlite.sql are created on opening.
+/// All access is serialized over one connection.
+pub struct SqliteRepository {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteRepository {
+    /// Open or create the database file
+    pub fn open(path: &Path) -> Result<SqliteRepository> {
+        SqliteRepository::setup(Connection::open(path)?)
+    }
+
+    /// Open a new in-memory database, used by the one-shot mode
+    pub fn open_in_memory() -> Result<SqliteRepository> {
+        SqliteRepository::setup(Connection::open_in_memory()?)
+    }
+
+    fn setup(conn: Connection) -> Result<SqliteRepository> {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        let db = SqliteRepository {
+            conn: Mutex::new(conn),
+        };
+        db.migrate()?;
+        Ok(db)
+    }
+
+    fn conn(&self) -> Result<MutexGuard<'_, Connection>> {
+        Ok(self.conn.lock()?)
+    }
+
+    /// Execute a statement, returns the amount of affected rows
+    fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> Result<usize> {
+        Ok(self.conn()?.execute(sql, params)?)
+    }
+
+    /// Query all rows, mapped by f
+    fn query<T, F>(&self, sql: &str, params: &[&dyn ToSql], f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, f)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<T>>>()?)
+    }
+
+    /// Query the first row, mapped by f
+    fn query_first<T, F>(&self, sql: &str, params: &[&dyn ToSql], f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        Ok(self.conn()?.query_row(sql, params, f).optional()?)
+    }
+}
+
+/// Convert an unsigned id into its column type
+fn id(v: &u64) -> i64 {
+    *v as i64
+}
+
+/// Read an unsigned id column
+fn get_id(row: &Row<'_>, idx: usize) -> rusqlite::Result<u64> {
+    Ok(row.get::<_, i64>(idx)? as u64)
+}
+
+impl Repository for SqliteRepository {
+    fn next_query(&self) -> Result<Option<QueuedQuery>> {
+        self.query_first(
+            "SELECT queries.qid,url,quality,`split`,`from`,`to`,uid,`type` FROM queries \
+             JOIN querydetails ON queries.qid = querydetails.qid \
+             LEFT JOIN playlists ON queries.qid = playlists.qid \
+             WHERE querydetails.code = ? \
+             ORDER BY queries.created, queries.qid \
+             LIMIT 1",
+            params![CODE_WAITING],
+            |row| {
+                let split: Option<bool> = row.get(3)?;
+                let from: Option<i16> = row.get(4)?;
+                let to: Option<i16> = row.get(5)?;
+                let playlist = match (split, from, to) {
+                    (Some(split), Some(from), Some(to)) => Some(PlaylistRange { from, to, split }),
+                    _ => None,
+                };
+                Ok(QueuedQuery {
+                    qid: get_id(row, 0)?,
+                    url: row.get(1)?,
+                    quality: row.get(2)?,
+                    r_type: row.get(7)?,
+                    uid: row.get(6)?,
+                    playlist,
+                })
+            },
+        )
+    }
+
+    fn add_query(
+        &self,
+        url: &str,
+        quality: &i16,
+        uid: &u32,
+        r_type: &i16,
+        playlist: Option<PlaylistRange>,
+    ) -> Result<u64> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        transaction.execute(
+            "INSERT INTO `queries` (url,quality,uid,created,`type`) VALUES(?,?,?,?,?)",
+            params![url, quality, uid, local_now(), r_type],
+        )?;
+        let qid = transaction.last_insert_rowid();
+        transaction.execute(
+            "INSERT INTO `querydetails` (qid,`code`) VALUES(?,?)",
+            params![qid, CODE_WAITING],
+        )?;
+        if let Some(range) = playlist {
+            transaction.execute(
+                "INSERT INTO `playlists` (`qid`,`from`,`to`,`split`) VALUES(?,?,?,?)",
+                params![qid, range.from, range.to, range.split],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(qid as u64)
+    }
+
+    fn add_sub_query_link(&self, qid: &u64, origin: &u64) -> Result<()> {
+        self.execute(
+            "INSERT INTO `subqueries` (qid,origin_id) VALUES(?,?)",
+            params![id(qid), id(origin)],
+        )?;
+        Ok(())
+    }
+
+    fn get_query_info(&self, qid: &u64) -> Result<Option<QueryInfo>> {
+        let info = self.query_first(
+            "SELECT queries.qid,uid,url,quality,`type`,created,`code`,progress,`status`,msg FROM queries \
+             JOIN querydetails ON queries.qid = querydetails.qid \
+             LEFT JOIN queryerror ON queries.qid = queryerror.qid \
+             WHERE queries.qid = ?",
+            params![id(qid)],
+            |row| {
+                Ok(QueryInfo {
+                    qid: get_id(row, 0)?,
+                    uid: row.get(1)?,
+                    url: row.get(2)?,
+                    quality: row.get(3)?,
+                    r_type: row.get(4)?,
+                    created: row.get(5)?,
+                    code: row.get(6)?,
+                    progress: row.get(7)?,
+                    status: row.get(8)?,
+                    error: row.get(9)?,
+                    files: Vec::new(),
+                })
+            },
+        )?;
+        let mut info = match info {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        info.files = self.query(
+            "SELECT `fid` FROM `query_files` WHERE `qid` = ? ORDER BY `fid`",
+            params![id(qid)],
+            |row| get_id(row, 0),
+        )?;
+        Ok(Some(info))
+    }
+
+    fn get_queries_by_code(&self, codes: &[i8]) -> Result<Vec<u64>> {
+        let params: Vec<&dyn ToSql> = codes.iter().map(|c| c as &dyn ToSql).collect();
+        self.query(
+            &format!(
+                "SELECT `qid` FROM `querydetails` WHERE `code` IN ({})",
+                vec!["?"; codes.len()].join(",")
+            ),
+            &params,
+            |row| get_id(row, 0),
+        )
+    }
+
+    fn get_queue_stats(&self) -> Result<(u64, Option<i64>)> {
+        let (waiting, oldest): (i64, Option<NaiveDateTime>) = self.conn()?.query_row(
+            "SELECT COUNT(*), MIN(`created`) FROM `queries` \
+             JOIN `querydetails` ON `queries`.`qid` = `querydetails`.`qid` \
+             WHERE `querydetails`.`code` = ?",
+            params![CODE_WAITING],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok((
+            waiting as u64,
+            oldest.map(|v| (local_now() - v).num_seconds()),
+        ))
+    }
+
+    fn set_query_code(&self, qid: &u64, code: &i8) {
+        trace!("Setting query code {} for id {}", code, qid);
+        if let Err(why) = self.execute(
+            "UPDATE querydetails SET code = ? WHERE qid = ?",
+            params![code, id(qid)],
+        ) {
+            error!("Error inserting querystatus: {}", why);
+        }
+    }
+
+    fn set_query_state(&self, qid: &u64, state: &str) {
+        if let Err(why) = self.execute(
+            "UPDATE querydetails SET status = ?, progress = 0 WHERE qid = ?",
+            params![state, id(qid)],
+        ) {
+            error!("Error setting query state: {}", why);
+        }
+    }
+
+    fn set_null_state(&self, qid: &u64) {
+        if let Err(why) = self.execute(
+            "UPDATE querydetails SET status = NULL, progress = 100 WHERE qid = ?",
+            params![id(qid)],
+        ) {
+            error!("Error setting query null sate: {}", why);
+        }
+    }
+
+    fn reset_query(&self, qid: &u64, code: &i8) -> Result<()> {
+        self.execute(
+            "UPDATE `querydetails` SET `code` = ?, `status` = NULL WHERE `qid` = ?",
+            params![code, id(qid)],
+        )?;
+        Ok(())
+    }
+
+    fn set_progress(&self, qid: &u64, progress: f64) -> Result<()> {
+        self.execute(
+            "UPDATE querydetails SET progress = ? WHERE qid = ?",
+            params![progress, id(qid)],
+        )?;
+        Ok(())
+    }
+
+    fn insert_query_error(&self, qid: &u64, msg: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO queryerror (qid,msg) VALUES (?,?)",
+            params![id(qid), msg],
+        )?;
+        Ok(())
+    }
+
+    fn add_event(&self, qid: &u64, stage: &str, code: Option<i8>, message: Option<&str>) {
+        if let Err(why) = self.execute(
+            "INSERT INTO `queryevents` (`qid`,`created`,`stage`,`code`,`worker`,`message`) \
+             VALUES (?,?,?,?,?,?)",
+            params![id(qid), local_now(), stage, code, lib::worker_id(), message],
+        ) {
+            error!("Error inserting query event: {}", why);
+        }
+    }
+
+    fn get_events(&self, qid: &u64) -> Result<Vec<QueryEvent>> {
+        self.query(
+            "SELECT `created`,`stage`,`code`,`worker`,`message` FROM `queryevents` \
+             WHERE `qid` = ? ORDER BY `id`",
+            params![id(qid)],
+            |row| {
+                Ok(QueryEvent {
+                    created: row.get(0)?,
+                    stage: row.get(1)?,
+                    code: row.get(2)?,
+                    worker: row.get(3)?,
+                    message: row.get(4)?,
+                })
+            },
+        )
+    }
+
+    fn is_stage_done(&self, qid: &u64, stage: &str) -> Result<bool> {
+        Ok(self
+            .query_first(
+                "SELECT `qid` FROM `querystages` WHERE `qid` = ? AND `stage` = ?",
+                params![id(qid), stage],
+                |row| get_id(row, 0),
+            )?
+            .is_some())
+    }
+
+    fn add_stage(&self, qid: &u64, stage: &str) -> Result<()> {
+        self.execute(
+            "INSERT OR IGNORE INTO `querystages` (`qid`,`stage`) VALUES (?,?)",
+            params![id(qid), stage],
+        )?;
+        Ok(())
+    }
+
+    fn clear_stages(&self, qid: &u64) -> Result<()> {
+        self.execute(
+            "DELETE FROM `querystages` WHERE `qid` = ?",
+            params![id(qid)],
+        )?;
+        Ok(())
+    }
+
+    fn insert_file(&self, name: &str, real_name: &str, size: &u64) -> Result<u64> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO files (rname,name,valid,size,created) VALUES (?,?,1,?,?)",
+            params![real_name, name, id(size), local_now()],
+        )?;
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    fn add_file_link(&self, qid: &u64, fid: &u64) -> Result<()> {
+        self.execute(
+            "INSERT INTO `query_files` (qid,fid) VALUES(?,?)",
+            params![id(qid), id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn replace_file_link(&self, fid: &u64, new_fid: &u64) -> Result<()> {
+        self.execute(
+            "UPDATE `query_files` SET `fid` = ? WHERE `fid` = ?",
+            params![id(new_fid), id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn set_file_cache_key(&self, fid: &u64, key: &str) -> Result<()> {
+        self.execute(
+            "UPDATE `files` SET `cache_key` = ? WHERE `fid` = ?",
+            params![key, id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn get_cached_file(&self, key: &str) -> Result<Option<(u64, String)>> {
+        self.query_first(
+            "SELECT `fid`,`name` FROM `files` \
+             WHERE `cache_key` = ? AND `valid` = 1 AND `delete` = 0 \
+             ORDER BY `fid` DESC LIMIT 1",
+            params![key],
+            |row| Ok((get_id(row, 0)?, row.get(1)?)),
+        )
+    }
+
+    fn get_live_file_refs(&self, fid: &u64, age: &u16) -> Result<Vec<u64>> {
+        self.query(
+            "SELECT `query_files`.`qid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` \
+             WHERE `query_files`.`fid` = ? AND `queries`.`created` >= ?",
+            params![id(fid), local_now() - Duration::minutes(*age as i64)],
+            |row| get_id(row, 0),
+        )
+    }
+
+    fn set_file_delete_flag(&self, fid: &u64, delete: bool) -> Result<()> {
+        self.execute(
+            "UPDATE files SET `delete` = ? WHERE fid = ?",
+            params![delete, id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn set_file_link_delete_flag(&self, qid: &u64, fid: &u64, delete: bool) -> Result<()> {
+        self.execute(
+            "UPDATE `query_files` SET `delete` = ? WHERE `qid` = ? AND `fid` = ?",
+            params![delete, id(qid), id(fid)],
+        )?;
+        Ok(())
+    }
+
+    fn get_file_refs(&self, fid: &u64) -> Result<Vec<u64>> {
+        self.query(
+            "SELECT `qid` FROM `query_files` WHERE `fid` = ? AND `delete` = 0",
+            params![id(fid)],
+            |row| get_id(row, 0),
+        )
+    }
+
+    fn remove_marked_file_links(&self) -> Result<()> {
+        self.execute(
+            "DELETE FROM `query_files` WHERE `delete` = 1 \
+             AND `fid` IN (SELECT `fid` FROM `files` WHERE `valid` = 1)",
+            NO_PARAMS,
+        )?;
+        Ok(())
+    }
+
+    fn set_file_valid_flag(&self, fid: &u64, valid: bool) -> Result<()> {
+        if self.execute(
+            "UPDATE `files` SET `valid` = ? WHERE `fid` = ?",
+            params![valid, id(fid)],
+        )? != 1
+        {
+            return Err(Error::InternalError(String::from(
+                "Invalid affected lines count!",
+            )));
+        }
+        Ok(())
+    }
+
+    fn get_files_to_delete(
+        &self,
+        del_type: DeleteRequestType,
+    ) -> Result<(Vec<u64>, Vec<(u64, String)>)> {
+        let sql = "SELECT `query_files`.`qid`,`files`.`fid`,`name` FROM files \
+                   LEFT JOIN `query_files` ON files.fid = query_files.fid ";
+        let map = |row: &Row<'_>| {
+            Ok((
+                row.get::<_, Option<i64>>(0)?.map(|v| v as u64),
+                get_id(row, 1)?,
+                row.get(2)?,
+            ))
+        };
+        let rows = match del_type {
+            DeleteRequestType::AgedMin(x) => self.query(
+                &format!("{} WHERE `valid` = 1 AND `created` < ?", sql),
+                params![local_now() - Duration::minutes(*x as i64)],
+                map,
+            )?,
+            DeleteRequestType::Marked => self.query(
+                &format!(
+                    "{} WHERE `valid` = 1 AND (files.`delete` = 1 OR query_files.`delete` = 1)",
+                    sql
+                ),
+                NO_PARAMS,
+                map,
+            )?,
+        };
+        Ok(split_deletions(rows))
+    }
+
+    fn get_user_usage(&self, uid: &u32) -> Result<u64> {
+        let usage: i64 = self.conn()?.query_row(
+            "SELECT COALESCE(SUM(`size`),0) FROM `files` \
+             WHERE `valid` = 1 AND `fid` IN ( \
+             SELECT `query_files`.`fid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `queries`.`uid` = ?)",
+            params![uid],
+            |row| row.get(0),
+        )?;
+        Ok(usage as u64)
+    }
+
+    fn get_total_usage(&self) -> Result<u64> {
+        let usage: i64 = self.conn()?.query_row(
+            "SELECT COALESCE(SUM(`size`),0) FROM `files` WHERE `valid` = 1",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        Ok(usage as u64)
+    }
+
+    fn get_user_files_oldest(&self, uid: &u32) -> Result<Vec<(u64, String, u64)>> {
+        self.query(
+            "SELECT `fid`,`name`,COALESCE(`size`,0) FROM `files` \
+             WHERE `valid` = 1 AND `fid` IN ( \
+             SELECT `query_files`.`fid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `queries`.`uid` = ?1) \
+             AND `fid` NOT IN ( \
+             SELECT `query_files`.`fid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `queries`.`uid` != ?1) \
+             ORDER BY `created` ASC",
+            params![uid],
+            |row| Ok((get_id(row, 0)?, row.get(1)?, get_id(row, 2)?)),
+        )
+    }
+
+    fn get_file_names(&self) -> Result<Vec<String>> {
+        self.query("SELECT `name` FROM `files`", NO_PARAMS, |row| row.get(0))
+    }
+
+    fn file_name_exists(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .query_first(
+                "SELECT `fid` FROM `files` WHERE `name` = ? LIMIT 1",
+                params![name],
+                |row| get_id(row, 0),
+            )?
+            .is_some())
+    }
+
+    fn get_valid_files(&self) -> Result<Vec<(u64, String)>> {
+        self.query(
+            "SELECT `fid`,`name` FROM `files` WHERE `valid` = 1",
+            NO_PARAMS,
+            |row| Ok((get_id(row, 0)?, row.get(1)?)),
+        )
+    }
+
+    fn get_user_files(&self, uid: &u32) -> Result<Vec<FileInfo>> {
+        self.query(
+            "SELECT `fid`,`name`,`rname`,`size`,`valid`,`delete`,`created` FROM `files` \
+             WHERE `fid` IN ( \
+             SELECT `query_files`.`fid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `queries`.`uid` = ?) \
+             ORDER BY `created` DESC, `fid` DESC",
+            params![uid],
+            |row| {
+                Ok(FileInfo {
+                    fid: get_id(row, 0)?,
+                    name: row.get(1)?,
+                    real_name: row.get(2)?,
+                    size: row.get::<_, Option<i64>>(3)?.map(|v| v as u64),
+                    valid: row.get(4)?,
+                    delete: row.get(5)?,
+                    created: row.get(6)?,
+                })
+            },
+        )
+    }
+
+    fn get_file(&self, fid: &u64) -> Result<Option<FileInfo>> {
+        self.query_first(
+            "SELECT `fid`,`name`,`rname`,`size`,`valid`,`delete`,`created` FROM `files` \
+             WHERE `fid` = ?",
+            params![id(fid)],
+            |row| {
+                Ok(FileInfo {
+                    fid: get_id(row, 0)?,
+                    name: row.get(1)?,
+                    real_name: row.get(2)?,
+                    size: row.get::<_, Option<i64>>(3)?.map(|v| v as u64),
+                    valid: row.get(4)?,
+                    delete: row.get(5)?,
+                    created: row.get(6)?,
+                })
+            },
+        )
+    }
+
+    fn get_file_links(&self, fid: &u64) -> Result<Vec<(u64, u32)>> {
+        self.query(
+            "SELECT `queries`.`qid`,`queries`.`uid` FROM `query_files` \
+             JOIN `queries` ON `queries`.`qid` = `query_files`.`qid` WHERE `query_files`.`fid` = ?",
+            params![id(fid)],
+            |row| Ok((get_id(row, 0)?, row.get(1)?)),
+        )
+    }
+
+    fn get_query_files(&self, qid: &u64) -> Result<Vec<(String, String)>> {
+        self.query(
+            "SELECT `name`,`rname` FROM `files` \
+             JOIN `query_files` ON `files`.`fid` = `query_files`.`fid` \
+             WHERE `query_files`.`qid` = ? ORDER BY `files`.`fid`",
+            params![id(qid)],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    fn delete_requests(&self, qids: Vec<u64>, files: Vec<(u64, String)>) -> Result<()> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        {
+            let mut stmt = transaction.prepare("DELETE FROM files WHERE fid = ?")?;
+            for (fid, _) in files {
+                stmt.execute(params![id(&fid)])?;
+            }
+        }
+        for table in REQ_DB_TABLES.iter() {
+            let mut stmt = transaction.prepare(&format!("DELETE FROM {} WHERE qid = ?", table))?;
+            for qid in &qids {
+                stmt.execute(params![id(qid)])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn add_notify_target(
+        &self,
+        uid: Option<u32>,
+        qid: Option<u64>,
+        kind: &str,
+        target: &str,
+    ) -> Result<()> {
+        self.execute(
+            "INSERT INTO `notifytargets` (`uid`,`qid`,`kind`,`target`) VALUES(?,?,?,?)",
+            params![uid, qid.map(|v| v as i64), kind, target],
+        )?;
+        Ok(())
+    }
+
+    fn get_notify_targets(&self, qid: &u64, uid: &u32) -> Result<Vec<(String, String)>> {
+        self.query(
+            "SELECT DISTINCT `kind`,`target` FROM `notifytargets` WHERE `qid` = ? OR `uid` = ?",
+            params![id(qid), uid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    fn add_notification(&self, qid: &u64, kind: &str, target: &str, payload: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO `notifyoutbox` (`qid`,`kind`,`target`,`payload`,`next_try`) \
+             VALUES(?,?,?,?,?)",
+            params![id(qid), kind, target, payload, local_now()],
+        )?;
+        Ok(())
+    }
+
+    fn get_due_notifications(&self) -> Result<Vec<Notification>> {
+        self.query(
+            "SELECT `id`,`qid`,`kind`,`target`,`payload`,`attempts` FROM `notifyoutbox` \
+             WHERE `next_try` <= ? ORDER BY `id`",
+            params![local_now()],
+            |row| {
+                Ok(Notification {
+                    id: get_id(row, 0)?,
+                    qid: get_id(row, 1)?,
+                    kind: row.get(2)?,
+                    target: row.get(3)?,
+                    payload: row.get(4)?,
+                    attempts: row.get(5)?,
+                })
+            },
+        )
+    }
+
+    fn remove_notification(&self, id_: &u64) -> Result<()> {
+        self.execute(
+            "DELETE FROM `notifyoutbox` WHERE `id` = ?",
+            params![id(id_)],
+        )?;
+        Ok(())
+    }
+
+    fn retry_notification(&self, id_: &u64, delay_min: &u32) -> Result<()> {
+        self.execute(
+            "UPDATE `notifyoutbox` SET `attempts` = `attempts` + 1, `next_try` = ? WHERE `id` = ?",
+            params![local_now() + Duration::minutes(*delay_min as i64), id(id_)],
+        )?;
+        Ok(())
+    }
+
+    fn set_credential(&self, uid: &u32, name: &str, kind: &str, data: &str) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO `credentials` (`uid`,`name`,`kind`,`data`) VALUES(?,?,?,?)",
+            params![uid, name, kind, data],
+        )?;
+        Ok(())
+    }
+
+    fn get_credential(&self, uid: &u32, name: &str) -> Result<Option<(String, String)>> {
+        self.query_first(
+            "SELECT `kind`,`data` FROM `credentials` WHERE `uid` = ? AND `name` = ?",
+            params![uid, name],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    fn get_credential_names(&self, uid: &u32) -> Result<Vec<(String, String)>> {
+        self.query(
+            "SELECT `name`,`kind` FROM `credentials` WHERE `uid` = ? ORDER BY `name`",
+            params![uid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    fn delete_credential(&self, uid: &u32, name: &str) -> Result<bool> {
+        Ok(self.execute(
+            "DELETE FROM `credentials` WHERE `uid` = ? AND `name` = ?",
+            params![uid, name],
+        )? > 0)
+    }
+
+    fn set_query_credential(&self, qid: &u64, name: &str) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO `querycredentials` (`qid`,`name`) VALUES(?,?)",
+            params![id(qid), name],
+        )?;
+        Ok(())
+    }
+
+    fn get_query_credential(&self, qid: &u64) -> Result<Option<String>> {
+        self.query_first(
+            "SELECT `name` FROM `querycredentials` WHERE `qid` = ?",
+            params![id(qid)],
+            |row| row.get(0),
+        )
+    }
+
+    fn set_query_retry(&self, qid: &u64, origin: &u64) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO `queryretries` (`qid`,`origin`) VALUES(?,?)",
+            params![id(qid), id(origin)],
+        )?;
+        Ok(())
+    }
+
+    fn get_query_retry(&self, qid: &u64) -> Result<Option<u64>> {
+        self.query_first(
+            "SELECT `origin` FROM `queryretries` WHERE `qid` = ?",
+            params![id(qid)],
+            |row| get_id(row, 0),
+        )
+    }
+
+    fn get_pending_retries(&self, origin: &u64) -> Result<Vec<u64>> {
+        self.query(
+            "SELECT `queryretries`.`qid` FROM `queryretries` \
+             JOIN `querydetails` ON `querydetails`.`qid` = `queryretries`.`qid` \
+             WHERE `origin` = ? AND `code` IN (?,?,?)",
+            params![
+                id(origin),
+                CODE_WAITING,
+                crate::CODE_STARTED,
+                crate::CODE_IN_PROGRESS
+            ],
+            |row| get_id(row, 0),
+        )
+    }
+
+    fn set_ytdl_version(&self, host: &str, version: &str, channel: &str) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO `ytdlversions` (`host`,`version`,`channel`,`updated`) \
+             VALUES(?,?,?,?)",
+            params![host, version, channel, local_now()],
+        )?;
+        Ok(())
+    }
+
+    fn get_ytdl_version(&self, host: &str) -> Result<Option<(String, String)>> {
+        self.query_first(
+            "SELECT `version`,`channel` FROM `ytdlversions` WHERE `host` = ?",
+            params![host],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    fn add_entry_failure(&self, qid: &u64, failure: &EntryFailure) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO `playlistfailures` (`qid`,`idx`,`url`,`title`,`kind`,`msg`) \
+             VALUES(?,?,?,?,?,?)",
+            params![
+                id(qid),
+                failure.index,
+                failure.url,
+                failure.title,
+                failure.kind,
+                failure.message
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_entry_failures(&self, qid: &u64) -> Result<Vec<EntryFailure>> {
+        self.query(
+            "SELECT `idx`,`url`,`title`,`kind`,`msg` FROM `playlistfailures` \
+             WHERE `qid` = ? ORDER BY `idx`",
+            params![id(qid)],
+            |row| {
+                Ok(EntryFailure {
+                    index: row.get(0)?,
+                    url: row.get(1)?,
+                    title: row.get(2)?,
+                    kind: row.get(3)?,
+                    message: row.get(4)?,
+                })
+            },
+        )
+    }
+
+    fn clear_entry_failures(&self, qid: &u64) -> Result<()> {
+        self.execute(
+            "DELETE FROM `playlistfailures` WHERE `qid` = ?",
+            params![id(qid)],
+        )?;
+        Ok(())
+    }
+
+    fn migrate(&self) -> Result<()> {
+        self.conn()?
+            .execute_batch(include_str!("../../../setup_sqlite.sql"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lib::db::suite;
+
+    #[test]
+    fn suite_test() {
+        let db = SqliteRepository::open_in_memory().unwrap();
+        suite::run(&db);
+    }
+
+    #[test]
+    fn reopen_test() {
+        let path = std::env::temp_dir().join("yayd_sqlite_test.db");
+        let _ = std::fs::remove_file(&path);
+        let qid = {
+            let db = SqliteRepository::open(&path).unwrap();
+            db.add_query("test.com", &1, &1, &-2, None).unwrap()
+        };
+        // tables are kept on reopening
+        let db = SqliteRepository::open(&path).unwrap();
+        assert_eq!(db.next_query().unwrap().unwrap().qid, qid);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn migrate_test() {
+        let db = SqliteRepository::open_in_memory().unwrap();
+        let qid = db.add_query("test.com", &1, &1, &-2, None).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.next_query().unwrap().unwrap().qid, qid);
+    }
+}
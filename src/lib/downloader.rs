@@ -1,33 +1,55 @@
 extern crate regex;
-use mysql::prelude::Queryable;
-use mysql::PooledConn;
-use mysql::Statement;
 
 use std::convert::Into;
+use std::fs::{
+    copy, create_dir_all, metadata, read_dir, read_to_string, remove_file, rename, OpenOptions,
+};
 use std::io::prelude::*;
 use std::io::BufReader;
 #[cfg(not(target_os = "windows"))]
 use std::os::unix::prelude::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Stdio};
+use std::process::{Command, Stdio};
 
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
-use crate::lib::config::ConfigGen;
-use crate::lib::db::prep_progress_updater;
+use crate::lib::bandwidth;
+use crate::lib::config::{ConfigGen, YtdlChannel};
+use crate::lib::db::Repository;
+use crate::lib::failure::Failure;
+use crate::lib::metrics;
+use crate::lib::process::{self, Process, Runner, SystemRunner, Timeouts, Watchdog};
+use crate::lib::proxy;
 use crate::lib::Request;
 use crate::lib::{Error, Result};
+use crate::CONFIG;
 
 use crate::lib;
 
-const UPDATE_VERSION_URL: &'static str =
-    "https://api.github.com/repos/yt-dlp/yt-dlp/releases/latest"; // youtube-dl version check url
-const UPDATE_SHA256_FILE: &'static str = "SHA2-256SUMS";
+const UPDATE_API_URL: &str = "https://api.github.com/repos"; // release lookup url
+const UPDATE_STABLE_REPO: &str = "yt-dlp/yt-dlp";
+const UPDATE_NIGHTLY_REPO: &str = "yt-dlp/yt-dlp-nightly-builds";
+const UPDATE_SHA256_FILE: &str = "SHA2-256SUMS";
 #[cfg(not(target_os = "windows"))]
-const YTDL_NAME: &'static str = "yt-dlp"; // name of the python program file
+const YTDL_NAME: &str = "yt-dlp"; // name of the python program file
 #[cfg(target_os = "windows")]
-const YTDL_NAME: &'static str = "yt-dlp.exe"; // name of the python program file
-const UPDATE_ASSET_NAME: &'static str = YTDL_NAME;
+const YTDL_NAME: &str = "yt-dlp.exe"; // name of the python program file
+const UPDATE_ASSET_NAME: &str = YTDL_NAME;
+/// Folder of the kept previous binaries, inside youtube_dl_dir
+const KEPT_DIR: &str = "versions";
+/// File next to `KEPT_DIR` listing the versions which failed their smoke test, one per line
+const REJECTED_FILE: &str = "rejected";
+/// Channel recorded for pinned versions
+const CHANNEL_PINNED: &str = "pinned";
+/// Proxy pool used by the smoke test
+const SMOKE_PROXY_HANDLER: &str = "youtube";
+/// yt-dl errors of failed proxy connections
+const PROXY_ERRORS: [&str; 4] = [
+    "ProxyError",
+    "Unable to connect to proxy",
+    "Cannot connect to proxy",
+    "Tunnel connection failed",
+];
 
 macro_rules! regex(
     ($s:expr) => (regex::Regex::new($s).unwrap());
@@ -51,11 +73,17 @@ pub struct Downloader {
     defaults: &'static ConfigGen,
     lock: RwLock<()>,
     cmd_path: PathBuf,
+    runner: Arc<dyn Runner>,
+    timeouts: Timeouts,
+    /// DB recording the active version
+    db: Option<Arc<dyn Repository>>,
 }
 
 #[derive(Deserialize)]
 pub struct PlaylistEntry {
-    pub url: String
+    pub url: String,
+    #[serde(default)]
+    pub title: Option<String>,
 }
 
 /// Filename and extension storage
@@ -68,17 +96,48 @@ impl Filename {
     pub fn full_name(&self) -> String {
         format!("{}.{}", &self.name, &self.extension)
     }
+
+    /// Split up the file name of a path into name & extension
+    pub fn from_path(path: &Path) -> Result<Filename> {
+        let file_name = path
+            .file_name()
+            .ok_or(Error::InternalError(format!("no file name: {:?}", path)))?
+            .to_string_lossy();
+        match REGEX_NAME.captures(&file_name) {
+            Some(caps) => Ok(Filename {
+                name: caps[1].to_string(),
+                extension: caps[2].to_string(),
+            }),
+            None => Err(Error::InternalError(format!(
+                "no name match! {}",
+                file_name
+            ))),
+        }
+    }
 }
 
 impl Downloader {
     pub fn new(defaults: &'static ConfigGen) -> Downloader {
+        Downloader::with_runner(defaults, Arc::new(SystemRunner))
+    }
+
+    /// Create a downloader spawning its processes via `runner`
+    pub fn with_runner(defaults: &'static ConfigGen, runner: Arc<dyn Runner>) -> Downloader {
         Downloader {
             defaults: defaults,
             lock: RwLock::new(()),
             cmd_path: PathBuf::from(&defaults.youtube_dl_dir),
+            runner: runner,
+            timeouts: Timeouts::from_config(&CONFIG.timeouts),
+            db: None,
         }
     }
 
+    /// Record the active yt-dl version of this host in `db`
+    pub fn set_db(&mut self, db: Arc<dyn Repository>) {
+        self.db = Some(db);
+    }
+
     /// Run a self-test checking for either yt-dl binaries or update failure
     /// depending on the config
     /// Returns true on success
@@ -103,7 +162,10 @@ impl Downloader {
             }
         } else {
             match self.version() {
-                Ok(_) => true,
+                Ok(v) => {
+                    self.set_active_version(&v);
+                    true
+                }
                 Err(e) => {
                     error!("Failed retrieving version of yt-dl {:?}", e);
                     false
@@ -116,9 +178,9 @@ impl Downloader {
     /// Does not check for the guard!
     pub fn version(&self) -> Result<String> {
         trace!("Checking own version");
-        let result = self.ytdl_base().arg("--version").output()?;
-        if result.status.success() {
-            Ok(String::from_utf8_lossy(&result.stdout).trim().to_string())
+        let result = self.runner.output(self.ytdl_base().arg("--version"))?;
+        if result.success {
+            Ok(result.stdout.trim().to_string())
         } else {
             Err(Error::InternalError("Process errored".into()))
         }
@@ -128,17 +190,25 @@ impl Downloader {
     /// file_path specifies the download location.
     /// DMCA errors will get thrown.
     /// download_audio option: ignore the specified quality & download CONFIG.codecs.yt.audio_normal quality for split containers
-    fn download_file_in(&self, request: &Request, file_path: &Path, quality: &str) -> Result<bool> {
+    fn download_file_in(
+        &self,
+        request: &Request,
+        file_path: &Path,
+        quality: &str,
+        proxy: Option<&str>,
+    ) -> Result<bool> {
         trace!("{:?}", request.url);
 
         trace!("quality: {}", quality);
-        let mut child = self.run_download_process(file_path, &request.url, quality)?;
-        let stdout = BufReader::new(child.stdout.take().unwrap());
+        // share of the bandwidth budget, held until the download finished
+        let lease = bandwidth::acquire();
+        let rate = lease.rate_arg();
+        let mut child =
+            self.run_download_process(request, file_path, quality, proxy, rate.as_deref())?;
+        let watchdog = Watchdog::start(&*child, "download", self.timeouts.download);
+        let stdout = BufReader::new(child.take_stdout().unwrap());
 
-        let mut stderr_buffer = BufReader::new(child.stderr.take().unwrap());
-
-        let mut conn = request.get_conn();
-        let statement = prep_progress_updater(&mut conn)?;
+        let mut stderr_buffer = BufReader::new(child.take_stderr().unwrap());
 
         for line in stdout.lines() {
             match line {
@@ -152,10 +222,10 @@ impl Downloader {
                         Some(cap) => {
                             //println!("Match at {}", s.0);
                             debug!("{}", cap.get(1).unwrap().as_str()); // ONLY with ASCII chars makeable!
+                            watchdog.progress();
                             self.update_progress(
                                 &request.qid,
-                                &mut conn,
-                                &statement,
+                                request.db(),
                                 cap.get(1).unwrap().as_str(),
                             )?;
                         }
@@ -165,17 +235,27 @@ impl Downloader {
             }
         }
 
-        child.wait()?; // waits for finish & then exists zombi process, fixes #10
+        let success = child.wait()?; // waits for finish & then exists zombi process, fixes #10
+        watchdog.finish()?;
 
         let mut stderr: String = String::new();
         stderr_buffer.read_to_string(&mut stderr)?;
 
-        if stderr.is_empty() {
+        if stderr.is_empty() && success {
             Ok(true)
+        } else if stderr.is_empty() {
+            // killed or crashed without any output
+            Err(Error::DownloadError(String::from(
+                "yt-dl exited unsuccessfully",
+            )))
+        } else if is_proxy_error(&stderr) {
+            Err(Error::ProxyError(stderr))
         } else if stderr.contains("requested format not available") {
             Err(Error::QualityNotAvailable)
         } else if stderr.contains("ExtractorError") {
             Err(Error::ExtractorError)
+        } else if let Some(kind) = Failure::classify(&stderr) {
+            Err(Error::Unavailable(kind, stderr))
         } else {
             warn!("Unknown error at download");
             Err(Error::InternalError(stderr))
@@ -190,9 +270,16 @@ impl Downloader {
         quality: &str,
     ) -> Result<bool> {
         let _guard = self.lock.read()?;
+        let _timer = metrics::stage_timer(metrics::STAGE_DOWNLOAD);
+        let pool = proxy::pool(request.handler);
         for attempts in 0..2 {
-            match self.download_file_in(&request, file_path, quality) {
-                Ok(v) => return Ok(v),
+            match pool.run(|proxy| self.download_file_in(request, file_path, quality, proxy)) {
+                Ok(v) => {
+                    if let Ok(meta) = metadata(file_path) {
+                        metrics::add_downloaded_bytes(meta.len());
+                    }
+                    return Ok(v);
+                }
                 Err(e) => match e {
                     Error::ExtractorError => warn!("download try no {}", attempts),
                     _ => return Err(e),
@@ -205,55 +292,111 @@ impl Downloader {
     /// Trys to get the original name of a file, while checking for availability
     /// As an ExtractError can appear randomly, bug 11, we're retrying again 2 times if it should occour
     /// Through specifying a quality it's possible to get extension specific for the format.
-    pub fn get_file_name(&self, url: &str, quality: Option<String>) -> Result<Filename> {
+    pub fn get_file_name(&self, request: &Request, quality: Option<String>) -> Result<Filename> {
         let _guard = self.lock.read()?;
+        let pool = proxy::pool(request.handler);
         for attempts in 0..2 {
-            let mut child = self.run_filename_process(url, quality.as_ref())?;
-            let mut stdout_buffer = BufReader::new(child.stdout.take().unwrap());
-            let mut stderr_buffer = BufReader::new(child.stderr.take().unwrap());
-
-            let mut stdout: String = String::new();
-            stdout_buffer.read_to_string(&mut stdout)?;
-            let mut stderr: String = String::new();
-            stderr_buffer.read_to_string(&mut stderr)?;
-
-            child.wait()?;
-            let capture = REGEX_NAME.captures(&stdout.trim());
-            if stderr.is_empty() && capture.is_some() {
-                let caps = capture.unwrap();
-                debug!("get_file_name: {:?}", stdout);
-                return Ok(Filename {
-                    name: caps[1].to_string(),
-                    extension: caps[2].to_string(),
-                });
-            } else {
-                if stderr.contains("not available in your country")
-                    || stderr.contains("contains content from")
-                    || stderr.contains("This video is available in")
-                {
-                    return Err(Error::DMCAError);
-                } else if stderr.contains("Please sign in to view this video") {
-                    return Err(Error::NotAvailable);
-                } else if stderr.contains("ExtractorError") {
-                    // #11
-                    info!("ExtractorError on attempt {}", attempts + 1);
-                } else {
-                    return Err(Error::DownloadError(stderr));
-                }
+            match pool.run(|proxy| self.get_file_name_in(request, quality.as_ref(), proxy)) {
+                // #11
+                Err(Error::ExtractorError) => info!("ExtractorError on attempt {}", attempts + 1),
+                v => return v,
             }
         }
         Err(Error::ExtractorError)
     }
 
+    /// Single file name request, see get_file_name
+    fn get_file_name_in(
+        &self,
+        request: &Request,
+        quality: Option<&String>,
+        proxy: Option<&str>,
+    ) -> Result<Filename> {
+        let mut child = self.run_filename_process(request, quality, proxy)?;
+        let watchdog = Watchdog::start(&*child, "file name request", self.timeouts.probe);
+        let mut stdout_buffer = BufReader::new(child.take_stdout().unwrap());
+        let mut stderr_buffer = BufReader::new(child.take_stderr().unwrap());
+
+        let mut stdout: String = String::new();
+        stdout_buffer.read_to_string(&mut stdout)?;
+        let mut stderr: String = String::new();
+        stderr_buffer.read_to_string(&mut stderr)?;
+
+        child.wait()?;
+        watchdog.finish()?;
+        let capture = REGEX_NAME
+            .captures(stdout.trim())
+            .filter(|_| stderr.is_empty());
+        if let Some(caps) = capture {
+            debug!("get_file_name: {:?}", stdout);
+            Ok(Filename {
+                name: caps[1].to_string(),
+                extension: caps[2].to_string(),
+            })
+        } else if is_proxy_error(&stderr) {
+            Err(Error::ProxyError(stderr))
+        } else {
+            match Failure::classify(&stderr) {
+                // could be bypassed by the lib
+                Some(Failure::GeoBlocked) | Some(Failure::Copyright) => {
+                    Err(Error::DMCAError(stderr))
+                }
+                Some(kind) => Err(Error::Unavailable(kind, stderr)),
+                None if stderr.contains("ExtractorError") => Err(Error::ExtractorError),
+                None => Err(Error::DownloadError(stderr)),
+            }
+        }
+    }
+
+    /// Returns the estimated size in bytes of the requested format, if known by yt-dl
+    pub fn get_size_estimate(
+        &self,
+        request: &Request,
+        quality: Option<String>,
+    ) -> Result<Option<u64>> {
+        let _guard = self.lock.read()?;
+        let output = proxy::pool(request.handler).run(|proxy| {
+            let mut cmd = self.ytdl_cmd(request, proxy);
+            cmd.arg("-s")
+                .arg("--no-warnings")
+                .args(["--print", "%(filesize,filesize_approx)s"]);
+            if let Some(ref quality) = quality {
+                cmd.args(["-f", quality]);
+            }
+            let output = process::output_limited(
+                &*self.runner,
+                cmd.arg(&request.url),
+                "size estimate",
+                self.timeouts.probe,
+            )?;
+            if !output.success && is_proxy_error(&output.stderr) {
+                return Err(Error::ProxyError(output.stderr));
+            }
+            Ok(output)
+        })?;
+        trace!("size estimate: {}", output.stdout);
+        Ok(output.stdout.trim().parse::<u64>().ok())
+    }
+
     /// Gets the playlist ids needed for furture download requests.
     /// The output is a vector of IDs
     pub fn get_playlist_entries(&self, request: &Request) -> Result<Vec<PlaylistEntry>> {
-
         let _guard = self.lock.read()?;
-        let mut child = self.run_playlist_extract(request)?;
+        proxy::pool(request.handler).run(|proxy| self.get_playlist_entries_in(request, proxy))
+    }
+
+    /// Single playlist extraction, see get_playlist_entries
+    fn get_playlist_entries_in(
+        &self,
+        request: &Request,
+        proxy: Option<&str>,
+    ) -> Result<Vec<PlaylistEntry>> {
+        let mut child = self.run_playlist_extract(request, proxy)?;
+        // every entry counts as progress, large playlists take a while
+        let watchdog = Watchdog::start(&*child, "playlist extraction", self.timeouts.download);
         trace!("retrieving playlist ids");
-        let stdout = BufReader::new(child.stdout.take().unwrap());
-        let mut stderr_buffer = BufReader::new(child.stderr.take().unwrap());
+        let stdout = BufReader::new(child.take_stdout().unwrap());
+        let mut stderr_buffer = BufReader::new(child.take_stderr().unwrap());
 
         let re = regex!(r#""url": "([a-zA-Z0-9_-]+)""#);
 
@@ -264,18 +407,17 @@ impl Downloader {
                     error!("couldn't read cmd stdout: {}", why);
                     panic!();
                 }
-                Ok(text) => {
-                    match serde_json::from_str::<PlaylistEntry>(&text) {
-                        Ok(entry) => {
-                            trace!("Found list entry url {}",entry.url);
-                            entries.push(entry);
-                        },
-                        Err(e) => {
-                            warn!("Failed to parse playlist-extractor line: {}",e);
-                            warn!("Reponse from ytdl: {}", text);
-                        },
+                Ok(text) => match serde_json::from_str::<PlaylistEntry>(&text) {
+                    Ok(entry) => {
+                        trace!("Found list entry url {}", entry.url);
+                        watchdog.progress();
+                        entries.push(entry);
                     }
-                }
+                    Err(e) => {
+                        warn!("Failed to parse playlist-extractor line: {}", e);
+                        warn!("Reponse from ytdl: {}", text);
+                    }
+                },
             }
         }
 
@@ -283,20 +425,30 @@ impl Downloader {
         stderr_buffer.read_to_string(&mut stderr)?;
 
         child.wait()?;
+        watchdog.finish()?;
 
-        if !stderr.is_empty() {
+        if is_proxy_error(&stderr) {
+            return Err(Error::ProxyError(stderr));
+        } else if !stderr.is_empty() {
             warn!("stderr: {:?}", stderr);
-            return Err(Error::InternalError(stderr));
+            return Err(classified(stderr));
         }
 
         Ok(entries)
     }
 
     /// Retrives the playlist name, will kill the process due to yt-dl starting detailed retrieval afterwards.
-    pub fn get_playlist_name(&self, url: &str) -> Result<String> {
+    pub fn get_playlist_name(&self, request: &Request) -> Result<String> {
         let _guard = self.lock.read()?;
-        let mut child = self.run_playlist_get_name(url)?;
-        let stdout = BufReader::new(child.stdout.take().unwrap());
+        proxy::pool(request.handler).run(|proxy| self.get_playlist_name_in(request, proxy))
+    }
+
+    /// Single playlist name request, see get_playlist_name
+    fn get_playlist_name_in(&self, request: &Request, proxy: Option<&str>) -> Result<String> {
+        let mut child = self.run_playlist_get_name(request, proxy)?;
+        let watchdog = Watchdog::start(&*child, "playlist name request", self.timeouts.probe);
+        let stdout = BufReader::new(child.take_stdout().unwrap());
+        let mut stderr_buffer = BufReader::new(child.take_stderr().unwrap());
 
         let re = regex!(r"\[download\] Downloading playlist: (.*)");
 
@@ -314,6 +466,7 @@ impl Downloader {
                             trace!("{}", cap.get(1).unwrap().as_str()); // ONLY with ASCII chars makeable!
                             name = cap.get(1).unwrap().as_str().to_string();
                             child.wait()?;
+                            watchdog.finish()?;
                             trace!("done");
                             return Ok(name);
                         }
@@ -323,8 +476,17 @@ impl Downloader {
             }
         }
 
+        let mut stderr: String = String::new();
+        stderr_buffer.read_to_string(&mut stderr)?;
+
         child.wait()?; // waits for finish & then exists zombi process fixes #10
+        watchdog.finish()?;
 
+        if is_proxy_error(&stderr) {
+            return Err(Error::ProxyError(stderr));
+        } else if let Some(kind) = Failure::classify(&stderr) {
+            return Err(Error::Unavailable(kind, stderr));
+        }
         Err(Error::DownloadError("no playlist name".to_string()))
     }
 
@@ -342,18 +504,26 @@ impl Downloader {
         get_video: bool,
     ) -> Result<Filename> {
         let _guard = self.lock.read()?;
-        let mut child = self.lib_request_video_cmd(&request.url, file_path, quality, get_video)?;
+        let _timer = metrics::stage_timer(metrics::STAGE_DOWNLOAD);
+        let lease = bandwidth::acquire();
+        let rate = lease.rate_arg();
+        let mut child = self.lib_request_video_cmd(
+            &request.url,
+            file_path,
+            quality,
+            get_video,
+            rate.as_deref(),
+        )?;
+        let watchdog = Watchdog::start(&*child, "lib download", self.timeouts.download);
         trace!("Requesting video via lib..");
         let stdout = BufReader::new(
             child
-                .stdout
-                .take()
+                .take_stdout()
                 .ok_or(Error::InternalError("stdout socket error!".into()))?,
         );
         let mut stderr_buffer = BufReader::new(
             child
-                .stderr
-                .take()
+                .take_stderr()
                 .ok_or(Error::InternalError("stderr socket error".into()))?,
         );
 
@@ -371,9 +541,9 @@ impl Downloader {
                     match re.captures(&text) {
                         Some(cap) => {
                             debug!("Match: {}", cap.get(1).unwrap().as_str()); // ONLY with ASCII chars makeable!
+                            watchdog.progress();
                             if !request.playlist {
-                                lib::db::update_steps(
-                                    &mut request.get_conn(),
+                                request.db().update_steps(
                                     &request.qid,
                                     current_steps
                                         + &cap.get(1).unwrap().as_str().parse::<i32>().unwrap(),
@@ -393,10 +563,14 @@ impl Downloader {
         stderr_buffer.read_to_string(&mut stderr)?;
 
         child.wait()?;
+        watchdog.finish()?;
 
         if !stderr.is_empty() {
             warn!("stderr: {:?}", stderr);
-            return Err(Error::InternalError(stderr));
+            return Err(classified(stderr));
+        }
+        if let Ok(meta) = metadata(file_path) {
+            metrics::add_downloaded_bytes(meta.len());
         }
         //this ONLY works because `filename: ` is ASCII..
         let mut out = last_line[last_line.find("filename: ").unwrap() + 9..]
@@ -420,81 +594,114 @@ impl Downloader {
         cmd
     }
 
-    /// Formats the download command.
-    fn run_download_process(&self, file_path: &Path, url: &str, quality: &str) -> Result<Child> {
-        Ok(self
-            .ytdl_base()
-            .arg("--newline")
-            .arg("--no-warnings")
-            .args(&["-r", &format!("{}M", self.defaults.download_mbps / 8)]) // yt-dl uses MB/s, we're using MBit/s
-            .args(&["-f", &quality.to_string()])
-            .arg("-o")
-            .arg(file_path)
-            .arg("--hls-prefer-native") // this is needed for twitch extraction
-            .args(&["--ffmpeg-location", &self.defaults.ffmpeg_bin_dir]) // backup if internal converter fails
-            .arg(url)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?)
+    /// Provides the base of the youtube-dl command for a request, connecting via `proxy`
+    fn ytdl_cmd(&self, request: &Request, proxy: Option<&str>) -> Command {
+        let mut cmd = self.ytdl_base();
+        cmd.args(&request.ytdl_args);
+        if let Some(proxy) = proxy {
+            cmd.args(["--proxy", proxy]);
+        }
+        cmd
+    }
+
+    /// Formats the download command, `rate` is the yt-dl rate limit, None for unlimited
+    fn run_download_process(
+        &self,
+        request: &Request,
+        file_path: &Path,
+        quality: &str,
+        proxy: Option<&str>,
+        rate: Option<&str>,
+    ) -> Result<Box<dyn Process>> {
+        let mut cmd = self.ytdl_cmd(request, proxy);
+        if let Some(rate) = rate {
+            cmd.args(["-r", rate]);
+        }
+        self.runner.spawn(
+            cmd.arg("--newline")
+                .arg("--no-warnings")
+                .args(["-f", &quality.to_string()])
+                .arg("-o")
+                .arg(file_path)
+                .arg("--hls-prefer-native") // this is needed for twitch extraction
+                .args(["--ffmpeg-location", &self.defaults.ffmpeg_bin_dir]) // backup if internal converter fails
+                .arg(&request.url)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )
     }
 
     /// Runs the filename retrival process.
-    fn run_filename_process(&self, url: &str, quality: Option<&String>) -> Result<Child> {
-        let mut cmd = self.ytdl_base();
+    fn run_filename_process(
+        &self,
+        request: &Request,
+        quality: Option<&String>,
+        proxy: Option<&str>,
+    ) -> Result<Box<dyn Process>> {
+        let mut cmd = self.ytdl_cmd(request, proxy);
         cmd.arg("--get-filename")
             .arg("--no-warnings")
-            .args(&["-o", "%(title)s.%(ext)s"]);
+            .args(["-o", "%(title)s.%(ext)s"]);
         if quality.is_some() {
-            cmd.args(&["-f", &quality.unwrap()]);
+            cmd.args(["-f", &quality.unwrap()]);
         }
-        Ok(cmd
-            .arg(url)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?)
+        self.runner.spawn(
+            cmd.arg(&request.url)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )
     }
 
     /// Generate the lib command.
-    /// binary [args] -q {quality} -r {rate} -f {file} -v {true/false} {url}
+    /// binary [args] -q {quality} [-r {rate}] -f {file} -v {true/false} {url}
+    /// `rate` uses the yt-dl format, None for unlimited
     fn lib_request_video_cmd(
         &self,
         url: &str,
         file_path: &Path,
         quality: &str,
         get_video: bool,
-    ) -> Result<Child> {
+        rate: Option<&str>,
+    ) -> Result<Box<dyn Process>> {
         let java_path = Path::new(&self.defaults.lib_dir);
 
         debug!(
-            "{} {:?} -q {} -r {}M -f {} -v {} {}",
+            "{} {:?} -q {} -r {:?} -f {} -v {} {}",
             self.defaults.lib_bin,
             self.defaults.lib_args,
             quality,
-            self.defaults.download_mbps,
+            rate,
             file_path.to_string_lossy(),
             get_video,
             url
         );
-        Ok(Command::new(&self.defaults.lib_bin)
-            .current_dir(&java_path)
+        let mut cmd = Command::new(&self.defaults.lib_bin);
+        cmd.current_dir(&java_path)
             .args(&self.defaults.lib_args)
-            .args(&["-q", quality])
-            .args(&["-r", &format!("{}M", self.defaults.download_mbps)])
-            .arg("-f")
-            .arg(file_path)
-            .args(&["-v", &(get_video).to_string()])
-            .arg(&url)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?)
+            .args(["-q", quality]);
+        if let Some(rate) = rate {
+            cmd.args(["-r", rate]);
+        }
+        self.runner.spawn(
+            cmd.arg("-f")
+                .arg(file_path)
+                .args(["-v", &(get_video).to_string()])
+                .arg(&url)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )
     }
 
     /// Runs the playlist extraction process.
-    fn run_playlist_extract(&self, request: &Request) -> Result<Child> {
-        let mut cmd = self.ytdl_base();
+    fn run_playlist_extract(
+        &self,
+        request: &Request,
+        proxy: Option<&str>,
+    ) -> Result<Box<dyn Process>> {
+        let mut cmd = self.ytdl_cmd(request, proxy);
         cmd.arg("-s")
             .arg("--dump-json")
             .arg("--flat-playlist")
@@ -507,44 +714,56 @@ impl Downloader {
             cmd.arg("--playlist-end");
             cmd.arg(request.to.to_string());
         }
-        Ok(cmd
-            .arg(&request.url)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?)
+        self.runner.spawn(
+            cmd.arg(&request.url)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )
     }
 
     /// Runs the playlist name retrival process.
-    fn run_playlist_get_name(&self, url: &str) -> Result<Child> {
-        Ok(self
-            .ytdl_base()
-            .arg("-s")
-            .arg("--no-warnings")
-            .args(&["--playlist-start", "1"])
-            .args(&["--playlist-end", "1"])
-            .arg(url)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .spawn()?)
+    fn run_playlist_get_name(
+        &self,
+        request: &Request,
+        proxy: Option<&str>,
+    ) -> Result<Box<dyn Process>> {
+        self.runner.spawn(
+            self.ytdl_cmd(request, proxy)
+                .arg("-s")
+                .arg("--no-warnings")
+                .args(["--playlist-start", "1"])
+                .args(["--playlist-end", "1"])
+                .arg(&request.url)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )
     }
 
-    /// Executes the progress update statement.
-    fn update_progress(
-        &self,
-        qid: &u64,
-        conn: &mut PooledConn,
-        stmt: &Statement,
-        progress: &str,
-    ) -> Result<()> {
-        conn.exec_drop(stmt, (progress, qid))?;
-        Ok(())
-        //-> only return errors, ignore the return value of stmt.execute
+    /// Stores the progress percentage printed by yt-dl.
+    fn update_progress(&self, qid: &u64, db: &dyn Repository, progress: &str) -> Result<()> {
+        match progress.parse::<f64>() {
+            Ok(v) => db.set_progress(qid, v),
+            Err(_) => {
+                debug!("invalid progress value {}", progress);
+                Ok(())
+            }
+        }
     }
 
-    /// Returns the latest upstream version number and sha256
-    pub fn get_latest_version() -> Result<Version> {
-        let release: GHRelease = lib::http::http_json_get(UPDATE_VERSION_URL)?;
+    /// Returns version number, sha256 & download url of a release
+    /// `tag` selects a release by version, None the latest of the channel
+    pub fn get_release(channel: YtdlChannel, tag: Option<&str>) -> Result<Version> {
+        let repo = match channel {
+            YtdlChannel::Stable => UPDATE_STABLE_REPO,
+            YtdlChannel::Nightly => UPDATE_NIGHTLY_REPO,
+        };
+        let url = match tag {
+            Some(tag) => format!("{}/{}/releases/tags/{}", UPDATE_API_URL, repo, tag),
+            None => format!("{}/{}/releases/latest", UPDATE_API_URL, repo),
+        };
+        let release: GHRelease = lib::http::http_json_get(&url)?;
         let version = release.tag_name;
         let hashes = {
             let sha256_asset = match release.assets.iter().find(|v| v.name == UPDATE_SHA256_FILE) {
@@ -571,41 +790,107 @@ impl Downloader {
     }
 
     /// Update youtube-dl
-    /// Check for version, download update and check for sha2
+    /// Installs the pinned version or the latest of the channel, verifying it via smoke test.
+    /// Failing versions are rolled back to the previous binary & never installed again.
     /// W-Lcok
     pub fn update_downloader(&self) -> Result<()> {
-        use std::fs::{remove_file, rename};
-
-        let guard_ = self.lock.write()?;
-        // check for existence of lib
-        let download_file = self.ytdl_path();
-        let backup_file = self.cmd_path.join("ytdl_backup");
-        let r_version = Downloader::get_latest_version()?;
-        debug!("Latest version: {}", r_version.version);
-        if download_file.exists() {
-            let version = self.version()?;
-            debug!("Current version: {}", version);
-            if version != r_version.version {
-                match self.inner_update(&download_file, &r_version) {
-                    Ok(_) => {}
-                    Err(v) => {
-                        // rollback to old version
-                        info!("Update failed, doing rollback");
-                        if download_file.exists() {
-                            remove_file(&download_file)?;
-                        }
-                        rename(&backup_file, &download_file)?;
-                        return Err(v);
-                    }
-                }
-            } else {
-                trace!("equal version");
+        let result = self.update_downloader_in();
+        metrics::ytdl_updated(result.is_ok());
+        if let Ok(version) = self.version() {
+            self.set_active_version(&version);
+        }
+        result
+    }
+
+    /// Update youtube-dl, see update_downloader
+    fn update_downloader_in(&self) -> Result<()> {
+        let _guard = self.lock.write()?;
+        let binary = self.ytdl_path();
+        // a broken binary is replaced without keeping it
+        let current = match binary.exists() {
+            true => self
+                .version()
+                .map_err(|e| warn!("Unable to read the installed yt-dl version: {:?}", e))
+                .ok(),
+            false => None,
+        };
+        // pinned versions kept on disk don't require a release lookup
+        let (version, release) = match self.pinned() {
+            Some(v) => (v.to_string(), None),
+            None => {
+                let release = Downloader::get_release(self.defaults.youtube_dl_channel, None)?;
+                (release.version.clone(), Some(release))
             }
+        };
+        debug!("Target version: {}, current: {:?}", version, current);
+        if current.as_deref() == Some(version.as_str()) {
+            trace!("equal version");
+            return Ok(());
+        }
+        if self.is_rejected(&version)? {
+            debug!("Skipping yt-dl {}, it failed its smoke test", version);
+            return Ok(());
+        }
+
+        info!("Updating yt-dl from {:?} to {}", current, version);
+        let previous = match current {
+            Some(ref v) => Some(self.keep_binary(v)?),
+            None => None,
+        };
+        let staged = self.cmd_path.join(format!("{}.new", YTDL_NAME));
+        let kept = self.kept_path(&version);
+        if kept.exists() {
+            debug!("Installing kept yt-dl {}", version);
+            rename(&kept, &staged)?;
         } else {
-            self.inner_update(&download_file, &r_version)?;
+            let release = match release {
+                Some(v) => v,
+                None => Downloader::get_release(self.defaults.youtube_dl_channel, Some(&version))?,
+            };
+            self.inner_update(&staged, &release)?;
+        }
+        Self::check_ytdl_perm(&staged)?;
+        rename(&staged, &binary)?;
+
+        if let Err(e) = self.smoke_test() {
+            self.reject(&version)?;
+            return match previous {
+                Some(previous) => {
+                    warn!(
+                        "yt-dl {} failed its smoke test, rolling back: {}",
+                        version, e
+                    );
+                    rename(&previous, &binary)?;
+                    Err(Error::InternalError(format!(
+                        "yt-dl {} failed its smoke test, rolled back to {}: {}",
+                        version,
+                        current.unwrap_or_default(),
+                        e
+                    )))
+                }
+                None => {
+                    warn!(
+                        "yt-dl {} failed its smoke test, removing it: {}",
+                        version, e
+                    );
+                    remove_file(&binary)?;
+                    Err(Error::InternalError(format!(
+                        "yt-dl {} failed its smoke test, no previous version to roll back to: {}",
+                        version, e
+                    )))
+                }
+            };
+        }
+        info!("yt-dl updated to {}", version);
+        self.prune_kept()
+    }
+
+    /// Returns the pinned version, None to follow the channel
+    fn pinned(&self) -> Option<&str> {
+        match self.defaults.youtube_dl_version.trim() {
+            "" => None,
+            v => Some(v),
         }
-        drop(guard_);
-        Ok(())
     }
 
     /// download & verify update
@@ -614,16 +899,121 @@ impl Downloader {
         use crate::lib::http;
 
         http::http_download(&version.url, &file_path)?;
-        debug!("yt-dl updated");
+        debug!("yt-dl downloaded");
         if !lib::check_SHA256(&file_path, &version.sha256)? {
+            let _ = remove_file(file_path);
             return Err(Error::InternalError("Hash mismatch".into()));
         }
+        Ok(())
+    }
 
-        Self::check_ytdl_perm(file_path)?;
+    /// Check the installed binary: its version output & the extraction of `youtube_dl_smoke_url`
+    /// does NOT lock!
+    fn smoke_test(&self) -> Result<()> {
+        let version = self.version()?;
+        let url = &self.defaults.youtube_dl_smoke_url;
+        if url.is_empty() {
+            return Ok(());
+        }
+        let output = proxy::pool(Some(SMOKE_PROXY_HANDLER)).run(|proxy| {
+            let mut cmd = self.ytdl_base();
+            if let Some(proxy) = proxy {
+                cmd.args(["--proxy", proxy]);
+            }
+            cmd.arg("-s")
+                .arg("--no-warnings")
+                .args(["--print", "id"])
+                .arg(url);
+            let output = process::output_limited(
+                &*self.runner,
+                &mut cmd,
+                "smoke test",
+                self.timeouts.probe,
+            )?;
+            if !output.success && is_proxy_error(&output.stderr) {
+                return Err(Error::ProxyError(output.stderr));
+            }
+            Ok(output)
+        })?;
+        if output.success && !output.stdout.trim().is_empty() {
+            debug!("yt-dl {} passed its smoke test", version);
+            Ok(())
+        } else {
+            Err(Error::InternalError(format!(
+                "extraction of {} failed: {}",
+                url,
+                output.stderr.trim()
+            )))
+        }
+    }
 
+    /// Path of a kept previous binary
+    fn kept_path(&self, version: &str) -> PathBuf {
+        self.cmd_path
+            .join(KEPT_DIR)
+            .join(format!("{}-{}", version, YTDL_NAME))
+    }
+
+    /// Returns whether `version` failed its smoke test before
+    fn is_rejected(&self, version: &str) -> Result<bool> {
+        let path = self.cmd_path.join(REJECTED_FILE);
+        if !path.exists() {
+            return Ok(false);
+        }
+        Ok(read_to_string(path)?.lines().any(|v| v == version))
+    }
+
+    /// Record `version` as failing its smoke test, it isn't installed again
+    fn reject(&self, version: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.cmd_path.join(REJECTED_FILE))?;
+        writeln!(file, "{}", version)?;
+        Ok(())
+    }
+
+    /// Copy the installed binary of `version` to the kept binaries, returns its path
+    fn keep_binary(&self, version: &str) -> Result<PathBuf> {
+        let path = self.kept_path(version);
+        create_dir_all(self.cmd_path.join(KEPT_DIR))?;
+        copy(self.ytdl_path(), &path)?;
+        Ok(path)
+    }
+
+    /// Remove kept binaries exceeding `youtube_dl_keep`, oldest first
+    fn prune_kept(&self) -> Result<()> {
+        let dir = self.cmd_path.join(KEPT_DIR);
+        if !dir.exists() {
+            return Ok(());
+        }
+        let mut kept = Vec::new();
+        for entry in read_dir(&dir)? {
+            let entry = entry?;
+            kept.push((entry.metadata()?.modified()?, entry.path()));
+        }
+        kept.sort_by_key(|v| std::cmp::Reverse(v.0));
+        for (_, path) in kept.iter().skip(self.defaults.youtube_dl_keep as usize) {
+            debug!("Removing kept yt-dl {:?}", path);
+            remove_file(path)?;
+        }
         Ok(())
     }
 
+    /// Record the active version in the metrics & DB
+    fn set_active_version(&self, version: &str) {
+        metrics::set_ytdl_version(version);
+        if let Some(ref db) = self.db {
+            let channel = match self.pinned() {
+                Some(_) => CHANNEL_PINNED,
+                None => self.defaults.youtube_dl_channel.as_str(),
+            };
+            if let Err(e) = db.set_ytdl_version(&lib::hostname(), version, channel) {
+                warn!("Unable to record yt-dl version: {}", e);
+            }
+        }
+    }
+
     fn ytdl_path(&self) -> PathBuf {
         self.cmd_path.join(YTDL_NAME)
     }
@@ -638,6 +1028,19 @@ impl Downloader {
     }
 }
 
+/// Returns true if yt-dl failed to connect via its proxy
+fn is_proxy_error(stderr: &str) -> bool {
+    PROXY_ERRORS.iter().any(|v| stderr.contains(v))
+}
+
+/// Returns the typed error of yt-dl output, an InternalError for unknown failures
+fn classified(stderr: String) -> Error {
+    match Failure::classify(&stderr) {
+        Some(kind) => Error::Unavailable(kind, stderr),
+        None => Error::InternalError(stderr),
+    }
+}
+
 fn parse_hashfile(input: &str) -> Result<&str> {
     input
         .split('\n')
@@ -662,6 +1065,167 @@ struct GHAsset {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::lib::db::{self, SqliteRepository};
+    use crate::lib::process::{Transcript, TranscriptRunner};
+
+    const URL: &str = "https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+
+    fn with_transcripts(runner: TranscriptRunner) -> (Downloader, Arc<TranscriptRunner>) {
+        let runner = Arc::new(runner);
+        (
+            Downloader::with_runner(&CONFIG.general, runner.clone()),
+            runner,
+        )
+    }
+
+    /// Request of a new query in an in-memory DB
+    fn request(playlist: Option<db::PlaylistRange>) -> Request {
+        let db: Arc<dyn Repository> = Arc::new(SqliteRepository::open_in_memory().unwrap());
+        db.add_query(URL, &137, &1, &0, playlist).unwrap();
+        db::request_entry(&db).unwrap()
+    }
+
+    #[test]
+    fn download_test() {
+        let (downloader, runner) = with_transcripts(TranscriptRunner::new().add(
+            "yt-dlp",
+            None,
+            Transcript::ok(include_str!("../../tests/transcripts/ytdl_download.txt")),
+        ));
+        let request = request(None);
+        let file = Path::new("video.mp4");
+        assert!(downloader.download_file(&request, file, "137").unwrap());
+        let info = request.db().get_query_info(&request.qid).unwrap().unwrap();
+        assert_eq!(Some(100.0), info.progress);
+        let call = &runner.calls()[0];
+        assert!(call.windows(2).any(|v| v == ["-f", "137"]));
+        assert!(call.windows(2).any(|v| v == ["-o", "video.mp4"]));
+        // share of the global budget, other tests may download concurrently
+        assert!(call.windows(2).any(|v| v[0] == "-r" && v[1].ends_with('K')));
+        assert_eq!(Some(&URL.to_string()), call.last());
+    }
+
+    #[test]
+    fn download_errors_test() {
+        let request = request(None);
+        let file = Path::new("video.mp4");
+        let failing = |stderr: &str| {
+            with_transcripts(TranscriptRunner::new().add(
+                "yt-dlp",
+                None,
+                Transcript::failed(stderr),
+            ))
+        };
+
+        let (downloader, _) = failing("ERROR: requested format not available");
+        match downloader.download_file(&request, file, "137") {
+            Err(Error::QualityNotAvailable) => (),
+            v => panic!("unexpected {:?}", v),
+        }
+
+        let (downloader, runner) = failing("ERROR: ExtractorError: unable to extract");
+        match downloader.download_file(&request, file, "137") {
+            Err(Error::ExtractorError) => (),
+            v => panic!("unexpected {:?}", v),
+        }
+        assert_eq!(2, runner.calls().len());
+
+        let (downloader, _) = failing("ERROR: unable to download video data");
+        match downloader.download_file(&request, file, "137") {
+            Err(Error::InternalError(e)) => assert!(e.contains("video data")),
+            v => panic!("unexpected {:?}", v),
+        }
+
+        let (downloader, _) = failing("ERROR: HTTP Error 429: Too Many Requests");
+        match downloader.download_file(&request, file, "137") {
+            Err(Error::Unavailable(Failure::RateLimited, e)) => assert!(e.contains("429")),
+            v => panic!("unexpected {:?}", v),
+        }
+
+        let (downloader, _) =
+            failing("ERROR: Unable to download webpage: ProxyError('Unable to connect to proxy')");
+        match downloader.download_file(&request, file, "137") {
+            Err(Error::ProxyError(_)) => (),
+            v => panic!("unexpected {:?}", v),
+        }
+    }
+
+    #[test]
+    fn file_name_test() {
+        let (downloader, runner) = with_transcripts(TranscriptRunner::new().add(
+            "yt-dlp",
+            None,
+            Transcript::ok("Rick Astley - Never Gonna Give You Up (Video).mp4\n"),
+        ));
+        let request = request(None);
+        let name = downloader
+            .get_file_name(&request, Some("137".to_string()))
+            .unwrap();
+        assert_eq!("Rick Astley - Never Gonna Give You Up (Video)", name.name);
+        assert_eq!("mp4", name.extension);
+        assert!(runner.calls()[0].windows(2).any(|v| v == ["-f", "137"]));
+
+        let failing = |stderr: &str| {
+            with_transcripts(TranscriptRunner::new().add(
+                "yt-dlp",
+                None,
+                Transcript::failed(stderr),
+            ))
+            .0
+            .get_file_name(&request, None)
+        };
+        match failing("ERROR: This video is not available in your country.") {
+            Err(Error::DMCAError(e)) => assert!(e.contains("your country")),
+            v => panic!("unexpected {:?}", v.map(|v| v.full_name())),
+        }
+        match failing("ERROR: Sign in to confirm your age\nPlease sign in to view this video") {
+            Err(Error::Unavailable(Failure::AgeRestricted, _)) => (),
+            v => panic!("unexpected {:?}", v.map(|v| v.full_name())),
+        }
+        match failing("ERROR: ExtractorError: unable to extract") {
+            Err(Error::ExtractorError) => (),
+            v => panic!("unexpected {:?}", v.map(|v| v.full_name())),
+        }
+        match failing("ERROR: Unsupported URL") {
+            Err(Error::DownloadError(e)) => assert!(e.contains("Unsupported")),
+            v => panic!("unexpected {:?}", v.map(|v| v.full_name())),
+        }
+    }
+
+    #[test]
+    fn playlist_entries_test() {
+        let (downloader, runner) = with_transcripts(TranscriptRunner::new().add(
+            "yt-dlp",
+            Some("--flat-playlist"),
+            Transcript::ok(include_str!("../../tests/transcripts/ytdl_playlist.jsonl")),
+        ));
+        let request = request(Some(db::PlaylistRange {
+            from: 2,
+            to: 4,
+            split: false,
+        }));
+        let entries = downloader.get_playlist_entries(&request).unwrap();
+        let urls: Vec<&str> = entries.iter().map(|v| v.url.as_str()).collect();
+        assert_eq!(
+            vec![
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+                "https://www.youtube.com/watch?v=yPYZpwSpKmA",
+                "https://www.youtube.com/watch?v=AC3Ejf7vPEY"
+            ],
+            urls
+        );
+        assert_eq!(Some("Never Gonna Give You Up"), entries[0].title.as_deref());
+        let call = &runner.calls()[0];
+        assert!(call.windows(2).any(|v| v == ["--playlist-start", "2"]));
+        assert!(call.windows(2).any(|v| v == ["--playlist-end", "4"]));
+
+        let (downloader, _) = with_transcripts(TranscriptRunner::new().add(
+            "yt-dlp",
+            None,
+            Transcript::failed("ERROR: The playlist does not exist."),
+        ));
+        assert!(downloader.get_playlist_entries(&request).is_err());
+    }
 
     #[test]
     fn filenames() {
@@ -670,9 +1234,90 @@ mod test {
         assert!(!REGEX_NAME.is_match("A#B\"C.ABCÜ02."));
     }
 
+    /// Downloader of its own youtube_dl_dir, pinned to 2021.12.27 kept on disk
+    /// The installed binary reports 2021.12.01, the smoke test replays `smoke`.
+    fn updater(dir: &Path, smoke: Transcript) -> (Downloader, Arc<dyn Repository>) {
+        let mut conf = CONFIG.general.clone();
+        conf.youtube_dl_dir = dir.to_string_lossy().into_owned();
+        conf.youtube_dl_version = String::from("2021.12.27");
+        conf.youtube_dl_keep = 1;
+        conf.youtube_dl_smoke_url = String::from(URL);
+        let runner = TranscriptRunner::new()
+            .add("yt-dlp", Some("--version"), Transcript::ok("2021.12.01\n"))
+            .add("yt-dlp", Some("--print"), smoke);
+        let mut downloader = Downloader::with_runner(Box::leak(Box::new(conf)), Arc::new(runner));
+        let db: Arc<dyn Repository> = Arc::new(SqliteRepository::open_in_memory().unwrap());
+        downloader.set_db(db.clone());
+
+        std::fs::create_dir_all(dir.join(KEPT_DIR)).unwrap();
+        std::fs::write(dir.join(YTDL_NAME), "old").unwrap();
+        std::fs::write(downloader.kept_path("2021.12.27"), "new").unwrap();
+        let outdated = downloader.kept_path("2021.11.01");
+        std::fs::write(&outdated, "outdated").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&outdated)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(3600))
+            .unwrap();
+        (downloader, db)
+    }
+
+    #[test]
+    fn update_test() {
+        let dir = std::env::temp_dir().join(format!("yayd-update-{}", std::process::id()));
+        let (downloader, db) = updater(&dir, Transcript::ok("jNQXAC9IVRw\n"));
+        downloader.update_downloader().unwrap();
+        assert_eq!("new", std::fs::read_to_string(dir.join(YTDL_NAME)).unwrap());
+        // only the previous binary is kept
+        assert_eq!(
+            "old",
+            std::fs::read_to_string(downloader.kept_path("2021.12.01")).unwrap()
+        );
+        assert_eq!(1, read_dir(dir.join(KEPT_DIR)).unwrap().count());
+        assert_eq!(
+            Some(String::from("pinned")),
+            db.get_ytdl_version(&lib::hostname()).unwrap().map(|v| v.1)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn update_rollback_test() {
+        let dir = std::env::temp_dir().join(format!("yayd-rollback-{}", std::process::id()));
+        let (downloader, _) = updater(&dir, Transcript::failed("ERROR: unable to extract"));
+        match downloader.update_downloader() {
+            Err(Error::InternalError(e)) => assert!(e.contains("rolled back to 2021.12.01")),
+            v => panic!("unexpected {:?}", v),
+        }
+        assert_eq!("old", std::fs::read_to_string(dir.join(YTDL_NAME)).unwrap());
+        assert!(!downloader.kept_path("2021.12.01").exists());
+        // the failed version isn't retried, also after a restart
+        downloader.update_downloader().unwrap();
+        assert_eq!("old", std::fs::read_to_string(dir.join(YTDL_NAME)).unwrap());
+        let (restarted, _) = updater(&dir, Transcript::ok("jNQXAC9IVRw\n"));
+        restarted.update_downloader().unwrap();
+        assert_eq!("old", std::fs::read_to_string(dir.join(YTDL_NAME)).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn update_rejected_without_previous_test() {
+        let dir = std::env::temp_dir().join(format!("yayd-rejected-{}", std::process::id()));
+        let (downloader, _) = updater(&dir, Transcript::failed("ERROR: unable to extract"));
+        std::fs::remove_file(dir.join(YTDL_NAME)).unwrap();
+        match downloader.update_downloader() {
+            Err(Error::InternalError(e)) => assert!(e.contains("no previous version")),
+            v => panic!("unexpected {:?}", v),
+        }
+        // the failed binary isn't activated
+        assert!(!dir.join(YTDL_NAME).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn latest_version() {
-        let version = Downloader::get_latest_version().unwrap();
+        let version = Downloader::get_release(YtdlChannel::Stable, None).unwrap();
         dbg!(version);
     }
 
@@ -0,0 +1,217 @@
+//! Typed failures of yt-dl, classified by its error output
+//!
+//! Every kind has its own status code & a readable message for the frontend,
+//! the raw output is kept in `queryerror` for admins.
+
+/// Known reason of a failed extraction or download
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    /// Not available in the region of the server
+    GeoBlocked,
+    /// Requires an account confirming its age
+    AgeRestricted,
+    /// Requires a login or membership
+    LoginRequired,
+    /// Private video
+    Private,
+    /// Removed by its uploader or terminated account
+    Removed,
+    /// Scheduled premiere or live stream that hasn't started yet
+    Premiere,
+    /// Throttled by the site, HTTP 429
+    RateLimited,
+    /// Taken down after a copyright claim
+    Copyright,
+}
+
+/// Lowercase patterns of yt-dl output per kind, checked in this order
+/// Copyright claims are also reported as "Video unavailable", so they're checked before removals.
+const PATTERNS: [(Failure, &[&str]); 8] = [
+    (
+        Failure::RateLimited,
+        &["http error 429", "too many requests"],
+    ),
+    (
+        Failure::GeoBlocked,
+        &[
+            "available in your country",
+            "blocked it in your country",
+            "geo restriction",
+            "geo-restricted",
+            "this video is available in",
+        ],
+    ),
+    (
+        Failure::Copyright,
+        &[
+            "copyright claim",
+            "copyright grounds",
+            "contains content from",
+        ],
+    ),
+    (
+        Failure::AgeRestricted,
+        &[
+            "confirm your age",
+            "age-restricted",
+            "inappropriate for some users",
+        ],
+    ),
+    (
+        Failure::Premiere,
+        &[
+            "premieres in",
+            "premiere will begin",
+            "live event will begin in",
+        ],
+    ),
+    (
+        Failure::Private,
+        &["private video", "this video is private"],
+    ),
+    (
+        Failure::LoginRequired,
+        &[
+            "please sign in to view this video",
+            "only available for registered users",
+            "members-only",
+            "join this channel to get access",
+            "use --cookies",
+        ],
+    ),
+    (
+        Failure::Removed,
+        &[
+            "has been removed",
+            "no longer available",
+            "has been terminated",
+            "video unavailable",
+            "http error 404",
+        ],
+    ),
+];
+
+impl Failure {
+    /// Classify the error output of yt-dl, None for unknown failures
+    pub fn classify(output: &str) -> Option<Failure> {
+        let output = output.to_lowercase();
+        PATTERNS
+            .iter()
+            .find(|(_, patterns)| patterns.iter().any(|v| output.contains(v)))
+            .map(|(kind, _)| *kind)
+    }
+
+    /// Kind of a failure status code, None for other codes
+    pub fn from_code(code: i8) -> Option<Failure> {
+        PATTERNS
+            .iter()
+            .map(|(kind, _)| *kind)
+            .find(|kind| kind.code() == code)
+    }
+
+    /// Status code of jobs failing with this kind
+    pub fn code(&self) -> i8 {
+        match self {
+            Failure::GeoBlocked => crate::CODE_FAILED_GEO_BLOCKED,
+            Failure::AgeRestricted => crate::CODE_FAILED_AGE_RESTRICTED,
+            Failure::LoginRequired => crate::CODE_FAILED_LOGIN_REQUIRED,
+            Failure::Private => crate::CODE_FAILED_PRIVATE,
+            Failure::Removed => crate::CODE_FAILED_REMOVED,
+            Failure::Premiere => crate::CODE_FAILED_PREMIERE,
+            Failure::RateLimited => crate::CODE_FAILED_RATE_LIMITED,
+            Failure::Copyright => crate::CODE_FAILED_COPYRIGHT,
+        }
+    }
+
+    /// Short name, used in logs & the DB
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Failure::GeoBlocked => "geo_blocked",
+            Failure::AgeRestricted => "age_restricted",
+            Failure::LoginRequired => "login_required",
+            Failure::Private => "private",
+            Failure::Removed => "removed",
+            Failure::Premiere => "premiere",
+            Failure::RateLimited => "rate_limited",
+            Failure::Copyright => "copyright",
+        }
+    }
+
+    /// Readable message for users
+    pub fn message(&self) -> &'static str {
+        match self {
+            Failure::GeoBlocked => "not available in the region of the server",
+            Failure::AgeRestricted => "age restricted, requires a login",
+            Failure::LoginRequired => "requires a login or membership",
+            Failure::Private => "private video",
+            Failure::Removed => "removed by the uploader or the site",
+            Failure::Premiere => "premiere or live stream hasn't started yet",
+            Failure::RateLimited => "rate limited by the site, retry later",
+            Failure::Copyright => "removed after a copyright claim",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify_test() {
+        let cases = [
+            (
+                "ERROR: [youtube] abc: The uploader has not made this video available in your country.",
+                Failure::GeoBlocked,
+            ),
+            (
+                "ERROR: [youtube] abc: Video unavailable. This video contains content from SME, who has blocked it in your country on copyright grounds.",
+                Failure::GeoBlocked,
+            ),
+            (
+                "ERROR: [youtube] abc: Video unavailable. This video is no longer available due to a copyright claim by SME",
+                Failure::Copyright,
+            ),
+            (
+                "ERROR: [youtube] abc: Sign in to confirm your age. This video may be inappropriate for some users.",
+                Failure::AgeRestricted,
+            ),
+            (
+                "ERROR: [youtube] abc: Private video. Sign in if you've been granted access to this video",
+                Failure::Private,
+            ),
+            (
+                "ERROR: [youtube] abc: Video unavailable. This video has been removed by the uploader",
+                Failure::Removed,
+            ),
+            ("ERROR: [youtube] abc: Video unavailable", Failure::Removed),
+            (
+                "ERROR: [youtube] abc: Premieres in 3 hours",
+                Failure::Premiere,
+            ),
+            (
+                "ERROR: [youtube] abc: This live event will begin in 20 minutes.",
+                Failure::Premiere,
+            ),
+            (
+                "ERROR: unable to download video data: HTTP Error 429: Too Many Requests",
+                Failure::RateLimited,
+            ),
+            (
+                "ERROR: [youtube] abc: Join this channel to get access to members-only content",
+                Failure::LoginRequired,
+            ),
+        ];
+        for (output, kind) in cases.iter() {
+            assert_eq!(Some(*kind), Failure::classify(output), "{}", output);
+        }
+        assert_eq!(None, Failure::classify("ERROR: Unsupported URL"));
+    }
+
+    #[test]
+    fn code_test() {
+        for (kind, _) in PATTERNS.iter() {
+            assert_eq!(Some(*kind), Failure::from_code(kind.code()));
+        }
+        assert_eq!(None, Failure::from_code(crate::CODE_SUCCESS));
+    }
+}
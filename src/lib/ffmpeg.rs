@@ -0,0 +1,411 @@
+//! Managed ffmpeg builds
+//!
+//! Downloads static builds from `ffmpeg.mirror` into `ffmpeg_bin_dir`, verified by their sha256
+//! & the required encoders. Builds are extracted beside the binaries and swapped via rename,
+//! restoring the installed binaries if the swap fails.
+
+use flate2::read::GzDecoder;
+
+use std::fs::{self, create_dir_all, remove_dir_all, remove_file, rename, File};
+use std::io;
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::prelude::PermissionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::lib::config::ConfigFfmpeg;
+use crate::lib::converter::Converter;
+use crate::lib::http;
+use crate::lib::process::{Runner, SystemRunner};
+use crate::lib::{self, Error, Result};
+
+#[cfg(not(target_os = "windows"))]
+const BINARIES: [&str; 2] = ["ffmpeg", "ffprobe"];
+#[cfg(target_os = "windows")]
+const BINARIES: [&str; 2] = ["ffmpeg.exe", "ffprobe.exe"];
+/// Downloaded archive, inside ffmpeg_bin_dir
+const DOWNLOAD_FILE: &str = ".ffmpeg-download";
+/// Extracted build before the swap, inside ffmpeg_bin_dir
+const STAGING_DIR: &str = ".ffmpeg-staging";
+/// Previously installed binaries during the swap, inside the staging dir
+const BACKUP_DIR: &str = "backup";
+/// Checksum of the installed archive, inside ffmpeg_bin_dir
+const INSTALLED_FILE: &str = ".ffmpeg-installed";
+
+lazy_static! {
+    /// Prevents concurrent updates
+    static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Supported build archives
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveType {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveType {
+    /// Returns the type of an archive by its name
+    pub fn of(name: &str) -> Option<ArchiveType> {
+        if name.ends_with(".zip") {
+            Some(ArchiveType::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveType::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Install the build of the mirror if it differs from the installed one
+/// Returns true if a new build was installed
+pub fn update(conf: &ConfigFfmpeg, bin_dir: &Path) -> Result<bool> {
+    let _guard = LOCK.lock()?;
+    let base = conf.mirror.trim_end_matches('/');
+    let sums = http::http_text_get(&format!("{}/{}", base, conf.checksums))?;
+    let sha256 = parse_checksum(&sums, &conf.archive).ok_or_else(|| {
+        Error::InternalError(format!(
+            "no checksum for {} in {}",
+            conf.archive, conf.checksums
+        ))
+    })?;
+    if installed(bin_dir).as_deref() == Some(sha256.as_str()) {
+        trace!("ffmpeg is up to date");
+        return Ok(false);
+    }
+
+    info!("Installing ffmpeg build {}", sha256);
+    let archive = bin_dir.join(DOWNLOAD_FILE);
+    let result =
+        http::http_download(&format!("{}/{}", base, conf.archive), &archive).and_then(|_| {
+            install(
+                &archive,
+                &conf.archive,
+                &sha256,
+                bin_dir,
+                &conf.encoders,
+                Arc::new(SystemRunner),
+            )
+        });
+    if archive.exists() {
+        remove_file(&archive)?;
+    }
+    result.map(|_| true)
+}
+
+/// Returns the checksum of the installed build, None if it isn't managed
+fn installed(bin_dir: &Path) -> Option<String> {
+    fs::read_to_string(bin_dir.join(INSTALLED_FILE))
+        .ok()
+        .map(|v| v.trim().to_string())
+}
+
+/// Returns the sha256 of `archive` in a checksum file
+/// Files with a single checksum without name are accepted too.
+fn parse_checksum(sums: &str, archive: &str) -> Option<String> {
+    let mut single = None;
+    for line in sums.lines() {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(hash), Some(name)) if name.trim_start_matches('*') == archive => {
+                single = Some(hash);
+                break;
+            }
+            (Some(hash), None) => single = Some(hash),
+            _ => (),
+        }
+    }
+    single
+        .filter(|v| v.len() == 64 && v.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|v| v.to_lowercase())
+}
+
+/// Verify & extract a downloaded build, swapping it with the installed one
+/// The installed binaries are untouched if any check fails.
+fn install(
+    archive: &Path,
+    name: &str,
+    sha256: &str,
+    bin_dir: &Path,
+    encoders: &[String],
+    runner: Arc<dyn Runner>,
+) -> Result<()> {
+    if !lib::check_SHA256(archive, sha256)? {
+        return Err(Error::InternalError("ffmpeg build hash mismatch".into()));
+    }
+    let kind = ArchiveType::of(name)
+        .ok_or_else(|| Error::InternalError(format!("unsupported archive {}", name)))?;
+    let staging = bin_dir.join(STAGING_DIR);
+    if staging.exists() {
+        remove_dir_all(&staging)?;
+    }
+    create_dir_all(&staging)?;
+    let result = extract(archive, kind, &staging).and_then(|_| {
+        let dir = staging.to_string_lossy();
+        Converter::with_runner(&dir, &0, runner).verify(encoders)?;
+        swap(&staging, bin_dir, sha256)
+    });
+    remove_dir_all(&staging)?;
+    result
+}
+
+/// Move the staged binaries into `bin_dir`, restoring the installed ones if any step fails
+fn swap(staging: &Path, bin_dir: &Path, sha256: &str) -> Result<()> {
+    let backup = staging.join(BACKUP_DIR);
+    create_dir_all(&backup)?;
+    let mut saved = Vec::new();
+    let mut moved = Vec::new();
+    let mut result = Ok(());
+    for binary in BINARIES.iter() {
+        let target = bin_dir.join(binary);
+        if target.exists() {
+            result = rename(&target, backup.join(binary));
+            if result.is_err() {
+                break;
+            }
+            saved.push(*binary);
+        }
+        result = rename(staging.join(binary), &target);
+        if result.is_err() {
+            break;
+        }
+        moved.push(*binary);
+    }
+    let result = result.and_then(|_| fs::write(bin_dir.join(INSTALLED_FILE), sha256));
+    if result.is_err() {
+        warn!("ffmpeg swap failed, restoring the installed build");
+        for binary in moved {
+            remove_file(bin_dir.join(binary))?;
+        }
+        for binary in saved {
+            rename(backup.join(binary), bin_dir.join(binary))?;
+        }
+    }
+    Ok(result?)
+}
+
+/// Extract ffmpeg & ffprobe of an archive into `target`, ignoring their folders
+fn extract(archive: &Path, kind: ArchiveType, target: &Path) -> Result<()> {
+    let file = File::open(archive)?;
+    let mut extracted = Vec::new();
+    let mut write = |name: &str, reader: &mut dyn io::Read| -> Result<()> {
+        if let Some(binary) = BINARIES.iter().find(|v| **v == name) {
+            let path = target.join(binary);
+            io::copy(reader, &mut File::create(&path)?)?;
+            #[cfg(not(target_os = "windows"))]
+            fs::set_permissions(&path, PermissionsExt::from_mode(0o755))?;
+            extracted.push(*binary);
+        }
+        Ok(())
+    };
+    match kind {
+        ArchiveType::Zip => {
+            let mut zip = zip::ZipArchive::new(file)?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i)?;
+                let name = file_name(entry.name());
+                write(&name, &mut entry)?;
+            }
+        }
+        ArchiveType::TarGz => {
+            let mut tar = tar::Archive::new(GzDecoder::new(file));
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                let name = file_name(&entry.path()?.to_string_lossy());
+                write(&name, &mut entry)?;
+            }
+        }
+    }
+    match BINARIES.iter().find(|v| !extracted.contains(v)) {
+        Some(missing) => Err(Error::InternalError(format!(
+            "{} not found in the ffmpeg build",
+            missing
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Returns the last component of an archive path
+fn file_name(path: &str) -> String {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lib::process::{Transcript, TranscriptRunner};
+    use sha2::{Digest, Sha256};
+    use std::io::Write;
+
+    fn runner(encoders: &str) -> Arc<dyn Runner> {
+        Arc::new(
+            TranscriptRunner::new()
+                .add(
+                    "ffmpeg",
+                    Some("-version"),
+                    Transcript::ok("ffmpeg version 4.4"),
+                )
+                .add(
+                    "ffprobe",
+                    Some("-version"),
+                    Transcript::ok("ffprobe version 4.4"),
+                )
+                .add("ffmpeg", Some("-encoders"), Transcript::ok(encoders)),
+        )
+    }
+
+    fn sha256(path: &Path) -> String {
+        format!("{:x}", Sha256::digest(&fs::read(path).unwrap()))
+    }
+
+    /// Zip build with binaries in a sub folder
+    fn zip_build(path: &Path, binaries: &[&str]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for binary in binaries {
+            zip.start_file(format!("ffmpeg-4.4/bin/{}", binary), Default::default())
+                .unwrap();
+            zip.write_all(format!("new {}", binary).as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn bin_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("yayd-{}-{}", name, std::process::id()));
+        create_dir_all(&dir).unwrap();
+        for binary in BINARIES.iter() {
+            fs::write(dir.join(binary), "old").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn checksum_test() {
+        let hash = "5c37c8f9aaf8cc12faea034de96deb5794b7177f071425ce69dad3f315335559";
+        let sums = format!("{}  other.zip\n{} *ffmpeg.zip\n", "0".repeat(64), hash);
+        assert_eq!(Some(hash.to_string()), parse_checksum(&sums, "ffmpeg.zip"));
+        assert_eq!(None, parse_checksum(&sums, "missing.zip"));
+        assert_eq!(Some(hash.to_string()), parse_checksum(hash, "ffmpeg.zip"));
+        assert_eq!(None, parse_checksum("no hash", "ffmpeg.zip"));
+    }
+
+    #[test]
+    fn install_test() {
+        let dir = bin_dir("ffmpeg-install");
+        let archive = dir.join("build.zip");
+        zip_build(&archive, &BINARIES);
+        let hash = sha256(&archive);
+        let encoders = vec![String::from("libmp3lame")];
+        install(
+            &archive,
+            "build.zip",
+            &hash,
+            &dir,
+            &encoders,
+            runner(" A....D libmp3lame  MP3"),
+        )
+        .unwrap();
+        for binary in BINARIES.iter() {
+            assert_eq!(
+                format!("new {}", binary),
+                fs::read_to_string(dir.join(binary)).unwrap()
+            );
+        }
+        assert_eq!(Some(hash), installed(&dir));
+        assert!(!dir.join(STAGING_DIR).exists());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn install_tar_test() {
+        let dir = bin_dir("ffmpeg-tar");
+        let archive = dir.join("build.tar.gz");
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(
+            File::create(&archive).unwrap(),
+            flate2::Compression::default(),
+        ));
+        for binary in BINARIES.iter() {
+            let data = format!("new {}", binary);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            tar.append_data(&mut header, format!("build/{}", binary), data.as_bytes())
+                .unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+        let hash = sha256(&archive);
+        install(&archive, "build.tar.gz", &hash, &dir, &[], runner("")).unwrap();
+        assert_eq!(
+            format!("new {}", BINARIES[0]),
+            fs::read_to_string(dir.join(BINARIES[0])).unwrap()
+        );
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn install_errors_test() {
+        let dir = bin_dir("ffmpeg-errors");
+        let archive = dir.join("build.zip");
+        zip_build(&archive, &BINARIES);
+        let hash = sha256(&archive);
+        let encoders = vec![String::from("libmp3lame")];
+        let failing = |hash: &str, encoders: &str| {
+            install(
+                &archive,
+                "build.zip",
+                hash,
+                &dir,
+                &[String::from("libmp3lame")],
+                runner(encoders),
+            )
+        };
+        assert!(failing(&"0".repeat(64), " A....D libmp3lame  MP3").is_err());
+        assert!(failing(&hash, " A....D aac  AAC").is_err());
+
+        zip_build(&archive, &BINARIES[..1]);
+        let hash = sha256(&archive);
+        assert!(install(
+            &archive,
+            "build.zip",
+            &hash,
+            &dir,
+            &encoders,
+            runner(" A....D libmp3lame  MP3")
+        )
+        .is_err());
+
+        // the installed build is untouched
+        for binary in BINARIES.iter() {
+            assert_eq!("old", fs::read_to_string(dir.join(binary)).unwrap());
+        }
+        assert_eq!(None, installed(&dir));
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn swap_rollback_test() {
+        let dir = bin_dir("ffmpeg-swap");
+        let staging = dir.join(STAGING_DIR);
+        create_dir_all(&staging).unwrap();
+        // only the first binary is staged, moving the second fails
+        fs::write(staging.join(BINARIES[0]), "new").unwrap();
+        assert!(swap(&staging, &dir, "hash").is_err());
+        for binary in BINARIES.iter() {
+            assert_eq!("old", fs::read_to_string(dir.join(binary)).unwrap());
+        }
+        assert_eq!(None, installed(&dir));
+
+        fs::write(staging.join(BINARIES[0]), "new").unwrap();
+        fs::write(staging.join(BINARIES[1]), "new").unwrap();
+        swap(&staging, &dir, "hash").unwrap();
+        for binary in BINARIES.iter() {
+            assert_eq!("new", fs::read_to_string(dir.join(binary)).unwrap());
+        }
+        assert_eq!(Some(String::from("hash")), installed(&dir));
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,27 +1,33 @@
 use reqwest::blocking::{Client, Response};
 use reqwest::header::HeaderMap;
 use reqwest::header::{
-    ACCEPT, ACCEPT_ENCODING, CONNECTION, CONTENT_ENCODING, LOCATION, USER_AGENT,
+    ACCEPT, ACCEPT_ENCODING, CONNECTION, CONTENT_ENCODING, CONTENT_TYPE, LOCATION, USER_AGENT,
 };
+use reqwest::Proxy;
 use serde::de::DeserializeOwned;
 
 use std::fs::File;
+use std::io;
 use std::path::Path;
 use std::time::Duration;
 
+use crate::lib::bandwidth;
+use crate::lib::proxy;
 use crate::lib::{Error, Result};
 
 use crate::C_USER_AGENT;
 
-/// Download into file
+/// Download into file, limited by the bandwidth budget
 pub fn http_download<P: AsRef<Path>>(url: &str, target: P) -> Result<()> {
-    let mut response = get_raw(url, HeaderType::Html)?;
+    let response = get_raw(url, HeaderType::Html)?;
     let mut file = File::create(target)?;
-    response.copy_to(&mut file)?;
+    let mut reader = bandwidth::Throttled::new(response, bandwidth::acquire());
+    io::copy(&mut reader, &mut file)?;
     Ok(())
 }
 
 /// Header type for get requests
+#[derive(Clone, Copy)]
 pub enum HeaderType {
     /// Html browser request
     Html,
@@ -29,16 +35,29 @@ pub enum HeaderType {
     Ajax,
 }
 
+/// Proxy pool name of the updater requests
+const PROXY_HANDLER: &str = "updater";
+
 /// Does a raw get request under the provided url & header
+/// Uses the updater proxy pool, retrying failed proxy connections with the next proxy.
 fn get_raw(url: &str, htype: HeaderType) -> Result<Response> {
     trace!("Starting request {}", url);
 
-    let client = Client::builder()
-        .gzip(true)
-        .timeout(Duration::from_secs(10))
-        .build()?;
-    let builder = client.get(url);
-    let res = builder.headers(header(htype)).send()?;
+    let res = proxy::pool(Some(PROXY_HANDLER)).run(|proxy| {
+        let mut builder = Client::builder()
+            .gzip(true)
+            .timeout(Duration::from_secs(10));
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        let client = builder.build()?;
+        match client.get(url).headers(header(htype)).send() {
+            Err(e) if proxy.is_some() && (e.is_connect() || e.is_timeout()) => {
+                Err(Error::ProxyError(e.to_string()))
+            }
+            v => Ok(v?),
+        }
+    })?;
 
     debug!("Response header: {:?}", res.headers());
     debug!("Response status: {:?}", res.status());
@@ -71,6 +90,31 @@ where
     }
 }
 
+/// Do an http(s) post request with a JSON body
+/// Returns an error for non success status codes
+pub fn http_post_json(url: &str, body: &str, extra_headers: &[(&str, String)]) -> Result<()> {
+    trace!("Starting post request {}", url);
+    let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
+    let mut builder = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(USER_AGENT, C_USER_AGENT)
+        .body(body.to_string());
+    for (k, v) in extra_headers {
+        builder = builder.header(*k, v.as_str());
+    }
+    let res = builder.send()?;
+    debug!("Response status: {:?}", res.status());
+    if !res.status().is_success() {
+        return Err(Error::InternalError(format!(
+            "Post to {} failed: {}",
+            url,
+            res.status()
+        )));
+    }
+    Ok(())
+}
+
 /// Construct a header
 /// This function does not check for errors and is
 /// verified by the tests
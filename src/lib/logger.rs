@@ -6,21 +6,34 @@ use crate::{LOG_CONFIG, LOG_PATTERN};
 use std;
 use std::default::Default;
 use std::fs::metadata;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use log::LevelFilter;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Logger, Root};
 use log4rs::encode::pattern::PatternEncoder;
+use log4rs::Handle;
 
-const APPENDER_FILE: &'static str = "file";
-const APPENDER_STDOUT: &'static str = "stdout";
+const APPENDER_FILE: &str = "file";
+const APPENDER_STDOUT: &str = "stdout";
 
-/// Initializes the logger.
-pub fn initialize() {
+lazy_static! {
+    /// Handle of the initialized logger, for reloads
+    static ref HANDLE: Mutex<Option<Handle>> = Mutex::new(None);
+}
+
+/// Returns the path of the log config
+fn log_path() -> PathBuf {
     let mut log_path = get_executable_folder().unwrap_or(std::path::PathBuf::from("/"));
     log_path.push(LOG_CONFIG);
+    log_path
+}
+
+/// Initializes the logger.
+pub fn initialize() {
+    let log_path = log_path();
     println!("Logging file: {:?}", log_path);
     match metadata(log_path.as_path()) {
         Ok(v) => {
@@ -36,12 +49,39 @@ pub fn initialize() {
 
 /// Initialize log config from file
 fn init_file(conf: &Path) {
-    match log4rs::init_file(conf, Default::default()) {
-        Ok(_) => (),
+    let config = match log4rs::config::load_config_file(conf, Default::default()) {
+        Ok(v) => v,
+        Err(e) => panic!("Log initialization failed! {:?}", e),
+    };
+    match log4rs::init_config(config) {
+        Ok(handle) => set_handle(handle),
         Err(e) => panic!("Log initialization failed! {:?}", e),
     }
 }
 
+fn set_handle(handle: Handle) {
+    *HANDLE.lock().unwrap_or_else(|e| e.into_inner()) = Some(handle);
+}
+
+/// Reload the log config file, keeps the current config if it's missing or invalid
+pub fn reload() {
+    let log_path = log_path();
+    let config = match log4rs::config::load_config_file(&log_path, Default::default()) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Unable to reload log config {:?}: {:?}", log_path, e);
+            return;
+        }
+    };
+    match &*HANDLE.lock().unwrap_or_else(|e| e.into_inner()) {
+        Some(handle) => {
+            handle.set_config(config);
+            info!("Reloaded log config {:?}", log_path);
+        }
+        None => warn!("Logger not initialized, unable to reload"),
+    }
+}
+
 /// Initialize a fallback configurated logger.
 /// Consisting of log to conole & if possible to file.
 fn init_config() {
@@ -74,7 +114,11 @@ fn init_config() {
 
     let config = config_builder.build(root).unwrap();
 
-    println!("Log fallback init: {}", log4rs::init_config(config).is_ok());
+    let handle = log4rs::init_config(config);
+    println!("Log fallback init: {}", handle.is_ok());
+    if let Ok(handle) = handle {
+        set_handle(handle);
+    }
     trace!("Log fallback init");
 
     if !file_success {
@@ -0,0 +1,368 @@
+//! Prometheus metrics
+//!
+//! Collected in memory by the workers, rendered in the text exposition format on `/metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use chrono::Utc;
+use tiny_http::{Header, Response, Server};
+
+use crate::lib::config::ConfigMetrics;
+use crate::lib::db::Repository;
+use crate::lib::{Error, Result};
+
+/// yt-dl download stage
+pub const STAGE_DOWNLOAD: &str = "download";
+/// ffmpeg audio & video merge stage
+pub const STAGE_MERGE: &str = "merge";
+/// ffmpeg audio extraction stage
+pub const STAGE_EXTRACT: &str = "extract";
+/// playlist zip stage
+pub const STAGE_ZIP: &str = "zip";
+
+/// Cleanup of aged files
+pub const CLEANUP_AGED: &str = "aged";
+/// Cleanup of files marked for deletion
+pub const CLEANUP_MARKED: &str = "marked";
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+/// State of the installed yt-dl
+#[derive(Default)]
+struct YtdlState {
+    version: Option<String>,
+    /// success & unix timestamp of the last update
+    last_update: Option<(bool, i64)>,
+}
+
+/// Collected metrics
+#[derive(Default)]
+struct Metrics {
+    /// finished jobs by handler & code
+    jobs: Mutex<BTreeMap<(String, i8), u64>>,
+    /// stage durations: sum in seconds & count
+    stages: Mutex<BTreeMap<&'static str, (f64, u64)>>,
+    downloaded_bytes: AtomicU64,
+    /// cleanup runs by type & success
+    cleanup_runs: Mutex<BTreeMap<(&'static str, bool), u64>>,
+    /// files by cleanup type & success of their deletion
+    cleanup_files: Mutex<BTreeMap<(&'static str, bool), u64>>,
+    /// findings of the last reconciliation by kind
+    reconcile: Mutex<BTreeMap<&'static str, u64>>,
+    ytdl: Mutex<YtdlState>,
+}
+
+/// Measures the duration of a stage until dropped
+pub struct StageTimer {
+    stage: &'static str,
+    start: Instant,
+}
+
+impl Drop for StageTimer {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed().as_secs_f64();
+        let mut stages = METRICS.stages.lock().unwrap();
+        let entry = stages.entry(self.stage).or_insert((0.0, 0));
+        entry.0 += duration;
+        entry.1 += 1;
+    }
+}
+
+/// Start measuring the duration of a stage, recorded when the timer is dropped
+pub fn stage_timer(stage: &'static str) -> StageTimer {
+    StageTimer {
+        stage,
+        start: Instant::now(),
+    }
+}
+
+/// Record a finished job with its final status code
+pub fn job_finished(handler: &str, code: i8) {
+    *METRICS
+        .jobs
+        .lock()
+        .unwrap()
+        .entry((handler.to_string(), code))
+        .or_insert(0) += 1;
+}
+
+/// Record downloaded bytes
+pub fn add_downloaded_bytes(bytes: u64) {
+    METRICS.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Record a cleanup run
+pub fn cleanup_run(cleanup: &'static str, success: bool) {
+    *METRICS
+        .cleanup_runs
+        .lock()
+        .unwrap()
+        .entry((cleanup, success))
+        .or_insert(0) += 1;
+}
+
+/// Record deleted & failed files of a cleanup run
+pub fn cleanup_files(cleanup: &'static str, deleted: u64, failed: u64) {
+    let mut files = METRICS.cleanup_files.lock().unwrap();
+    *files.entry((cleanup, true)).or_insert(0) += deleted;
+    *files.entry((cleanup, false)).or_insert(0) += failed;
+}
+
+/// Record the findings of a reconciliation run
+pub fn reconcile_findings(orphans: usize, missing: usize, stale_workspaces: usize) {
+    let mut reconcile = METRICS.reconcile.lock().unwrap();
+    reconcile.insert("orphans", orphans as u64);
+    reconcile.insert("missing", missing as u64);
+    reconcile.insert("stale_workspaces", stale_workspaces as u64);
+}
+
+/// Set the installed yt-dl version
+pub fn set_ytdl_version(version: &str) {
+    METRICS.ytdl.lock().unwrap().version = Some(version.to_string());
+}
+
+/// Record the outcome of a yt-dl update
+pub fn ytdl_updated(success: bool) {
+    METRICS.ytdl.lock().unwrap().last_update = Some((success, Utc::now().timestamp()));
+}
+
+/// Start the metrics server
+pub fn start(db: Arc<dyn Repository>, conf: &ConfigMetrics) -> Result<()> {
+    let server = Server::http(&conf.listen).map_err(|e| {
+        Error::InternalError(format!("Unable to bind metrics to {}: {}", conf.listen, e))
+    })?;
+    info!("Metrics listening on {}", conf.listen);
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let path = request.url().split('?').next().unwrap_or_default();
+            let response = if path == "/metrics" {
+                let queue = match db.get_queue_stats() {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        warn!("Unable to retrieve queue stats: {}", e);
+                        None
+                    }
+                };
+                Response::from_string(METRICS.render(queue)).with_header(
+                    "Content-Type: text/plain; version=0.0.4"
+                        .parse::<Header>()
+                        .expect("valid header"),
+                )
+            } else {
+                Response::from_string("not found").with_status_code(404)
+            };
+            if let Err(e) = request.respond(response) {
+                debug!("Unable to send metrics response: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Write the HELP & TYPE lines of a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Returns the label value for a success flag
+fn result_label(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+impl Metrics {
+    /// Render all metrics, queue: waiting jobs & age of the oldest one in seconds
+    fn render(&self, queue: Option<(u64, Option<i64>)>) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "yayd_jobs_finished_total",
+            "counter",
+            "Finished jobs by handler and final status code",
+        );
+        for ((handler, code), count) in self.jobs.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "yayd_jobs_finished_total{{handler=\"{}\",code=\"{}\"}} {}",
+                escape(handler),
+                code,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "yayd_stage_duration_seconds",
+            "summary",
+            "Duration of processing stages",
+        );
+        for (stage, (sum, count)) in self.stages.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "yayd_stage_duration_seconds_sum{{stage=\"{}\"}} {}",
+                stage, sum
+            );
+            let _ = writeln!(
+                out,
+                "yayd_stage_duration_seconds_count{{stage=\"{}\"}} {}",
+                stage, count
+            );
+        }
+
+        header(
+            &mut out,
+            "yayd_downloaded_bytes_total",
+            "counter",
+            "Size of all downloaded files",
+        );
+        let _ = writeln!(
+            out,
+            "yayd_downloaded_bytes_total {}",
+            self.downloaded_bytes.load(Ordering::Relaxed)
+        );
+
+        if let Some((waiting, oldest)) = queue {
+            header(&mut out, "yayd_queue_waiting", "gauge", "Waiting jobs");
+            let _ = writeln!(out, "yayd_queue_waiting {}", waiting);
+            header(
+                &mut out,
+                "yayd_queue_oldest_age_seconds",
+                "gauge",
+                "Age of the oldest waiting job",
+            );
+            let _ = writeln!(out, "yayd_queue_oldest_age_seconds {}", oldest.unwrap_or(0));
+        }
+
+        header(
+            &mut out,
+            "yayd_cleanup_runs_total",
+            "counter",
+            "Cleanup runs by type and result",
+        );
+        for ((cleanup, success), count) in self.cleanup_runs.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "yayd_cleanup_runs_total{{type=\"{}\",result=\"{}\"}} {}",
+                cleanup,
+                result_label(*success),
+                count
+            );
+        }
+        header(
+            &mut out,
+            "yayd_cleanup_files_total",
+            "counter",
+            "Files handled by cleanups, by type and result of their deletion",
+        );
+        for ((cleanup, success), count) in self.cleanup_files.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "yayd_cleanup_files_total{{type=\"{}\",result=\"{}\"}} {}",
+                cleanup,
+                result_label(*success),
+                count
+            );
+        }
+        header(
+            &mut out,
+            "yayd_reconcile_findings",
+            "gauge",
+            "Findings of the last reconciliation run",
+        );
+        for (kind, count) in self.reconcile.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "yayd_reconcile_findings{{kind=\"{}\"}} {}",
+                kind, count
+            );
+        }
+
+        let ytdl = self.ytdl.lock().unwrap();
+        if let Some(ref version) = ytdl.version {
+            header(
+                &mut out,
+                "yayd_ytdl_info",
+                "gauge",
+                "Installed yt-dl version",
+            );
+            let _ = writeln!(out, "yayd_ytdl_info{{version=\"{}\"}} 1", escape(version));
+        }
+        if let Some((success, time)) = ytdl.last_update {
+            header(
+                &mut out,
+                "yayd_ytdl_last_update_success",
+                "gauge",
+                "Outcome of the last yt-dl update",
+            );
+            let _ = writeln!(out, "yayd_ytdl_last_update_success {}", success as u8);
+            header(
+                &mut out,
+                "yayd_ytdl_last_update_timestamp_seconds",
+                "gauge",
+                "Time of the last yt-dl update",
+            );
+            let _ = writeln!(out, "yayd_ytdl_last_update_timestamp_seconds {}", time);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics
+            .jobs
+            .lock()
+            .unwrap()
+            .insert((String::from("youtube"), 2), 3);
+        metrics.stages.lock().unwrap().insert(STAGE_MERGE, (1.5, 2));
+        metrics.downloaded_bytes.store(1024, Ordering::Relaxed);
+        metrics
+            .cleanup_files
+            .lock()
+            .unwrap()
+            .insert((CLEANUP_AGED, false), 1);
+        metrics.ytdl.lock().unwrap().version = Some(String::from("2021.10.10"));
+
+        let out = metrics.render(Some((4, Some(120))));
+        assert!(out.contains("yayd_jobs_finished_total{handler=\"youtube\",code=\"2\"} 3\n"));
+        assert!(out.contains("yayd_stage_duration_seconds_sum{stage=\"merge\"} 1.5\n"));
+        assert!(out.contains("yayd_stage_duration_seconds_count{stage=\"merge\"} 2\n"));
+        assert!(out.contains("yayd_downloaded_bytes_total 1024\n"));
+        assert!(out.contains("yayd_queue_waiting 4\n"));
+        assert!(out.contains("yayd_queue_oldest_age_seconds 120\n"));
+        assert!(out.contains("yayd_cleanup_files_total{type=\"aged\",result=\"failure\"} 1\n"));
+        assert!(out.contains("yayd_ytdl_info{version=\"2021.10.10\"} 1\n"));
+        assert!(!out.contains("yayd_ytdl_last_update_success"));
+        assert!(!metrics.render(None).contains("yayd_queue_waiting"));
+    }
+
+    #[test]
+    fn label_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -1,27 +1,41 @@
 extern crate regex;
 extern crate zip;
 
+pub mod api;
+pub mod bandwidth;
 pub mod config;
+pub mod console;
 pub mod converter;
+pub mod credentials;
 pub mod db;
 pub mod downloader;
+pub mod failure;
+pub mod ffmpeg;
 pub mod http;
 pub mod logger;
+pub mod metrics;
+pub mod notify;
+pub mod process;
+pub mod proxy;
+pub mod quota;
+pub mod reconcile;
+pub mod signals;
+pub mod storage;
 
+use crate::lib::db::Repository;
 use crate::lib::downloader::Filename;
+use crate::lib::failure::Failure;
+use crate::lib::storage::Storage;
 
 use mysql;
-use mysql::{Pool, PooledConn};
 use thiserror::Error;
 
-use std::cell::RefCell;
-use std::cell::RefMut;
 use std::env::current_exe;
 use std::error::Error as OriginError;
-use std::fs::remove_file;
 use std::fs::{metadata, read_dir, rename, File};
 use std::io::copy;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::{self, io};
 
 use crate::CONFIG;
@@ -45,9 +59,19 @@ pub struct Request {
     /// Path for temp save folder, can be changed to, for example, sub dirs
     /// If it should differe from the default folder this folder will be deleted on failure with all it's content!
     pub temp_path: PathBuf,
-    pub conn: RefCell<PooledConn>,
+    pub db: Arc<dyn Repository>,
     /// User ID, needed for non-zipped playlist downloads, creating new query & job entries
     pub uid: u32,
+    /// Name of the handling module, set by the registry, selects the proxy pool
+    pub handler: Option<&'static str>,
+    /// Additional yt-dl arguments of the job, like its credential profile
+    pub ytdl_args: Vec<String>,
+    /// Set by handlers finishing with warnings, like failed playlist entries
+    pub warnings: bool,
+    /// Set by the registry if the job was served from the result cache
+    pub cached: bool,
+    /// qid of the playlist job whose failed entries are retried, set by the registry
+    pub retry: Option<u64>,
 }
 
 /// Core for assertions
@@ -85,8 +109,8 @@ impl ReqCore {
 }
 
 impl<'a> Request {
-    pub fn get_conn(&self) -> RefMut<PooledConn> {
-        self.conn.borrow_mut()
+    pub fn db(&self) -> &dyn Repository {
+        &*self.db
     }
 }
 
@@ -101,11 +125,12 @@ pub enum Error {
     #[error("Failed to convert: `{0}`")]
     FFMPEGError(String),
     /// Content down as of region lock, could be bypassed, see youtube handler
+    /// Contains the output of yt-dl.
     #[error("DMCA error, failed to retrieve source")]
-    DMCAError,
-    /// Unavailable (login, region lock etc)
-    #[error("Source not available, region lock or login required")]
-    NotAvailable,
+    DMCAError(String),
+    /// Source unavailable for a known reason, contains the output of yt-dl
+    #[error("Source unavailable, {}: `{1}`", .0.message())]
+    Unavailable(Failure, String),
     /// Quality not available => valid input, but unavailable
     #[error("Quality not vailable for source")]
     QualityNotAvailable,
@@ -121,16 +146,55 @@ pub enum Error {
     /// Can't handle this URL, no valid handler found
     #[error("Unknown URL, can't handle this website")]
     UnknownURL,
+    /// Storage quota or free disk space exceeded
+    #[error("Storage quota exceeded: `{0}`")]
+    QuotaExceeded(String),
+    /// Process killed after exceeding a time limit or stalling
+    #[error("Timeout: `{0}`")]
+    Timeout(String),
+    /// Connection via proxy failed, retried with the next proxy of the pool
+    #[error("Proxy error: `{0}`")]
+    ProxyError(String),
     #[error("Database error: `{0}`")]
     MysqlError(#[from] mysql::Error),
     #[error("Database error: `{0}`")]
     MysqlDeserializeError(#[from] mysql::FromRowError),
+    #[error("Database error: `{0}`")]
+    PostgresError(#[from] postgres::Error),
+    #[error("Database error: `{0}`")]
+    PostgresPoolError(#[from] r2d2_postgres::r2d2::Error),
+    #[error("Database error: `{0}`")]
+    SqliteError(#[from] rusqlite::Error),
     #[error("Internal Error: `{0}`")]
-    IOError(#[from] std::io::Error)
+    IOError(#[from] std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// Short name of the error kind, stored for failed playlist entries
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Unavailable(kind, _) => kind.as_str(),
+            Error::DMCAError(_) => Failure::GeoBlocked.as_str(),
+            Error::QualityNotAvailable => "quality",
+            Error::ExtractorError => "extractor",
+            Error::UnknownURL => "unknown_url",
+            Error::QuotaExceeded(_) => "quota",
+            Error::Timeout(_) => "timeout",
+            _ => "internal",
+        }
+    }
+
+    /// Raw error details for admins, the yt-dl output if available
+    pub fn details(&self) -> String {
+        match self {
+            Error::Unavailable(_, output) | Error::DMCAError(output) => output.clone(),
+            e => e.to_string(),
+        }
+    }
+}
+
 impl From<zip::result::ZipError> for Error {
     fn from(err: zip::result::ZipError) -> Error {
         Error::InternalError(err.to_string())
@@ -233,6 +297,7 @@ pub fn format_save_path<'a>(path: &Path, fname: &Filename) -> Result<PathBuf> {
 /// Zips all files inside folder into one file
 pub fn zip_folder(folder: &Path, destination: &Path) -> Result<()> {
     trace!("Starting zipping..");
+    let _timer = metrics::stage_timer(metrics::STAGE_ZIP);
     if metadata(folder)?.is_dir() {
         let output_file = File::create(destination)?;
         let mut writer = zip::ZipWriter::new(output_file);
@@ -258,6 +323,41 @@ pub fn zip_folder(folder: &Path, destination: &Path) -> Result<()> {
     }
 }
 
+/// Extract the files of a zip archive into the folder
+pub fn unzip_folder(archive: &Path, folder: &Path) -> Result<()> {
+    trace!("Extracting {:?}", archive);
+    let mut reader = zip::ZipArchive::new(File::open(archive)?)?;
+    reader.extract(folder)?;
+    Ok(())
+}
+
+lazy_static! {
+    static ref WORKER_ID: String = format!("{}:{}", hostname(), std::process::id());
+}
+
+/// Returns the id of this yayd instance: host & process id
+pub fn worker_id() -> &'static str {
+    &WORKER_ID
+}
+
+/// Returns the host name, "localhost" if it can't be retrieved
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| String::from("localhost"))
+}
+
+/// Returns the per-query workspace inside the temp dir
+/// Kept over restarts for resumable downloads, removed after the query was handled
+pub fn workspace_path(qid: &u64) -> PathBuf {
+    let mut path = PathBuf::from(&CONFIG.general.temp_dir);
+    path.push(qid.to_string());
+    path
+}
+
 /// Returns the current executable folder
 pub fn get_executable_folder() -> std::result::Result<std::path::PathBuf, io::Error> {
     let mut folder = current_exe()?;
@@ -265,44 +365,99 @@ pub fn get_executable_folder() -> std::result::Result<std::path::PathBuf, io::Er
     Ok(folder)
 }
 
+/// Returns the qids & files (fid, name) to be removed by `delete_files`
+/// Aged files shared with live queries are kept, as are marked files still referenced by other queries
+pub fn files_to_delete(
+    db: &dyn Repository,
+    delete_type: db::DeleteRequestType,
+) -> Result<(Vec<u64>, Vec<(u64, String)>)> {
+    select_deletions(db, delete_type).map(|(qids, files, _)| (qids, files))
+}
+
+/// See `files_to_delete`, additionally returns the file marks to move onto a link: (qid, fid)
+/// A marked file which is shared with other queries is only removed for the query which created it
+fn select_deletions(
+    db: &dyn Repository,
+    delete_type: db::DeleteRequestType,
+) -> Result<(Vec<u64>, Vec<(u64, String)>, Vec<(u64, u64)>)> {
+    let (mut qids, mut files) = db.get_files_to_delete(delete_type)?;
+
+    let mut shared = Vec::new();
+    let mut live_qids = Vec::new();
+    let mut moved_marks = Vec::new();
+    for &(fid, _) in &files {
+        let refs = match delete_type {
+            // shared files are kept as long as any query linked to them is alive
+            db::DeleteRequestType::AgedMin(age) => db.get_live_file_refs(&fid, age)?,
+            // only the marked links are removed while other queries reference the file
+            db::DeleteRequestType::Marked => {
+                let mut refs = db.get_file_refs(&fid)?;
+                if let Some(owner) = marked_file_owner(db, &fid)? {
+                    refs.retain(|qid| *qid != owner);
+                    if !refs.is_empty() {
+                        moved_marks.push((owner, fid));
+                    }
+                }
+                refs
+            }
+        };
+        if !refs.is_empty() {
+            trace!("keeping shared file {}, referenced by {:?}", fid, refs);
+            shared.push(fid);
+            live_qids.extend(refs);
+        }
+    }
+    files.retain(|&(fid, _)| !shared.contains(&fid));
+    qids.retain(|qid| !live_qids.contains(qid));
+    Ok((qids, files, moved_marks))
+}
+
+/// Returns the query which created the file, if the file itself is marked for deletion
+fn marked_file_owner(db: &dyn Repository, fid: &u64) -> Result<Option<u64>> {
+    match db.get_file(fid)? {
+        Some(file) if file.delete => Ok(db
+            .get_file_links(fid)?
+            .into_iter()
+            .map(|(qid, _)| qid)
+            .min()),
+        _ => Ok(None),
+    }
+}
+
 /// Delete files aged or marked for removal
 /// Additionally erases the DB entries if configured to do so
-/// dir_path markes the directory the files are located in
+/// storage is the backend the files are located in
 pub fn delete_files(
-    pool: &Pool,
+    db: &dyn Repository,
     delete_type: db::DeleteRequestType,
-    dir_path: &Path,
+    storage: &dyn Storage,
 ) -> Result<()> {
-    let mut conn = pool.get_conn()?;
-    let (qids, mut files) = db::get_files_to_delete(&mut conn, delete_type)?;
+    let (qids, mut files, moved_marks) = select_deletions(db, delete_type)?;
+    // the file is kept for the other queries, only the link of its creator is removed
+    for (qid, fid) in moved_marks {
+        db.set_file_link_delete_flag(&qid, &fid, true)?;
+        db.set_file_delete_flag(&fid, false)?;
+    }
 
     debug!("Len before: {}", files.len());
-    files.retain(|&(_, ref url)| {
-        // remove all not matching
-        trace!("deleting {:?}", url);
-        let mut path = dir_path.to_path_buf();
-        path.push(url);
-        match remove_file(&path) {
-            Ok(_) => true,
-            Err(e) => {
-                if path.exists() {
-                    error!("Couldn't delete file {:?} {:?}", path, e);
-                    false
-                } else {
-                    warn!("File was already deleted: {:?}", path);
-                    true
-                }
-            }
-        }
-    });
+    let total = files.len();
+    files.retain(|&(_, ref url)| storage::remove_logged(storage, url));
     debug!("Len after: {}", files.len());
+    let cleanup = match delete_type {
+        db::DeleteRequestType::AgedMin(_) => metrics::CLEANUP_AGED,
+        db::DeleteRequestType::Marked => metrics::CLEANUP_MARKED,
+    };
+    metrics::cleanup_files(cleanup, files.len() as u64, (total - files.len()) as u64);
     if CONFIG.cleanup.auto_delete_request {
-        db::delete_requests(&mut conn, qids, files)?;
+        db.delete_requests(qids, files)?;
     } else {
         for (fid, _) in files {
-            db::set_file_delete_flag(&mut conn, &fid, false)?;
-            db::set_file_valid_flag(&mut conn, &fid, false)?;
+            db.set_file_delete_flag(&fid, false)?;
+            db.set_file_valid_flag(&fid, false)?;
         }
     }
+    if let db::DeleteRequestType::Marked = delete_type {
+        db.remove_marked_file_links()?;
+    }
     Ok(())
 }
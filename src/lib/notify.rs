@@ -0,0 +1,378 @@
+//! Notifications about finished jobs
+//!
+//! Notifications are queued in the `notifyoutbox` table when a job is finished
+//! and delivered by a scheduled task, retrying failed deliveries.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::lib::config::{self, ConfigNotify};
+use crate::lib::db::Repository;
+use crate::lib::failure::Failure;
+use crate::lib::http;
+use crate::lib::{Error, Result};
+
+/// Webhook target, an URL receiving a signed JSON POST
+pub const KIND_WEBHOOK: &str = "webhook";
+/// Email target, an address
+pub const KIND_EMAIL: &str = "email";
+/// Header containing the webhook signature
+const SIGNATURE_HEADER: &str = "X-Yayd-Signature";
+/// Timeout for SMTP connections
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns a readable name of the status code
+/// Classified failures use the message of their kind, see `Failure::message`
+pub fn status_name(code: i8) -> &'static str {
+    match code {
+        crate::CODE_WAITING => "waiting",
+        crate::CODE_STARTED => "started",
+        crate::CODE_IN_PROGRESS => "running",
+        crate::CODE_SUCCESS => "finished",
+        crate::CODE_SUCCESS_WARNINGS => "finished with warnings",
+        crate::CODE_FAILED_INTERNAL => "internal error",
+        crate::CODE_FAILED_QUALITY => "quality not available",
+        crate::CODE_FAILED_UNAVAILABLE => "source unavailable",
+        crate::CODE_FAILED_UNKNOWN => "unknown URL",
+        crate::CODE_FAILED_QUOTA => "storage quota exceeded",
+        crate::CODE_FAILED_TIMEOUT => "timeout",
+        _ => Failure::from_code(code).map_or("unknown", |kind| kind.message()),
+    }
+}
+
+/// Returns true if the target is valid for the kind
+pub fn valid_target(kind: &str, target: &str) -> bool {
+    if target.is_empty() || target.len() > 255 || target.contains(['\r', '\n']) {
+        return false;
+    }
+    match kind {
+        KIND_WEBHOOK => target.starts_with("http://") || target.starts_with("https://"),
+        KIND_EMAIL => target.contains('@') && !target.contains(['<', '>']),
+        _ => false,
+    }
+}
+
+/// Queue notifications for all targets of the finished query
+pub fn job_finished(db: &dyn Repository, qid: &u64) -> Result<()> {
+    let info = match db.get_query_info(qid)? {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let targets = db.get_notify_targets(qid, &info.uid)?;
+    if targets.is_empty() {
+        return Ok(());
+    }
+    let files: Vec<Value> = db
+        .get_query_files(qid)?
+        .into_iter()
+        .map(|(name, real_name)| json!({ "name": name, "real_name": real_name }))
+        .collect();
+    let payload = json!({
+        "qid": info.qid,
+        "uid": info.uid,
+        "url": info.url,
+        "code": info.code,
+        "status": status_name(info.code),
+        "files": files,
+        "error": info.error,
+    })
+    .to_string();
+    for (kind, target) in targets {
+        trace!("queueing {} notification for {}", kind, qid);
+        db.add_notification(qid, &kind, &target, &payload)?;
+    }
+    Ok(())
+}
+
+/// Deliver all due notifications of the outbox
+/// Failed deliveries are retried later, until the max attempts are reached
+pub fn deliver_pending(db: &dyn Repository) -> Result<()> {
+    let conf = &config::live().notify;
+    for notification in db.get_due_notifications()? {
+        match deliver(
+            conf,
+            &notification.kind,
+            &notification.target,
+            &notification.payload,
+        ) {
+            Ok(_) => {
+                debug!("delivered notification {}", notification.id);
+                db.remove_notification(&notification.id)?;
+            }
+            Err(e) => {
+                let attempts = notification.attempts + 1;
+                if attempts >= conf.max_attempts {
+                    error!(
+                        "Dropping notification {} for {} after {} attempts: {}",
+                        notification.id, notification.qid, attempts, e
+                    );
+                    db.remove_notification(&notification.id)?;
+                } else {
+                    warn!(
+                        "Notification {} for {} failed: {}",
+                        notification.id, notification.qid, e
+                    );
+                    let delay = conf.retry_delay * attempts as u32;
+                    db.retry_notification(&notification.id, &delay)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deliver a single notification
+fn deliver(conf: &ConfigNotify, kind: &str, target: &str, payload: &str) -> Result<()> {
+    if !valid_target(kind, target) {
+        return Err(Error::InputError(format!(
+            "invalid {} target {:?}",
+            kind, target
+        )));
+    }
+    match kind {
+        KIND_WEBHOOK => http::http_post_json(
+            target,
+            payload,
+            &[(
+                SIGNATURE_HEADER,
+                format!("sha256={}", sign(&conf.webhook_secret, payload)),
+            )],
+        ),
+        _ => {
+            let (subject, body) = format_email(payload)?;
+            send_email(conf, target, &subject, &body)
+        }
+    }
+}
+
+/// Returns the hex encoded HMAC-SHA256 of the payload
+fn sign(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(payload.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Returns subject & body of the email for the payload
+fn format_email(payload: &str) -> Result<(String, String)> {
+    let payload: Value = serde_json::from_str(payload)
+        .map_err(|e| Error::InternalError(format!("invalid payload: {}", e)))?;
+    let subject = format!(
+        "yayd job {} {}",
+        payload["qid"],
+        payload["status"].as_str().unwrap_or_default()
+    );
+    let mut body = format!(
+        "Your job {} for {} is {}.\r\n",
+        payload["qid"],
+        payload["url"].as_str().unwrap_or_default(),
+        payload["status"].as_str().unwrap_or_default()
+    );
+    if let Some(files) = payload["files"].as_array() {
+        if !files.is_empty() {
+            body.push_str("\r\nFiles:\r\n");
+            for file in files {
+                body.push_str(&format!(
+                    "- {} ({})\r\n",
+                    file["real_name"].as_str().unwrap_or_default(),
+                    file["name"].as_str().unwrap_or_default()
+                ));
+            }
+        }
+    }
+    if let Some(error) = payload["error"].as_str() {
+        body.push_str(&format!("\r\nError: {}\r\n", error));
+    }
+    Ok((subject, body))
+}
+
+/// Send a plain text email via SMTP
+fn send_email(conf: &ConfigNotify, to: &str, subject: &str, body: &str) -> Result<()> {
+    let addr = (conf.smtp_host.as_str(), conf.smtp_port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::InternalError(format!("Unable to resolve {}", conf.smtp_host)))?;
+    let stream = TcpStream::connect_timeout(&addr, SMTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(SMTP_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    smtp_expect(&mut reader, 220)?;
+    smtp_command(&mut writer, &mut reader, "EHLO localhost", 250)?;
+    smtp_command(
+        &mut writer,
+        &mut reader,
+        &format!("MAIL FROM:<{}>", conf.mail_from),
+        250,
+    )?;
+    smtp_command(&mut writer, &mut reader, &format!("RCPT TO:<{}>", to), 250)?;
+    smtp_command(&mut writer, &mut reader, "DATA", 354)?;
+    let message = format!(
+        "From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\nDate: {}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}",
+        conf.mail_from,
+        to,
+        subject,
+        Utc::now().to_rfc2822(),
+        body
+    );
+    // dot stuffing, lines starting with a dot would end the data otherwise
+    let message = message.replace("\r\n.", "\r\n..");
+    writer.write_all(message.as_bytes())?;
+    smtp_command(&mut writer, &mut reader, "\r\n.", 250)?;
+    smtp_command(&mut writer, &mut reader, "QUIT", 221)?;
+    Ok(())
+}
+
+/// Send a SMTP command, expecting the reply code
+fn smtp_command<W: Write, R: BufRead>(
+    writer: &mut W,
+    reader: &mut R,
+    command: &str,
+    code: u16,
+) -> Result<()> {
+    trace!("SMTP: {}", command);
+    writer.write_all(command.as_bytes())?;
+    writer.write_all(b"\r\n")?;
+    writer.flush()?;
+    smtp_expect(reader, code)
+}
+
+/// Read a (multiline) SMTP reply, expecting the code
+fn smtp_expect<R: BufRead>(reader: &mut R, code: u16) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::InternalError("SMTP connection closed".into()));
+        }
+        trace!("SMTP reply: {}", line.trim_end());
+        if line.len() < 4 || !line.starts_with(&code.to_string()) {
+            return Err(Error::InternalError(format!(
+                "Unexpected SMTP reply: {}",
+                line.trim_end()
+            )));
+        }
+        // "250-" continues, "250 " ends the reply
+        if line.as_bytes()[3] != b'-' {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn targets() {
+        assert!(valid_target(KIND_WEBHOOK, "https://example.com/hook"));
+        assert!(!valid_target(KIND_WEBHOOK, "ftp://example.com"));
+        assert!(valid_target(KIND_EMAIL, "user@example.com"));
+        assert!(!valid_target(
+            KIND_EMAIL,
+            "user@example.com>\r\nRCPT TO:<other@example.com"
+        ));
+        assert!(!valid_target("sms", "123"));
+    }
+
+    #[test]
+    fn status_names() {
+        assert_eq!("finished", status_name(crate::CODE_SUCCESS));
+        assert_eq!(
+            Failure::Private.message(),
+            status_name(crate::CODE_FAILED_PRIVATE)
+        );
+        assert_eq!("unknown", status_name(99));
+    }
+
+    #[test]
+    fn email_format() {
+        let payload = json!({
+            "qid": 12,
+            "url": "https://youtu.be/x",
+            "status": "finished",
+            "files": [{"name": "a.mp4", "real_name": "A"}],
+            "error": null,
+        })
+        .to_string();
+        let (subject, body) = format_email(&payload).unwrap();
+        assert_eq!(subject, "yayd job 12 finished");
+        assert!(body.contains("- A (a.mp4)\r\n"));
+        assert!(!body.contains("Error"));
+    }
+
+    #[test]
+    fn smtp_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sink = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 sink\r\n").unwrap();
+            let mut received = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-sink\r\n250 8BITMIME\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                stream.write_all(reply).unwrap();
+            }
+            let mut rest = String::new();
+            let _ = reader.read_to_string(&mut rest);
+            received
+        });
+
+        let conf = ConfigNotify {
+            smtp_port: port,
+            ..Default::default()
+        };
+        send_email(&conf, "user@localhost", "subject", "body\r\n.dot\r\n").unwrap();
+        let received = sink.join().unwrap();
+        assert!(received.contains("RCPT TO:<user@localhost>\r\n"));
+        assert!(received.contains("Subject: subject\r\n"));
+        assert!(received.contains("\r\n..dot\r\n"));
+        assert!(received.ends_with(".\r\nQUIT\r\n"));
+    }
+}
@@ -0,0 +1,621 @@
+//! Process layer, spawning yt-dl, ffmpeg & the lib
+//! Swappable by recorded transcripts, allowing the output parsing to be tested offline
+
+use std::collections::HashSet;
+#[cfg(test)]
+use std::io::Cursor;
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::lib::config::ConfigTimeouts;
+use crate::lib::{Error, Result};
+
+/// Polling interval of watchdogs
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Spawns processes for commands
+pub trait Runner: Send + Sync {
+    /// Spawn the command, stdin/stdout/stderr are configured by the caller
+    fn spawn(&self, cmd: &mut Command) -> Result<Box<dyn Process>>;
+
+    /// Run the command to completion, collecting all output
+    fn output(&self, cmd: &mut Command) -> Result<Output> {
+        let mut process = self.spawn(cmd)?;
+        let stdout = read_all(process.take_stdout())?;
+        let stderr = read_all(process.take_stderr())?;
+        let success = process.wait()?;
+        Ok(Output {
+            stdout,
+            stderr,
+            success,
+        })
+    }
+}
+
+/// Running process
+pub trait Process: Send {
+    fn take_stdout(&mut self) -> Option<Box<dyn Read + Send>>;
+    fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>>;
+    /// Wait for the process to exit, returns true on success
+    fn wait(&mut self) -> Result<bool>;
+    /// Process id, None if not backed by a real process
+    fn id(&self) -> Option<u32>;
+}
+
+/// Output of a finished process
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    pub success: bool,
+}
+
+lazy_static! {
+    /// Process ids of all running processes spawned by the SystemRunner
+    static ref RUNNING: Mutex<HashSet<u32>> = Mutex::new(HashSet::new());
+}
+
+/// Runner spawning real processes
+/// Every process gets its own process group, so a SIGINT of the terminal doesn't reach it
+/// and `kill_running` also kills its children.
+pub struct SystemRunner;
+
+impl SystemRunner {
+    fn start(cmd: &mut Command) -> Result<Tracked> {
+        #[cfg(unix)]
+        cmd.process_group(0);
+        let child = cmd.spawn()?;
+        RUNNING.lock()?.insert(child.id());
+        Ok(Tracked(Some(child)))
+    }
+}
+
+impl Runner for SystemRunner {
+    fn spawn(&self, cmd: &mut Command) -> Result<Box<dyn Process>> {
+        Ok(Box::new(SystemRunner::start(cmd)?))
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<Output> {
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        collect(&mut SystemRunner::start(cmd)?)
+    }
+}
+
+/// Kill all running processes & their children
+/// Returns the amount of killed processes
+pub fn kill_running() -> usize {
+    let running = match RUNNING.lock() {
+        Ok(v) => v,
+        Err(e) => e.into_inner(),
+    };
+    for pid in running.iter() {
+        warn!("killing process {}", pid);
+        kill(*pid);
+    }
+    running.len()
+}
+
+/// Kill a running process & its children, returns false if it already exited
+/// Processes are untracked before being reaped, so its pid can't be reused meanwhile.
+fn kill_tracked(pid: u32) -> bool {
+    let running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+    if !running.contains(&pid) {
+        return false;
+    }
+    kill(pid);
+    true
+}
+
+/// Kill a process spawned by the SystemRunner & its children
+fn kill(pid: u32) {
+    #[cfg(unix)]
+    unsafe {
+        // negative pid: the whole process group
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+/// Runtime limits of a process
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// Max runtime
+    pub max: Option<Duration>,
+    /// Max time without progress
+    pub stall: Option<Duration>,
+}
+
+impl Limits {
+    /// Limits in seconds, 0 = unlimited
+    pub fn secs(max: u32, stall: u32) -> Limits {
+        let limit = |v: u32| match v {
+            0 => None,
+            v => Some(Duration::from_secs(v as u64)),
+        };
+        Limits {
+            max: limit(max),
+            stall: limit(stall),
+        }
+    }
+}
+
+/// Limits of the process stages of a job
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    pub download: Limits,
+    pub convert: Limits,
+    /// Metadata requests, which don't report progress
+    pub probe: Limits,
+}
+
+impl Timeouts {
+    pub fn from_config(conf: &ConfigTimeouts) -> Timeouts {
+        Timeouts {
+            download: Limits::secs(conf.download, conf.stall),
+            convert: Limits::secs(conf.convert, conf.stall),
+            probe: Limits::secs(conf.probe, 0),
+        }
+    }
+}
+
+/// Watchdog killing a process exceeding its limits
+pub struct Watchdog {
+    state: Arc<WatchState>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct WatchState {
+    done: AtomicBool,
+    last_progress: Mutex<Instant>,
+    /// Reason of the kill
+    timeout: Mutex<Option<String>>,
+}
+
+impl Watchdog {
+    /// Watch the process of the stage, without limits nothing is watched
+    pub fn start(process: &dyn Process, stage: &str, limits: Limits) -> Watchdog {
+        let state = Arc::new(WatchState {
+            done: AtomicBool::new(false),
+            last_progress: Mutex::new(Instant::now()),
+            timeout: Mutex::new(None),
+        });
+        if limits == Limits::default() {
+            return Watchdog {
+                state,
+                handle: None,
+            };
+        }
+        let pid = process.id();
+        let stage = stage.to_string();
+        let state_c = state.clone();
+        let handle = thread::spawn(move || {
+            let started = Instant::now();
+            while !state_c.done.load(Ordering::SeqCst) {
+                let idle = state_c
+                    .last_progress
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .elapsed();
+                let reason = match (limits.max, limits.stall) {
+                    (Some(max), _) if started.elapsed() >= max => {
+                        format!("{} exceeded the limit of {}", stage, format_secs(max))
+                    }
+                    (_, Some(stall)) if idle >= stall => {
+                        format!("{} stalled, no progress for {}", stage, format_secs(stall))
+                    }
+                    _ => {
+                        // unparked by finish
+                        thread::park_timeout(WATCH_INTERVAL);
+                        continue;
+                    }
+                };
+                // exited meanwhile, not killed by the limit
+                if let Some(pid) = pid {
+                    if !kill_tracked(pid) {
+                        break;
+                    }
+                }
+                warn!("{}, killed process {:?}", reason, pid);
+                *state_c.timeout.lock().unwrap_or_else(|e| e.into_inner()) = Some(reason);
+                break;
+            }
+        });
+        Watchdog {
+            state,
+            handle: Some(handle),
+        }
+    }
+
+    /// Report progress of the process, resetting the stall timer
+    pub fn progress(&self) {
+        *self
+            .state
+            .last_progress
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    /// Stop watching, returns a timeout error if the process got killed
+    pub fn finish(mut self) -> Result<()> {
+        self.state.done.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+        let timeout = self.state.timeout.lock()?.take();
+        match timeout {
+            Some(reason) => Err(Error::Timeout(reason)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.state.done.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Format a duration as seconds or minutes
+fn format_secs(duration: Duration) -> String {
+    match duration.as_secs() {
+        v if v >= 60 && v % 60 == 0 => format!("{} min", v / 60),
+        v => format!("{} s", v),
+    }
+}
+
+/// Run the command to completion within the limits, collecting all output
+pub fn output_limited(
+    runner: &dyn Runner,
+    cmd: &mut Command,
+    stage: &str,
+    limits: Limits,
+) -> Result<Output> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut process = runner.spawn(cmd)?;
+    let watchdog = Watchdog::start(&*process, stage, limits);
+    let output = collect(&mut *process)?;
+    watchdog.finish()?;
+    Ok(output)
+}
+
+/// Collect all output of the process & wait for it
+fn collect(process: &mut dyn Process) -> Result<Output> {
+    // stderr is read in parallel, a full stderr pipe would block the process otherwise
+    let stderr = process.take_stderr();
+    let stderr = thread::spawn(move || read_all(stderr));
+    let stdout = read_all(process.take_stdout());
+    let stderr = stderr
+        .join()
+        .map_err(|_| Error::InternalError("stderr reader panicked".into()))?;
+    let success = process.wait()?;
+    Ok(Output {
+        stdout: stdout?,
+        stderr: stderr?,
+        success,
+    })
+}
+
+/// Child process, tracked as running until waited for or dropped
+struct Tracked(Option<Child>);
+
+/// Remove a process from the running processes
+fn untrack(process: &Tracked) {
+    if let Some(pid) = process.0.as_ref().map(|c| c.id()) {
+        RUNNING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&pid);
+    }
+}
+
+impl Process for Tracked {
+    fn take_stdout(&mut self) -> Option<Box<dyn Read + Send>> {
+        self.0
+            .as_mut()
+            .and_then(|c| c.stdout.take())
+            .map(|v| Box::new(v) as Box<dyn Read + Send>)
+    }
+
+    fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>> {
+        self.0
+            .as_mut()
+            .and_then(|c| c.stderr.take())
+            .map(|v| Box::new(v) as Box<dyn Read + Send>)
+    }
+
+    fn wait(&mut self) -> Result<bool> {
+        let pid = match self.0.as_ref() {
+            Some(child) => child.id(),
+            None => return Ok(false),
+        };
+        // untracked before reaping, a kill can't hit a reused pid afterwards
+        wait_exited(pid)?;
+        untrack(self);
+        let status = self.0.as_mut().unwrap().wait()?;
+        Ok(status.success())
+    }
+
+    fn id(&self) -> Option<u32> {
+        self.0.as_ref().map(|c| c.id())
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        untrack(self);
+    }
+}
+
+/// Wait for the exit of a child process, without reaping it
+#[cfg(unix)]
+fn wait_exited(pid: u32) -> Result<()> {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let res = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if res == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err.into());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_exited(_pid: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Read a stream to its end, None is read as empty
+fn read_all(stream: Option<Box<dyn Read + Send>>) -> Result<String> {
+    let mut out = String::new();
+    if let Some(mut stream) = stream {
+        stream.read_to_string(&mut out)?;
+    }
+    Ok(out)
+}
+
+/// Recorded output of a process call
+#[cfg(test)]
+#[derive(Clone)]
+pub struct Transcript {
+    pub stdout: String,
+    pub stderr: String,
+    pub success: bool,
+}
+
+#[cfg(test)]
+impl Transcript {
+    /// Successful call printing `stdout`
+    pub fn ok(stdout: &str) -> Transcript {
+        Transcript {
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+            success: true,
+        }
+    }
+
+    /// Failed call printing `stderr`
+    pub fn failed(stderr: &str) -> Transcript {
+        Transcript {
+            stdout: String::new(),
+            stderr: stderr.to_string(),
+            success: false,
+        }
+    }
+}
+
+/// Runner replaying transcripts instead of spawning processes
+/// A transcript is selected by the called binary name & optionally an argument of the call.
+/// The first matching transcript is used, every call is recorded.
+#[cfg(test)]
+pub struct TranscriptRunner {
+    transcripts: Vec<(String, Option<String>, Transcript)>,
+    calls: Mutex<Vec<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl TranscriptRunner {
+    pub fn new() -> TranscriptRunner {
+        TranscriptRunner {
+            transcripts: Vec::new(),
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Replay `transcript` for calls of `binary`, containing `arg` if set
+    pub fn add(mut self, binary: &str, arg: Option<&str>, transcript: Transcript) -> Self {
+        self.transcripts
+            .push((binary.to_string(), arg.map(String::from), transcript));
+        self
+    }
+
+    /// Returns all calls, each as binary name followed by its arguments
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Runner for TranscriptRunner {
+    fn spawn(&self, cmd: &mut Command) -> Result<Box<dyn Process>> {
+        let binary = std::path::Path::new(cmd.get_program())
+            .file_name()
+            .map(|v| v.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|v| v.to_string_lossy().into_owned())
+            .collect();
+        let transcript = self
+            .transcripts
+            .iter()
+            .find(|(b, arg, _)| *b == binary && arg.iter().all(|arg| args.contains(arg)))
+            .map(|(_, _, t)| t.clone())
+            .ok_or(Error::InternalError(format!(
+                "no transcript for {} {:?}",
+                binary, args
+            )))?;
+        let mut call = vec![binary];
+        call.extend(args);
+        self.calls.lock()?.push(call);
+        Ok(Box::new(Replay {
+            stdout: Some(transcript.stdout.into_bytes()),
+            stderr: Some(transcript.stderr.into_bytes()),
+            success: transcript.success,
+        }))
+    }
+}
+
+/// Process replaying a transcript
+#[cfg(test)]
+struct Replay {
+    stdout: Option<Vec<u8>>,
+    stderr: Option<Vec<u8>>,
+    success: bool,
+}
+
+#[cfg(test)]
+impl Process for Replay {
+    fn take_stdout(&mut self) -> Option<Box<dyn Read + Send>> {
+        self.stdout
+            .take()
+            .map(|v| Box::new(Cursor::new(v)) as Box<dyn Read + Send>)
+    }
+
+    fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>> {
+        self.stderr
+            .take()
+            .map(|v| Box::new(Cursor::new(v)) as Box<dyn Read + Send>)
+    }
+
+    fn wait(&mut self) -> Result<bool> {
+        Ok(self.success)
+    }
+
+    fn id(&self) -> Option<u32> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::Stdio;
+
+    #[test]
+    fn transcript_test() {
+        let runner = TranscriptRunner::new()
+            .add("yt-dlp", Some("--version"), Transcript::ok("2021.12.01\n"))
+            .add("yt-dlp", None, Transcript::failed("ERROR: no"));
+        let output = runner
+            .output(Command::new("/bin/yt-dlp").arg("--version"))
+            .unwrap();
+        assert!(output.success);
+        assert_eq!("2021.12.01\n", output.stdout);
+        let output = runner.output(Command::new("yt-dlp").arg("-s")).unwrap();
+        assert!(!output.success);
+        assert_eq!("ERROR: no", output.stderr);
+        assert!(runner.output(&mut Command::new("ffmpeg")).is_err());
+        assert_eq!(
+            vec![
+                vec!["yt-dlp".to_string(), "--version".to_string()],
+                vec!["yt-dlp".to_string(), "-s".to_string()]
+            ],
+            runner.calls()
+        );
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn system_test() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo out; echo err >&2; exit 3"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let output = SystemRunner.output(&mut cmd).unwrap();
+        assert_eq!("out\n", output.stdout);
+        assert_eq!("err\n", output.stderr);
+        assert!(!output.success);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn watchdog_test() {
+        let start = |script: &str| SystemRunner::start(Command::new("sh").args(["-c", script]));
+        let limits = |max: u64, stall: u64| Limits {
+            max: Some(Duration::from_millis(max)),
+            stall: Some(Duration::from_millis(stall)),
+        };
+
+        // finished within the limits
+        let mut process = start("exit 0").unwrap();
+        let watchdog = Watchdog::start(&process, "test", limits(5000, 5000));
+        assert!(process.wait().unwrap());
+        watchdog.finish().unwrap();
+
+        let mut process = start("sleep 30").unwrap();
+        let watchdog = Watchdog::start(&process, "test", limits(5000, 300));
+        assert!(!process.wait().unwrap());
+        match watchdog.finish() {
+            Err(Error::Timeout(reason)) => assert_eq!("test stalled, no progress for 0 s", reason),
+            v => panic!("no stall detected: {:?}", v.is_ok()),
+        }
+
+        // progress keeps the process alive until the max runtime
+        let mut process = start("sleep 30").unwrap();
+        let watchdog = Watchdog::start(&process, "test", limits(1000, 300));
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(700) {
+            watchdog.progress();
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!process.wait().unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(1000));
+        match watchdog.finish() {
+            Err(Error::Timeout(reason)) => assert!(reason.contains("exceeded the limit")),
+            v => panic!("no timeout detected: {:?}", v.is_ok()),
+        }
+    }
+
+    #[test]
+    fn limits_test() {
+        assert_eq!(Limits::default(), Limits::secs(0, 0));
+        assert_eq!(Some(Duration::from_secs(600)), Limits::secs(600, 0).max);
+        assert_eq!("10 min", format_secs(Duration::from_secs(600)));
+        assert_eq!("90 s", format_secs(Duration::from_secs(90)));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn tracking_test() {
+        let mut process = SystemRunner::start(Command::new("sh").args(["-c", "sleep 30"])).unwrap();
+        let pid = process.0.as_ref().unwrap().id();
+        assert!(RUNNING.lock().unwrap().contains(&pid));
+        // as done by kill_running, which would also kill processes of parallel tests
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+        assert!(!process.wait().unwrap());
+        assert!(!RUNNING.lock().unwrap().contains(&pid));
+        // a reaped process isn't killed again
+        assert!(!kill_tracked(pid));
+    }
+}
@@ -0,0 +1,291 @@
+//! Proxy pools for yt-dl & the updater
+//!
+//! Every handler uses the global pool unless `proxy.handlers` overrides it.
+//! Proxies failing `max_failures` times in a row are taken out of their pool for `cooldown`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::lib::config::{ConfigProxy, ProxyStrategy};
+use crate::lib::{Error, Result};
+use crate::CONFIG;
+
+lazy_static! {
+    static ref PROXIES: Proxies = Proxies::from_config(&CONFIG.proxy);
+}
+
+/// Global pool & per handler overrides
+struct Proxies {
+    default: ProxyPool,
+    handlers: HashMap<String, ProxyPool>,
+}
+
+impl Proxies {
+    fn from_config(config: &ConfigProxy) -> Proxies {
+        let pool = |proxies: &Vec<String>| {
+            ProxyPool::new(
+                proxies.clone(),
+                config.strategy,
+                config.max_failures,
+                Duration::from_secs(config.cooldown as u64),
+            )
+        };
+        Proxies {
+            default: pool(&config.pool),
+            handlers: config
+                .handlers
+                .iter()
+                .map(|(k, v)| (k.clone(), pool(v)))
+                .collect(),
+        }
+    }
+}
+
+/// Returns the proxy pool of a handler, the global pool if it has no override
+pub fn pool(handler: Option<&str>) -> &'static ProxyPool {
+    handler
+        .and_then(|v| PROXIES.handlers.get(v))
+        .unwrap_or(&PROXIES.default)
+}
+
+/// Health of a single proxy
+#[derive(Clone, Default)]
+struct Health {
+    /// failures in a row
+    failures: u32,
+    /// taken out of the pool until
+    disabled_until: Option<Instant>,
+}
+
+struct PoolState {
+    /// next proxy for round-robin
+    next: usize,
+    health: Vec<Health>,
+}
+
+/// Pool of proxies with health tracking
+pub struct ProxyPool {
+    proxies: Vec<String>,
+    strategy: ProxyStrategy,
+    max_failures: u32,
+    cooldown: Duration,
+    state: Mutex<PoolState>,
+}
+
+impl ProxyPool {
+    pub fn new(
+        proxies: Vec<String>,
+        strategy: ProxyStrategy,
+        max_failures: u32,
+        cooldown: Duration,
+    ) -> ProxyPool {
+        let health = vec![Health::default(); proxies.len()];
+        ProxyPool {
+            proxies,
+            strategy,
+            max_failures,
+            cooldown,
+            state: Mutex::new(PoolState { next: 0, health }),
+        }
+    }
+
+    /// Select the next proxy, preferring ones not in `tried`, None for an empty pool
+    /// If all proxies are in cooldown the one available first is returned.
+    fn select_from(&self, tried: &[&str]) -> Option<&str> {
+        if self.proxies.is_empty() {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        for health in state.health.iter_mut() {
+            if health.disabled_until.is_some_and(|v| v <= now) {
+                health.disabled_until = None;
+                health.failures = 0;
+            }
+        }
+        let len = self.proxies.len();
+        let start = match self.strategy {
+            ProxyStrategy::RoundRobin => state.next,
+            ProxyStrategy::Failover => 0,
+        };
+        let order: Vec<usize> = (0..len).map(|i| (start + i) % len).collect();
+        let available = |i: &usize| {
+            state.health[*i].disabled_until.is_none() && !tried.contains(&&*self.proxies[*i])
+        };
+        let selected = match order.iter().copied().find(available) {
+            Some(v) => v,
+            None => order
+                .iter()
+                .copied()
+                .filter(|i| !tried.contains(&&*self.proxies[*i]))
+                .min_by_key(|i| state.health[*i].disabled_until)
+                .unwrap_or(start),
+        };
+        state.next = (selected + 1) % len;
+        Some(&self.proxies[selected])
+    }
+
+    /// Report the result of a request made via `proxy`
+    pub fn report(&self, proxy: &str, success: bool) {
+        let index = match self.proxies.iter().position(|v| v == proxy) {
+            Some(v) => v,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let health = &mut state.health[index];
+        if success {
+            health.failures = 0;
+            return;
+        }
+        health.failures += 1;
+        if health.failures >= self.max_failures && health.disabled_until.is_none() {
+            warn!(
+                "Proxy {} failed {} times, disabled for {:?}",
+                redact(proxy),
+                health.failures,
+                self.cooldown
+            );
+            health.disabled_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    /// Run `f` with a proxy of the pool, None for an empty pool
+    /// Proxy errors are retried with the next proxy, up to once per proxy.
+    pub fn run<T, F: FnMut(Option<&str>) -> Result<T>>(&self, mut f: F) -> Result<T> {
+        let mut tried: Vec<&str> = Vec::new();
+        loop {
+            let proxy = match self.select_from(&tried) {
+                Some(v) => v,
+                None => return f(None),
+            };
+            let result = f(Some(proxy));
+            match result {
+                Err(Error::ProxyError(ref e)) => {
+                    warn!("Proxy {} failed: {}", redact(proxy), e);
+                    self.report(proxy, false);
+                    tried.push(proxy);
+                    if tried.len() >= self.proxies.len() {
+                        return result;
+                    }
+                }
+                _ => {
+                    self.report(proxy, true);
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+/// Hide the credentials of a proxy URL for logging
+pub fn redact(url: &str) -> String {
+    match (url.find("://"), url.rfind('@')) {
+        (Some(scheme), Some(at)) if at > scheme => {
+            format!("{}***{}", &url[..scheme + 3], &url[at..])
+        }
+        _ => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pool(strategy: ProxyStrategy, cooldown: Duration) -> ProxyPool {
+        ProxyPool::new(
+            vec!["http://a".to_string(), "http://b".to_string()],
+            strategy,
+            2,
+            cooldown,
+        )
+    }
+
+    #[test]
+    fn failover_test() {
+        let pool = pool(ProxyStrategy::Failover, Duration::from_secs(60));
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+        pool.report("http://a", false);
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+        pool.report("http://a", false);
+        assert_eq!(Some("http://b"), pool.select_from(&[]));
+        // all proxies in cooldown, a is available first
+        pool.report("http://b", false);
+        pool.report("http://b", false);
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+
+        assert_eq!(
+            None,
+            ProxyPool::new(vec![], ProxyStrategy::Failover, 1, Duration::ZERO).select_from(&[])
+        );
+    }
+
+    #[test]
+    fn round_robin_test() {
+        let pool = pool(ProxyStrategy::RoundRobin, Duration::from_secs(60));
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+        assert_eq!(Some("http://b"), pool.select_from(&[]));
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+        pool.report("http://b", false);
+        pool.report("http://b", false);
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+    }
+
+    #[test]
+    fn cooldown_test() {
+        let pool = pool(ProxyStrategy::Failover, Duration::from_millis(50));
+        pool.report("http://a", false);
+        pool.report("http://a", false);
+        assert_eq!(Some("http://b"), pool.select_from(&[]));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+        // failures are reset after the cooldown
+        pool.report("http://a", false);
+        assert_eq!(Some("http://a"), pool.select_from(&[]));
+    }
+
+    #[test]
+    fn run_test() {
+        let pool = pool(ProxyStrategy::Failover, Duration::from_secs(60));
+        let mut used = Vec::new();
+        let result = pool.run(|proxy| {
+            used.push(proxy.map(String::from));
+            match proxy {
+                Some("http://a") => Err(Error::ProxyError("refused".into())),
+                _ => Ok(1),
+            }
+        });
+        assert_eq!(1, result.unwrap());
+        assert_eq!(
+            vec![Some("http://a".to_string()), Some("http://b".to_string())],
+            used
+        );
+
+        // other errors aren't retried
+        let mut calls = 0;
+        let result: Result<()> = pool.run(|_| {
+            calls += 1;
+            Err(Error::ExtractorError)
+        });
+        assert!(result.is_err());
+        assert_eq!(1, calls);
+
+        // every proxy failed
+        let result: Result<()> = pool.run(|_| Err(Error::ProxyError("refused".into())));
+        assert!(matches!(result, Err(Error::ProxyError(_))));
+
+        let empty = ProxyPool::new(vec![], ProxyStrategy::Failover, 1, Duration::ZERO);
+        assert_eq!(
+            None,
+            empty.run(|proxy| Ok(proxy.map(String::from))).unwrap()
+        );
+    }
+
+    #[test]
+    fn redact_test() {
+        assert_eq!("http://***@host:3128", redact("http://user:pw@host:3128"));
+        assert_eq!("socks5://host:1080", redact("socks5://host:1080"));
+    }
+}
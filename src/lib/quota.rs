@@ -0,0 +1,159 @@
+use std::path::Path;
+
+use crate::lib::config;
+use crate::lib::db::Repository;
+use crate::lib::downloader::Downloader;
+use crate::lib::storage::{self, Storage};
+use crate::lib::{Error, Request, Result};
+
+use crate::CONFIG;
+
+/// Check storage quotas and free disk space before starting the request
+/// The size estimate is probed from yt-dl, playlists are only checked against the current usage
+/// Evicts the oldest files of the user if configured and required to fit into the user quota
+pub fn check_request(
+    downloader: &Downloader,
+    storage: &dyn Storage,
+    request: &Request,
+) -> Result<()> {
+    let conf = &config::live().quota;
+    // nothing to check, the size probe is skipped too
+    if conf.user_max_bytes == 0 && conf.global_max_bytes == 0 && conf.min_free_bytes == 0 {
+        return Ok(());
+    }
+
+    let estimate = if request.playlist {
+        0
+    } else {
+        estimate_size(downloader, request)
+    };
+    debug!("size estimate: {}", estimate);
+
+    check_free_space(Path::new(&CONFIG.general.temp_dir), estimate)?;
+    if storage.is_local() {
+        check_free_space(Path::new(&CONFIG.general.download_dir), estimate)?;
+    }
+
+    let db = request.db();
+    if conf.global_max_bytes > 0 {
+        let usage = db.get_total_usage()?;
+        if usage + estimate > conf.global_max_bytes {
+            return Err(Error::QuotaExceeded(format!(
+                "Global storage quota exceeded, {} of {} bytes used",
+                usage, conf.global_max_bytes
+            )));
+        }
+    }
+
+    if conf.user_max_bytes > 0 {
+        let mut usage = db.get_user_usage(&request.uid)?;
+        if usage + estimate > conf.user_max_bytes && conf.evict_oldest {
+            let required = usage + estimate - conf.user_max_bytes;
+            usage = usage.saturating_sub(evict_oldest(db, storage, &request.uid, required)?);
+        }
+        if usage + estimate > conf.user_max_bytes {
+            return Err(Error::QuotaExceeded(format!(
+                "User storage quota exceeded, {} of {} bytes used, {} required",
+                usage, conf.user_max_bytes, estimate
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the probed size of the request, 0 if unknown
+/// Video qualities are merged with an audio stream, which is probed as well
+fn estimate_size(downloader: &Downloader, request: &Request) -> u64 {
+    let formats = if request.quality > 0 {
+        let codecs = &CONFIG.codecs.yt;
+        // the audio format depends on the container of the video, either one is close enough
+        vec![
+            Some(request.quality.to_string()),
+            Some(format!(
+                "{}/{}",
+                codecs.audio_normal_mp4, codecs.audio_normal_webm
+            )),
+        ]
+    } else {
+        vec![None]
+    };
+    formats
+        .into_iter()
+        .map(
+            |quality| match downloader.get_size_estimate(request, quality) {
+                Ok(v) => v.unwrap_or(0),
+                Err(e) => {
+                    warn!("Unable to probe size of {}: {}", request.url, e);
+                    0
+                }
+            },
+        )
+        .sum()
+}
+
+/// Check that the required space plus the configured reserve is available
+fn check_free_space(path: &Path, required: u64) -> Result<()> {
+    let available = fs2::available_space(path)?;
+    trace!("available space in {:?}: {}", path, available);
+    if available < required + config::live().quota.min_free_bytes {
+        return Err(Error::QuotaExceeded(format!(
+            "Not enough disk space, {} bytes available, {} required",
+            available, required
+        )));
+    }
+    Ok(())
+}
+
+/// Delete the oldest files of the user until the required amount of bytes is freed
+/// Files shared with other users are never evicted
+/// Returns the amount of freed bytes
+fn evict_oldest(
+    db: &dyn Repository,
+    storage: &dyn Storage,
+    uid: &u32,
+    required: u64,
+) -> Result<u64> {
+    let mut freed = 0;
+    for (fid, name, size) in db.get_user_files_oldest(uid)? {
+        if freed >= required {
+            break;
+        }
+        if storage::remove_logged(storage, &name) {
+            info!("Evicted file {} of user {}, {} bytes", name, uid, size);
+            db.set_file_valid_flag(&fid, false)?;
+            freed += size;
+        }
+    }
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lib::db::{self, SqliteRepository};
+    use crate::lib::process::{Transcript, TranscriptRunner};
+    use std::sync::Arc;
+
+    #[test]
+    fn estimate_test() {
+        let audio = format!(
+            "{}/{}",
+            CONFIG.codecs.yt.audio_normal_mp4, CONFIG.codecs.yt.audio_normal_webm
+        );
+        let runner = TranscriptRunner::new()
+            .add("yt-dlp", Some(&audio), Transcript::ok("200\n"))
+            .add("yt-dlp", Some("137"), Transcript::ok("1000\n"));
+        let downloader = Downloader::with_runner(&CONFIG.general, Arc::new(runner));
+        let db: Arc<dyn Repository> = Arc::new(SqliteRepository::open_in_memory().unwrap());
+        db.add_query("https://www.youtube.com/watch?v=a", &137, &1, &0, None)
+            .unwrap();
+        let mut request = db::request_entry(&db).unwrap();
+        assert_eq!(estimate_size(&downloader, &request), 1200);
+
+        // audio only, no merged stream
+        request.quality = -1;
+        let runner = TranscriptRunner::new().add("yt-dlp", None, Transcript::ok("300\n"));
+        let downloader = Downloader::with_runner(&CONFIG.general, Arc::new(runner));
+        assert_eq!(estimate_size(&downloader, &request), 300);
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs::{read_dir, remove_dir_all, remove_file};
+use std::path::{Path, PathBuf};
+
+use crate::lib::config::OrphanAction;
+use crate::lib::db::Repository;
+use crate::lib::metrics;
+use crate::lib::storage::Storage;
+use crate::lib::Result;
+
+use crate::CONFIG;
+
+/// Findings of a reconciliation run
+#[derive(Debug, Default)]
+pub struct Report {
+    /// stored files without a files entry
+    pub orphans: Vec<String>,
+    /// valid files entries whose file is missing: fid, name
+    pub missing: Vec<(u64, String)>,
+    /// workspaces of queries which aren't waiting or running
+    pub stale_workspaces: Vec<PathBuf>,
+}
+
+impl Report {
+    /// Log the findings, listing every entry on debug level
+    pub fn log(&self, dry_run: bool) {
+        let prefix = if dry_run { "[dry run] " } else { "" };
+        info!(
+            "{}Reconciliation: {} orphaned files, {} missing files, {} stale workspaces",
+            prefix,
+            self.orphans.len(),
+            self.missing.len(),
+            self.stale_workspaces.len()
+        );
+        for name in &self.orphans {
+            debug!("{}orphaned file: {:?}", prefix, name);
+        }
+        for (fid, name) in &self.missing {
+            debug!("{}missing file: {} {:?}", prefix, fid, name);
+        }
+        for path in &self.stale_workspaces {
+            debug!("{}stale workspace: {:?}", prefix, path);
+        }
+    }
+}
+
+/// Compare the storage with the files table and the temp dir with the active queries
+/// Fixes the findings unless dry_run is set, returns the report of all findings
+pub fn reconcile(db: &dyn Repository, storage: &dyn Storage, dry_run: bool) -> Result<Report> {
+    let mut report = Report::default();
+
+    // list the storage first, files are stored before their entry is inserted
+    let stored = storage.list()?;
+    let known = db.get_file_names()?;
+    report.orphans = stored
+        .iter()
+        .filter(|name| !known.contains(name))
+        .cloned()
+        .collect();
+    report.missing = db
+        .get_valid_files()?
+        .into_iter()
+        .filter(|(_, name)| !stored.contains(name))
+        .collect();
+    let active = db.get_active_queries()?;
+    report.stale_workspaces = get_workspaces(Path::new(&CONFIG.general.temp_dir))?
+        .into_iter()
+        .filter(|(qid, _)| !active.contains(qid))
+        .map(|(_, path)| path)
+        .collect();
+
+    report.log(dry_run);
+    metrics::reconcile_findings(
+        report.orphans.len(),
+        report.missing.len(),
+        report.stale_workspaces.len(),
+    );
+    if dry_run {
+        return Ok(report);
+    }
+
+    for name in &report.orphans {
+        // re-check, the file could've been stored by a running query meanwhile
+        if db.file_name_exists(name)? {
+            continue;
+        }
+        let result = match CONFIG.reconcile.orphan_action {
+            OrphanAction::Remove => storage.remove(name).map(|_| ()),
+            OrphanAction::Quarantine => storage.quarantine(name),
+        };
+        if let Err(e) = result {
+            error!("Couldn't handle orphaned file {:?} {:?}", name, e);
+        }
+    }
+    for (fid, name) in &report.missing {
+        // re-check, a file inserted after listing the storage isn't missing
+        match storage.exists(name) {
+            Ok(true) => continue,
+            Ok(false) => db.set_file_valid_flag(fid, false)?,
+            Err(e) => error!("Couldn't check missing file {:?} {:?}", name, e),
+        }
+    }
+    for path in &report.stale_workspaces {
+        if let Err(e) = remove_dir_all(path) {
+            warn!("unable to remove dir {:?} {}", path, e);
+        }
+    }
+    Ok(report)
+}
+
+/// Delete everything inside the temp dir, except for the workspaces of the keep qids
+/// Used by the `clean_temp_dir` option on startup
+pub fn clean_temp_dir(keep: &[u64]) -> Result<()> {
+    let dir = Path::new(&CONFIG.general.temp_dir);
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if let Some(qid) = parse_workspace(&path) {
+            if keep.contains(&qid) {
+                trace!("keeping workspace {:?}", path);
+                continue;
+            }
+        }
+        trace!("cleaning up {:?}", path);
+        let result = if entry.file_type()?.is_dir() {
+            remove_dir_all(&path)
+        } else {
+            remove_file(&path)
+        };
+        if let Err(e) = result {
+            warn!("unable to remove {:?} {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+/// Returns all query workspaces inside the dir
+fn get_workspaces(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut workspaces = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        if let Some(qid) = parse_workspace(&path) {
+            workspaces.push((qid, path));
+        }
+    }
+    Ok(workspaces)
+}
+
+/// Returns the qid of a workspace path, see `lib::workspace_path`
+fn parse_workspace(path: &Path) -> Option<u64> {
+    path.file_name()
+        .and_then(|v| v.to_str())
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{create_dir_all, File};
+
+    #[test]
+    fn workspaces() {
+        let mut dir = std::env::temp_dir();
+        dir.push("yayd_reconcile_test");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(dir.join("12")).unwrap();
+        create_dir_all(dir.join("other")).unwrap();
+        File::create(dir.join("13")).unwrap();
+
+        let workspaces = get_workspaces(&dir).unwrap();
+        assert_eq!(workspaces, vec![(12, dir.join("12"))]);
+        remove_dir_all(&dir).unwrap();
+    }
+}
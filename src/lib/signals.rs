@@ -0,0 +1,188 @@
+//! Signal handling, graceful shutdown on SIGTERM/SIGINT & reloads on SIGHUP
+//!
+//! The signal handlers only set flags, which are polled by the worker loop & the shutdown watchdog.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::lib::process;
+
+/// Polling interval of the shutdown watchdog & interruptible sleeps
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static STATE: State = State::new();
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Shutdown state of the worker
+pub struct State {
+    /// Shutdown requested, no new jobs are claimed
+    shutdown: AtomicBool,
+    /// A job is currently handled
+    active: AtomicBool,
+    /// The current job got aborted after the grace period
+    aborted: AtomicBool,
+}
+
+impl State {
+    pub const fn new() -> State {
+        State {
+            shutdown: AtomicBool::new(false),
+            active: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Mark a job as started, it's marked as finished when the guard is dropped
+    pub fn job_started(&self) -> JobGuard<'_> {
+        self.aborted.store(false, Ordering::SeqCst);
+        self.active.store(true, Ordering::SeqCst);
+        JobGuard { state: self }
+    }
+
+    /// Returns true if the current job got aborted by the shutdown
+    pub fn job_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Abort the current job like an exceeded grace period, without a shutdown
+    #[cfg(test)]
+    pub fn abort_job(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+
+    /// Wait for a shutdown request, then give the current job `grace` to finish.
+    /// Afterwards `kill` is called until the job returns.
+    fn watch<F: Fn() -> usize>(&self, grace: Duration, kill: F) {
+        while !self.shutdown_requested() {
+            thread::sleep(POLL_INTERVAL);
+        }
+        let deadline = Instant::now() + grace;
+        while self.active.load(Ordering::SeqCst) {
+            if Instant::now() >= deadline {
+                if !self.aborted.swap(true, Ordering::SeqCst) {
+                    warn!("Grace period of {:?} exceeded, aborting job", grace);
+                }
+                let killed = kill();
+                if killed > 0 {
+                    debug!("killed {} processes", killed);
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Marks the current job as running until dropped
+pub struct JobGuard<'a> {
+    state: &'a State,
+}
+
+impl<'a> Drop for JobGuard<'a> {
+    fn drop(&mut self) {
+        self.state.active.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Returns the global shutdown state, set by the signal handlers
+pub fn state() -> &'static State {
+    &STATE
+}
+
+/// Install the signal handlers & start the shutdown watchdog
+/// Running jobs get `grace` to finish after SIGTERM/SIGINT, before their processes are killed.
+/// `reload` is called on SIGHUP, also while a job is running.
+pub fn install(grace: Duration, reload: fn()) {
+    #[cfg(unix)]
+    unsafe {
+        let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+            if libc::signal(signal, handler) == libc::SIG_ERR {
+                warn!("Unable to install handler for signal {}", signal);
+            }
+        }
+    }
+    thread::spawn(move || STATE.watch(grace, process::kill_running));
+    thread::spawn(move || loop {
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            reload();
+        }
+        thread::sleep(POLL_INTERVAL);
+    });
+}
+
+/// Sleep for the duration, returns early on a shutdown request
+pub fn sleep(duration: Duration) {
+    let end = Instant::now() + duration;
+    while !STATE.shutdown_requested() {
+        let now = Instant::now();
+        if now >= end {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL.min(end - now));
+    }
+}
+
+/// Signal handler, only async-signal-safe operations are allowed here
+#[cfg(unix)]
+extern "C" fn handle_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        _ => STATE.request_shutdown(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    #[test]
+    fn grace_test() {
+        let state = Arc::new(State::new());
+        let kills = Arc::new(AtomicUsize::new(0));
+        let state_c = state.clone();
+        let kills_c = kills.clone();
+        let watchdog = thread::spawn(move || {
+            state_c.watch(Duration::from_millis(300), || {
+                kills_c.fetch_add(1, Ordering::SeqCst);
+                1
+            })
+        });
+
+        let job = state.job_started();
+        state.request_shutdown();
+        thread::sleep(Duration::from_millis(150));
+        // still within the grace period
+        assert_eq!(0, kills.load(Ordering::SeqCst));
+        assert!(!state.job_aborted());
+        thread::sleep(Duration::from_millis(400));
+        assert!(kills.load(Ordering::SeqCst) > 0);
+        assert!(state.job_aborted());
+        drop(job);
+        watchdog.join().unwrap();
+    }
+
+    #[test]
+    fn finished_in_grace_test() {
+        let state = Arc::new(State::new());
+        let state_c = state.clone();
+        let job = state.job_started();
+        state.request_shutdown();
+        let watchdog =
+            thread::spawn(move || state_c.watch(Duration::from_secs(10), || panic!("job killed")));
+        thread::sleep(Duration::from_millis(150));
+        drop(job);
+        watchdog.join().unwrap();
+        assert!(!state.job_aborted());
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs::{copy, create_dir_all, read_dir, remove_file, rename};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{Storage, QUARANTINE_DIR};
+use crate::lib::Result;
+
+/// Storage inside a local directory, also used for mounted NFS targets
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: &str) -> LocalStorage {
+        LocalStorage {
+            dir: PathBuf::from(dir),
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn store(&self, source: &Path, name: &str) -> Result<()> {
+        let target = self.dir.join(name);
+        trace!("storing {:?} at {:?}", source, target);
+        if rename(source, &target).is_err() {
+            // temp dir on another filesystem
+            copy(source, &target)?;
+            remove_file(source)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<bool> {
+        match remove_file(self.dir.join(name)) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn fetch(&self, name: &str, target: &Path) -> Result<()> {
+        copy(self.dir.join(name), target)?;
+        Ok(())
+    }
+
+    fn exists(&self, name: &str) -> Result<bool> {
+        Ok(self.dir.join(name).exists())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in read_dir(&self.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            match entry.file_name().into_string() {
+                Ok(name) => names.push(name),
+                Err(name) => warn!("ignoring non UTF-8 file name {:?}", name),
+            }
+        }
+        Ok(names)
+    }
+
+    fn quarantine(&self, name: &str) -> Result<()> {
+        let dir = self.dir.join(QUARANTINE_DIR);
+        create_dir_all(&dir)?;
+        rename(self.dir.join(name), dir.join(name))?;
+        Ok(())
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, File};
+
+    #[test]
+    fn store_remove() {
+        let mut dir = std::env::temp_dir();
+        dir.push("yayd_local_storage_test");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let source = dir.join("source");
+        File::create(&source).unwrap();
+
+        let storage = LocalStorage::new(&dir.join("store").to_string_lossy());
+        create_dir_all(dir.join("store")).unwrap();
+        assert!(!storage.exists("a.mp4").unwrap());
+        storage.store(&source, "a.mp4").unwrap();
+        assert!(!source.exists());
+        assert!(storage.exists("a.mp4").unwrap());
+        assert_eq!(storage.list().unwrap(), vec![String::from("a.mp4")]);
+        storage.fetch("a.mp4", &source).unwrap();
+        assert!(source.exists());
+        assert!(storage.exists("a.mp4").unwrap());
+        remove_file(&source).unwrap();
+        assert!(storage.remove("a.mp4").unwrap());
+        assert!(!storage.remove("a.mp4").unwrap());
+
+        File::create(&source).unwrap();
+        storage.store(&source, "b.mp4").unwrap();
+        storage.quarantine("b.mp4").unwrap();
+        assert!(!storage.exists("b.mp4").unwrap());
+        assert!(dir
+            .join("store")
+            .join(QUARANTINE_DIR)
+            .join("b.mp4")
+            .exists());
+        assert!(storage.list().unwrap().is_empty());
+        remove_dir_all(&dir).unwrap();
+    }
+}
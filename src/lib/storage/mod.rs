@@ -0,0 +1,83 @@
+//! Storage backends for finished files
+//!
+//! Handlers produce their files inside the query workspace, the registry stores them
+//! afterwards via the configured backend. Deletions and cleanups are done by the backend too.
+
+mod local;
+mod s3;
+mod sftp;
+
+pub use self::local::LocalStorage;
+pub use self::s3::S3Storage;
+pub use self::sftp::SftpStorage;
+
+use std::path::Path;
+
+use crate::lib::config::{Config, StorageBackend};
+use crate::lib::downloader::Filename;
+use crate::lib::{url_sanitize, Result};
+
+/// Sub dir / key prefix of the storage used for quarantined files
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Storage backend for finished files, addressed by their name
+pub trait Storage: Send + Sync {
+    /// Store the local file under the name, the source file is consumed
+    fn store(&self, source: &Path, name: &str) -> Result<()>;
+    /// Remove a stored file
+    /// Returns false if the file didn't exist
+    fn remove(&self, name: &str) -> Result<bool>;
+    /// Copy a stored file to the local target, keeping the stored file
+    fn fetch(&self, name: &str, target: &Path) -> Result<()>;
+    /// Check whether a file is stored under this name
+    fn exists(&self, name: &str) -> Result<bool>;
+    /// List the names of all stored files, excluding sub dirs and quarantined files
+    fn list(&self) -> Result<Vec<String>>;
+    /// Move a stored file into the quarantine dir of the storage
+    fn quarantine(&self, name: &str) -> Result<()>;
+    /// Returns true if files are stored in the local download_dir
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// Create the configured storage backend
+pub fn init_storage(conf: &Config) -> Box<dyn Storage> {
+    match conf.storage.backend {
+        StorageBackend::Local => Box::new(LocalStorage::new(&conf.general.download_dir)),
+        StorageBackend::S3 => Box::new(S3Storage::new(&conf.storage.s3)),
+        StorageBackend::Sftp => Box::new(SftpStorage::new(&conf.storage.sftp)),
+    }
+}
+
+/// Returns a unique name for the storage, if the name is already taken a '-X' number will be added to it.
+pub fn unique_name(storage: &dyn Storage, fname: &Filename) -> Result<String> {
+    let clean_name = url_sanitize(&fname.name);
+    let mut name = format!("{}.{}", clean_name, fname.extension);
+    for i in 1..100 {
+        if !storage.exists(&name)? {
+            break;
+        }
+        debug!("Name exists: {}", name);
+        name = format!("{}-{}.{}", clean_name, i, fname.extension);
+    }
+    debug!("Name: {}", name);
+    Ok(name)
+}
+
+/// Remove a stored file, logging failures
+/// Returns true if the file is gone afterwards
+pub fn remove_logged(storage: &dyn Storage, name: &str) -> bool {
+    trace!("deleting {:?}", name);
+    match storage.remove(name) {
+        Ok(true) => true,
+        Ok(false) => {
+            warn!("File was already deleted: {:?}", name);
+            true
+        }
+        Err(e) => {
+            error!("Couldn't delete file {:?} {:?}", name, e);
+            false
+        }
+    }
+}
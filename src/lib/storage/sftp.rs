@@ -0,0 +1,184 @@
+use std::fs::remove_file;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+use regex::Regex;
+
+use super::{Storage, QUARANTINE_DIR};
+use crate::lib::config::ConfigSftp;
+use crate::lib::{Error, Result};
+
+/// Storage on a SFTP target, using the sftp binary in batch mode
+/// Requires key based authentication, as no password can be entered
+pub struct SftpStorage {
+    conf: ConfigSftp,
+}
+
+impl SftpStorage {
+    pub fn new(conf: &ConfigSftp) -> SftpStorage {
+        SftpStorage { conf: conf.clone() }
+    }
+
+    /// Returns the remote path of a file
+    fn remote_path(&self, name: &str) -> String {
+        format!("{}/{}", self.conf.remote_dir.trim_end_matches('/'), name)
+    }
+
+    /// Run sftp batch commands
+    /// Batch mode aborts on the first failing command
+    fn run_batch(&self, commands: &str) -> Result<Output> {
+        trace!("sftp batch: {}", commands);
+        let mut cmd = Command::new("sftp");
+        cmd.args(["-b", "-"])
+            .args(["-P", &self.conf.port.to_string()])
+            .args(["-o", "BatchMode=yes"]);
+        if !self.conf.identity_file.is_empty() {
+            cmd.args(["-i", &self.conf.identity_file]);
+        }
+        let mut child = cmd
+            .arg(format!("{}@{}", self.conf.user, self.conf.host))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        {
+            let mut stdin = child
+                .stdin
+                .take()
+                .ok_or(Error::InternalError("stdin socket error!".into()))?;
+            stdin.write_all(commands.as_bytes())?;
+        }
+        Ok(child.wait_with_output()?)
+    }
+}
+
+impl Storage for SftpStorage {
+    fn store(&self, source: &Path, name: &str) -> Result<()> {
+        let output = self.run_batch(&format!(
+            "put {} {}\n",
+            quote(&source.to_string_lossy()),
+            quote(&self.remote_path(name))
+        ))?;
+        if !output.status.success() {
+            return Err(Error::InternalError(format!(
+                "sftp upload of {} failed: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        remove_file(source)?;
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<bool> {
+        if !self.exists(name)? {
+            return Ok(false);
+        }
+        let output = self.run_batch(&format!("rm {}\n", quote(&self.remote_path(name))))?;
+        if !output.status.success() {
+            return Err(Error::InternalError(format!(
+                "sftp deletion of {} failed: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(true)
+    }
+
+    fn fetch(&self, name: &str, target: &Path) -> Result<()> {
+        let output = self.run_batch(&format!(
+            "get {} {}\n",
+            quote(&self.remote_path(name)),
+            quote(&target.to_string_lossy())
+        ))?;
+        if !output.status.success() {
+            return Err(Error::InternalError(format!(
+                "sftp download of {} failed: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    fn exists(&self, name: &str) -> Result<bool> {
+        // ls fails for missing files, connection errors are reported via exit code 255 by ssh
+        let output = self.run_batch(&format!("ls {}\n", quote(&self.remote_path(name))))?;
+        match output.status.code() {
+            Some(0) => Ok(true),
+            Some(255) | None => Err(Error::InternalError(format!(
+                "sftp connection failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ))),
+            Some(_) => Ok(false),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let output = self.run_batch(&format!(
+            "cd {}\nls -l\n",
+            quote(self.conf.remote_dir.trim_end_matches('/'))
+        ))?;
+        if !output.status.success() {
+            return Err(Error::InternalError(format!(
+                "sftp listing failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(parse_ls_line)
+            .collect())
+    }
+
+    fn quarantine(&self, name: &str) -> Result<()> {
+        // '-' prefix: ignore the failure of mkdir for an existing dir
+        let dir = self.remote_path(QUARANTINE_DIR);
+        let output = self.run_batch(&format!(
+            "-mkdir {}\nrename {} {}\n",
+            quote(&dir),
+            quote(&self.remote_path(name)),
+            quote(&format!("{}/{}", dir, name))
+        ))?;
+        if !output.status.success() {
+            return Err(Error::InternalError(format!(
+                "sftp quarantine of {} failed: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the file name of a `ls -l` line, None for anything but regular files
+fn parse_ls_line(line: &str) -> Option<String> {
+    lazy_static! {
+        static ref REGEX_LS: Regex = Regex::new(r"^-\S+(?:\s+\S+){7}\s+(.+)$").unwrap();
+    }
+    REGEX_LS.captures(line).map(|cap| cap[1].to_string())
+}
+
+/// Quote a path for sftp batch files
+fn quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ls_line() {
+        assert_eq!(
+            parse_ls_line("-rw-r--r--    1 yayd     yayd      1048576 Oct 19 10:00 a b.mp4"),
+            Some(String::from("a b.mp4"))
+        );
+        assert_eq!(
+            parse_ls_line("drwxr-xr-x    2 yayd     yayd         4096 Oct 19 10:00 quarantine"),
+            None
+        );
+        assert_eq!(parse_ls_line("sftp> ls -l"), None);
+    }
+}
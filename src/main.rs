@@ -18,28 +18,41 @@ extern crate serde_json;
 extern crate sha2;
 extern crate timer;
 
+mod cli;
 mod handler;
+#[cfg(test)]
+mod harness;
 mod lib;
 
+use clap::Parser;
 use color_eyre::eyre::eyre;
-use mysql::Pool;
 
+use crate::cli::{Cli, Command};
 use crate::handler::init_handlers;
 use crate::handler::Registry;
+use crate::lib::api;
 use crate::lib::config;
 use crate::lib::converter::Converter;
-use crate::lib::db;
+use crate::lib::db::{self, Repository};
 use crate::lib::downloader::Downloader;
+use crate::lib::ffmpeg;
 use crate::lib::logger;
+use crate::lib::metrics;
+use crate::lib::notify;
+use crate::lib::reconcile;
+use crate::lib::signals;
+use crate::lib::storage::{self, Storage};
 use crate::lib::Error;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use timer::Timer;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const CONFIG_PATH: &'static str = "config.cfg";
-const C_USER_AGENT: &'static str = "hyper/yayd (github.com/0xpr03/yayd)";
-const LOG_CONFIG: &'static str = "logger.yaml";
-const LOG_PATTERN: &'static str = "{d(%d-%m-%Y %H:%M:%S)}\t{l}\t{f}:{L} \t{m:>10}{n}";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const CONFIG_PATH: &str = "config.cfg";
+const C_USER_AGENT: &str = "hyper/yayd (github.com/0xpr03/yayd)";
+const LOG_CONFIG: &str = "logger.yaml";
+const LOG_PATTERN: &str = "{d(%d-%m-%Y %H:%M:%S)}\t{l}\t{f}:{L} \t{m:>10}{n}";
 const CODE_WAITING: i8 = -1;
 const CODE_STARTED: i8 = 0;
 const CODE_IN_PROGRESS: i8 = 1;
@@ -47,13 +60,26 @@ const CODE_SUCCESS: i8 = 2;
 const CODE_SUCCESS_WARNINGS: i8 = 3; // finished with warnings
 const CODE_FAILED_INTERNAL: i8 = 10; // internal error
 const CODE_FAILED_QUALITY: i8 = 11; // qualitz not available
-const CODE_FAILED_UNAVAILABLE: i8 = 12; // source unavailable (private / removed)
+const CODE_FAILED_UNAVAILABLE: i8 = 12; // source unavailable, unknown reason
 const CODE_FAILED_UNKNOWN: i8 = 13; // URL invalid, no handler
+const CODE_FAILED_QUOTA: i8 = 14; // storage quota or disk space exceeded
+const CODE_FAILED_TIMEOUT: i8 = 15; // process exceeded a time limit or stalled
+const CODE_FAILED_GEO_BLOCKED: i8 = 16; // not available in the region of the server
+const CODE_FAILED_AGE_RESTRICTED: i8 = 17; // age restricted, requires a login
+const CODE_FAILED_LOGIN_REQUIRED: i8 = 18; // requires a login or membership
+const CODE_FAILED_PRIVATE: i8 = 19; // private video
+const CODE_FAILED_REMOVED: i8 = 20; // removed by the uploader or the site
+const CODE_FAILED_PREMIERE: i8 = 21; // premiere or live stream not started yet
+const CODE_FAILED_RATE_LIMITED: i8 = 22; // HTTP 429 by the site
+const CODE_FAILED_COPYRIGHT: i8 = 23; // removed after a copyright claim
 
 lazy_static! {
     pub static ref CONFIG: config::Config = {
-        println!("Starting yayd-backend v{}", &VERSION);
-        config::init_config()
+        #[cfg(not(test))]
+        let config = config::init_config();
+        #[cfg(test)]
+        let config = config::init_config_hermetic();
+        config
     };
     pub static ref SLEEP_TIME: std::time::Duration = std::time::Duration::new(5, 0);
 }
@@ -72,17 +98,56 @@ lazy_static! {
 //}
 fn main() -> color_eyre::eyre::Result<()> {
     color_eyre::install()?;
+    let cli = Cli::parse();
+    if let Some(path) = cli.config {
+        config::set_config_path(path);
+    }
     logger::initialize();
-    let pool = Arc::new(db::db_connect(
-        db::mysql_options(&CONFIG),
-        Some(*SLEEP_TIME),
-    ));
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(),
+        command => cli::execute(command),
+    }
+}
+
+/// Run the worker, processing queued jobs
+fn run() -> color_eyre::eyre::Result<()> {
+    println!("Starting yayd-backend v{}", &VERSION);
+    signals::install(
+        Duration::from_secs(CONFIG.general.shutdown_grace as u64),
+        reload,
+    );
+    let db = db::db_connect(&CONFIG, Some(*SLEEP_TIME));
     debug!("cleaning db...");
-    let mut conn = pool
-        .get_conn()
-        .map_err(|_| panic!("Couldn't retrieve connection!"))
-        .unwrap();
-    db::clear_query_states(&mut conn);
+    let interrupted = db.clear_query_states();
+    if CONFIG.general.clean_temp_dir {
+        debug!("cleaning temp dir...");
+        // requeued jobs, including those aborted by the last shutdown
+        let keep = if CONFIG.general.resume_interrupted {
+            db.get_active_queries().unwrap_or_else(|e| {
+                warn!("{}", e);
+                interrupted.clone()
+            })
+        } else {
+            Vec::new()
+        };
+        if let Err(e) = reconcile::clean_temp_dir(&keep) {
+            error!("Couldn't clean temp dir! {:?}", e);
+        }
+    } else if !CONFIG.general.resume_interrupted {
+        for qid in interrupted {
+            let workspace = lib::workspace_path(&qid);
+            if workspace.exists() {
+                if let Err(e) = std::fs::remove_dir_all(&workspace) {
+                    warn!("unable to remove dir {:?} {}", workspace, e);
+                }
+            }
+        }
+    }
+
+    debug!("Managed ffmpeg: {}", CONFIG.ffmpeg.managed);
+    if CONFIG.ffmpeg.managed {
+        update_ffmpeg();
+    }
 
     let converter = Converter::new(&CONFIG.general.ffmpeg_bin_dir, &CONFIG.general.mp3_quality);
 
@@ -91,14 +156,18 @@ fn main() -> color_eyre::eyre::Result<()> {
         return Err(eyre!("Converter self test failed! Shutting down"));
     }
 
-    let downloader = Arc::new(Downloader::new(&CONFIG.general));
+    let mut downloader = Downloader::new(&CONFIG.general);
+    downloader.set_db(db.clone());
+    let downloader = Arc::new(downloader);
 
     if !downloader.startup_test() {
         error!("Downloader self test failed! Shutting down");
         return Err(eyre!("Downloader self test failed! Shutting down"));
     }
 
-    let handler = init_handlers(downloader.clone(), converter);
+    let storage: Arc<dyn Storage> = Arc::from(storage::init_storage(&CONFIG));
+
+    let handler = init_handlers(downloader.clone(), converter, storage.clone());
 
     let timer = timer::Timer::new();
     debug!(
@@ -106,7 +175,7 @@ fn main() -> color_eyre::eyre::Result<()> {
         CONFIG.cleanup.auto_delete_files
     );
     if CONFIG.cleanup.auto_delete_files {
-        run_auto_cleanup_thread(pool.clone(), &timer);
+        run_auto_cleanup_thread(db.clone(), storage.clone(), &timer);
     }
 
     debug!(
@@ -114,7 +183,36 @@ fn main() -> color_eyre::eyre::Result<()> {
         CONFIG.cleanup.delete_files
     );
     if CONFIG.cleanup.delete_files {
-        run_cleanup_thread(pool.clone(), &timer);
+        run_cleanup_thread(db.clone(), storage.clone(), &timer);
+    }
+
+    debug!("HTTP API: {}", CONFIG.api.enabled);
+    if CONFIG.api.enabled {
+        if let Err(e) = api::start(db.clone(), &CONFIG.api) {
+            error!("Couldn't start API! {:?}", e);
+            return Err(eyre!("Couldn't start API! Shutting down"));
+        }
+    }
+
+    debug!("Metrics: {}", CONFIG.metrics.enabled);
+    if CONFIG.metrics.enabled {
+        if let Err(e) = metrics::start(db.clone(), &CONFIG.metrics) {
+            error!("Couldn't start metrics! {:?}", e);
+            return Err(eyre!("Couldn't start metrics! Shutting down"));
+        }
+    }
+
+    debug!("Notifications: {}", CONFIG.notify.enabled);
+    if CONFIG.notify.enabled {
+        run_notify_thread(db.clone(), &timer);
+    }
+
+    debug!("Reconcile storage: {}", CONFIG.reconcile.enabled);
+    if CONFIG.reconcile.enabled {
+        if let Err(e) = reconcile::reconcile(&*db, &*storage, CONFIG.reconcile.dry_run) {
+            error!("Couldn't reconcile storage! {:?}", e);
+        }
+        run_reconcile_thread(db.clone(), storage.clone(), &timer);
     }
 
     debug!(
@@ -124,67 +222,136 @@ fn main() -> color_eyre::eyre::Result<()> {
     if CONFIG.general.youtube_dl_auto_update {
         run_update_thread(downloader.clone(), &timer);
     }
+    if CONFIG.ffmpeg.managed {
+        run_ffmpeg_update_thread(&timer);
+    }
 
     debug!("finished startup");
-    main_loop(&*pool, handler);
+    main_loop(&db, handler);
     Ok(())
 }
 
-fn main_loop(pool: &Pool, mut handler: Registry) {
+/// Handle jobs until a shutdown is requested
+fn main_loop(db: &Arc<dyn Repository>, mut handler: Registry) {
     let mut print_pause = true;
 
-    loop {
-        if let Some(mut request) = db::request_entry(pool) {
-            trace!("got request");
+    while !signals::state().shutdown_requested() {
+        if handle_next(db, &mut handler) {
             print_pause = true;
-            let qid = request.qid.clone();
-            db::set_query_code(&mut request.get_conn(), &request.qid, &CODE_STARTED);
-            db::set_query_state(&mut request.get_conn(), &request.qid, "started");
-            trace!("starting handler");
-            let code: i8 = match handler.handle(&mut request) {
-                Ok(_) => CODE_SUCCESS,
-                Err(e) => {
-                    trace!("Error: {:?}", e);
-                    match e {
-                        Error::NotAvailable => CODE_FAILED_UNAVAILABLE,
-                        Error::ExtractorError => CODE_FAILED_UNAVAILABLE,
-                        Error::QualityNotAvailable => CODE_FAILED_QUALITY,
-                        Error::UnknownURL => CODE_FAILED_UNKNOWN,
-                        _ => {
-                            error!("Internal Error: {:?}", e);
-                            let details = e.to_string();
-                            db::add_query_error(&mut request.get_conn(), &qid, &details);
-                            CODE_FAILED_INTERNAL
-                        }
-                    }
-                }
-            };
-            trace!("handler finished");
-            db::set_query_code(&mut request.get_conn(), &qid, &code);
-            db::set_null_state(&mut request.get_conn(), &qid);
         } else {
             if print_pause {
                 trace!("Worker idle..");
                 print_pause = false;
             }
-            std::thread::sleep(*SLEEP_TIME);
+            signals::sleep(*SLEEP_TIME);
+        }
+    }
+    info!("Shutting down");
+}
+
+/// Reload the log config & the reloadable settings, on SIGHUP
+fn reload() {
+    logger::reload();
+    match config::reload() {
+        Ok(_) => info!("Reloaded config"),
+        Err(e) => error!("Unable to reload config, keeping the current: {}", e),
+    }
+}
+
+/// Handle the next waiting request, if existing
+/// Returns false if the queue is empty
+fn handle_next(db: &Arc<dyn Repository>, handler: &mut Registry) -> bool {
+    let mut request = match db::request_entry(db) {
+        Some(v) => v,
+        None => return false,
+    };
+    trace!("got request");
+    let qid = request.qid;
+    request.db().set_query_code(&request.qid, &CODE_STARTED);
+    request
+        .db()
+        .add_event(&qid, db::EVENT_STARTED, Some(CODE_STARTED), None);
+    request.db().set_query_state(&request.qid, "started");
+    trace!("starting handler");
+    let job = signals::state().job_started();
+    let result = handler.handle(&mut request);
+    drop(job);
+    if signals::state().job_aborted() {
+        warn!("Job {} aborted by shutdown, requeueing", qid);
+        if let Err(e) = request.db().reset_query(&qid, &CODE_WAITING) {
+            error!("Couldn't requeue {}: {:?}", qid, e);
+        }
+        request.db().add_event(
+            &qid,
+            db::EVENT_INTERRUPTED,
+            Some(CODE_WAITING),
+            Some("shutdown"),
+        );
+        return true;
+    }
+    let code: i8 = match result {
+        Ok(_) if request.warnings => CODE_SUCCESS_WARNINGS,
+        Ok(_) => CODE_SUCCESS,
+        Err(e) => {
+            trace!("Error: {:?}", e);
+            match e {
+                Error::ExtractorError => CODE_FAILED_UNAVAILABLE,
+                Error::QualityNotAvailable => CODE_FAILED_QUALITY,
+                Error::UnknownURL => CODE_FAILED_UNKNOWN,
+                Error::QuotaExceeded(ref details) => {
+                    request.db().add_query_error(&qid, details);
+                    CODE_FAILED_QUOTA
+                }
+                Error::Timeout(ref details) => {
+                    request.db().add_query_error(&qid, details);
+                    CODE_FAILED_TIMEOUT
+                }
+                Error::Unavailable(kind, ref output) => {
+                    info!("Source unavailable: {}", kind.as_str());
+                    request.db().add_query_error(&qid, output);
+                    kind.code()
+                }
+                _ => {
+                    error!("Internal Error: {:?}", e);
+                    let details = e.to_string();
+                    request.db().add_query_error(&qid, &details);
+                    CODE_FAILED_INTERNAL
+                }
+            }
+        }
+    };
+    trace!("handler finished");
+    let label = match request.handler {
+        _ if request.cached => "cache",
+        Some(name) => name,
+        None => "none",
+    };
+    metrics::job_finished(label, code);
+    request.db().set_query_code(&qid, &code);
+    request.db().set_null_state(&qid);
+    request.db().add_event(
+        &qid,
+        db::EVENT_FINISHED,
+        Some(code),
+        Some(notify::status_name(code)),
+    );
+    if CONFIG.notify.enabled {
+        if let Err(e) = notify::job_finished(request.db(), &qid) {
+            error!("Couldn't queue notifications for {}: {:?}", qid, e);
         }
     }
+    true
 }
 
 /// Auto cleanup task
-fn run_auto_cleanup_thread<'a>(pool: Arc<Pool>, timer: &'a Timer) {
-    let path = std::path::PathBuf::from(&CONFIG.general.download_dir);
+fn run_auto_cleanup_thread(db: Arc<dyn Repository>, storage: Arc<dyn Storage>, timer: &Timer) {
     let a = timer.schedule_repeating(
         chrono::Duration::minutes(CONFIG.cleanup.delete_interval as i64),
         move || {
             trace!("performing auto cleanup");
-            let local_pool = &*pool;
-            let val = lib::delete_files(
-                local_pool,
-                db::DeleteRequestType::AgedMin(&CONFIG.cleanup.auto_delete_age),
-                &path,
-            );
+            let age = config::live().auto_delete_age;
+            let val = lib::delete_files(&*db, db::DeleteRequestType::AgedMin(&age), &*storage);
+            metrics::cleanup_run(metrics::CLEANUP_AGED, val.is_ok());
             match val {
                 Ok(_) => (),
                 Err(e) => error!("Couldn't auto cleanup! {:?}", e),
@@ -195,14 +362,13 @@ fn run_auto_cleanup_thread<'a>(pool: Arc<Pool>, timer: &'a Timer) {
 }
 
 /// Cleanup requested task
-fn run_cleanup_thread<'a>(pool: Arc<Pool>, timer: &'a Timer) {
-    let path = std::path::PathBuf::from(&CONFIG.general.download_dir);
+fn run_cleanup_thread(db: Arc<dyn Repository>, storage: Arc<dyn Storage>, timer: &Timer) {
     let a = timer.schedule_repeating(
         chrono::Duration::minutes(CONFIG.cleanup.delete_interval as i64),
         move || {
             trace!("performing deletion requests");
-            let local_pool = &*pool;
-            let val = lib::delete_files(local_pool, db::DeleteRequestType::Marked, &path);
+            let val = lib::delete_files(&*db, db::DeleteRequestType::Marked, &*storage);
+            metrics::cleanup_run(metrics::CLEANUP_MARKED, val.is_ok());
             match val {
                 Ok(_) => (),
                 Err(e) => error!("Couldn't perform deletions! {:?}", e),
@@ -212,8 +378,38 @@ fn run_cleanup_thread<'a>(pool: Arc<Pool>, timer: &'a Timer) {
     a.ignore(); // ignore schedule guard a
 }
 
+/// Storage reconciliation task
+fn run_reconcile_thread(db: Arc<dyn Repository>, storage: Arc<dyn Storage>, timer: &Timer) {
+    let a = timer.schedule_repeating(
+        chrono::Duration::minutes(CONFIG.reconcile.interval as i64),
+        move || {
+            trace!("performing reconciliation");
+            match reconcile::reconcile(&*db, &*storage, CONFIG.reconcile.dry_run) {
+                Ok(_) => (),
+                Err(e) => error!("Couldn't reconcile storage! {:?}", e),
+            }
+        },
+    );
+    a.ignore(); // ignore schedule guard a
+}
+
+/// Notification delivery task
+fn run_notify_thread(db: Arc<dyn Repository>, timer: &Timer) {
+    let a = timer.schedule_repeating(
+        chrono::Duration::seconds(CONFIG.notify.interval as i64),
+        move || {
+            trace!("delivering notifications");
+            match notify::deliver_pending(&*db) {
+                Ok(_) => (),
+                Err(e) => error!("Couldn't deliver notifications! {:?}", e),
+            }
+        },
+    );
+    a.ignore(); // ignore schedule guard a
+}
+
 /// youtube-dl update task
-fn run_update_thread<'a>(downloader: Arc<Downloader>, timer: &'a Timer) {
+fn run_update_thread(downloader: Arc<Downloader>, timer: &Timer) {
     let a = timer.schedule_repeating(chrono::Duration::hours(24), move || {
         match downloader.update_downloader() {
             Ok(_) => (),
@@ -222,3 +418,18 @@ fn run_update_thread<'a>(downloader: Arc<Downloader>, timer: &'a Timer) {
     });
     a.ignore(); // ignore schedule guard a
 }
+
+/// Install the ffmpeg build of the mirror if it changed
+fn update_ffmpeg() {
+    match ffmpeg::update(&CONFIG.ffmpeg, Path::new(&CONFIG.general.ffmpeg_bin_dir)) {
+        Ok(true) => info!("Installed new ffmpeg build"),
+        Ok(false) => (),
+        Err(e) => error!("Couldn't perform ffmpeg update! {:?}", e),
+    }
+}
+
+/// ffmpeg update task
+fn run_ffmpeg_update_thread(timer: &Timer) {
+    let a = timer.schedule_repeating(chrono::Duration::hours(24), update_ffmpeg);
+    a.ignore(); // ignore schedule guard a
+}
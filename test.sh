@@ -1,4 +1,5 @@
 #!/bin/bash
+# runs all tests, including the ones requiring a MySQL test DB
 ip="192.168.91.1"
 pw=""
 user="root"
@@ -8,4 +9,4 @@ downl="/mnt/hgfs/VM-WORK"
 ffmpeg="/mnt/hgfs/VM-WORK/ffmpeg-release-32bit/"
 mysql -h $ip -u $user -e 'drop database if exists test'
 mysql -h $ip -u $user -e 'create database test'
-db_test=true db_ip=$ip db_port="3306" db_user=yayd db_password=$pw db_db=test download_dir=$downl temp_dir=$temp mbps=$mbps ffmpeg_dir=$ffmpeg cargo test
+ip=$ip port="3306" user=yayd pass=$pw db=test download_dir=$downl temp_dir=$temp mbps=$mbps ffmpeg_dir=$ffmpeg cargo test -- --include-ignored
@@ -40,3 +40,81 @@ ALTER TABLE playlists KEY_BLOCK_SIZE=0;
 ALTER TABLE files KEY_BLOCK_SIZE=0;
 ALTER TABLE queryerror KEY_BLOCK_SIZE=0;
 ALTER TABLE subqueries KEY_BLOCK_SIZE=0;
+
+/* from 0.7.0 */
+CREATE TABLE `querystages` (
+ `qid` int(10) unsigned NOT NULL,
+ `stage` varchar(20) NOT NULL,
+ PRIMARY KEY (`qid`,`stage`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+ALTER TABLE `files`
+    ADD `cache_key` varchar(100) CHARACTER SET ascii DEFAULT NULL,
+    ADD KEY `cache_key` (`cache_key`);
+ALTER TABLE `files` ADD `size` bigint(20) unsigned DEFAULT NULL;
+ALTER TABLE `query_files` ADD `delete` tinyint(1) NOT NULL DEFAULT '0';
+CREATE TABLE `notifytargets` (
+ `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+ `uid` int(11) unsigned DEFAULT NULL,
+ `qid` int(10) unsigned DEFAULT NULL,
+ `kind` varchar(10) NOT NULL,
+ `target` varchar(255) NOT NULL,
+ PRIMARY KEY (`id`),
+ KEY `uid` (`uid`),
+ KEY `qid` (`qid`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+CREATE TABLE `notifyoutbox` (
+ `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
+ `qid` int(10) unsigned NOT NULL,
+ `kind` varchar(10) NOT NULL,
+ `target` varchar(255) NOT NULL,
+ `payload` text NOT NULL,
+ `attempts` smallint(6) NOT NULL DEFAULT '0',
+ `next_try` datetime NOT NULL,
+ PRIMARY KEY (`id`),
+ KEY `qid` (`qid`),
+ KEY `next_try` (`next_try`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+CREATE TABLE `queryevents` (
+ `id` bigint(20) unsigned NOT NULL AUTO_INCREMENT,
+ `qid` int(10) unsigned NOT NULL,
+ `created` datetime(3) NOT NULL,
+ `stage` varchar(20) NOT NULL,
+ `code` tinyint(4) DEFAULT NULL,
+ `worker` varchar(100) NOT NULL,
+ `message` text DEFAULT NULL,
+ PRIMARY KEY (`id`),
+ KEY `qid` (`qid`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+CREATE TABLE `credentials` (
+ `uid` int(11) unsigned NOT NULL,
+ `name` varchar(50) NOT NULL,
+ `kind` varchar(10) NOT NULL,
+ `data` mediumtext NOT NULL,
+ PRIMARY KEY (`uid`,`name`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+CREATE TABLE `querycredentials` (
+ `qid` int(10) unsigned NOT NULL,
+ `name` varchar(50) NOT NULL,
+ PRIMARY KEY (`qid`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+CREATE TABLE `ytdlversions` (
+ `host` varchar(100) NOT NULL,
+ `version` varchar(50) NOT NULL,
+ `channel` varchar(10) NOT NULL,
+ `updated` datetime NOT NULL,
+ PRIMARY KEY (`host`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+CREATE TABLE `playlistfailures` (
+ `qid` int(10) unsigned NOT NULL,
+ `idx` int(10) unsigned NOT NULL,
+ `url` varchar(125) NOT NULL,
+ `title` varchar(255) DEFAULT NULL,
+ `kind` varchar(20) NOT NULL,
+ `msg` text NOT NULL,
+ PRIMARY KEY (`qid`,`idx`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+CREATE TABLE `queryretries` (
+ `qid` int(10) unsigned NOT NULL,
+ `origin` int(10) unsigned NOT NULL,
+ PRIMARY KEY (`qid`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;